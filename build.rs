@@ -0,0 +1,30 @@
+use tonic_build::manual::{Builder, Method, Service};
+
+/// gRPC service of the peer-to-peer mode. The messages are the serde types of
+/// `client::peer_protocol`, so no .proto file is needed.
+fn main() {
+    let method = |name: &str, route_name: &str, input_type: &str, output_type: &str| {
+        Method::builder()
+            .name(name)
+            .route_name(route_name)
+            .input_type(format!("crate::client::peer_protocol::{}", input_type))
+            .output_type(format!("crate::client::peer_protocol::{}", output_type))
+            .codec_path("crate::client::peer_protocol::MessagePackCodec")
+    };
+    let peer_service = Service::builder()
+        .name("Peer")
+        .package("fs_synchronizer")
+        .method(
+            method("events", "Events", "EventsRequest", "PeerEvent")
+                .server_streaming()
+                .build(),
+        )
+        .method(method("list_files", "ListFiles", "ListFilesRequest", "FileList").build())
+        .method(method("file_hash", "FileHash", "FileRequest", "FileHash").build())
+        .method(method("get_file", "GetFile", "FileRequest", "FileContent").build())
+        .build();
+    // the clients are created on lazy channels: no need for the generated `connect`
+    Builder::new()
+        .build_transport(false)
+        .compile(&[peer_service]);
+}
@@ -0,0 +1,144 @@
+use crate::client::redis_client::{RedisClient, RedisPublishPayload};
+use anyhow::{bail, Context, Result};
+use log::debug;
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Delay between two resolutions of the redis host by the subscriber, which reconnects when the
+/// server moved
+const RESOLUTION_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A message received on a channel of the bus
+#[derive(Debug, Clone)]
+pub struct EventMessage {
+    pub channel: String,
+    pub payload: Vec<u8>,
+}
+
+/// Where the change events are published, and received by the peers
+pub trait EventBus: Debug + Send + Sync {
+    fn publish(&self, channel: &str, payload: RedisPublishPayload) -> Result<()>;
+
+    /// Subscribe to the channels matching the pattern, and call `on_message` with every message
+    /// received, or with None after `tick` without message. Returns when the connection is lost,
+    /// or when `on_message` fails.
+    fn listen(
+        &self,
+        pattern: &str,
+        tick: Duration,
+        on_message: &mut dyn FnMut(Option<EventMessage>) -> Result<()>,
+    ) -> Result<()>;
+
+    /// Pattern of the channels notifying the operations on the keys matching `key_pattern`,
+    /// or None when the bus does not see the key-value store
+    fn keyspace_pattern(&self, _key_pattern: &str) -> Option<String> {
+        None
+    }
+
+    /// The keyspace notifications enabled on the server, None when unknown
+    fn keyspace_notification_flags(&self) -> Result<Option<String>> {
+        Ok(None)
+    }
+}
+
+/// Compression of the large payloads, for the constrained links. The peers of the previous
+/// versions cannot read them: they are only compressed while every live peer can.
+#[derive(Debug, Clone, Default)]
+pub struct PayloadCompression {
+    /// Payloads up to this many bytes are never compressed. 0 disables the compression.
+    pub min_size: usize,
+    /// Whether every live peer decompresses the payloads, as their presence tells
+    pub peers_support: Arc<AtomicBool>,
+}
+
+impl PayloadCompression {
+    /// Size above which the payloads are compressed now, if any
+    fn compress_above(&self) -> Option<usize> {
+        if self.min_size > 0 && self.peers_support.load(Ordering::SeqCst) {
+            Some(self.min_size)
+        } else {
+            None
+        }
+    }
+}
+
+/// Events sent through the redis pub/sub
+#[derive(Debug, Clone)]
+pub struct RedisEventBus {
+    client: RedisClient,
+    compression: PayloadCompression,
+}
+
+impl RedisEventBus {
+    pub fn new(client: RedisClient, compression: PayloadCompression) -> RedisEventBus {
+        RedisEventBus {
+            client,
+            compression,
+        }
+    }
+}
+
+impl EventBus for RedisEventBus {
+    fn publish(&self, channel: &str, payload: RedisPublishPayload) -> Result<()> {
+        self.client
+            .publish(channel, payload, self.compression.compress_above())
+    }
+
+    fn listen(
+        &self,
+        pattern: &str,
+        tick: Duration,
+        on_message: &mut dyn FnMut(Option<EventMessage>) -> Result<()>,
+    ) -> Result<()> {
+        debug!("[event_bus] subscribing to redis...");
+        let mut connection = self
+            .client
+            .take_connection()
+            .context("unable to take connection to Redis server")?;
+        // the pooled connections are checked when they are taken, not this one
+        let address = connection.address();
+        let mut pubsub: redis::PubSub = connection.as_pubsub();
+        pubsub
+            .psubscribe(pattern)
+            .with_context(|| format!("unable to subscribe to redis channels `{}`", pattern))?;
+        pubsub.set_read_timeout(Some(tick))?;
+        let mut last_resolution = Instant::now();
+
+        loop {
+            if let Some(address) = address {
+                if last_resolution.elapsed() >= RESOLUTION_INTERVAL {
+                    last_resolution = Instant::now();
+                    match self.client.is_moved_from(address) {
+                        Ok(true) => bail!("the redis server is not at {} anymore", address),
+                        Ok(false) => (),
+                        // the server may still be there while the resolver is not
+                        Err(error) => debug!("[event_bus] {:#}", error),
+                    }
+                }
+            }
+            let msg = match pubsub.get_message() {
+                Err(error) if error.is_timeout() => {
+                    on_message(None)?;
+                    continue;
+                }
+                Err(error) => return Err(error).context("unable to get message from redis"),
+                Ok(msg) => msg,
+            };
+            on_message(Some(EventMessage {
+                channel: msg.get_channel_name().to_string(),
+                payload: msg.get_payload_bytes().to_vec(),
+            }))?;
+        }
+    }
+
+    /// Keyspace notifications of our database
+    fn keyspace_pattern(&self, key_pattern: &str) -> Option<String> {
+        Some(format!("__keyspace@{}__:{}", self.client.db(), key_pattern))
+    }
+
+    fn keyspace_notification_flags(&self) -> Result<Option<String>> {
+        self.client.config_get("notify-keyspace-events")
+    }
+}
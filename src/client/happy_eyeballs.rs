@@ -0,0 +1,183 @@
+use anyhow::{anyhow, bail, Context, Result};
+use log::{debug, info, warn};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+/// Delay before trying the next address while the previous attempts are still pending, as
+/// recommended by RFC 8305
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+/// An address not answering within this delay is given up
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A host reached through the first of its addresses answering, whatever its family. A
+/// dual-stack host may have one of its families unreachable from here.
+#[derive(Debug)]
+pub struct Endpoint {
+    host: String,
+    port: u16,
+    state: Mutex<EndpointState>,
+}
+
+#[derive(Debug, Default)]
+struct EndpointState {
+    /// Address of the last connection, used while it answers and the host resolves to it
+    working_address: Option<SocketAddr>,
+    /// Family of the last address answering, tried first by the next race
+    prefers_ipv6: Option<bool>,
+}
+
+impl Endpoint {
+    pub fn new(host: &str, port: u16) -> Endpoint {
+        Endpoint {
+            host: host.to_string(),
+            port,
+            state: Mutex::new(EndpointState::default()),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, EndpointState> {
+        self.state.lock().expect("endpoint state lock poisoned")
+    }
+
+    /// The address answering last, or the first one answering among the addresses of the host.
+    /// The host is resolved again each time, as it may have moved, like a Kubernetes service
+    /// whose pod is rescheduled.
+    pub fn address(&self) -> Result<SocketAddr> {
+        let working_address = self.lock().working_address;
+        let addresses = match (self.sorted_addresses(), working_address) {
+            (Ok(addresses), _) => addresses,
+            // the resolver may be down while the server is not
+            (Err(error), Some(address)) => {
+                warn!(
+                    "unable to resolve {}, connecting to {}. Error: {:#}",
+                    self.host, address, error
+                );
+                return Ok(address);
+            }
+            (Err(error), None) => return Err(error),
+        };
+        match working_address {
+            Some(address) if addresses.contains(&address) => return Ok(address),
+            Some(address) => info!(
+                "{} does not resolve to {} anymore, connecting to its new addresses",
+                self.host, address
+            ),
+            None => (),
+        }
+        let address = race(&addresses)
+            .with_context(|| format!("unable to connect to {}:{}", self.host, self.port))?;
+        let mut state = self.lock();
+        if state.prefers_ipv6 != Some(address.is_ipv6()) {
+            info!(
+                "{}:{} is reached over {}, at {}",
+                self.host,
+                self.port,
+                if address.is_ipv6() { "IPv6" } else { "IPv4" },
+                address
+            );
+        }
+        state.working_address = Some(address);
+        state.prefers_ipv6 = Some(address.is_ipv6());
+        Ok(address)
+    }
+
+    /// The address of the last connection, if any
+    pub fn working_address(&self) -> Option<SocketAddr> {
+        self.lock().working_address
+    }
+
+    /// Whether the host still resolves to this address
+    pub fn resolves_to(&self, address: SocketAddr) -> Result<bool> {
+        Ok(self.sorted_addresses()?.contains(&address))
+    }
+
+    /// The address does not answer anymore: the next connection races the addresses again
+    pub fn forget_address(&self) {
+        let mut state = self.lock();
+        if let Some(address) = state.working_address.take() {
+            debug!("[happy_eyeballs] {} does not answer anymore", address);
+        }
+    }
+
+    /// The addresses of the host, alternating the families, the preferred one first. IPv6 is
+    /// preferred until a family answers.
+    fn sorted_addresses(&self) -> Result<Vec<SocketAddr>> {
+        let addresses: Vec<SocketAddr> = (self.host.as_str(), self.port)
+            .to_socket_addrs()
+            .with_context(|| format!("unable to resolve {}", self.host))?
+            .collect();
+        let prefers_ipv6 = self.lock().prefers_ipv6.unwrap_or(true);
+        let (preferred, others): (Vec<SocketAddr>, Vec<SocketAddr>) = addresses
+            .iter()
+            .partition(|address| address.is_ipv6() == prefers_ipv6);
+        let mut sorted_addresses = Vec::with_capacity(addresses.len());
+        let (mut preferred, mut others) = (preferred.into_iter(), others.into_iter());
+        loop {
+            match (preferred.next(), others.next()) {
+                (None, None) => break,
+                (address, other_address) => {
+                    sorted_addresses.extend(address.into_iter().chain(other_address))
+                }
+            }
+        }
+        debug!(
+            "[happy_eyeballs] {} resolved to {:?}",
+            self.host, sorted_addresses
+        );
+        Ok(sorted_addresses)
+    }
+}
+
+/// Connect to the addresses in order, starting the next attempt when the previous one failed or
+/// is still pending after the attempt delay. Returns the first address connected to.
+fn race(addresses: &[SocketAddr]) -> Result<SocketAddr> {
+    if addresses.is_empty() {
+        bail!("no address");
+    }
+    let (sender, receiver) = channel();
+    let mut pending_attempts = 0;
+    let mut last_error = None;
+    let mut on_result = |result: Result<SocketAddr>, pending_attempts: &mut usize| match result {
+        Ok(address) => Some(address),
+        Err(error) => {
+            *pending_attempts -= 1;
+            last_error = Some(error);
+            None
+        }
+    };
+
+    for address in addresses.iter().copied() {
+        let sender = sender.clone();
+        // the losing attempts finish in the background, their connection is closed
+        std::thread::Builder::new()
+            .name(String::from("connection attempt"))
+            .spawn(move || {
+                let result = TcpStream::connect_timeout(&address, CONNECT_TIMEOUT)
+                    .map(|_| address)
+                    .with_context(|| format!("unable to connect to {}", address));
+                let _ = sender.send(result);
+            })
+            .context("unable to create connection attempt thread")?;
+        pending_attempts += 1;
+        match receiver.recv_timeout(CONNECTION_ATTEMPT_DELAY) {
+            Ok(result) => {
+                if let Some(address) = on_result(result, &mut pending_attempts) {
+                    return Ok(address);
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => unreachable!("the sender is still held"),
+        }
+    }
+    while pending_attempts > 0 {
+        let result = receiver
+            .recv()
+            .map_err(|_| anyhow!("connection attempts lost"))?;
+        if let Some(address) = on_result(result, &mut pending_attempts) {
+            return Ok(address);
+        }
+    }
+    Err(last_error.unwrap_or_else(|| anyhow!("no address answered")))
+}
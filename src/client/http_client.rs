@@ -0,0 +1,252 @@
+use anyhow::{anyhow, bail, Context, Result};
+use log::debug;
+use std::convert::TryFrom;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Timeout of every request, so that a dead server cannot block the handlers
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Minimal HTTP/1.1 client, enough to talk to the JSON and object storage APIs.
+/// One connection per request.
+#[derive(Clone)]
+pub struct HttpClient {
+    host: String,
+    port: u16,
+    /// None for `http://` addresses
+    tls: Option<Arc<rustls::ClientConfig>>,
+}
+
+#[derive(Debug)]
+pub struct HttpResponse {
+    pub status: u16,
+    /// Names in lower case
+    headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        let name = name.to_ascii_lowercase();
+        self.headers
+            .iter()
+            .find(|(header_name, _)| *header_name == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+impl std::fmt::Debug for HttpClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HttpClient")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("tls", &self.tls.is_some())
+            .finish()
+    }
+}
+
+impl HttpClient {
+    /// Parse a `http[s]://host[:port]` address. The certificates are checked against the system ones.
+    pub fn new(address: &str) -> Result<HttpClient> {
+        let url =
+            url::Url::parse(address).with_context(|| format!("invalid address {}", address))?;
+        let tls = match url.scheme() {
+            "http" => None,
+            "https" => Some(Arc::new(HttpClient::tls_config()?)),
+            scheme => bail!(
+                "address must start with http:// or https://, got {}",
+                scheme
+            ),
+        };
+        let host = url
+            .host_str()
+            .ok_or_else(|| anyhow!("address has no host: {}", url))?
+            .to_string();
+        let port = url
+            .port_or_known_default()
+            .expect("http urls always have a known default port");
+        Ok(HttpClient { host, port, tls })
+    }
+
+    /// Value of the Host header: the port is omitted when it is the default one
+    pub fn host_header(&self) -> String {
+        match (self.tls.is_some(), self.port) {
+            (false, 80) | (true, 443) => self.host.clone(),
+            (_, port) => format!("{}:{}", self.host, port),
+        }
+    }
+
+    pub fn request(
+        &self,
+        method: &str,
+        path: &str,
+        headers: &[(&str, String)],
+        body: &[u8],
+    ) -> Result<HttpResponse> {
+        debug!("[http_client] sending {} {}", method, path);
+        let mut request = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+            method,
+            path,
+            self.host_header(),
+            body.len()
+        );
+        for (name, value) in headers {
+            request.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        request.push_str("\r\n");
+        let mut request = request.into_bytes();
+        request.extend_from_slice(body);
+
+        let tcp_stream = TcpStream::connect((self.host.as_str(), self.port))
+            .with_context(|| format!("unable to connect to {}:{}", self.host, self.port))?;
+        tcp_stream.set_read_timeout(Some(HTTP_TIMEOUT))?;
+        tcp_stream.set_write_timeout(Some(HTTP_TIMEOUT))?;
+        let raw_response = match &self.tls {
+            None => HttpClient::exchange(tcp_stream, &request),
+            Some(tls) => {
+                let server_name = rustls::pki_types::ServerName::try_from(self.host.clone())
+                    .context("invalid host name")?;
+                let connection = rustls::ClientConnection::new(tls.clone(), server_name)
+                    .context("unable to start the TLS session")?;
+                HttpClient::exchange(rustls::StreamOwned::new(connection, tcp_stream), &request)
+            }
+        }
+        .with_context(|| format!("error during the request to {}", self.host))?;
+        HttpClient::parse_response(&raw_response)
+    }
+
+    /// Certificates checked against the system ones
+    pub fn tls_config() -> Result<rustls::ClientConfig> {
+        let mut roots = rustls::RootCertStore::empty();
+        for certificate in rustls_native_certs::load_native_certs()
+            .context("unable to load the system certificates")?
+        {
+            // an unparsable system certificate should not prevent using the others
+            if let Err(error) = roots.add(certificate) {
+                debug!("[http_client] ignoring system certificate: {}", error);
+            }
+        }
+        Ok(rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth())
+    }
+
+    fn exchange(mut stream: impl Read + Write, request: &[u8]) -> std::io::Result<Vec<u8>> {
+        stream.write_all(request)?;
+        stream.flush()?;
+        let mut response = Vec::new();
+        match stream.read_to_end(&mut response) {
+            // some servers close the TLS connection without notifying it
+            Err(error)
+                if error.kind() == std::io::ErrorKind::UnexpectedEof && !response.is_empty() =>
+            {
+                Ok(response)
+            }
+            Err(error) => Err(error),
+            Ok(_) => Ok(response),
+        }
+    }
+
+    fn parse_response(raw_response: &[u8]) -> Result<HttpResponse> {
+        let header_end = raw_response
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .ok_or_else(|| anyhow!("truncated HTTP response"))?;
+        let head = String::from_utf8_lossy(&raw_response[..header_end]);
+        let body = &raw_response[header_end + 4..];
+
+        let mut lines = head.split("\r\n");
+        let status: u16 = lines
+            .next()
+            .and_then(|status_line| status_line.split(' ').nth(1))
+            .and_then(|status| status.parse().ok())
+            .ok_or_else(|| anyhow!("invalid HTTP status line"))?;
+        let headers: Vec<(String, String)> = lines
+            .filter_map(|header| header.split_once(':'))
+            .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_string()))
+            .collect();
+        let mut response = HttpResponse {
+            status,
+            headers,
+            body: Vec::new(),
+        };
+        response.body = match response.header("transfer-encoding") {
+            Some(encoding) if encoding.contains("chunked") => HttpClient::decode_chunked(body)?,
+            _ => body.to_vec(),
+        };
+        Ok(response)
+    }
+
+    fn decode_chunked(mut body: &[u8]) -> Result<Vec<u8>> {
+        let mut decoded = Vec::with_capacity(body.len());
+        loop {
+            let size_end = body
+                .windows(2)
+                .position(|window| window == b"\r\n")
+                .ok_or_else(|| anyhow!("truncated HTTP chunk"))?;
+            let size_line = String::from_utf8_lossy(&body[..size_end]);
+            let size_hex = size_line.split(';').next().unwrap_or("").trim();
+            let size = usize::from_str_radix(size_hex, 16)
+                .with_context(|| format!("invalid HTTP chunk size: {}", size_hex))?;
+            if size == 0 {
+                return Ok(decoded);
+            }
+            let chunk_start = size_end + 2;
+            if body.len() < chunk_start + size + 2 {
+                bail!("truncated HTTP chunk");
+            }
+            decoded.extend_from_slice(&body[chunk_start..chunk_start + size]);
+            body = &body[chunk_start + size + 2..];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_the_chunks() {
+        let body = b"4\r\nWiki\r\n6;name=value\r\npedia \r\nE\r\nin \r\n\r\nchunks.\r\n0\r\nExpires: never\r\n\r\n";
+        assert_eq!(
+            HttpClient::decode_chunked(body).unwrap(),
+            b"Wikipedia in \r\n\r\nchunks."
+        );
+    }
+
+    #[test]
+    fn rejects_the_truncated_chunks() {
+        assert!(HttpClient::decode_chunked(b"a\r\nshort\r\n").is_err());
+        assert!(HttpClient::decode_chunked(b"4\r\nWiki\r\n").is_err());
+        assert!(HttpClient::decode_chunked(b"zz\r\nWiki\r\n0\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn parses_a_chunked_response() {
+        let response = HttpClient::parse_response(
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("content-type"), Some("text/plain"));
+        assert_eq!(response.body, b"hello");
+    }
+
+    #[test]
+    fn parses_a_response_with_a_length() {
+        let response =
+            HttpClient::parse_response(b"HTTP/1.1 404 Not Found\r\nContent-Length: 4\r\n\r\nnope")
+                .unwrap();
+        assert_eq!(response.status, 404);
+        assert!(!response.is_success());
+        assert_eq!(response.body, b"nope");
+        assert!(HttpClient::parse_response(b"HTTP/1.1 200 OK\r\n").is_err());
+    }
+}
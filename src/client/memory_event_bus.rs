@@ -0,0 +1,68 @@
+use crate::client::event_bus::{EventBus, EventMessage};
+use crate::client::redis_client::RedisPublishPayload;
+use anyhow::{Context, Result};
+use crossbeam_channel::{RecvTimeoutError, Sender};
+use log::debug;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Channels pattern of a listener, and where its messages go
+type Listener = (glob::Pattern, Sender<EventMessage>);
+
+/// Events delivered to the listeners of the same process, so that the event handlers can be
+/// tested without a redis server. The clones share the listeners.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryEventBus {
+    listeners: Arc<Mutex<Vec<Listener>>>,
+}
+
+impl MemoryEventBus {
+    pub fn new() -> MemoryEventBus {
+        MemoryEventBus::default()
+    }
+}
+
+impl EventBus for MemoryEventBus {
+    /// The listeners gone are dropped
+    fn publish(&self, channel: &str, payload: RedisPublishPayload) -> Result<()> {
+        debug!("[memory_event_bus] publishing on {} {:?}", channel, payload);
+        let message = EventMessage {
+            channel: channel.to_string(),
+            payload: rmp_serde::to_vec(&payload).expect(
+                "messagepack serialization of RedisPublishPayload messages should never fail",
+            ),
+        };
+        self.listeners
+            .lock()
+            .expect("memory event bus lock poisoned")
+            .retain(|(channels, listener)| {
+                !channels.matches(channel) || listener.send(message.clone()).is_ok()
+            });
+        Ok(())
+    }
+
+    /// Returns only when `on_message` fails
+    fn listen(
+        &self,
+        pattern: &str,
+        tick: Duration,
+        on_message: &mut dyn FnMut(Option<EventMessage>) -> Result<()>,
+    ) -> Result<()> {
+        let channels = glob::Pattern::new(pattern)
+            .with_context(|| format!("invalid channel pattern {}", pattern))?;
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        self.listeners
+            .lock()
+            .expect("memory event bus lock poisoned")
+            .push((channels, sender));
+        loop {
+            match receiver.recv_timeout(tick) {
+                Ok(message) => on_message(Some(message))?,
+                Err(RecvTimeoutError::Timeout) => on_message(None)?,
+                Err(RecvTimeoutError::Disconnected) => {
+                    unreachable!("the bus holds the sender while we listen")
+                }
+            }
+        }
+    }
+}
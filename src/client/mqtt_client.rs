@@ -0,0 +1,407 @@
+use crate::client::event_bus::{EventBus, EventMessage};
+use crate::client::http_client::HttpClient;
+use crate::client::redis_client::RedisPublishPayload;
+use anyhow::{anyhow, bail, Context, Result};
+use log::debug;
+use std::convert::TryFrom;
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Timeout of the publications and of the connection handshake
+const MQTT_TIMEOUT: Duration = Duration::from_secs(10);
+/// The broker drops the connections silent for 1.5 times this delay
+const KEEP_ALIVE: Duration = Duration::from_secs(60);
+const DEFAULT_MQTT_PORT: u16 = 1883;
+const DEFAULT_MQTTS_PORT: u16 = 8883;
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PUBACK: u8 = 0x40;
+const SUBSCRIBE: u8 = 0x82;
+const SUBACK: u8 = 0x90;
+const PINGREQ: u8 = 0xc0;
+const PINGRESP: u8 = 0xd0;
+
+trait ReadWrite: Read + Write + Send {}
+impl<T: Read + Write + Send> ReadWrite for T {}
+
+/// Minimal MQTT 3.1.1 client: publications and subscriptions with QoS 0 or 1, clean sessions.
+/// `mqtt://[user:password@]host[:port]`, or `mqtts://` for TLS.
+#[derive(Clone)]
+pub struct MqttClient {
+    host: String,
+    port: u16,
+    is_tls: bool,
+    user: Option<String>,
+    password: Option<String>,
+    /// Prefix of the topics, followed by `/` and the channel
+    topic_prefix: String,
+    /// 0: at most once, 1: at least once
+    qos: u8,
+    /// Connection used to publish, opened on first use and reopened after an error
+    publisher: Arc<Mutex<Option<MqttConnection>>>,
+}
+
+impl std::fmt::Debug for MqttClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MqttClient")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("is_tls", &self.is_tls)
+            .field("user", &self.user)
+            .field("topic_prefix", &self.topic_prefix)
+            .field("qos", &self.qos)
+            .finish()
+    }
+}
+
+struct MqttConnection {
+    stream: Box<dyn ReadWrite>,
+    /// Bytes received and not parsed yet
+    buffer: Vec<u8>,
+    next_packet_id: u16,
+    last_sent: Instant,
+}
+
+/// A control packet: the type and flags byte, and what follows the remaining length
+struct Packet {
+    header: u8,
+    body: Vec<u8>,
+}
+
+impl MqttClient {
+    /// Create new client, ensuring that the connection to the broker is OK
+    pub fn new(mqtt_url: &str, topic_prefix: String, qos: u8) -> Result<MqttClient> {
+        let url = url::Url::parse(mqtt_url).context("invalid MQTT url")?;
+        let (is_tls, default_port) = match url.scheme() {
+            "mqtt" => (false, DEFAULT_MQTT_PORT),
+            "mqtts" => (true, DEFAULT_MQTTS_PORT),
+            scheme => bail!(
+                "MQTT url must start with mqtt:// or mqtts://, got {}",
+                scheme
+            ),
+        };
+        if qos > 1 {
+            bail!("only the QoS 0 and 1 are supported, got {}", qos);
+        }
+        let decode = |value: &str| -> Result<String> {
+            Ok(percent_encoding::percent_decode_str(value)
+                .decode_utf8()?
+                .into_owned())
+        };
+        let client = MqttClient {
+            host: url
+                .host_str()
+                .ok_or_else(|| anyhow!("MQTT url has no host: {}", mqtt_url))?
+                .to_string(),
+            port: url.port().unwrap_or(default_port),
+            is_tls,
+            user: match url.username() {
+                "" => None,
+                user => Some(decode(user)?),
+            },
+            password: url.password().map(decode).transpose()?,
+            topic_prefix: topic_prefix.trim_end_matches('/').to_string(),
+            qos,
+            publisher: Arc::new(Mutex::new(None)),
+        };
+        let connection = client
+            .connect(MQTT_TIMEOUT)
+            .with_context(|| format!("unable to connect to the MQTT broker {}", client.host))?;
+        *client
+            .publisher
+            .lock()
+            .expect("MQTT publisher lock poisoned") = Some(connection);
+        Ok(client)
+    }
+
+    fn to_topic(&self, channel: &str) -> String {
+        format!("{}/{}", self.topic_prefix, channel)
+    }
+
+    /// Open a connection, and authenticate. `read_timeout` applies once the connection is established.
+    fn connect(&self, read_timeout: Duration) -> Result<MqttConnection> {
+        debug!("[mqtt_client] connecting to {}:{}", self.host, self.port);
+        let tcp_stream = TcpStream::connect((self.host.as_str(), self.port))
+            .with_context(|| format!("unable to connect to {}:{}", self.host, self.port))?;
+        tcp_stream.set_read_timeout(Some(MQTT_TIMEOUT))?;
+        tcp_stream.set_write_timeout(Some(MQTT_TIMEOUT))?;
+        // the TLS stream hides the socket, whose timeout is changed after the handshake
+        let socket = tcp_stream
+            .try_clone()
+            .context("unable to clone the MQTT socket")?;
+        let stream: Box<dyn ReadWrite> = if self.is_tls {
+            let server_name = rustls::pki_types::ServerName::try_from(self.host.clone())
+                .context("invalid host name")?;
+            let tls_connection =
+                rustls::ClientConnection::new(Arc::new(HttpClient::tls_config()?), server_name)
+                    .context("unable to start the TLS session")?;
+            Box::new(rustls::StreamOwned::new(tls_connection, tcp_stream))
+        } else {
+            Box::new(tcp_stream)
+        };
+
+        let mut flags = 0x02; // clean session
+        let mut payload = Vec::new();
+        push_string(
+            &mut payload,
+            &format!("fs-synchronizer-{:016x}", rand::random::<u64>()),
+        );
+        if let Some(user) = &self.user {
+            flags |= 0x80;
+            push_string(&mut payload, user);
+        }
+        if let Some(password) = &self.password {
+            flags |= 0x40;
+            push_string(&mut payload, password);
+        }
+        let mut body = Vec::new();
+        push_string(&mut body, "MQTT");
+        body.push(4); // protocol level of 3.1.1
+        body.push(flags);
+        body.extend_from_slice(&(KEEP_ALIVE.as_secs() as u16).to_be_bytes());
+        body.extend_from_slice(&payload);
+
+        let mut connection = MqttConnection {
+            stream,
+            buffer: Vec::new(),
+            next_packet_id: 1,
+            last_sent: Instant::now(),
+        };
+        connection.send(CONNECT, &body)?;
+        let connack = connection.wait_packet(CONNACK)?;
+        match connack.body.get(1) {
+            Some(0) => (),
+            Some(4) | Some(5) => bail!("the MQTT broker refused the credentials"),
+            Some(code) => bail!("the MQTT broker refused the connection (code {})", code),
+            None => bail!("invalid CONNACK from the MQTT broker"),
+        }
+        socket.set_read_timeout(Some(read_timeout))?;
+        Ok(connection)
+    }
+
+    fn publish_on(
+        &self,
+        connection: &mut MqttConnection,
+        topic: &str,
+        payload: &[u8],
+    ) -> Result<()> {
+        let mut body = Vec::new();
+        push_string(&mut body, topic);
+        let packet_id = connection.take_packet_id();
+        if self.qos == 1 {
+            body.extend_from_slice(&packet_id.to_be_bytes());
+        }
+        body.extend_from_slice(payload);
+        connection.send(PUBLISH | (self.qos << 1), &body)?;
+        if self.qos == 1 {
+            connection.wait_packet(PUBACK).map(|_| ())
+        } else {
+            // the answer tells that the broker received the publication
+            connection.send(PINGREQ, &[])?;
+            connection.wait_packet(PINGRESP).map(|_| ())
+        }
+    }
+}
+
+impl EventBus for MqttClient {
+    /// The broker closes the silent connections, so a failed publication is retried once on a new one.
+    /// The peers may receive the event twice, which is harmless.
+    fn publish(&self, channel: &str, payload: RedisPublishPayload) -> Result<()> {
+        let topic = self.to_topic(channel);
+        debug!("[mqtt_client] sending PUBLISH {} {:?}", topic, payload);
+        let serialized_payload = rmp_serde::to_vec(&payload)
+            .expect("messagepack serialization of RedisPublishPayload messages should never fail");
+        let mut publisher = self.publisher.lock().expect("MQTT publisher lock poisoned");
+        if let Some(connection) = publisher.as_mut() {
+            match self.publish_on(connection, &topic, &serialized_payload) {
+                Ok(()) => return Ok(()),
+                Err(error) => debug!(
+                    "[mqtt_client] publication failed, reconnecting. Error: {:?}",
+                    error
+                ),
+            }
+        }
+        *publisher = None;
+        let mut connection = self.connect(MQTT_TIMEOUT)?;
+        self.publish_on(&mut connection, &topic, &serialized_payload)
+            .context("error during the MQTT PUBLISH")?;
+        *publisher = Some(connection);
+        Ok(())
+    }
+
+    fn listen(
+        &self,
+        pattern: &str,
+        tick: Duration,
+        on_message: &mut dyn FnMut(Option<EventMessage>) -> Result<()>,
+    ) -> Result<()> {
+        let topic = self.to_topic(pattern);
+        debug!("[mqtt_client] subscribing to {}...", topic);
+        let mut connection = self.connect(tick)?;
+        let mut body = connection.take_packet_id().to_be_bytes().to_vec();
+        push_string(&mut body, &topic);
+        body.push(self.qos);
+        connection.send(SUBSCRIBE, &body)?;
+
+        loop {
+            if connection.last_sent.elapsed() >= KEEP_ALIVE / 2 {
+                connection.send(PINGREQ, &[])?;
+            }
+            let packet = match connection.next_packet()? {
+                None => {
+                    on_message(None)?;
+                    continue;
+                }
+                Some(packet) => packet,
+            };
+            match packet.header & 0xf0 {
+                PUBLISH => {
+                    let qos = (packet.header >> 1) & 0x03;
+                    let (topic, mut payload_start) = read_string(&packet.body)?;
+                    if qos > 0 {
+                        let packet_id = packet
+                            .body
+                            .get(payload_start..payload_start + 2)
+                            .ok_or_else(|| anyhow!("truncated MQTT PUBLISH"))?
+                            .to_vec();
+                        payload_start += 2;
+                        connection.send(PUBACK, &packet_id)?;
+                    }
+                    let channel = topic
+                        .strip_prefix(&format!("{}/", self.topic_prefix))
+                        .unwrap_or(&topic)
+                        .to_string();
+                    on_message(Some(EventMessage {
+                        channel,
+                        payload: packet.body[payload_start..].to_vec(),
+                    }))?;
+                }
+                SUBACK => {
+                    if packet.body.get(2) == Some(&0x80) {
+                        bail!("the MQTT broker refused the subscription to {}", topic);
+                    }
+                    // the subscription is active
+                    on_message(None)?;
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+impl MqttConnection {
+    fn take_packet_id(&mut self) -> u16 {
+        let packet_id = self.next_packet_id;
+        // 0 is not a valid packet id
+        self.next_packet_id = self.next_packet_id.checked_add(1).unwrap_or(1);
+        packet_id
+    }
+
+    fn send(&mut self, header: u8, body: &[u8]) -> Result<()> {
+        let mut packet = vec![header];
+        let mut remaining_length = body.len();
+        loop {
+            let mut byte = (remaining_length % 128) as u8;
+            remaining_length /= 128;
+            if remaining_length > 0 {
+                byte |= 0x80;
+            }
+            packet.push(byte);
+            if remaining_length == 0 {
+                break;
+            }
+        }
+        packet.extend_from_slice(body);
+        self.stream
+            .write_all(&packet)
+            .and_then(|_| self.stream.flush())
+            .context("unable to send to the MQTT broker")?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+
+    /// Read until a packet of this type, skipping the others
+    fn wait_packet(&mut self, packet_type: u8) -> Result<Packet> {
+        loop {
+            match self.next_packet()? {
+                None => bail!("no answer from the MQTT broker"),
+                Some(packet) if packet.header & 0xf0 == packet_type => return Ok(packet),
+                Some(packet) => {
+                    debug!("[mqtt_client] skipping packet of type {:#x}", packet.header)
+                }
+            }
+        }
+    }
+
+    /// The next packet of the broker, or None when nothing came before the read timeout
+    fn next_packet(&mut self) -> Result<Option<Packet>> {
+        loop {
+            if let Some(packet) = self.parse_packet()? {
+                return Ok(Some(packet));
+            }
+            let mut chunk = [0u8; 8192];
+            match self.stream.read(&mut chunk) {
+                Ok(0) => bail!("connection closed by the MQTT broker"),
+                Ok(length) => self.buffer.extend_from_slice(&chunk[..length]),
+                Err(error)
+                    if error.kind() == ErrorKind::WouldBlock
+                        || error.kind() == ErrorKind::TimedOut =>
+                {
+                    return Ok(None)
+                }
+                Err(error) => return Err(error).context("unable to read from the MQTT broker"),
+            }
+        }
+    }
+
+    /// Take the first complete packet out of the buffer
+    fn parse_packet(&mut self) -> Result<Option<Packet>> {
+        let mut remaining_length = 0usize;
+        let mut position = 1;
+        loop {
+            let byte = match self.buffer.get(position) {
+                None => return Ok(None),
+                Some(byte) => *byte,
+            };
+            remaining_length += ((byte & 0x7f) as usize) << (7 * (position - 1));
+            position += 1;
+            if byte & 0x80 == 0 {
+                break;
+            }
+            if position > 4 {
+                bail!("invalid remaining length in MQTT packet");
+            }
+        }
+        if self.buffer.len() < position + remaining_length {
+            return Ok(None);
+        }
+        let packet = Packet {
+            header: self.buffer[0],
+            body: self.buffer[position..position + remaining_length].to_vec(),
+        };
+        self.buffer.drain(..position + remaining_length);
+        Ok(Some(packet))
+    }
+}
+
+/// Append an UTF-8 string prefixed by its length
+fn push_string(buffer: &mut Vec<u8>, value: &str) {
+    buffer.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    buffer.extend_from_slice(value.as_bytes());
+}
+
+/// Read a string prefixed by its length, and return the position after it
+fn read_string(buffer: &[u8]) -> Result<(String, usize)> {
+    let length = match buffer {
+        [high, low, ..] => u16::from_be_bytes([*high, *low]) as usize,
+        _ => bail!("truncated MQTT string"),
+    };
+    let value = buffer
+        .get(2..2 + length)
+        .ok_or_else(|| anyhow!("truncated MQTT string"))?;
+    Ok((String::from_utf8_lossy(value).into_owned(), 2 + length))
+}
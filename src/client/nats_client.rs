@@ -0,0 +1,332 @@
+use crate::client::event_bus::{EventBus, EventMessage};
+use crate::client::http_client::HttpClient;
+use crate::client::redis_client::RedisPublishPayload;
+use anyhow::{anyhow, bail, Context, Result};
+use log::{debug, warn};
+use serde::Deserialize;
+use serde_json::json;
+use std::convert::TryFrom;
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Timeout of the publications and of the connection handshake
+const NATS_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_NATS_PORT: u16 = 4222;
+
+trait ReadWrite: Read + Write + Send {}
+impl<T: Read + Write + Send> ReadWrite for T {}
+
+/// The fields of the server INFO we care about
+#[derive(Debug, Default, Deserialize)]
+struct ServerInfo {
+    #[serde(default)]
+    tls_required: bool,
+}
+
+/// A protocol message sent by the server
+#[derive(Debug)]
+enum ServerOp {
+    Msg(EventMessage),
+    Ping,
+    Pong,
+    Ok,
+    Info,
+    Err(String),
+}
+
+/// Minimal client of the NATS core protocol: publications, and subscriptions without queue groups.
+/// `nats://[user:password@]host[:port]`, or `nats://token@host[:port]`. `tls://` requires TLS,
+/// which is also used when the server requires it.
+#[derive(Clone)]
+pub struct NatsClient {
+    host: String,
+    port: u16,
+    is_tls_required: bool,
+    user: Option<String>,
+    password: Option<String>,
+    /// Connection used to publish, opened on first use and reopened after an error
+    publisher: Arc<Mutex<Option<NatsConnection>>>,
+}
+
+impl std::fmt::Debug for NatsClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NatsClient")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("is_tls_required", &self.is_tls_required)
+            .field("user", &self.user)
+            .finish()
+    }
+}
+
+struct NatsConnection {
+    stream: Box<dyn ReadWrite>,
+    /// Bytes received and not parsed yet
+    buffer: Vec<u8>,
+}
+
+impl NatsClient {
+    /// Create new client, ensuring that the connection to the NATS server is OK
+    pub fn new(nats_url: &str) -> Result<NatsClient> {
+        let url = url::Url::parse(nats_url).context("invalid NATS url")?;
+        let is_tls_required = match url.scheme() {
+            "nats" => false,
+            "tls" => true,
+            scheme => bail!("NATS url must start with nats:// or tls://, got {}", scheme),
+        };
+        let user = match url.username() {
+            "" => None,
+            user => Some(
+                percent_encoding::percent_decode_str(user)
+                    .decode_utf8()?
+                    .into_owned(),
+            ),
+        };
+        let password = match url.password() {
+            None => None,
+            Some(password) => Some(
+                percent_encoding::percent_decode_str(password)
+                    .decode_utf8()?
+                    .into_owned(),
+            ),
+        };
+        let client = NatsClient {
+            host: url
+                .host_str()
+                .ok_or_else(|| anyhow!("NATS url has no host: {}", nats_url))?
+                .to_string(),
+            port: url.port().unwrap_or(DEFAULT_NATS_PORT),
+            is_tls_required,
+            user,
+            password,
+            publisher: Arc::new(Mutex::new(None)),
+        };
+        let connection = client
+            .connect(NATS_TIMEOUT)
+            .with_context(|| format!("unable to connect to the NATS server {}", client.host))?;
+        *client
+            .publisher
+            .lock()
+            .expect("NATS publisher lock poisoned") = Some(connection);
+        Ok(client)
+    }
+
+    /// Open a connection, and authenticate. `read_timeout` applies once the connection is established.
+    fn connect(&self, read_timeout: Duration) -> Result<NatsConnection> {
+        debug!("[nats_client] connecting to {}:{}", self.host, self.port);
+        let mut tcp_stream = TcpStream::connect((self.host.as_str(), self.port))
+            .with_context(|| format!("unable to connect to {}:{}", self.host, self.port))?;
+        tcp_stream.set_read_timeout(Some(NATS_TIMEOUT))?;
+        tcp_stream.set_write_timeout(Some(NATS_TIMEOUT))?;
+
+        // the INFO is sent in clear, before the TLS handshake
+        let info_line = NatsClient::read_info_line(&mut tcp_stream)?;
+        let info: ServerInfo = serde_json::from_str(info_line.trim_start_matches("INFO").trim())
+            .context("invalid INFO from the NATS server")?;
+        // the TLS stream hides the socket, whose timeout is changed after the handshake
+        let socket = tcp_stream
+            .try_clone()
+            .context("unable to clone the NATS socket")?;
+        let stream: Box<dyn ReadWrite> = if self.is_tls_required || info.tls_required {
+            let server_name = rustls::pki_types::ServerName::try_from(self.host.clone())
+                .context("invalid host name")?;
+            let tls_connection =
+                rustls::ClientConnection::new(Arc::new(HttpClient::tls_config()?), server_name)
+                    .context("unable to start the TLS session")?;
+            Box::new(rustls::StreamOwned::new(tls_connection, tcp_stream))
+        } else {
+            Box::new(tcp_stream)
+        };
+
+        let mut connect_options = json!({
+            "verbose": false,
+            "pedantic": false,
+            "lang": "rust",
+            "name": "fs-synchronizer",
+            "version": env!("CARGO_PKG_VERSION"),
+            "protocol": 1,
+        });
+        match (&self.user, &self.password) {
+            (Some(user), Some(password)) => {
+                connect_options["user"] = json!(user);
+                connect_options["pass"] = json!(password);
+            }
+            (Some(token), None) => connect_options["auth_token"] = json!(token),
+            _ => (),
+        }
+        let mut connection = NatsConnection {
+            stream,
+            buffer: Vec::new(),
+        };
+        connection.send(format!("CONNECT {}\r\nPING\r\n", connect_options).as_bytes())?;
+        connection
+            .wait_pong()
+            .context("the NATS server refused the connection")?;
+        socket.set_read_timeout(Some(read_timeout))?;
+        Ok(connection)
+    }
+
+    /// Read byte by byte, so that nothing after the INFO line is consumed before the TLS handshake
+    fn read_info_line(tcp_stream: &mut TcpStream) -> Result<String> {
+        let mut line = Vec::new();
+        let mut byte = [0u8; 1];
+        while !line.ends_with(b"\r\n") {
+            if tcp_stream.read(&mut byte)? == 0 {
+                bail!("connection closed by the NATS server");
+            }
+            line.push(byte[0]);
+        }
+        let line = String::from_utf8_lossy(&line).trim().to_string();
+        if !line.starts_with("INFO") {
+            bail!("unexpected greeting from the NATS server: {}", line);
+        }
+        Ok(line)
+    }
+
+    fn publish_on(connection: &mut NatsConnection, subject: &str, payload: &[u8]) -> Result<()> {
+        let mut message = format!("PUB {} {}\r\n", subject, payload.len()).into_bytes();
+        message.extend_from_slice(payload);
+        // the PONG tells that the publication was processed
+        message.extend_from_slice(b"\r\nPING\r\n");
+        connection.send(&message)?;
+        connection.wait_pong()
+    }
+}
+
+impl EventBus for NatsClient {
+    /// The server closes the idle connections, so a failed publication is retried once on a new one.
+    /// The peers may receive the event twice, which is harmless.
+    fn publish(&self, channel: &str, payload: RedisPublishPayload) -> Result<()> {
+        debug!("[nats_client] sending PUB {} {:?}", channel, payload);
+        let serialized_payload = rmp_serde::to_vec(&payload)
+            .expect("messagepack serialization of RedisPublishPayload messages should never fail");
+        let mut publisher = self.publisher.lock().expect("NATS publisher lock poisoned");
+        if let Some(connection) = publisher.as_mut() {
+            match NatsClient::publish_on(connection, channel, &serialized_payload) {
+                Ok(()) => return Ok(()),
+                Err(error) => debug!(
+                    "[nats_client] publication failed, reconnecting. Error: {:?}",
+                    error
+                ),
+            }
+        }
+        *publisher = None;
+        let mut connection = self.connect(NATS_TIMEOUT)?;
+        NatsClient::publish_on(&mut connection, channel, &serialized_payload)
+            .context("error during the NATS PUB")?;
+        *publisher = Some(connection);
+        Ok(())
+    }
+
+    fn listen(
+        &self,
+        pattern: &str,
+        tick: Duration,
+        on_message: &mut dyn FnMut(Option<EventMessage>) -> Result<()>,
+    ) -> Result<()> {
+        debug!("[nats_client] subscribing to {}...", pattern);
+        let mut connection = self.connect(tick)?;
+        connection.send(format!("SUB {} 1\r\nPING\r\n", pattern).as_bytes())?;
+        loop {
+            match connection.next_op()? {
+                None => on_message(None)?,
+                Some(ServerOp::Msg(message)) => on_message(Some(message))?,
+                Some(ServerOp::Ping) => connection.send(b"PONG\r\n")?,
+                // the answer to our PING: the subscription is active
+                Some(ServerOp::Pong) => on_message(None)?,
+                Some(ServerOp::Ok) | Some(ServerOp::Info) => (),
+                Some(ServerOp::Err(error)) => bail!("NATS server error: {}", error),
+            }
+        }
+    }
+}
+
+impl NatsConnection {
+    fn send(&mut self, bytes: &[u8]) -> Result<()> {
+        self.stream
+            .write_all(bytes)
+            .and_then(|_| self.stream.flush())
+            .context("unable to send to the NATS server")
+    }
+
+    /// Read until the PONG, answering the PINGs of the server meanwhile
+    fn wait_pong(&mut self) -> Result<()> {
+        loop {
+            match self.next_op()? {
+                None => bail!("no answer from the NATS server"),
+                Some(ServerOp::Pong) => return Ok(()),
+                Some(ServerOp::Ping) => self.send(b"PONG\r\n")?,
+                Some(ServerOp::Err(error)) => bail!("NATS server error: {}", error),
+                Some(ServerOp::Msg(message)) => {
+                    warn!("unexpected NATS message on {}", message.channel)
+                }
+                Some(ServerOp::Ok) | Some(ServerOp::Info) => (),
+            }
+        }
+    }
+
+    /// The next message of the server, or None when nothing came before the read timeout
+    fn next_op(&mut self) -> Result<Option<ServerOp>> {
+        loop {
+            if let Some(op) = self.parse_op()? {
+                return Ok(Some(op));
+            }
+            let mut chunk = [0u8; 8192];
+            match self.stream.read(&mut chunk) {
+                Ok(0) => bail!("connection closed by the NATS server"),
+                Ok(length) => self.buffer.extend_from_slice(&chunk[..length]),
+                Err(error)
+                    if error.kind() == ErrorKind::WouldBlock
+                        || error.kind() == ErrorKind::TimedOut =>
+                {
+                    return Ok(None)
+                }
+                Err(error) => return Err(error).context("unable to read from the NATS server"),
+            }
+        }
+    }
+
+    /// Take the first complete message out of the buffer
+    fn parse_op(&mut self) -> Result<Option<ServerOp>> {
+        let line_end = match self.buffer.windows(2).position(|window| window == b"\r\n") {
+            None => return Ok(None),
+            Some(line_end) => line_end,
+        };
+        let line = String::from_utf8_lossy(&self.buffer[..line_end]).into_owned();
+        let mut words = line.split_whitespace();
+        let op = match words.next().map(|op| op.to_ascii_uppercase()).as_deref() {
+            Some("MSG") => {
+                // MSG <subject> <sid> [reply-to] <size>
+                let arguments: Vec<&str> = words.collect();
+                let (subject, size) = match arguments.as_slice() {
+                    [subject, _, size] | [subject, _, _, size] => (subject, size),
+                    _ => bail!("invalid MSG from the NATS server: {}", line),
+                };
+                let size: usize = size
+                    .parse()
+                    .with_context(|| format!("invalid MSG size: {}", size))?;
+                let payload_start = line_end + 2;
+                if self.buffer.len() < payload_start + size + 2 {
+                    return Ok(None);
+                }
+                let payload = self.buffer[payload_start..payload_start + size].to_vec();
+                let message = EventMessage {
+                    channel: subject.to_string(),
+                    payload,
+                };
+                self.buffer.drain(..payload_start + size + 2);
+                return Ok(Some(ServerOp::Msg(message)));
+            }
+            Some("PING") => ServerOp::Ping,
+            Some("PONG") => ServerOp::Pong,
+            Some("+OK") => ServerOp::Ok,
+            Some("INFO") => ServerOp::Info,
+            Some("-ERR") => ServerOp::Err(line[4..].trim().trim_matches('\'').to_string()),
+            _ => bail!("unexpected message from the NATS server: {}", line),
+        };
+        self.buffer.drain(..line_end + 2);
+        Ok(Some(op))
+    }
+}
@@ -0,0 +1,269 @@
+use crate::client::event_bus::{EventBus, EventMessage};
+use crate::client::peer_protocol::grpc::peer_client::PeerClient as GrpcPeerClient;
+use crate::client::peer_protocol::grpc::peer_server::PeerServer;
+use crate::client::peer_protocol::{EventsRequest, FileRequest, ListFilesRequest, PeerEvent};
+use crate::client::peer_server::LocalFilesService;
+use crate::client::redis_client::RedisPublishPayload;
+use crate::event_handler::local_files_event_handler::UploadPolicy;
+use anyhow::{bail, Context, Result};
+use log::{debug, info, warn};
+use std::collections::BTreeSet;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::runtime::Runtime;
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::TcpListenerStream;
+use tokio_stream::{StreamExt, StreamMap};
+use tonic::transport::{Channel, Endpoint, Server};
+use tonic::Status;
+
+/// Timeout of the connections and of the requests to the peers
+const PEER_TIMEOUT: Duration = Duration::from_secs(10);
+/// Detects the peers gone without closing the connection of the events stream
+const PEER_KEEP_ALIVE: Duration = Duration::from_secs(30);
+/// Events kept for a slow peer before it is disconnected, and has to reconcile
+pub const PUBLISHED_EVENTS_CAPACITY: usize = 1024;
+/// The events held may carry their content
+pub const LOW_MEMORY_PUBLISHED_EVENTS_CAPACITY: usize = 64;
+/// While some peers are unreachable, the events of the others are listened to, and the
+/// subscription is restarted after this delay to retry them
+const UNREACHABLE_PEERS_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Exchange the events and the contents directly with the peers, through gRPC.
+/// Every instance serves its local files and streams the events it publishes to the peers
+/// subscribed to them. The peers are given on the command line, as `host:port`.
+#[derive(Debug, Clone)]
+pub struct PeerClient {
+    /// The blocking code of the handlers calls the gRPC clients and server through it
+    runtime: Arc<Runtime>,
+    peers: Vec<(String, GrpcPeerClient<Channel>)>,
+    /// The events published by this instance, streamed to the subscribed peers
+    published: broadcast::Sender<PeerEvent>,
+}
+
+impl PeerClient {
+    /// The connections to the peers are opened on first use, and reopened after an error
+    pub fn new(peer_addresses: &[String], published_events_capacity: usize) -> Result<PeerClient> {
+        if peer_addresses.is_empty() {
+            bail!("at least one peer is required");
+        }
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .thread_name("peer runtime")
+            .enable_all()
+            .build()
+            .context("unable to start the runtime of the peer connections")?;
+        // the lazy channels spawn their connection task on the runtime
+        let runtime_guard = runtime.enter();
+        let mut peers = Vec::with_capacity(peer_addresses.len());
+        for address in peer_addresses {
+            let channel = Endpoint::from_shared(format!("http://{}", address))
+                .with_context(|| format!("invalid peer address {}", address))?
+                .connect_timeout(PEER_TIMEOUT)
+                .timeout(PEER_TIMEOUT)
+                .http2_keep_alive_interval(PEER_KEEP_ALIVE)
+                .keep_alive_while_idle(true)
+                .connect_lazy();
+            // the contents are sent in one message
+            let client = GrpcPeerClient::new(channel).max_decoding_message_size(usize::MAX);
+            peers.push((address.clone(), client));
+        }
+        drop(runtime_guard);
+        let (published, _) = broadcast::channel(published_events_capacity);
+        Ok(PeerClient {
+            runtime: Arc::new(runtime),
+            peers,
+            published,
+        })
+    }
+
+    /// Serve the files under the roots to the peers, in the background
+    pub fn serve(
+        &self,
+        listen_address: SocketAddr,
+        roots: &[PathBuf],
+        upload_policy: UploadPolicy,
+    ) -> Result<()> {
+        let listener = self
+            .runtime
+            .block_on(tokio::net::TcpListener::bind(listen_address))
+            .with_context(|| format!("unable to listen on {}", listen_address))?;
+        let service = LocalFilesService::new(roots, upload_policy, self.published.clone());
+        info!("serving the local files to the peers on {}", listen_address);
+        self.runtime.spawn(async move {
+            let result = Server::builder()
+                .http2_keepalive_interval(Some(PEER_KEEP_ALIVE))
+                .add_service(PeerServer::new(service))
+                .serve_with_incoming(TcpListenerStream::new(listener))
+                .await;
+            if let Err(error) = result {
+                panic!("Error in the peer server: {:?}", error);
+            }
+        });
+        Ok(())
+    }
+
+    /// The files held by the reachable peers
+    pub fn list_files(&self) -> Result<BTreeSet<PathBuf>> {
+        let mut files = BTreeSet::new();
+        let mut is_any_reachable = false;
+        for (address, client) in &self.peers {
+            let mut client = client.clone();
+            match self
+                .runtime
+                .block_on(async move { client.list_files(ListFilesRequest {}).await })
+            {
+                Ok(response) => {
+                    is_any_reachable = true;
+                    files.extend(response.into_inner().paths);
+                }
+                Err(status) => warn!(
+                    "unable to list the files of the peer {}: {}",
+                    address,
+                    status.message()
+                ),
+            }
+        }
+        if !is_any_reachable {
+            bail!("no peer is reachable");
+        }
+        Ok(files)
+    }
+
+    /// Hash of the file on the first reachable peer holding it
+    pub fn file_hash(&self, path: &Path) -> Result<Option<u64>> {
+        self.first_holder(path, |mut client, request| async move {
+            client
+                .file_hash(request)
+                .await
+                .map(|response| response.into_inner().hash)
+        })
+    }
+
+    /// Hash and compressed content of the file on the first reachable peer holding it
+    pub fn get_file(&self, path: &Path) -> Result<Option<(u64, Vec<u8>)>> {
+        self.first_holder(path, |mut client, request| async move {
+            client
+                .get_file(request)
+                .await
+                .map(|response| response.into_inner().content)
+        })
+    }
+
+    /// Ask the peers in turn, until one holds the file
+    fn first_holder<T, F, R>(&self, path: &Path, request: F) -> Result<Option<T>>
+    where
+        F: Fn(GrpcPeerClient<Channel>, FileRequest) -> R,
+        R: Future<Output = std::result::Result<Option<T>, Status>>,
+    {
+        let mut is_any_reachable = false;
+        for (address, client) in &self.peers {
+            let file_request = FileRequest {
+                path: path.to_path_buf(),
+            };
+            match self.runtime.block_on(request(client.clone(), file_request)) {
+                Ok(Some(answer)) => return Ok(Some(answer)),
+                Ok(None) => is_any_reachable = true,
+                Err(status) => debug!(
+                    "[peer_client] unable to ask {} for {}: {}",
+                    address,
+                    path.display(),
+                    status.message()
+                ),
+            }
+        }
+        if !is_any_reachable {
+            bail!("no peer is reachable");
+        }
+        Ok(None)
+    }
+}
+
+impl EventBus for PeerClient {
+    /// The event is lost for the peers not subscribed: they reconcile when they subscribe
+    fn publish(&self, channel: &str, payload: RedisPublishPayload) -> Result<()> {
+        debug!("[peer_client] publishing on {} {:?}", channel, payload);
+        let event = PeerEvent {
+            channel: channel.to_string(),
+            payload,
+        };
+        if self.published.send(event).is_err() {
+            debug!("[peer_client] no peer is subscribed to the events");
+        }
+        Ok(())
+    }
+
+    fn listen(
+        &self,
+        pattern: &str,
+        tick: Duration,
+        on_message: &mut dyn FnMut(Option<EventMessage>) -> Result<()>,
+    ) -> Result<()> {
+        let channels = glob::Pattern::new(pattern)
+            .with_context(|| format!("invalid channel pattern {}", pattern))?;
+        let mut streams = StreamMap::new();
+        for (address, client) in &self.peers {
+            debug!("[peer_client] subscribing to the events of {}...", address);
+            let mut client = client.clone();
+            match self
+                .runtime
+                .block_on(async move { client.events(EventsRequest {}).await })
+            {
+                Ok(response) => {
+                    streams.insert(address.clone(), response.into_inner());
+                }
+                Err(status) => warn!(
+                    "unable to subscribe to the events of the peer {}: {}",
+                    address,
+                    status.message()
+                ),
+            }
+        }
+        if streams.is_empty() {
+            bail!("no peer is reachable");
+        }
+        let subscribed_peers = streams.len();
+        let subscribed_at = Instant::now();
+
+        loop {
+            if streams.len() < subscribed_peers {
+                bail!("a peer closed its events stream");
+            }
+            if subscribed_peers < self.peers.len()
+                && subscribed_at.elapsed() >= UNREACHABLE_PEERS_RETRY_INTERVAL
+            {
+                bail!(
+                    "{} peers are unreachable",
+                    self.peers.len() - subscribed_peers
+                );
+            }
+            let next_event = self
+                .runtime
+                .block_on(async { tokio::time::timeout(tick, streams.next()).await });
+            let event = match next_event {
+                // no event during the tick
+                Err(_) => None,
+                Ok(None) => bail!("all the peers closed their events stream"),
+                Ok(Some((address, Err(status)))) => bail!(
+                    "lost the events stream of the peer {}: {}",
+                    address,
+                    status.message()
+                ),
+                Ok(Some((_, Ok(event)))) if channels.matches(&event.channel) => Some(event),
+                Ok(Some((_, Ok(event)))) => {
+                    debug!("[peer_client] ignoring event on {}", event.channel);
+                    None
+                }
+            };
+            on_message(event.map(|event| EventMessage {
+                channel: event.channel,
+                payload: rmp_serde::to_vec(&event.payload).expect(
+                    "messagepack serialization of RedisPublishPayload messages should never fail",
+                ),
+            }))?;
+        }
+    }
+}
@@ -0,0 +1,111 @@
+use crate::client::redis_client::RedisPublishPayload;
+use bytes::{Buf, BufMut};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::marker::PhantomData;
+use std::path::PathBuf;
+use tonic::codec::{Codec, DecodeBuf, Decoder, EncodeBuf, Encoder};
+use tonic::Status;
+
+/// Client and server of the `fs_synchronizer.Peer` gRPC service, generated by build.rs
+#[allow(clippy::all)]
+pub mod grpc {
+    include!(concat!(env!("OUT_DIR"), "/fs_synchronizer.Peer.rs"));
+}
+
+/// Subscription to the events published by the peer
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct EventsRequest {}
+
+/// An event published by the peer: the same payloads as the ones sent through redis
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerEvent {
+    pub channel: String,
+    pub payload: RedisPublishPayload,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ListFilesRequest {}
+
+/// The files held by the peer
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FileList {
+    pub paths: Vec<PathBuf>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileRequest {
+    pub path: PathBuf,
+}
+
+/// None when the peer does not hold the file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileHash {
+    pub hash: Option<u64>,
+}
+
+/// None when the peer does not hold the file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileContent {
+    /// (hash, compressed content)
+    pub content: Option<(u64, Vec<u8>)>,
+}
+
+/// Encodes the messages with messagepack, as the redis payloads
+#[derive(Debug)]
+pub struct MessagePackCodec<E, D>(PhantomData<(E, D)>);
+
+impl<E, D> Default for MessagePackCodec<E, D> {
+    fn default() -> Self {
+        MessagePackCodec(PhantomData)
+    }
+}
+
+impl<E, D> Codec for MessagePackCodec<E, D>
+where
+    E: Serialize + Send + 'static,
+    D: DeserializeOwned + Send + 'static,
+{
+    type Encode = E;
+    type Decode = D;
+    type Encoder = MessagePackEncoder<E>;
+    type Decoder = MessagePackDecoder<D>;
+
+    fn encoder(&mut self) -> Self::Encoder {
+        MessagePackEncoder(PhantomData)
+    }
+
+    fn decoder(&mut self) -> Self::Decoder {
+        MessagePackDecoder(PhantomData)
+    }
+}
+
+#[derive(Debug)]
+pub struct MessagePackEncoder<E>(PhantomData<E>);
+
+impl<E: Serialize> Encoder for MessagePackEncoder<E> {
+    type Item = E;
+    type Error = Status;
+
+    fn encode(&mut self, item: E, dst: &mut EncodeBuf<'_>) -> Result<(), Status> {
+        let serialized_item = rmp_serde::to_vec(&item)
+            .map_err(|error| Status::internal(format!("unable to encode message: {}", error)))?;
+        dst.put_slice(&serialized_item);
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct MessagePackDecoder<D>(PhantomData<D>);
+
+impl<D: DeserializeOwned> Decoder for MessagePackDecoder<D> {
+    type Item = D;
+    type Error = Status;
+
+    fn decode(&mut self, src: &mut DecodeBuf<'_>) -> Result<Option<D>, Status> {
+        let serialized_item = src.copy_to_bytes(src.remaining());
+        rmp_serde::from_slice(&serialized_item)
+            .map(Some)
+            .map_err(|error| Status::internal(format!("unable to decode message: {}", error)))
+    }
+}
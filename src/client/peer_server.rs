@@ -0,0 +1,145 @@
+use crate::client::peer_protocol::grpc::peer_server::Peer;
+use crate::client::peer_protocol::{
+    EventsRequest, FileContent, FileHash, FileList, FileRequest, ListFilesRequest, PeerEvent,
+};
+use crate::event_handler::local_files_event_handler::UploadPolicy;
+use crate::store::local_fs_store::LocalFSStore;
+use log::debug;
+use std::path::{Component, Path, PathBuf};
+use std::pin::Pin;
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::{Stream, StreamExt};
+use tonic::{Request, Response, Status};
+
+/// Serves the local files to the peers, and streams them the events published by this instance.
+/// Only the files this instance would publish are served.
+#[derive(Debug)]
+pub struct LocalFilesService {
+    /// The watched paths, as given and canonicalized, as the events may use both
+    roots: Vec<PathBuf>,
+    upload_policy: UploadPolicy,
+    published: broadcast::Sender<PeerEvent>,
+}
+
+impl LocalFilesService {
+    pub fn new(
+        roots: &[PathBuf],
+        upload_policy: UploadPolicy,
+        published: broadcast::Sender<PeerEvent>,
+    ) -> LocalFilesService {
+        let mut served_roots = roots.to_vec();
+        served_roots.extend(roots.iter().filter_map(|root| root.canonicalize().ok()));
+        served_roots.sort();
+        served_roots.dedup();
+        LocalFilesService {
+            roots: served_roots,
+            upload_policy,
+            published,
+        }
+    }
+
+    fn is_served(&self, path: &Path) -> bool {
+        path.is_absolute()
+            && !path
+                .components()
+                .any(|component| component == Component::ParentDir)
+            && self.roots.iter().any(|root| path.starts_with(root))
+            && !self.upload_policy.no_upload.matches(path)
+            && !self.upload_policy.is_ignored(path)
+            && !LocalFSStore::is_placeholder(path)
+            && path.is_file()
+    }
+
+    /// The unreadable directories are skipped: the other files can still be synchronized
+    fn list_served_files(&self, directory: &Path, files: &mut Vec<PathBuf>) {
+        let entries = match std::fs::read_dir(directory) {
+            Err(error) => {
+                debug!(
+                    "[peer_server] unable to list directory {}: {}",
+                    directory.display(),
+                    error
+                );
+                return;
+            }
+            Ok(entries) => entries,
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                self.list_served_files(&path, files);
+            } else if self.is_served(&path) {
+                files.push(path);
+            }
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Peer for LocalFilesService {
+    type EventsStream = Pin<Box<dyn Stream<Item = Result<PeerEvent, Status>> + Send>>;
+
+    /// A peer too slow to receive the events gets an error, and has to reconcile
+    // the stream items are imposed by tonic
+    #[allow(clippy::result_large_err)]
+    async fn events(
+        &self,
+        request: Request<EventsRequest>,
+    ) -> Result<Response<Self::EventsStream>, Status> {
+        debug!(
+            "[peer_server] {:?} subscribed to the events",
+            request.remote_addr()
+        );
+        let events = BroadcastStream::new(self.published.subscribe()).map(|event| {
+            event.map_err(|BroadcastStreamRecvError::Lagged(count)| {
+                Status::data_loss(format!("{} events were dropped", count))
+            })
+        });
+        Ok(Response::new(Box::pin(events)))
+    }
+
+    async fn list_files(
+        &self,
+        _request: Request<ListFilesRequest>,
+    ) -> Result<Response<FileList>, Status> {
+        let mut paths = Vec::new();
+        tokio::task::block_in_place(|| {
+            for root in self.roots.iter() {
+                if root.is_dir() {
+                    self.list_served_files(root, &mut paths);
+                } else if self.is_served(root) {
+                    paths.push(root.clone());
+                }
+            }
+        });
+        paths.sort();
+        paths.dedup();
+        Ok(Response::new(FileList { paths }))
+    }
+
+    async fn file_hash(&self, request: Request<FileRequest>) -> Result<Response<FileHash>, Status> {
+        let path = request.into_inner().path;
+        let hash = if self.is_served(&path) {
+            tokio::task::block_in_place(|| LocalFSStore::local_hash(&path)).ok()
+        } else {
+            None
+        };
+        Ok(Response::new(FileHash { hash }))
+    }
+
+    async fn get_file(
+        &self,
+        request: Request<FileRequest>,
+    ) -> Result<Response<FileContent>, Status> {
+        let path = request.into_inner().path;
+        let content = if self.is_served(&path) {
+            tokio::task::block_in_place(|| LocalFSStore::local_file_content_compressed(&path))
+                .ok()
+                .map(|(content, hash)| (hash, content))
+        } else {
+            None
+        };
+        Ok(Response::new(FileContent { content }))
+    }
+}
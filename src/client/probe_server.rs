@@ -0,0 +1,114 @@
+use crate::client::redis_latency::RedisLatencies;
+use anyhow::{Context, Result};
+use log::{debug, info};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// A probe not sending its request within this delay is dropped, so that it cannot block the others
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Whether the instances can be relied upon. Cloning it gives a handle on the same state.
+#[derive(Debug, Clone, Default)]
+pub struct Readiness {
+    /// The first synchronization of every instance is done
+    is_synchronized: Arc<AtomicBool>,
+    is_terminating: Arc<AtomicBool>,
+}
+
+impl Readiness {
+    pub fn set_synchronized(&self) {
+        self.is_synchronized.store(true, Ordering::Relaxed);
+    }
+
+    pub fn set_terminating(&self) {
+        self.is_terminating.store(true, Ordering::Relaxed);
+    }
+
+    /// None when ready
+    fn not_ready_reason(&self) -> Option<&'static str> {
+        if self.is_terminating.load(Ordering::Relaxed) {
+            Some("terminating")
+        } else if !self.is_synchronized.load(Ordering::Relaxed) {
+            Some("first synchronization in progress")
+        } else {
+            None
+        }
+    }
+}
+
+/// Serves the liveness and readiness probes of an orchestrator such as Kubernetes, over plain
+/// HTTP: `/healthz` answers while the process runs, `/readyz` once the instances are synchronized
+/// and until they terminate. `/metrics` gives the latency of the redis commands to Prometheus.
+#[derive(Debug, Clone)]
+pub struct ProbeServer {
+    readiness: Readiness,
+}
+
+impl ProbeServer {
+    pub fn new(readiness: Readiness) -> ProbeServer {
+        ProbeServer { readiness }
+    }
+
+    pub fn serve(self, listen_address: SocketAddr) -> Result<JoinHandle<()>> {
+        let listener = TcpListener::bind(listen_address)
+            .with_context(|| format!("unable to listen on {}", listen_address))?;
+        info!("serving the probes on http://{}", listen_address);
+        let handle = std::thread::Builder::new()
+            .name(String::from("probe server"))
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    let result = stream
+                        .context("unable to accept the probe connection")
+                        .and_then(|stream| self.answer(stream));
+                    if let Err(error) = result {
+                        debug!("[probe_server] {:#}", error);
+                    }
+                }
+            })
+            .context("unable to create probe server thread")?;
+        Ok(handle)
+    }
+
+    /// One request per connection: the probes do not reuse them
+    fn answer(&self, mut stream: TcpStream) -> Result<()> {
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        let mut request_line = String::new();
+        BufReader::new(&stream)
+            .read_line(&mut request_line)
+            .context("unable to read the probe request")?;
+        let mut parts = request_line.split_whitespace();
+        let (method, target) = (parts.next(), parts.next());
+        let path = target.map(|target| target.split('?').next().unwrap_or(target));
+        let (status, body) = match (method, path) {
+            (Some("GET"), Some("/healthz")) => ("200 OK", String::from("ok\n")),
+            (Some("GET"), Some("/readyz")) => match self.readiness.not_ready_reason() {
+                None => ("200 OK", String::from("ready\n")),
+                Some(reason) => ("503 Service Unavailable", format!("{}\n", reason)),
+            },
+            (Some("GET"), Some("/metrics")) => ("200 OK", RedisLatencies::render()),
+            (Some("GET"), _) => ("404 Not Found", String::from("not found\n")),
+            _ => (
+                "405 Method Not Allowed",
+                String::from("method not allowed\n"),
+            ),
+        };
+        debug!(
+            "[probe_server] {} {}: {}",
+            method.unwrap_or_default(),
+            target.unwrap_or_default(),
+            status
+        );
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        )
+        .context("unable to answer the probe")
+    }
+}
@@ -1,19 +1,190 @@
-use anyhow::{anyhow, bail, Context, Result};
-use log::{debug, error};
-use r2d2_redis::{r2d2, redis, RedisConnectionManager};
+use crate::client::happy_eyeballs::Endpoint;
+use crate::client::redis_latency::RedisLatencies;
+use crate::store::local_fs_store::{Compression, LocalFSStore};
+use anyhow::{bail, Context, Result};
+use log::{debug, warn};
+use redis::IntoConnectionInfo;
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Instant;
 
-type RedisConnection = r2d2::PooledConnection<r2d2_redis::RedisConnectionManager>;
-type RedisPool = r2d2::Pool<r2d2_redis::RedisConnectionManager>;
+type RedisConnection = r2d2::PooledConnection<ConnectionManager>;
+type RedisPool = r2d2::Pool<ConnectionManager>;
+
+/// Connections opened to the redis server
+pub const DEFAULT_POOL_SIZE: u32 = 15;
+/// The pub/sub holds one of them, the other threads take turns on the others
+pub const LOW_MEMORY_POOL_SIZE: u32 = 3;
+
+/// KEYS[1]: key to set
+/// ARGV: expected value, new value
+const COMPARE_AND_SET_SCRIPT: &str = r"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    redis.call('SET', KEYS[1], ARGV[2])
+    return 1
+end
+return 0
+";
+
+/// First byte of the compressed event payloads. Messagepack never uses it, so that the payloads
+/// are told apart from the uncompressed ones.
+const COMPRESSED_PAYLOAD_MARKER: u8 = 0xc1;
+
+/// Settings of the `rediss://` connections
+#[derive(Debug, Clone, Default)]
+pub struct TlsOptions {
+    /// PEM bundle of the certificate authorities trusted instead of the system ones
+    pub ca_certificates: Option<PathBuf>,
+    /// Do not verify the server certificate. For test setups only.
+    pub insecure: bool,
+}
+
+/// ACL credentials given apart from the URL, so that they do not show in `ps`
+#[derive(Debug, Clone, Default)]
+pub struct Credentials {
+    /// ACL user. The `default` user is used when there is none.
+    pub user: Option<String>,
+    /// File holding the password, on its first line
+    pub password_file: Option<PathBuf>,
+}
+
+/// Opens the connections of the pool. The plain TCP connections go to the address of the host
+/// which answered first, as a dual-stack server may not be reachable over both families. The
+/// TLS connections resolve the host themselves: its certificate is verified against its name.
+#[derive(Debug)]
+pub struct ConnectionManager {
+    client: redis::Client,
+    endpoint: Option<Arc<Endpoint>>,
+}
+
+impl ConnectionManager {
+    fn new(client: redis::Client) -> ConnectionManager {
+        let endpoint = match &client.get_connection_info().addr {
+            redis::ConnectionAddr::Tcp(host, port) => Some(Arc::new(Endpoint::new(host, *port))),
+            _ => None,
+        };
+        ConnectionManager { client, endpoint }
+    }
+}
+
+impl ConnectionManager {
+    /// Opened to an address the host does not resolve to anymore, or which stopped answering
+    fn is_stale(&self, connection: &AddressedConnection) -> bool {
+        match (&self.endpoint, connection.address) {
+            (Some(endpoint), Some(address)) => endpoint.working_address() != Some(address),
+            _ => false,
+        }
+    }
+}
+
+impl r2d2::ManageConnection for ConnectionManager {
+    type Connection = AddressedConnection;
+    type Error = redis::RedisError;
+
+    fn connect(&self) -> Result<AddressedConnection, redis::RedisError> {
+        let endpoint = match &self.endpoint {
+            None => {
+                return Ok(AddressedConnection {
+                    connection: self.client.get_connection()?,
+                    address: None,
+                })
+            }
+            Some(endpoint) => endpoint,
+        };
+        let address = endpoint.address().map_err(|error| {
+            redis::RedisError::from(std::io::Error::new(
+                std::io::ErrorKind::ConnectionRefused,
+                format!("{:#}", error),
+            ))
+        })?;
+        let mut connection_info = self.client.get_connection_info().clone();
+        connection_info.addr = redis::ConnectionAddr::Tcp(address.ip().to_string(), address.port());
+        match redis::Client::open(connection_info).and_then(|client| client.get_connection()) {
+            Ok(connection) => Ok(AddressedConnection {
+                connection,
+                address: Some(address),
+            }),
+            Err(error) => {
+                endpoint.forget_address();
+                Err(error)
+            }
+        }
+    }
+
+    fn is_valid(&self, connection: &mut AddressedConnection) -> Result<(), redis::RedisError> {
+        if self.is_stale(connection) {
+            return Err(redis::RedisError::from(std::io::Error::new(
+                std::io::ErrorKind::ConnectionAborted,
+                "closing a connection to the previous address of the redis server",
+            )));
+        }
+        self.client.is_valid(&mut connection.connection)
+    }
+
+    fn has_broken(&self, connection: &mut AddressedConnection) -> bool {
+        self.is_stale(connection) || self.client.has_broken(&mut connection.connection)
+    }
+}
+
+/// A pooled connection, and the address it was opened to when the host is resolved by us
+pub struct AddressedConnection {
+    connection: redis::Connection,
+    address: Option<SocketAddr>,
+}
+
+impl AddressedConnection {
+    pub fn as_pubsub(&mut self) -> redis::PubSub<'_> {
+        self.connection.as_pubsub()
+    }
+
+    pub fn address(&self) -> Option<SocketAddr> {
+        self.address
+    }
+}
+
+impl redis::ConnectionLike for AddressedConnection {
+    fn req_packed_command(&mut self, cmd: &[u8]) -> redis::RedisResult<redis::Value> {
+        let started = Instant::now();
+        let result = self.connection.req_packed_command(cmd);
+        RedisLatencies::record(cmd, started.elapsed());
+        result
+    }
+
+    fn req_packed_commands(
+        &mut self,
+        cmd: &[u8],
+        offset: usize,
+        count: usize,
+    ) -> redis::RedisResult<Vec<redis::Value>> {
+        self.connection.req_packed_commands(cmd, offset, count)
+    }
+
+    fn get_db(&self) -> i64 {
+        self.connection.get_db()
+    }
+
+    fn check_connection(&mut self) -> bool {
+        self.connection.check_connection()
+    }
+
+    fn is_open(&self) -> bool {
+        self.connection.is_open()
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct RedisClient {
     pub redis_url: String,
+    /// Index of the redis database
+    db: i64,
     connection_pool: RedisPool,
+    /// The host resolved by us, for the plain TCP connections
+    endpoint: Option<Arc<Endpoint>>,
 }
 
-#[derive(Debug, PartialEq, Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub enum RedisPublishPayload {
     /// Emitter id, hash, then Path
     NewFile(u64, u64, PathBuf),
@@ -23,29 +194,151 @@ pub enum RedisPublishPayload {
     RemovedFile(u64, PathBuf),
     /// Emitter id, then Path, and Path
     RenamedFile(u64, PathBuf, PathBuf),
+    /// Emitter id, then Path whose content key is missing from the store
+    ContentMissing(u64, PathBuf),
+    /// Emitter id. The staged instances apply all the events they are holding.
+    RolloutApproved(u64),
+    /// Emitter id, hash, Path, then compressed content, small enough to skip the fetch
+    InlineNewFile(u64, u64, PathBuf, #[serde(with = "serde_bytes")] Vec<u8>),
+    /// Emitter id, hash, Path, then compressed content, small enough to skip the fetch
+    InlineModifiedFile(u64, u64, PathBuf, #[serde(with = "serde_bytes")] Vec<u8>),
+    /// Emitter id, Path whose content fetched from the store is unusable, then the reason
+    ContentRejected(u64, PathBuf, String),
+    /// Emitter id, request id, ids of the instances which must apply the remote state of the
+    /// Paths now, then acknowledge it
+    PullRequested(u64, u64, Vec<u64>, Vec<PathBuf>),
+    /// Emitter id, id of the request acknowledged, then the Paths which failed to apply and why
+    PullAcknowledged(u64, u64, Vec<(PathBuf, String)>),
+    /// Generation of the change in the store, then the event of the change. The peers drop the
+    /// events older than the last change they received for the same files.
+    Stamped(u64, Box<RedisPublishPayload>),
 }
 
 impl RedisPublishPayload {
+    /// The paths of the files changed by the event
+    pub fn get_changed_paths(&self) -> Vec<PathBuf> {
+        use RedisPublishPayload::*;
+        match self {
+            NewFile(_, _, path)
+            | ModifiedFile(_, _, path)
+            | InlineNewFile(_, _, path, _)
+            | InlineModifiedFile(_, _, path, _)
+            | RemovedFile(_, path) => vec![path.clone()],
+            RenamedFile(_, old_path, new_path) => vec![old_path.clone(), new_path.clone()],
+            Stamped(_, payload) => payload.get_changed_paths(),
+            ContentMissing(_, _)
+            | ContentRejected(_, _, _)
+            | RolloutApproved(_)
+            | PullRequested(_, _, _, _)
+            | PullAcknowledged(_, _, _) => Vec::new(),
+        }
+    }
+
+    /// The event with the paths of its files converted by `map`, e.g. to their local paths.
+    /// None when `map` converts none of them. A rename out of the converted paths becomes a
+    /// removal, and a rename into them a new file of unknown hash. The paths of the pulls are
+    /// left as they are.
+    pub fn map_paths(self, map: impl Fn(&Path) -> Option<PathBuf>) -> Option<RedisPublishPayload> {
+        use RedisPublishPayload::*;
+        let payload = match self {
+            NewFile(emitter_id, hash, path) => NewFile(emitter_id, hash, map(&path)?),
+            ModifiedFile(emitter_id, hash, path) => ModifiedFile(emitter_id, hash, map(&path)?),
+            InlineNewFile(emitter_id, hash, path, content) => {
+                InlineNewFile(emitter_id, hash, map(&path)?, content)
+            }
+            InlineModifiedFile(emitter_id, hash, path, content) => {
+                InlineModifiedFile(emitter_id, hash, map(&path)?, content)
+            }
+            RemovedFile(emitter_id, path) => RemovedFile(emitter_id, map(&path)?),
+            RenamedFile(emitter_id, old_path, new_path) => match (map(&old_path), map(&new_path)) {
+                (Some(old_path), Some(new_path)) => RenamedFile(emitter_id, old_path, new_path),
+                (Some(old_path), None) => RemovedFile(emitter_id, old_path),
+                // the dummy hash never matches, so that the content is fetched
+                (None, Some(new_path)) => NewFile(emitter_id, 0, new_path),
+                (None, None) => return None,
+            },
+            ContentMissing(emitter_id, path) => ContentMissing(emitter_id, map(&path)?),
+            ContentRejected(emitter_id, path, reason) => {
+                ContentRejected(emitter_id, map(&path)?, reason)
+            }
+            Stamped(generation, payload) => Stamped(generation, Box::new(payload.map_paths(map)?)),
+            RolloutApproved(_) | PullRequested(_, _, _, _) | PullAcknowledged(_, _, _) => self,
+        };
+        Some(payload)
+    }
+
     pub fn get_emitter_id(&self) -> u64 {
         use RedisPublishPayload::*;
         match self {
             NewFile(emitter_id, _, _)
             | ModifiedFile(emitter_id, _, _)
+            | InlineNewFile(emitter_id, _, _, _)
+            | InlineModifiedFile(emitter_id, _, _, _)
             | RemovedFile(emitter_id, _)
-            | RenamedFile(emitter_id, _, _) => *emitter_id,
+            | RenamedFile(emitter_id, _, _)
+            | ContentMissing(emitter_id, _)
+            | ContentRejected(emitter_id, _, _)
+            | RolloutApproved(emitter_id)
+            | PullRequested(emitter_id, _, _, _)
+            | PullAcknowledged(emitter_id, _, _) => *emitter_id,
+            Stamped(_, payload) => payload.get_emitter_id(),
+        }
+    }
+
+    /// The messagepack payload, compressed and flagged when it is larger than `compress_above`
+    pub fn to_bytes(&self, compress_above: Option<usize>) -> Vec<u8> {
+        let serialized_payload = rmp_serde::to_vec(self)
+            .expect("messagepack serialization of RedisPublishPayload messages should never fail");
+        match compress_above {
+            Some(min_size) if serialized_payload.len() > min_size => {
+                // the peers announcing that they read the compressed payloads may only know these
+                let compression = match LocalFSStore::compression() {
+                    Compression::Zstd => Compression::Zstd,
+                    _ => Compression::Snappy,
+                };
+                let mut compressed_payload = vec![COMPRESSED_PAYLOAD_MARKER];
+                compressed_payload.extend(LocalFSStore::compress_with(
+                    &serialized_payload,
+                    compression,
+                ));
+                compressed_payload
+            }
+            _ => serialized_payload,
+        }
+    }
+
+    /// A payload compressed or not
+    pub fn from_bytes(payload: &[u8]) -> Result<RedisPublishPayload> {
+        match payload.split_first() {
+            Some((&COMPRESSED_PAYLOAD_MARKER, compressed_payload)) => {
+                let serialized_payload = LocalFSStore::decompress(compressed_payload)
+                    .context("invalid compressed payload")?;
+                Ok(rmp_serde::from_slice(&serialized_payload)?)
+            }
+            _ => Ok(rmp_serde::from_slice(payload)?),
         }
     }
 }
 
 impl RedisClient {
     /// Create new client, ensuring that the connection to the redis server is OK
-    pub fn new(redis_url: String) -> Result<RedisClient> {
-        const DEFAULT_POOL_SIZE: u32 = 15;
-
-        let manager =
-            RedisConnectionManager::new(redis_url.clone()).context("Invalid Redis URL")?;
+    pub fn new(
+        redis_url: String,
+        tls: TlsOptions,
+        credentials: Credentials,
+        db: Option<i64>,
+        pool_size: u32,
+    ) -> Result<RedisClient> {
+        let manager = ConnectionManager::new(RedisClient::create_redis_client(
+            &redis_url,
+            tls,
+            credentials,
+            db,
+        )?);
+        let db = manager.client.get_connection_info().redis.db;
+        let endpoint = manager.endpoint.clone();
         let connection_pool: r2d2::Pool<_> = r2d2::Pool::builder()
-            .max_size(DEFAULT_POOL_SIZE)
+            .max_size(pool_size)
             .build(manager)
             .context("Unable to create the connexion pool")?;
 
@@ -54,11 +347,32 @@ impl RedisClient {
 
         let client = RedisClient {
             redis_url,
+            db,
             connection_pool,
+            endpoint,
         };
         Ok(client)
     }
 
+    /// Whether the host does not resolve to the address anymore, as when the server moved. The
+    /// pooled connections to it are closed then.
+    pub fn is_moved_from(&self, address: SocketAddr) -> Result<bool> {
+        let endpoint = match &self.endpoint {
+            None => return Ok(false),
+            Some(endpoint) => endpoint,
+        };
+        if endpoint.resolves_to(address)? {
+            return Ok(false);
+        }
+        endpoint.forget_address();
+        Ok(true)
+    }
+
+    /// Index of the redis database used by the connections
+    pub fn db(&self) -> i64 {
+        self.db
+    }
+
     /// run redis SET command: set a key to a value
     pub fn set(&self, key: &str, value: &[u8]) -> Result<()> {
         debug!("[redis_client] sending SET {} <value>", key);
@@ -66,11 +380,62 @@ impl RedisClient {
         redis::cmd("SET")
             .arg(key)
             .arg(value)
-            .query(&mut *connection)
+            .query::<()>(&mut *connection)
+            .context("error during the Redis SET query")?;
+        Ok(())
+    }
+
+    /// run redis SET command with EX option: set a key to a value expiring after the given seconds
+    pub fn set_with_expiration(&self, key: &str, value: &[u8], seconds: usize) -> Result<()> {
+        debug!("[redis_client] sending SET {} <value> EX {}", key, seconds);
+        let mut connection = self.take_connection()?;
+        redis::cmd("SET")
+            .arg(key)
+            .arg(value)
+            .arg("EX")
+            .arg(seconds)
+            .query::<()>(&mut *connection)
             .context("error during the Redis SET query")?;
         Ok(())
     }
 
+    /// run redis SETNX command: set a key to a value only if it does not exist yet.
+    /// Returns true when the key was set.
+    pub fn set_if_not_exists(&self, key: &str, value: &[u8]) -> Result<bool> {
+        debug!("[redis_client] sending SETNX {} <value>", key);
+        let mut connection = self.take_connection()?;
+        let is_set = redis::cmd("SETNX")
+            .arg(key)
+            .arg(value)
+            .query::<bool>(&mut *connection)
+            .context("error during the Redis SETNX query")?;
+        Ok(is_set)
+    }
+
+    /// run redis SET command with NX and EX options: set a key expiring after the given seconds,
+    /// only if it does not exist yet. Returns true when the key was set.
+    pub fn set_if_not_exists_with_expiration(
+        &self,
+        key: &str,
+        value: &[u8],
+        seconds: usize,
+    ) -> Result<bool> {
+        debug!(
+            "[redis_client] sending SET {} <value> NX EX {}",
+            key, seconds
+        );
+        let mut connection = self.take_connection()?;
+        let is_set = redis::cmd("SET")
+            .arg(key)
+            .arg(value)
+            .arg("NX")
+            .arg("EX")
+            .arg(seconds)
+            .query::<Option<String>>(&mut *connection)
+            .context("error during the Redis SET query")?;
+        Ok(is_set.is_some())
+    }
+
     /// run redis GET command: get the value of a key
     pub fn get(&self, key: &str) -> Result<Vec<u8>> {
         debug!("[redis_client] sending GET {}", key);
@@ -82,6 +447,60 @@ impl RedisClient {
         Ok(bytes)
     }
 
+    /// run redis GET command: get the value of a key, or None when the key does not exist
+    pub fn get_if_exists(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        debug!("[redis_client] sending GET {}", key);
+        let mut connection = self.take_connection()?;
+        let bytes = redis::cmd("GET")
+            .arg(key)
+            .query::<Option<Vec<u8>>>(&mut *connection)
+            .context("error during the Redis GET query")?;
+        Ok(bytes)
+    }
+
+    /// run redis GETSET command: set a key to a value, and get the old value, or None when
+    /// the key did not exist
+    pub fn get_set(&self, key: &str, value: &[u8]) -> Result<Option<Vec<u8>>> {
+        debug!("[redis_client] sending GETSET {} <value>", key);
+        let mut connection = self.take_connection()?;
+        let old_bytes = redis::cmd("GETSET")
+            .arg(key)
+            .arg(value)
+            .query::<Option<Vec<u8>>>(&mut *connection)
+            .context("error during the Redis GETSET query")?;
+        Ok(old_bytes)
+    }
+
+    /// run redis EVAL command: set a key to a value only if it still holds the expected value.
+    /// Returns true when the key was set.
+    pub fn compare_and_set(&self, key: &str, expected: &[u8], value: &[u8]) -> Result<bool> {
+        debug!(
+            "[redis_client] sending EVAL <compare and set> {} <value>",
+            key
+        );
+        let mut connection = self.take_connection()?;
+        let is_set = redis::cmd("EVAL")
+            .arg(COMPARE_AND_SET_SCRIPT)
+            .arg(1)
+            .arg(key)
+            .arg(expected)
+            .arg(value)
+            .query::<bool>(&mut *connection)
+            .context("error during the Redis EVAL query")?;
+        Ok(is_set)
+    }
+
+    /// run redis STRLEN command: get the length of the value of a key, 0 when it does not exist
+    pub fn strlen(&self, key: &str) -> Result<u64> {
+        debug!("[redis_client] sending STRLEN {}", key);
+        let mut connection = self.take_connection()?;
+        let length = redis::cmd("STRLEN")
+            .arg(key)
+            .query::<u64>(&mut *connection)
+            .context("error during the Redis STRLEN query")?;
+        Ok(length)
+    }
+
     /// run redis RENAME command: change a key
     pub fn rename(&self, old_key: &str, new_key: &str) -> Result<(), anyhow::Error> {
         debug!("[redis_client] sending RENAME {} {}", old_key, new_key);
@@ -105,101 +524,225 @@ impl RedisClient {
         Ok(())
     }
 
-    /// run redis PUBLISH command: publish an event on the given channel
-    pub fn publish(&self, channel: &str, message: RedisPublishPayload) -> Result<()> {
+    /// run redis PUBLISH command: publish an event on the given channel. Payloads larger than
+    /// `compress_above` are compressed.
+    pub fn publish(
+        &self,
+        channel: &str,
+        message: RedisPublishPayload,
+        compress_above: Option<usize>,
+    ) -> Result<()> {
         debug!("[redis_client] sending PUBLISH {} {:?}", channel, message);
         let mut connection = self.take_connection()?;
         redis::cmd("PUBLISH")
             .arg(channel)
-            .arg(rmp_serde::to_vec(&message).expect(
-                "messagepack serialization of RedisPublishPayload messages should never fail",
-            ))
-            .query(&mut *connection)
+            .arg(message.to_bytes(compress_above))
+            .query::<()>(&mut *connection)
             .context("error during the Redis PUBLISH query")?;
         Ok(())
     }
 
+    /// run redis SMEMBERS command: change a member name in a set
+    pub fn smembers(&self, set: &str) -> Result<Vec<String>> {
+        debug!("[redis_client] sending SMEMBERS {}", set);
+        let mut connection = self.take_connection()?;
+        let result = redis::cmd("SMEMBERS")
+            .arg(set)
+            .query::<Vec<String>>(&mut *connection)
+            .context("error during the Redis SMEMBERS query")?;
+        Ok(result)
+    }
+
     /// run redis SADD command: add a member to a set
-    pub fn sadd(&self, set: &str, member_key: &str) -> Result<()> {
-        debug!("[redis_client] sending SADD {} {}", set, member_key);
+    pub fn sadd(&self, set: &str, member: &str) -> Result<()> {
+        debug!("[redis_client] sending SADD {} {}", set, member);
         let mut connection = self.take_connection()?;
         redis::cmd("SADD")
             .arg(set)
-            .arg(member_key)
+            .arg(member)
             .query::<()>(&mut *connection)
             .context("error during the Redis SADD query")?;
         Ok(())
     }
 
-    /// run redis SREM command: remove a member to a set
-    pub fn srem(&self, set: &str, member_key: &str) -> Result<()> {
-        debug!("[redis_client] sending SREM {} {}", set, member_key);
+    /// run redis SREM command: remove a member from a set. Returns true when it was a member.
+    pub fn srem(&self, set: &str, member: &str) -> Result<bool> {
+        debug!("[redis_client] sending SREM {} {}", set, member);
         let mut connection = self.take_connection()?;
-        redis::cmd("SREM")
+        let is_removed = redis::cmd("SREM")
             .arg(set)
-            .arg(member_key)
-            .query::<()>(&mut *connection)
+            .arg(member)
+            .query::<bool>(&mut *connection)
             .context("error during the Redis SREM query")?;
-        Ok(())
+        Ok(is_removed)
     }
 
-    /// run redis SMOVE command: change a member name in a set
-    pub fn smove(&self, set: &str, old_member_key: &str, new_member_key: &str) -> Result<()> {
-        debug!(
-            "[redis_client] sending SMOVE {} {} {}",
-            set, old_member_key, new_member_key
-        );
+    /// run redis SISMEMBER command: whether the member is in the set
+    pub fn sismember(&self, set: &str, member: &str) -> Result<bool> {
+        debug!("[redis_client] sending SISMEMBER {} {}", set, member);
         let mut connection = self.take_connection()?;
-        redis::cmd("SMOVE")
+        let is_member = redis::cmd("SISMEMBER")
             .arg(set)
-            .arg(old_member_key)
-            .arg(new_member_key)
+            .arg(member)
+            .query::<bool>(&mut *connection)
+            .context("error during the Redis SISMEMBER query")?;
+        Ok(is_member)
+    }
+
+    /// run redis HSET command: set a field of a hash
+    pub fn hset(&self, key: &str, field: &str, value: &[u8]) -> Result<()> {
+        debug!("[redis_client] sending HSET {} {} <value>", key, field);
+        let mut connection = self.take_connection()?;
+        redis::cmd("HSET")
+            .arg(key)
+            .arg(field)
+            .arg(value)
             .query::<()>(&mut *connection)
-            .context("error during the Redis SMOVE query")?;
+            .context("error during the Redis HSET query")?;
         Ok(())
     }
 
-    /// run redis SMEMBERS command: change a member name in a set
-    pub fn smembers(&self, set: &str) -> Result<Vec<String>> {
-        debug!("[redis_client] sending SMEMBERS {}", set);
+    /// run redis HSETNX command: set a field of a hash only if it does not exist yet.
+    /// Returns true when the field was set.
+    pub fn hset_if_not_exists(&self, key: &str, field: &str, value: &[u8]) -> Result<bool> {
+        debug!("[redis_client] sending HSETNX {} {} <value>", key, field);
         let mut connection = self.take_connection()?;
-        let result = redis::cmd("SMEMBERS")
-            .arg(set)
-            .query::<Vec<String>>(&mut *connection)
-            .context("error during the Redis SMEMBERS query")?;
-        Ok(result)
+        let is_set = redis::cmd("HSETNX")
+            .arg(key)
+            .arg(field)
+            .arg(value)
+            .query::<bool>(&mut *connection)
+            .context("error during the Redis HSETNX query")?;
+        Ok(is_set)
+    }
+
+    /// run redis HGETALL command: get all the fields of a hash, empty when it does not exist
+    pub fn hgetall(&self, key: &str) -> Result<Vec<(String, Vec<u8>)>> {
+        debug!("[redis_client] sending HGETALL {}", key);
+        let mut connection = self.take_connection()?;
+        let fields = redis::cmd("HGETALL")
+            .arg(key)
+            .query::<Vec<(String, Vec<u8>)>>(&mut *connection)
+            .context("error during the Redis HGETALL query")?;
+        Ok(fields)
+    }
+
+    /// run redis HGET command: get a field of a hash, or None when it does not exist
+    pub fn hget(&self, key: &str, field: &str) -> Result<Option<String>> {
+        debug!("[redis_client] sending HGET {} {}", key, field);
+        let mut connection = self.take_connection()?;
+        let value = redis::cmd("HGET")
+            .arg(key)
+            .arg(field)
+            .query::<Option<String>>(&mut *connection)
+            .context("error during the Redis HGET query")?;
+        Ok(value)
     }
 
-    /// run redis MULTI command: open a new transaction
-    pub fn multi(&self) -> Result<()> {
-        debug!("[redis_client] sending MULTI (new transaction)",);
+    /// run redis HDEL command: remove a field of a hash
+    pub fn hdel(&self, key: &str, field: &str) -> Result<()> {
+        debug!("[redis_client] sending HDEL {} {}", key, field);
         let mut connection = self.take_connection()?;
-        redis::cmd("MULTI")
+        redis::cmd("HDEL")
+            .arg(key)
+            .arg(field)
             .query::<()>(&mut *connection)
-            .context("error during the Redis MULTI query")?;
+            .context("error during the Redis HDEL query")?;
         Ok(())
     }
 
-    /// run redis EXEC command: execute the opened transaction
-    pub fn exec(&self) -> Result<()> {
-        debug!("[redis_client] sending EXEC (resolve current transaction)",);
+    /// run redis ZADD command: add a member to a sorted set, or update its score
+    pub fn zadd(&self, sorted_set: &str, score: u64, member_key: &str) -> Result<()> {
+        debug!(
+            "[redis_client] sending ZADD {} {} {}",
+            sorted_set, score, member_key
+        );
         let mut connection = self.take_connection()?;
-        redis::cmd("EXEC")
+        redis::cmd("ZADD")
+            .arg(sorted_set)
+            .arg(score)
+            .arg(member_key)
             .query::<()>(&mut *connection)
-            .context("error during the Redis EXEC query")?;
+            .context("error during the Redis ZADD query")?;
         Ok(())
     }
 
-    /// run redis DISCARD command: discard the opened transaction
-    pub fn discard(&self) -> Result<()> {
-        debug!("[redis_client] sending DISCARD (discard current transaction)",);
+    /// run redis ZRANGEBYSCORE command: list the members of a sorted set scored at most `max_score`
+    pub fn zrange_up_to_score(&self, sorted_set: &str, max_score: u64) -> Result<Vec<String>> {
+        debug!(
+            "[redis_client] sending ZRANGEBYSCORE {} -inf {}",
+            sorted_set, max_score
+        );
+        let mut connection = self.take_connection()?;
+        let members = redis::cmd("ZRANGEBYSCORE")
+            .arg(sorted_set)
+            .arg("-inf")
+            .arg(max_score)
+            .query::<Vec<String>>(&mut *connection)
+            .context("error during the Redis ZRANGEBYSCORE query")?;
+        Ok(members)
+    }
+
+    /// run redis ZREM command: remove a member from a sorted set
+    pub fn zrem(&self, sorted_set: &str, member_key: &str) -> Result<()> {
+        debug!("[redis_client] sending ZREM {} {}", sorted_set, member_key);
         let mut connection = self.take_connection()?;
-        redis::cmd("DISCARD")
+        redis::cmd("ZREM")
+            .arg(sorted_set)
+            .arg(member_key)
             .query::<()>(&mut *connection)
-            .context("error during the Redis DISCARD query")?;
+            .context("error during the Redis ZREM query")?;
         Ok(())
     }
 
+    /// run redis EVAL command: run atomically a lua script returning an integer
+    pub fn eval(&self, script: &str, keys: &[&str], args: &[String]) -> Result<i64> {
+        debug!("[redis_client] sending EVAL <script> {:?} {:?}", keys, args);
+        let mut connection = self.take_connection()?;
+        let result = redis::cmd("EVAL")
+            .arg(script)
+            .arg(keys.len())
+            .arg(keys)
+            .arg(args)
+            .query::<i64>(&mut *connection)
+            .context("error during the Redis EVAL query")?;
+        Ok(result)
+    }
+
+    /// run redis CONFIG GET command: read a server setting
+    pub fn config_get(&self, parameter: &str) -> Result<Option<String>> {
+        debug!("[redis_client] sending CONFIG GET {}", parameter);
+        let mut connection = self.take_connection()?;
+        let name_and_value = redis::cmd("CONFIG")
+            .arg("GET")
+            .arg(parameter)
+            .query::<Vec<String>>(&mut *connection)
+            .context("error during the Redis CONFIG GET query")?;
+        // the reply is [name, value], or empty when the parameter is unknown
+        Ok(name_and_value.into_iter().nth(1))
+    }
+
+    /// run redis SCAN command until the end of the iteration: list the keys matching a pattern
+    pub fn scan_match(&self, pattern: &str) -> Result<Vec<String>> {
+        debug!("[redis_client] sending SCAN 0 MATCH {}", pattern);
+        let mut connection = self.take_connection()?;
+        let mut keys = Vec::new();
+        let mut cursor: u64 = 0;
+        loop {
+            let (next_cursor, mut batch) = redis::cmd("SCAN")
+                .arg(cursor)
+                .arg("MATCH")
+                .arg(pattern)
+                .query::<(u64, Vec<String>)>(&mut *connection)
+                .context("error during the Redis SCAN query")?;
+            keys.append(&mut batch);
+            if next_cursor == 0 {
+                return Ok(keys);
+            }
+            cursor = next_cursor;
+        }
+    }
+
     /// take a connection from the pool
     pub fn take_connection(&self) -> Result<RedisConnection> {
         let connection = self
@@ -209,29 +752,63 @@ impl RedisClient {
         Ok(connection)
     }
 
-    pub fn in_transaction(&self, commands: impl FnOnce() -> Result<()>) -> Result<()> {
-        self.multi()?;
+    fn create_redis_client(
+        redis_url: &str,
+        tls: TlsOptions,
+        credentials: Credentials,
+        db: Option<i64>,
+    ) -> Result<redis::Client> {
+        let is_tls = redis_url.starts_with("rediss://");
+        if !is_tls && (tls.insecure || tls.ca_certificates.is_some()) {
+            bail!("TLS options require a rediss:// URL");
+        }
+        let mut connection_info = redis_url
+            .into_connection_info()
+            .context("Invalid Redis URL")?;
+        // fail now rather than when the pool gives up waiting for a connection
+        if let redis::ConnectionAddr::Unix(socket_path) = &connection_info.addr {
+            if !socket_path.exists() {
+                bail!("the redis socket {} does not exist", socket_path.display());
+            }
+        }
+        if tls.insecure {
+            warn!("the certificate of the redis server is not verified");
+            if let redis::ConnectionAddr::TcpTls { insecure, .. } = &mut connection_info.addr {
+                *insecure = true;
+            }
+        }
+        // the settings given apart win over the ones of the URL
+        if let Some(db) = db {
+            connection_info.redis.db = db;
+        }
+        if let Some(user) = credentials.user {
+            connection_info.redis.username = Some(user);
+        }
+        if let Some(password_file) = credentials.password_file {
+            connection_info.redis.password = Some(read_password_file(&password_file)?);
+        }
 
-        let res = commands();
-        if let Err(error) = res {
-            error!(
-                "Error during in Redis commands: {:?}. Cancel the transaction.",
-                error
-            );
-            self.discard()?;
-            Err(anyhow!(
-                "transaction was cancelled because of the following error: {:?}",
-                error
-            ))
-        } else {
-            self.exec()?;
-            Ok(())
+        match tls.ca_certificates {
+            None => redis::Client::open(connection_info).context("Invalid Redis URL"),
+            Some(ca_certificates) => {
+                let root_cert = std::fs::read(&ca_certificates).with_context(|| {
+                    format!("unable to read the CA bundle {}", ca_certificates.display())
+                })?;
+                redis::Client::build_with_tls(
+                    connection_info,
+                    redis::TlsCertificates {
+                        client_tls: None,
+                        root_cert: Some(root_cert),
+                    },
+                )
+                .context("Invalid Redis TLS settings")
+            }
         }
     }
 
     /// run a PING command to the senver and ensure it respond with PONG
-    fn ping_server(connection: &mut dyn r2d2_redis::redis::ConnectionLike) -> Result<()> {
-        let response: String = r2d2_redis::redis::cmd("PING")
+    fn ping_server(connection: &mut dyn redis::ConnectionLike) -> Result<()> {
+        let response: String = redis::cmd("PING")
             .query::<String>(connection)
             .context("Unable to ping Redis")?;
 
@@ -242,3 +819,12 @@ impl RedisClient {
         }
     }
 }
+
+fn read_password_file(path: &Path) -> Result<String> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("unable to read the redis password file {}", path.display()))?;
+    match content.lines().next() {
+        Some(password) if !password.is_empty() => Ok(password.to_string()),
+        _ => bail!("the redis password file {} is empty", path.display()),
+    }
+}
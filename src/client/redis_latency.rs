@@ -0,0 +1,147 @@
+use log::warn;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Upper bounds of the latency buckets, in seconds. The last bucket is unbounded.
+const BUCKET_BOUNDS: [f64; 10] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.5, 1.0,
+];
+
+/// Latency of the redis commands of the process, by command name
+static HISTOGRAMS: Mutex<BTreeMap<String, Histogram>> = Mutex::new(BTreeMap::new());
+/// The commands slower than this many microseconds are logged. 0 disables it.
+static SLOW_THRESHOLD_MICROS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Default)]
+struct Histogram {
+    /// Commands per bucket, the unbounded one last
+    bucket_counts: [u64; BUCKET_BOUNDS.len() + 1],
+    count: u64,
+    sum_seconds: f64,
+}
+
+/// Latency of the redis commands, so that the operators can tell whether the synchronization is
+/// slowed down by the server or by the filesystem
+pub struct RedisLatencies;
+
+impl RedisLatencies {
+    /// Log the commands slower than this from now on
+    pub fn set_slow_threshold(threshold: Duration) {
+        SLOW_THRESHOLD_MICROS.store(threshold.as_micros() as u64, Ordering::SeqCst);
+    }
+
+    /// Count a command, given as sent to the server. Its values are never logged: they may be
+    /// the contents of the files.
+    pub fn record(packed_command: &[u8], elapsed: Duration) {
+        let (command, key) = command_and_key(packed_command);
+        let slow_threshold = SLOW_THRESHOLD_MICROS.load(Ordering::SeqCst);
+        if slow_threshold > 0 && elapsed.as_micros() as u64 >= slow_threshold {
+            warn!("slow redis command: {} {} took {:?}", command, key, elapsed);
+        }
+        let seconds = elapsed.as_secs_f64();
+        let bucket = BUCKET_BOUNDS
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(BUCKET_BOUNDS.len());
+        let mut histograms = HISTOGRAMS.lock().expect("redis latencies lock poisoned");
+        let histogram = histograms.entry(command).or_default();
+        histogram.bucket_counts[bucket] += 1;
+        histogram.count += 1;
+        histogram.sum_seconds += seconds;
+    }
+
+    /// The histograms in the Prometheus text format
+    pub fn render() -> String {
+        let mut text = String::from(
+            "# HELP fssync_redis_command_duration_seconds Latency of the redis commands\n\
+             # TYPE fssync_redis_command_duration_seconds histogram\n",
+        );
+        let histograms = HISTOGRAMS.lock().expect("redis latencies lock poisoned");
+        for (command, histogram) in histograms.iter() {
+            let mut cumulative_count = 0;
+            for (bucket, count) in histogram.bucket_counts.iter().enumerate() {
+                cumulative_count += count;
+                let bound = match BUCKET_BOUNDS.get(bucket) {
+                    None => String::from("+Inf"),
+                    Some(bound) => bound.to_string(),
+                };
+                let _ = writeln!(
+                    text,
+                    "fssync_redis_command_duration_seconds_bucket{{command=\"{}\",le=\"{}\"}} {}",
+                    command, bound, cumulative_count
+                );
+            }
+            let _ = writeln!(
+                text,
+                "fssync_redis_command_duration_seconds_sum{{command=\"{}\"}} {}\n\
+                 fssync_redis_command_duration_seconds_count{{command=\"{}\"}} {}",
+                command, histogram.sum_seconds, command, histogram.count
+            );
+        }
+        text
+    }
+}
+
+/// The name of the command, and the key it works on, read from its RESP encoding: an array of
+/// bulk strings
+fn command_and_key(packed_command: &[u8]) -> (String, String) {
+    let arguments = first_bulk_strings(packed_command, 4);
+    let command = arguments
+        .first()
+        .map(|command| String::from_utf8_lossy(command).to_uppercase())
+        .unwrap_or_default();
+    let key_position = match command.as_str() {
+        // EVAL script numkeys key...
+        "EVAL" | "EVALSHA" => 3,
+        // CONFIG GET parameter
+        "CONFIG" => 2,
+        _ => 1,
+    };
+    let key = arguments
+        .get(key_position)
+        .map(|key| String::from_utf8_lossy(key).into_owned())
+        .unwrap_or_default();
+    (command, key)
+}
+
+/// Up to `max_count` bulk strings from the start of the RESP array
+fn first_bulk_strings(packed_command: &[u8], max_count: usize) -> Vec<&[u8]> {
+    let mut strings = Vec::new();
+    let mut rest = match split_line(packed_command) {
+        Some((header, rest)) if header.starts_with(b"*") => rest,
+        _ => return strings,
+    };
+    while strings.len() < max_count {
+        let length: usize = match split_line(rest) {
+            Some((header, after_header)) if header.starts_with(b"$") => {
+                match std::str::from_utf8(&header[1..])
+                    .ok()
+                    .and_then(|length| length.parse().ok())
+                {
+                    None => break,
+                    Some(length) => {
+                        rest = after_header;
+                        length
+                    }
+                }
+            }
+            _ => break,
+        };
+        if rest.len() < length {
+            break;
+        }
+        let (string, after_string) = rest.split_at(length);
+        strings.push(string);
+        rest = after_string.get(2..).unwrap_or_default();
+    }
+    strings
+}
+
+/// The line before the first CRLF, and what follows it
+fn split_line(bytes: &[u8]) -> Option<(&[u8], &[u8])> {
+    let end = bytes.windows(2).position(|window| window == b"\r\n")?;
+    Some((&bytes[..end], &bytes[end + 2..]))
+}
@@ -0,0 +1,89 @@
+use crate::client::http_client::{HttpClient, HttpResponse};
+use anyhow::{anyhow, bail, Context, Result};
+use serde_json::{json, Value};
+
+/// Field of the KV secrets holding the content of the file
+const CONTENT_FIELD: &str = "content";
+
+/// Minimal client of the KV version 2 secrets engine of HashiCorp Vault.
+/// One connection per request: secrets are small and rarely changed.
+#[derive(Clone)]
+pub struct VaultClient {
+    http: HttpClient,
+    token: String,
+    /// Mount point of the KV engine, e.g. `secret`
+    mount: String,
+}
+
+impl std::fmt::Debug for VaultClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("VaultClient")
+            .field("http", &self.http)
+            .field("mount", &self.mount)
+            .finish()
+    }
+}
+
+impl VaultClient {
+    /// The address is `https://host[:port]`. The certificates are checked against the system ones.
+    pub fn new(address: &str, token: String, mount: String) -> Result<VaultClient> {
+        Ok(VaultClient {
+            http: HttpClient::new(address).context("invalid vault address")?,
+            token,
+            mount: mount.trim_matches('/').to_string(),
+        })
+    }
+
+    /// Returns None when the secret does not exist
+    pub fn read_secret(&self, path: &str) -> Result<Option<String>> {
+        let response = self.request("GET", &self.to_data_path(path), b"")?;
+        if response.status == 404 {
+            return Ok(None);
+        }
+        let response = VaultClient::ensure_success(response)?;
+        let content = response["data"]["data"][CONTENT_FIELD]
+            .as_str()
+            .ok_or_else(|| anyhow!("secret {} has no {} field", path, CONTENT_FIELD))?;
+        Ok(Some(content.to_string()))
+    }
+
+    pub fn write_secret(&self, path: &str, content: &str) -> Result<()> {
+        let body = json!({ "data": { CONTENT_FIELD: content } }).to_string();
+        let response = self.request("POST", &self.to_data_path(path), body.as_bytes())?;
+        VaultClient::ensure_success(response).map(|_| ())
+    }
+
+    /// Delete the last version of the secret. The older versions stay in Vault.
+    pub fn delete_secret(&self, path: &str) -> Result<()> {
+        let response = self.request("DELETE", &self.to_data_path(path), b"")?;
+        VaultClient::ensure_success(response).map(|_| ())
+    }
+
+    fn to_data_path(&self, path: &str) -> String {
+        format!("/v1/{}/data/{}", self.mount, path.trim_start_matches('/'))
+    }
+
+    fn request(&self, method: &str, path: &str, body: &[u8]) -> Result<HttpResponse> {
+        let mut headers = vec![("X-Vault-Token", self.token.clone())];
+        if !body.is_empty() {
+            headers.push(("Content-Type", String::from("application/json")));
+        }
+        self.http
+            .request(method, path, &headers, body)
+            .context("error during the vault request")
+    }
+
+    fn ensure_success(response: HttpResponse) -> Result<Value> {
+        if !response.is_success() {
+            bail!(
+                "vault answered {}: {}",
+                response.status,
+                String::from_utf8_lossy(&response.body).trim()
+            );
+        }
+        if response.body.is_empty() {
+            return Ok(Value::Null);
+        }
+        serde_json::from_slice(&response.body).context("invalid JSON response from vault")
+    }
+}
@@ -0,0 +1,218 @@
+use crate::client::http_client::HttpClient;
+use anyhow::{Context, Result};
+use futures_util::{SinkExt, StreamExt};
+use log::{debug, info, warn};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::Runtime;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::error::ProtocolError;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::{HeaderValue, StatusCode};
+use tokio_tungstenite::tungstenite::{Error as WebSocketError, Message};
+use tokio_tungstenite::{Connector, WebSocketStream};
+
+/// Size of the chunks of the TCP stream sent in each WebSocket message
+pub const TUNNEL_CHUNK_SIZE: usize = 16 * 1024;
+/// Each tunnel holds its chunk, and the WebSocket message made of it
+pub const LOW_MEMORY_TUNNEL_CHUNK_SIZE: usize = 4 * 1024;
+
+/// Relays TCP connections over WebSocket, so that the instances which cannot reach redis directly,
+/// behind a NAT or a firewall only letting HTTP(S) out, can still synchronize.
+/// The relay runs next to redis, and forwards every WebSocket connection to it. The instances
+/// tunnel their redis connections, with the events and the contents, through a local endpoint.
+#[derive(Debug, Clone)]
+pub struct WebSocketRelay {
+    /// Shared secret the instances present to the relay, as a bearer token
+    token: Option<String>,
+    /// Size of the chunks of the TCP streams relayed
+    chunk_size: usize,
+}
+
+impl WebSocketRelay {
+    pub fn new(token: Option<String>, chunk_size: usize) -> WebSocketRelay {
+        WebSocketRelay { token, chunk_size }
+    }
+
+    fn runtime(name: &str) -> Result<Runtime> {
+        tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .thread_name(name)
+            .enable_all()
+            .build()
+            .context("unable to start the runtime of the relay connections")
+    }
+
+    /// Accept the tunnels on `listen_address`, and forward them to `forward_to` (host:port)
+    /// until the process exits. Plain WebSocket: TLS is left to a reverse proxy.
+    pub fn serve(&self, listen_address: SocketAddr, forward_to: String) -> Result<()> {
+        let runtime = WebSocketRelay::runtime("relay server")?;
+        runtime.block_on(async {
+            let listener = TcpListener::bind(listen_address)
+                .await
+                .with_context(|| format!("unable to listen on {}", listen_address))?;
+            info!(
+                "relaying the WebSocket connections of {} to {}",
+                listen_address, forward_to
+            );
+            loop {
+                let (stream, peer_address) = listener
+                    .accept()
+                    .await
+                    .context("unable to accept a relay connection")?;
+                let relay = self.clone();
+                let forward_to = forward_to.clone();
+                tokio::spawn(async move {
+                    if let Err(error) = relay.relay_connection(stream, &forward_to).await {
+                        warn!(
+                            "relay connection of {} closed. Error: {:?}",
+                            peer_address, error
+                        );
+                    }
+                });
+            }
+        })
+    }
+
+    // the handshake callback signature is imposed by tungstenite
+    #[allow(clippy::result_large_err)]
+    async fn relay_connection(&self, stream: TcpStream, forward_to: &str) -> Result<()> {
+        let expected_authorization = self.token.as_ref().map(|token| format!("Bearer {}", token));
+        let check_token = |request: &Request, response: Response| {
+            let authorization = request
+                .headers()
+                .get("authorization")
+                .and_then(|value| value.to_str().ok());
+            match &expected_authorization {
+                Some(expected) if authorization != Some(expected.as_str()) => {
+                    let mut error = ErrorResponse::new(Some(String::from("invalid relay token")));
+                    *error.status_mut() = StatusCode::UNAUTHORIZED;
+                    Err(error)
+                }
+                _ => Ok(response),
+            }
+        };
+        let websocket = tokio_tungstenite::accept_hdr_async(stream, check_token)
+            .await
+            .context("WebSocket handshake failed")?;
+        let target = TcpStream::connect(forward_to)
+            .await
+            .with_context(|| format!("unable to connect to {}", forward_to))?;
+        debug!("[websocket_relay] relaying a connection to {}", forward_to);
+        pump(websocket, target, self.chunk_size).await
+    }
+
+    /// Listen on a local port, and tunnel every connection made to it through the relay.
+    /// Returns the local address, to connect to instead of the one behind the relay.
+    pub fn open_tunnel(&self, relay_url: &str) -> Result<(SocketAddr, JoinHandle<()>)> {
+        let mut request = relay_url
+            .into_client_request()
+            .context("invalid relay url")?;
+        if let Some(token) = &self.token {
+            request.headers_mut().insert(
+                "authorization",
+                HeaderValue::from_str(&format!("Bearer {}", token))
+                    .context("invalid relay token")?,
+            );
+        }
+        let runtime = WebSocketRelay::runtime("relay tunnel")?;
+        // the first tunnel checks the relay and the token, instead of the first redis command
+        let websocket = runtime
+            .block_on(connect_websocket(request.clone()))
+            .with_context(|| format!("unable to connect to the relay {}", relay_url))?;
+        drop(websocket);
+        let listener = runtime
+            .block_on(TcpListener::bind("127.0.0.1:0"))
+            .context("unable to listen on the local end of the relay tunnel")?;
+        let local_address = listener.local_addr()?;
+        let chunk_size = self.chunk_size;
+        info!(
+            "tunneling the connections to {} through the relay {}",
+            local_address, relay_url
+        );
+        let handle = std::thread::Builder::new()
+            .name(String::from("relay tunnel"))
+            .spawn(move || {
+                let result: Result<()> = runtime.block_on(async {
+                    loop {
+                        let (stream, _) = listener
+                            .accept()
+                            .await
+                            .context("unable to accept a local connection")?;
+                        let request = request.clone();
+                        tokio::spawn(async move {
+                            let result = match connect_websocket(request).await {
+                                Ok(websocket) => pump(websocket, stream, chunk_size).await,
+                                Err(error) => Err(error),
+                            };
+                            if let Err(error) = result {
+                                warn!("relay tunnel closed. Error: {:?}", error);
+                            }
+                        });
+                    }
+                });
+                if let Err(error) = result {
+                    panic!("Error in the relay tunnel: {:?}", error);
+                }
+            })
+            .context("unable to create relay tunnel thread")?;
+        Ok((local_address, handle))
+    }
+}
+
+async fn connect_websocket(
+    request: tokio_tungstenite::tungstenite::handshake::client::Request,
+) -> Result<WebSocketStream<tokio_tungstenite::MaybeTlsStream<TcpStream>>> {
+    let connector = Connector::Rustls(Arc::new(HttpClient::tls_config()?));
+    let (websocket, _) =
+        tokio_tungstenite::connect_async_tls_with_config(request, None, true, Some(connector))
+            .await
+            .context("WebSocket handshake failed")?;
+    Ok(websocket)
+}
+
+/// Copy the bytes both ways until one side closes the connection
+async fn pump<S>(websocket: WebSocketStream<S>, stream: TcpStream, chunk_size: usize) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut websocket_sink, mut websocket_source) = websocket.split();
+    let (mut stream_reader, mut stream_writer) = stream.into_split();
+    let upstream = async {
+        let mut chunk = vec![0u8; chunk_size];
+        loop {
+            let length = stream_reader.read(&mut chunk).await?;
+            if length == 0 {
+                websocket_sink.send(Message::Close(None)).await?;
+                return Ok::<(), anyhow::Error>(());
+            }
+            websocket_sink
+                .send(Message::Binary(chunk[..length].to_vec()))
+                .await?;
+        }
+    };
+    let downstream = async {
+        while let Some(message) = websocket_source.next().await {
+            match message {
+                Ok(Message::Binary(data)) => stream_writer.write_all(&data).await?,
+                // the other side exited without closing the tunnel, as redis clients do
+                Ok(Message::Close(_))
+                | Err(WebSocketError::Protocol(ProtocolError::ResetWithoutClosingHandshake)) => {
+                    break
+                }
+                Err(error) => return Err(error.into()),
+                // the pings are answered by tungstenite
+                _ => (),
+            }
+        }
+        stream_writer.shutdown().await?;
+        Ok::<(), anyhow::Error>(())
+    };
+    tokio::select! {
+        result = upstream => result,
+        result = downstream => result,
+    }
+}
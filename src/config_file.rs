@@ -0,0 +1,191 @@
+use anyhow::{bail, Context};
+use serde::Deserialize;
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+use structopt::clap::ArgMatches;
+
+/// The positional argument, given in the file as a list of paths
+const PATHS_ARGUMENT: &str = "paths-to-watch";
+/// Table of the named profiles, as `[profile.work]`
+const PROFILES_KEY: &str = "profile";
+
+/// Settings of a watched path overriding the flags, given in the file as
+/// `paths_to_watch = ["/etc/app", { path = "/srv/data", recursive = false }]`
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RootSettings {
+    pub path: PathBuf,
+    pub event_bounce_ms: Option<u64>,
+    /// Globs relative to the path, never synchronized in addition to the --exclude ones
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// Only the files directly in the path are synchronized when false
+    pub recursive: Option<bool>,
+    /// The path is synchronized in this namespace, by its own instance
+    pub namespace: Option<String>,
+}
+
+/// The arguments completed with the configuration file, and the settings of its watched paths
+pub struct Configuration {
+    pub args: Vec<OsString>,
+    pub root_settings: Vec<RootSettings>,
+}
+
+/// Complete the command line with the flags set in the TOML file. A flag is set under its long
+/// name, with `_` or `-`: `event_bounce_ms = 200`, `debug = true`, `no_apply = ["*.log"]`,
+/// `tag = { env = "prod" }`, and the watched paths as `paths_to_watch = ["/etc/app"]`, or as
+/// tables with their own settings (see `RootSettings`).
+///
+/// The flags of the `--profile` table, as `[profile.work]`, replace the ones at the top of the
+/// file, which are shared by the profiles. The other profiles are ignored.
+///
+/// The flags given on the command line or by their environment variable are not taken from the
+/// file. The arguments of the file are checked like the command line.
+pub fn with_config_file(
+    path: &Path,
+    profile: Option<&str>,
+    args: Vec<OsString>,
+    matches: &ArgMatches<'_>,
+) -> Result<Configuration, anyhow::Error> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("unable to read the configuration file {}", path.display()))?;
+    let mut table: toml::Table = content
+        .parse()
+        .with_context(|| format!("invalid configuration file {}", path.display()))?;
+    let profiles = match table.remove(PROFILES_KEY) {
+        None => toml::Table::new(),
+        Some(toml::Value::Table(profiles)) => profiles,
+        Some(_) => bail!(
+            "{} in {} must hold the tables of the profiles, as [profile.work]",
+            PROFILES_KEY,
+            path.display()
+        ),
+    };
+    if let Some(profile) = profile {
+        match profiles.get(profile) {
+            Some(toml::Value::Table(profile_table)) => table.extend(profile_table.clone()),
+            Some(_) => bail!(
+                "the profile {} in {} must be a table, as [profile.{}]",
+                profile,
+                path.display(),
+                profile
+            ),
+            None => bail!(
+                "no profile {} in {}, only: {}",
+                profile,
+                path.display(),
+                profiles.keys().cloned().collect::<Vec<String>>().join(", ")
+            ),
+        }
+    }
+
+    let mut paths = Vec::new();
+    let mut root_settings = Vec::new();
+    let mut flags = Vec::new();
+    for (key, value) in table {
+        let name = key.replace('_', "-");
+        if name == "config" {
+            bail!("the configuration file cannot include another one");
+        }
+        let flag = format!("--{}", name);
+        let is_overridden = args.iter().skip(1).any(|arg| is_flag(arg, &flag))
+            || matches.occurrences_of(&name) > 0
+            || std::env::var_os(name.replace('-', "_").to_uppercase()).is_some();
+        if is_overridden {
+            continue;
+        }
+        if name == PATHS_ARGUMENT {
+            for settings in to_root_settings(value)
+                .with_context(|| format!("invalid value for {} in {}", key, path.display()))?
+            {
+                paths.push(settings.path.clone().into_os_string());
+                root_settings.push(settings);
+            }
+            continue;
+        }
+        let values = to_values(&value)
+            .with_context(|| format!("invalid value for {} in {}", key, path.display()))?;
+        let flag = OsString::from(flag);
+        match value {
+            toml::Value::Boolean(true) => flags.push(flag),
+            toml::Value::Boolean(false) => (),
+            _ => {
+                for value in values {
+                    flags.push(flag.clone());
+                    flags.push(value);
+                }
+            }
+        }
+    }
+
+    let mut args = args.into_iter();
+    Ok(Configuration {
+        args: args
+            .next()
+            .into_iter()
+            .chain(paths)
+            .chain(flags)
+            .chain(args)
+            .collect(),
+        root_settings,
+    })
+}
+
+/// The watched paths, each given as a path or as a table of settings
+fn to_root_settings(value: toml::Value) -> Result<Vec<RootSettings>, anyhow::Error> {
+    let elements = match value {
+        toml::Value::Array(elements) => elements,
+        value => vec![value],
+    };
+    elements
+        .into_iter()
+        .map(|element| match element {
+            toml::Value::Table(_) => element
+                .try_into::<RootSettings>()
+                .context("invalid settings of a watched path"),
+            element => Ok(RootSettings {
+                path: PathBuf::from(to_scalar(&element)?),
+                ..RootSettings::default()
+            }),
+        })
+        .collect()
+}
+
+/// Whether the argument is the flag, as `--flag` or `--flag=value`
+fn is_flag(arg: &OsStr, flag: &str) -> bool {
+    arg.to_str().is_some_and(|arg| {
+        arg == flag
+            || arg
+                .strip_prefix(flag)
+                .is_some_and(|rest| rest.starts_with('='))
+    })
+}
+
+/// The values of a flag: one per element of a list, and `key=value` per entry of a table
+fn to_values(value: &toml::Value) -> Result<Vec<OsString>, anyhow::Error> {
+    let values = match value {
+        toml::Value::Array(elements) => elements
+            .iter()
+            .map(to_scalar)
+            .collect::<Result<Vec<_>, _>>()?,
+        toml::Value::Table(entries) => entries
+            .iter()
+            .map(|(key, value)| Ok(format!("{}={}", key, to_scalar(value)?)))
+            .collect::<Result<Vec<_>, anyhow::Error>>()?,
+        value => vec![to_scalar(value)?],
+    };
+    Ok(values.into_iter().map(OsString::from).collect())
+}
+
+fn to_scalar(value: &toml::Value) -> Result<String, anyhow::Error> {
+    match value {
+        toml::Value::String(string) => Ok(string.clone()),
+        toml::Value::Integer(integer) => Ok(integer.to_string()),
+        toml::Value::Float(float) => Ok(float.to_string()),
+        toml::Value::Boolean(boolean) => Ok(boolean.to_string()),
+        toml::Value::Datetime(datetime) => Ok(datetime.to_string()),
+        toml::Value::Array(_) | toml::Value::Table(_) => {
+            bail!("a list or a table cannot be nested")
+        }
+    }
+}
@@ -0,0 +1,321 @@
+use crate::event_handler::local_files_event_handler::WatchedPath;
+use crate::event_handler::path_filter::PathFilter;
+use crate::store::local_fs_store::Compression;
+use crate::store::redis_store::NamespaceMetadata;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+/// Files of a watched path checked against the filters: enough to tell whether they exclude
+/// everything, without walking the whole tree
+const SAMPLE_SIZE: usize = 1000;
+/// Checked against the filters for the empty watched paths
+const PROBE_FILES: &[&str] = &["notes.txt", "directory/data.bin"];
+
+/// A common mistake in the configuration, and how to fix it
+#[derive(Debug, Clone)]
+pub struct LintFinding {
+    pub problem: String,
+    pub fix: String,
+}
+
+/// The mistakes of the configuration of an instance which are not errors for the command line,
+/// but make it synchronize less, or more, than expected
+#[derive(Debug, Default)]
+pub struct ConfigLint {
+    findings: Vec<LintFinding>,
+}
+
+impl ConfigLint {
+    pub fn new() -> ConfigLint {
+        ConfigLint::default()
+    }
+
+    pub fn finding_count(&self) -> usize {
+        self.findings.len()
+    }
+
+    fn add(&mut self, problem: String, fix: &str) {
+        self.findings.push(LintFinding {
+            problem,
+            fix: fix.to_string(),
+        });
+    }
+
+    /// The watched paths under another one: their events are received once, with the settings
+    /// of the outer one
+    pub fn check_overlapping_roots(&mut self, roots: &[WatchedPath]) {
+        for (position, root) in roots.iter().enumerate() {
+            let path = absolute(&root.path);
+            for (other_position, other_root) in roots.iter().enumerate() {
+                if position == other_position {
+                    continue;
+                }
+                let other_path = absolute(&other_root.path);
+                if path == other_path && position > other_position {
+                    self.add(
+                        format!("{} is watched twice", root.path.display()),
+                        "remove one of them.",
+                    );
+                } else if path != other_path && is_under(other_root, &other_path, &path) {
+                    self.add(
+                        format!(
+                            "{} is inside the watched path {}",
+                            root.path.display(),
+                            other_root.path.display()
+                        ),
+                        "its events are received once, with the settings of the outer path: remove it, or --exclude it from the outer path.",
+                    );
+                }
+            }
+        }
+    }
+
+    /// The files and directories written by the instance under the watched paths: published as
+    /// any other file, they change at every event, or are applied into themselves
+    pub fn check_written_paths(
+        &mut self,
+        roots: &[WatchedPath],
+        ignored: &PathFilter,
+        written_paths: &[(&str, &Path)],
+    ) {
+        for (flag, written_path) in written_paths {
+            if let Some(root) = watching_root(roots, ignored, written_path) {
+                self.add(
+                    format!(
+                        "the {} {} is inside the watched path {}",
+                        flag,
+                        written_path.display(),
+                        root.path.display()
+                    ),
+                    "it is published as it changes, in a loop: move it out of the watched paths, or --exclude it.",
+                );
+            }
+        }
+    }
+
+    /// The filters leaving no file to synchronize under a watched path
+    pub fn check_filters(
+        &mut self,
+        roots: &[WatchedPath],
+        ignored: &PathFilter,
+        only: &PathFilter,
+    ) {
+        // the missing ones are reported by check_permissions
+        for root in roots.iter().filter(|root| root.path.exists()) {
+            let path = absolute(&root.path);
+            let mut files = Vec::new();
+            sample_files(&path, root.recursive, &mut files);
+            if files.is_empty() {
+                files = PROBE_FILES.iter().map(|probe| path.join(probe)).collect();
+            }
+            if files
+                .iter()
+                .all(|file| ignored.matches(file) || !only.includes(file))
+            {
+                self.add(
+                    format!(
+                        "every file of {} is excluded by the filters",
+                        root.path.display()
+                    ),
+                    "check the --exclude, --only and --ext globs, and the ignore files: a glob without a / matches the files of every directory, as `*` does.",
+                );
+            }
+        }
+    }
+
+    /// The settings of the instance which do not match the namespace: `namespace` is None
+    /// when no instance created it yet
+    pub fn check_namespace(
+        &mut self,
+        namespace: Option<&NamespaceMetadata>,
+        instance: &NamespaceMetadata,
+        compression: Option<Compression>,
+        compression_level: Option<i32>,
+    ) {
+        let namespace = match namespace {
+            None => return,
+            Some(namespace) => namespace,
+        };
+        let incompatibilities = namespace.incompatibilities(instance);
+        if !incompatibilities.is_empty() {
+            self.add(
+                format!(
+                    "the namespace created by {} has other settings: {}",
+                    namespace.created_by,
+                    incompatibilities.join(", ")
+                ),
+                "the instance refuses to start: use the settings of the other instances, or another --namespace.",
+            );
+        }
+        let namespace_compression = namespace.compression.parse::<Compression>().ok();
+        if let Some(compression) = compression {
+            if Some(compression) != namespace_compression {
+                self.add(
+                    format!(
+                        "--compression {} while the namespace uses {}",
+                        compression.name(),
+                        namespace.compression
+                    ),
+                    "the other instances keep compressing with the namespace codec: drop --compression, or switch the namespace with `migrate-content --to`.",
+                );
+            }
+        }
+        if compression_level.is_some()
+            && compression.or(namespace_compression) != Some(Compression::Zstd)
+        {
+            self.add(
+                String::from(
+                    "--compression-level is ignored: the contents are not compressed with zstd",
+                ),
+                "drop --compression-level, or use --compression zstd.",
+            );
+        }
+    }
+
+    /// The paths the instance cannot read, or write although it has to
+    pub fn check_permissions(
+        &mut self,
+        roots: &[WatchedPath],
+        is_applying: bool,
+        read_paths: &[(&str, &Path)],
+        written_paths: &[(&str, &Path)],
+    ) {
+        for root in roots {
+            if !root.path.exists() {
+                self.add(
+                    format!("the watched path {} does not exist", root.path.display()),
+                    "create it, or fix the path.",
+                );
+            } else if root.path.is_dir() && std::fs::read_dir(&root.path).is_err() {
+                self.add(
+                    format!("the watched path {} is not readable", root.path.display()),
+                    "let the user running the instance read it.",
+                );
+            } else if is_applying && !is_writable(&root.path) {
+                self.add(
+                    format!("the watched path {} is not writable", root.path.display()),
+                    "the remote files cannot be applied: let the user running the instance write it, or use --push-only.",
+                );
+            }
+        }
+        for (flag, path) in read_paths {
+            if let Err(error) = File::open(path) {
+                self.add(
+                    format!("the {} {} is not readable: {}", flag, path.display(), error),
+                    "let the user running the instance read it, or fix the path.",
+                );
+            }
+        }
+        for (flag, path) in written_paths {
+            let checked_path = if path.exists() {
+                path.to_path_buf()
+            } else {
+                match path.parent() {
+                    Some(parent) if parent != Path::new("") => parent.to_path_buf(),
+                    _ => PathBuf::from("."),
+                }
+            };
+            if !checked_path.exists() {
+                self.add(
+                    format!(
+                        "the directory of the {} {} does not exist",
+                        flag,
+                        path.display()
+                    ),
+                    "create it, or fix the path.",
+                );
+            } else if !is_writable(&checked_path) {
+                self.add(
+                    format!("the {} {} is not writable", flag, path.display()),
+                    "let the user running the instance write it, or fix the path.",
+                );
+            }
+        }
+    }
+
+    /// Print the findings along with their fix
+    pub fn print(&self) {
+        for finding in self.findings.iter() {
+            println!("{}", finding.problem);
+            println!("  -> {}", finding.fix);
+            println!();
+        }
+        if self.findings.is_empty() {
+            println!("no problem found");
+        }
+    }
+}
+
+/// The path made absolute, through its parent when it does not exist yet
+fn absolute(path: &Path) -> PathBuf {
+    if let Ok(canonical_path) = path.canonicalize() {
+        return canonical_path;
+    }
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) if parent != Path::new("") => absolute(parent).join(name),
+        _ => std::env::current_dir()
+            .map(|directory| directory.join(path))
+            .unwrap_or_else(|_| path.to_path_buf()),
+    }
+}
+
+/// The watched path whose watch receives the events of this path, unless it is ignored
+pub fn watching_root<'a>(
+    roots: &'a [WatchedPath],
+    ignored: &PathFilter,
+    path: &Path,
+) -> Option<&'a WatchedPath> {
+    let path = absolute(path);
+    if ignored.matches(&path) {
+        return None;
+    }
+    roots
+        .iter()
+        .find(|root| is_under(root, &absolute(&root.path), &path))
+}
+
+/// Whether the events of the path are received by the watch of the root
+fn is_under(root: &WatchedPath, root_path: &Path, path: &Path) -> bool {
+    if root.recursive {
+        path != root_path && path.starts_with(root_path)
+    } else {
+        path.parent() == Some(root_path)
+    }
+}
+
+/// Up to SAMPLE_SIZE files of the directory
+fn sample_files(directory: &Path, recursive: bool, files: &mut Vec<PathBuf>) {
+    let entries = match std::fs::read_dir(directory) {
+        Err(_) => return,
+        Ok(entries) => entries,
+    };
+    for entry in entries.flatten() {
+        if files.len() >= SAMPLE_SIZE {
+            return;
+        }
+        let path = entry.path();
+        match entry.file_type() {
+            Ok(file_type) if file_type.is_dir() => {
+                if recursive {
+                    sample_files(&path, recursive, files);
+                }
+            }
+            Ok(_) => files.push(path),
+            Err(_) => (),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn is_writable(path: &Path) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+    match std::ffi::CString::new(path.as_os_str().as_bytes()) {
+        Err(_) => false,
+        Ok(path) => unsafe { libc::access(path.as_ptr(), libc::W_OK) == 0 },
+    }
+}
+
+#[cfg(not(unix))]
+fn is_writable(path: &Path) -> bool {
+    std::fs::metadata(path).is_ok_and(|metadata| !metadata.permissions().readonly())
+}
@@ -0,0 +1,381 @@
+use crate::client::http_client::HttpClient;
+use crate::event_handler::sync_events::{EventSink, SyncEvent};
+use anyhow::{bail, Context};
+use log::{debug, error};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// Failures of a path in a period from which they are reported as repeated
+const REPEATED_FAILURE_COUNT: usize = 3;
+/// Removals by an instance in a period from which they are reported as a mass deletion
+const MASS_DELETION_COUNT: usize = 100;
+/// Paths listed for each finding, the others are only counted
+const MAX_LISTED_PATHS: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+impl Severity {
+    fn name(self) -> &'static str {
+        match self {
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        }
+    }
+}
+
+impl std::str::FromStr for Severity {
+    type Err = anyhow::Error;
+
+    fn from_str(severity: &str) -> Result<Severity, anyhow::Error> {
+        match severity {
+            "info" => Ok(Severity::Info),
+            "warning" => Ok(Severity::Warning),
+            "error" => Ok(Severity::Error),
+            _ => bail!("severity must be info, warning or error, got: {}", severity),
+        }
+    }
+}
+
+/// Where the findings of at least `min_severity` are sent: a Slack incoming webhook, another
+/// webhook receiving them as JSON, or `mailto:` an address, through the local sendmail
+#[derive(Debug, Clone)]
+pub struct DigestDestination {
+    pub min_severity: Severity,
+    pub url: String,
+}
+
+impl std::str::FromStr for DigestDestination {
+    type Err = anyhow::Error;
+
+    fn from_str(destination: &str) -> Result<DigestDestination, anyhow::Error> {
+        let (min_severity, url) = match destination.find('=') {
+            Some(position) => (&destination[..position], &destination[position + 1..]),
+            None => bail!(
+                "digest must be formatted as severity=url, got: {}",
+                destination
+            ),
+        };
+        let scheme = url::Url::parse(url)
+            .with_context(|| format!("invalid digest url {}", url))?
+            .scheme()
+            .to_string();
+        if !matches!(scheme.as_str(), "http" | "https" | "mailto") {
+            bail!(
+                "digest url must start with http://, https:// or mailto:, got {}",
+                url
+            );
+        }
+        Ok(DigestDestination {
+            min_severity: min_severity.parse()?,
+            url: url.to_string(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Finding {
+    pub severity: Severity,
+    pub summary: String,
+    pub paths: Vec<String>,
+}
+
+/// The noteworthy events since the last digest
+#[derive(Debug, Default)]
+struct DigestState {
+    /// Path -> the peer whose change won
+    conflicts: BTreeMap<PathBuf, u64>,
+    /// Path -> number of failures, last error
+    failures: BTreeMap<PathBuf, (usize, String)>,
+    /// Instance -> paths it removed
+    removals: BTreeMap<u64, Vec<PathBuf>>,
+    /// Verified and mismatched files
+    verification_alerts: Vec<(usize, usize)>,
+    joined_peers: Vec<String>,
+    left_peers: Vec<String>,
+}
+
+/// Batches the noteworthy events (conflicts, repeated failures, mass deletions, peers joining
+/// and leaving) and sends their summary every period, so that the teams without a metrics
+/// stack still hear about them. Nothing is sent for a period without any.
+pub struct DigestNotifier {
+    destinations: Vec<DigestDestination>,
+    instance_name: String,
+    state: Mutex<DigestState>,
+}
+
+impl DigestNotifier {
+    pub fn new(destinations: Vec<DigestDestination>, instance_name: String) -> DigestNotifier {
+        DigestNotifier {
+            destinations,
+            instance_name,
+            state: Mutex::new(DigestState::default()),
+        }
+    }
+
+    /// Send the digest of each period until the process exits
+    pub fn send_periodically(
+        self: Arc<DigestNotifier>,
+        period: Duration,
+    ) -> Result<JoinHandle<()>, anyhow::Error> {
+        let handle = std::thread::Builder::new()
+            .name(String::from("digest notifier"))
+            .spawn(move || loop {
+                std::thread::sleep(period);
+                let findings = self.take_findings();
+                debug!("[digest_notifier] {} findings", findings.len());
+                for destination in self.destinations.iter() {
+                    let findings: Vec<Finding> = findings
+                        .iter()
+                        .filter(|finding| finding.severity >= destination.min_severity)
+                        .cloned()
+                        .collect();
+                    if findings.is_empty() {
+                        continue;
+                    }
+                    if let Err(error) = self.send(&destination.url, &findings, period) {
+                        error!("unable to send the digest: {:?}", error);
+                    }
+                }
+            })
+            .context("unable to create digest notifier thread")?;
+        Ok(handle)
+    }
+
+    /// The findings since the last call, the most severe first
+    fn take_findings(&self) -> Vec<Finding> {
+        let state = std::mem::take(&mut *self.state.lock().expect("digest lock poisoned"));
+        let mut findings = Vec::new();
+        let list = |paths: Vec<String>| -> Vec<String> {
+            let mut listed: Vec<String> = paths.iter().take(MAX_LISTED_PATHS).cloned().collect();
+            if paths.len() > MAX_LISTED_PATHS {
+                listed.push(format!("... and {} more", paths.len() - MAX_LISTED_PATHS));
+            }
+            listed
+        };
+
+        for (verified, mismatched) in state.verification_alerts {
+            findings.push(Finding {
+                severity: Severity::Error,
+                summary: format!(
+                    "{} of the {} files verified did not match their remote content",
+                    mismatched, verified
+                ),
+                paths: Vec::new(),
+            });
+        }
+        for (emitter_id, paths) in state.removals {
+            if paths.len() < MASS_DELETION_COUNT {
+                continue;
+            }
+            findings.push(Finding {
+                severity: Severity::Error,
+                summary: format!(
+                    "{} files removed by the instance {}",
+                    paths.len(),
+                    emitter_id
+                ),
+                paths: list(
+                    paths
+                        .iter()
+                        .map(|path| path.display().to_string())
+                        .collect(),
+                ),
+            });
+        }
+        let (repeated, occasional): (Vec<_>, Vec<_>) = state
+            .failures
+            .into_iter()
+            .partition(|(_, (count, _))| *count >= REPEATED_FAILURE_COUNT);
+        if !repeated.is_empty() {
+            findings.push(Finding {
+                severity: Severity::Error,
+                summary: format!("{} paths failed repeatedly", repeated.len()),
+                paths: list(
+                    repeated
+                        .iter()
+                        .map(|(path, (count, error))| {
+                            format!("{} ({} times): {}", path.display(), count, error)
+                        })
+                        .collect(),
+                ),
+            });
+        }
+        if !occasional.is_empty() {
+            findings.push(Finding {
+                severity: Severity::Warning,
+                summary: format!("{} paths failed, then were retried", occasional.len()),
+                paths: list(
+                    occasional
+                        .iter()
+                        .map(|(path, _)| path.display().to_string())
+                        .collect(),
+                ),
+            });
+        }
+        if !state.conflicts.is_empty() {
+            findings.push(Finding {
+                severity: Severity::Warning,
+                summary: format!(
+                    "{} local changes were overwritten by a peer before being published",
+                    state.conflicts.len()
+                ),
+                paths: list(
+                    state
+                        .conflicts
+                        .iter()
+                        .map(|(path, emitter_id)| {
+                            format!("{} (by the instance {})", path.display(), emitter_id)
+                        })
+                        .collect(),
+                ),
+            });
+        }
+        if !state.joined_peers.is_empty() {
+            findings.push(Finding {
+                severity: Severity::Info,
+                summary: format!("{} peers joined", state.joined_peers.len()),
+                paths: list(state.joined_peers),
+            });
+        }
+        if !state.left_peers.is_empty() {
+            findings.push(Finding {
+                severity: Severity::Info,
+                summary: format!("{} peers left", state.left_peers.len()),
+                paths: list(state.left_peers),
+            });
+        }
+        findings
+    }
+
+    fn send(&self, url: &str, findings: &[Finding], period: Duration) -> Result<(), anyhow::Error> {
+        let period = match period.as_secs() {
+            seconds if seconds % 60 == 0 => format!("{}m", seconds / 60),
+            seconds => format!("{}s", seconds),
+        };
+        let title = format!(
+            "fs-synchronizer digest of {}, over the last {}",
+            self.instance_name, period
+        );
+        let mut text = title.clone();
+        for finding in findings {
+            text.push_str(&format!(
+                "\n[{}] {}",
+                finding.severity.name(),
+                finding.summary
+            ));
+            for path in finding.paths.iter() {
+                text.push_str(&format!("\n    {}", path));
+            }
+        }
+
+        let url = url::Url::parse(url).with_context(|| format!("invalid digest url {}", url))?;
+        if url.scheme() == "mailto" {
+            return DigestNotifier::send_mail(url.path(), &title, &text);
+        }
+        let body = if url.host_str() == Some("hooks.slack.com") {
+            serde_json::json!({ "text": text })
+        } else {
+            serde_json::json!({
+                "instance": self.instance_name,
+                "text": text,
+                "findings": findings,
+            })
+        };
+        let mut path = url.path().to_string();
+        if let Some(query) = url.query() {
+            path.push('?');
+            path.push_str(query);
+        }
+        let response = HttpClient::new(url.as_str())?.request(
+            "POST",
+            &path,
+            &[("Content-Type", String::from("application/json"))],
+            &serde_json::to_vec(&body)?,
+        )?;
+        if !response.is_success() {
+            bail!(
+                "the digest was refused by {}: status {}",
+                url.host_str().unwrap_or_default(),
+                response.status
+            );
+        }
+        Ok(())
+    }
+
+    fn send_mail(address: &str, subject: &str, text: &str) -> Result<(), anyhow::Error> {
+        let mut sendmail = Command::new("sendmail")
+            .args(["-t", "-i"])
+            .stdin(Stdio::piped())
+            .spawn()
+            .context("unable to run sendmail")?;
+        sendmail
+            .stdin
+            .take()
+            .expect("the standard input of sendmail is piped")
+            .write_all(format!("To: {}\nSubject: {}\n\n{}\n", address, subject, text).as_bytes())
+            .context("unable to write the mail to sendmail")?;
+        let status = sendmail.wait().context("sendmail failed")?;
+        if !status.success() {
+            bail!(
+                "sendmail failed to send the digest to {}: {}",
+                address,
+                status
+            );
+        }
+        Ok(())
+    }
+}
+
+impl EventSink for DigestNotifier {
+    fn handle(&self, event: &SyncEvent) {
+        let mut state = self.state.lock().expect("digest lock poisoned");
+        match event {
+            SyncEvent::PublishFailed { path, error } | SyncEvent::ApplyFailed { path, error } => {
+                let failures = state.failures.entry(path.clone()).or_default();
+                failures.0 += 1;
+                failures.1 = error.clone();
+            }
+            SyncEvent::Conflict { path, emitter_id } => {
+                state.conflicts.insert(path.clone(), *emitter_id);
+            }
+            SyncEvent::Removed { path, emitter_id } => {
+                state
+                    .removals
+                    .entry(*emitter_id)
+                    .or_default()
+                    .push(path.clone());
+            }
+            SyncEvent::VerificationAlert {
+                verified,
+                mismatched,
+            } => state.verification_alerts.push((*verified, *mismatched)),
+            SyncEvent::PeerJoined { instance_id, name } => {
+                state.joined_peers.push(peer_label(*instance_id, name))
+            }
+            SyncEvent::PeerLeft { instance_id, name } => {
+                state.left_peers.push(peer_label(*instance_id, name))
+            }
+            _ => (),
+        }
+    }
+}
+
+fn peer_label(instance_id: u64, name: &Option<String>) -> String {
+    match name {
+        Some(name) => format!("{} ({})", name, instance_id),
+        None => instance_id.to_string(),
+    }
+}
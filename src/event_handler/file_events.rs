@@ -3,14 +3,18 @@ use anyhow::bail;
 use std::path::PathBuf;
 
 pub enum FileEvents {
-    /// (absolute path, hash)
-    New(PathBuf, u64),
-    /// (absolute path, hash)
-    Modified(PathBuf, u64),
+    /// (absolute path, hash, compressed content when inlined in the event)
+    New(PathBuf, u64, Option<Vec<u8>>),
+    /// (absolute path, hash, compressed content when inlined in the event)
+    Modified(PathBuf, u64, Option<Vec<u8>>),
     /// (absolute path)
     Removed(PathBuf),
     /// (absolute path, hash)
     Renamed(PathBuf, PathBuf),
+    /// (absolute path)
+    ContentMissing(PathBuf),
+    /// (absolute path, reason)
+    ContentRejected(PathBuf, String),
 }
 
 pub static FILE_EVENT: &str = "file_event";
@@ -31,10 +35,21 @@ impl FileEvents {
         }
 
         let event = match payload {
-            NewFile(_, hash, path) => FileEvents::New(path, hash),
-            ModifiedFile(_, hash, path) => FileEvents::Modified(path, hash),
+            NewFile(_, hash, path) => FileEvents::New(path, hash, None),
+            ModifiedFile(_, hash, path) => FileEvents::Modified(path, hash, None),
+            InlineNewFile(_, hash, path, content) => FileEvents::New(path, hash, Some(content)),
+            InlineModifiedFile(_, hash, path, content) => {
+                FileEvents::Modified(path, hash, Some(content))
+            }
             RemovedFile(_, path) => FileEvents::Removed(path),
             RenamedFile(_, old, new) => FileEvents::Renamed(old, new),
+            ContentMissing(_, path) => FileEvents::ContentMissing(path),
+            ContentRejected(_, path, reason) => FileEvents::ContentRejected(path, reason),
+            RolloutApproved(_) => bail!("a rollout approval is not a file event"),
+            PullRequested(_, _, _, _) | PullAcknowledged(_, _, _) => {
+                bail!("a pull request is not a file event")
+            }
+            Stamped(_, payload) => return FileEvents::from_str_and_payload(kind, *payload),
         };
         Ok(event)
     }
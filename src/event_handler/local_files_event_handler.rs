@@ -1,35 +1,172 @@
-use crate::store::local_fs_store::LocalFSStore;
-use crate::store::redis_store::RedisStore;
-use anyhow::{anyhow, Context, Result};
-use log::{debug, error};
-use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use crate::event_handler::path_filter::PathFilter;
+use crate::event_handler::retry_scheduler::{RetryDirection, RetryScheduler};
+use crate::event_handler::sync_events::{SyncEvent, SyncEvents};
+use crate::event_handler::transfer_gate::TransferGate;
+use crate::event_handler::transfer_scheduler::{ScheduledEvent, Transfer, TransferScheduler};
+use crate::logs::ErrorAggregator;
+use crate::store::local_fs_store::{LocalFSStore, Symlinks};
+use crate::store::sync_store::SyncStore;
+use crate::store::synced_hashes::SyncedHashes;
+use anyhow::{anyhow, bail, Context, Result};
+use log::{debug, error, info};
+use notify::{PollWatcher, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::btree_map::Entry;
+use std::collections::BTreeMap;
 use std::path::{Path, PathBuf};
-use std::sync::mpsc::channel;
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::sync::{Arc, RwLock};
 use std::thread::JoinHandle;
 use std::time::Duration;
 
-pub struct LocalFilesEventHandler {
-    event_bounce_ms: u64,
+/// Identical errors are logged once per window
+const ERRORS_AGGREGATION_WINDOW: Duration = Duration::from_secs(60);
+/// Maximum delay before checking the due retries when there is no event
+const RETRY_TICK: Duration = Duration::from_secs(1);
+
+/// Name of the watcher backend of this platform, as told to the subscribers
+#[cfg(target_os = "linux")]
+const WATCHER_NAME: &str = "inotify";
+#[cfg(target_os = "macos")]
+const WATCHER_NAME: &str = "fsevents";
+#[cfg(target_os = "windows")]
+const WATCHER_NAME: &str = "ReadDirectoryChangesW";
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+const WATCHER_NAME: &str = "polling";
+const POLL_WATCHER_NAME: &str = "polling";
+
+/// Which local changes are published, and who is told about them
+#[derive(Debug, Clone, Default)]
+pub struct UploadPolicy {
+    /// Local files never published, as the rendered templates
+    pub no_upload: PathFilter,
+    /// Files never published: the excluded paths, and the files of the other tools
+    /// synchronizing the tree. A file renamed from one of them is published as a new file, as
+    /// it is how these tools write the files.
+    /// Shared, as a reload of the configuration changes it.
+    pub ignored: Arc<RwLock<PathFilter>>,
+    /// When not empty, the only files published
+    pub only: PathFilter,
+    /// Subscribers of the changes and of their publication
+    pub events: SyncEvents,
+    /// Hashes last published or applied by this instance, shared with the applies
+    pub synced_hashes: SyncedHashes,
+}
+
+impl UploadPolicy {
+    /// Ignored, or not one of the files published
+    pub fn is_ignored(&self, path: &Path) -> bool {
+        self.ignored
+            .read()
+            .expect("ignored paths lock poisoned")
+            .matches(path)
+            || !self.only.includes(path)
+    }
+}
+
+/// A watched path, and how it is watched
+#[derive(Debug, Clone, PartialEq)]
+pub struct WatchedPath {
+    pub path: PathBuf,
+    /// Watch the subdirectories too
+    pub recursive: bool,
+    /// Event bouncing duration in milliseconds
+    pub event_bounce_ms: u64,
+    /// Scan the path at this interval instead of being notified of its changes, for the
+    /// filesystems not delivering the notifications, as NFS or SMB mounts
+    pub poll_interval: Option<Duration>,
+}
+
+impl WatchedPath {
+    fn watcher_name(&self) -> &'static str {
+        match self.poll_interval {
+            None => WATCHER_NAME,
+            Some(_) => POLL_WATCHER_NAME,
+        }
+    }
+}
+
+/// The watcher of the platform, or the one scanning the paths
+enum FsWatcher {
+    Notified(RecommendedWatcher),
+    Polling(PollWatcher),
+}
+
+impl FsWatcher {
+    /// The polling watcher bounces the events as long as its interval
+    fn new(
+        sender: Sender<notify::DebouncedEvent>,
+        root: &WatchedPath,
+    ) -> notify::Result<FsWatcher> {
+        match root.poll_interval {
+            None => Watcher::new(sender, Duration::from_millis(root.event_bounce_ms))
+                .map(FsWatcher::Notified),
+            Some(poll_interval) => Watcher::new(sender, poll_interval).map(FsWatcher::Polling),
+        }
+    }
+
+    fn watch(&mut self, path: &Path, mode: RecursiveMode) -> notify::Result<()> {
+        match self {
+            FsWatcher::Notified(watcher) => watcher.watch(path, mode),
+            FsWatcher::Polling(watcher) => watcher.watch(path, mode),
+        }
+    }
+
+    fn unwatch(&mut self, path: &Path) -> notify::Result<()> {
+        match self {
+            FsWatcher::Notified(watcher) => watcher.unwatch(path),
+            FsWatcher::Polling(watcher) => watcher.unwatch(path),
+        }
+    }
+}
+
+/// The watchers, and the paths they watch
+struct Watchers {
+    sender: Sender<notify::DebouncedEvent>,
+    /// the bouncing duration, or the polling interval, is set per watcher: one watcher per
+    /// setting
+    by_setting: BTreeMap<(u64, Option<Duration>), FsWatcher>,
+    /// The paths to watch, as last read
+    watched_paths: Vec<WatchedPath>,
+    /// The paths given to the watchers, without the ones covered by a parent
+    roots: Vec<WatchedPath>,
+}
+
+pub struct LocalFilesEventHandler<S: SyncStore> {
     unique_id: u64,
-    paths_to_watch: Vec<PathBuf>,
-    store: RedisStore,
+    /// Shared, as a reload of the configuration changes them: the watchers follow the changes
+    watched_paths: Arc<RwLock<Vec<WatchedPath>>>,
+    upload_policy: UploadPolicy,
+    store: S,
+    errors: ErrorAggregator,
+    retries: RetryScheduler,
+    transfers: TransferGate,
 }
 
-impl LocalFilesEventHandler {
+impl<S: SyncStore> LocalFilesEventHandler<S> {
     pub fn new(
-        store: RedisStore,
+        store: S,
         unique_id: u64,
-        paths_to_watch: Vec<PathBuf>,
-        event_bounce_ms: u64,
-    ) -> LocalFilesEventHandler {
+        watched_paths: Arc<RwLock<Vec<WatchedPath>>>,
+        upload_policy: UploadPolicy,
+        retries: RetryScheduler,
+        transfers: TransferGate,
+    ) -> LocalFilesEventHandler<S> {
         LocalFilesEventHandler {
-            event_bounce_ms,
             unique_id,
-            paths_to_watch,
+            watched_paths,
+            upload_policy,
             store,
+            errors: ErrorAggregator::new(ERRORS_AGGREGATION_WINDOW),
+            retries,
+            transfers,
         }
     }
 
+    /// The errors of the handler, as logged
+    pub fn errors(&self) -> ErrorAggregator {
+        self.errors.clone()
+    }
+
     pub fn watch_events(self) -> Result<JoinHandle<()>, anyhow::Error> {
         let handle = std::thread::Builder::new()
             .name(String::from("local files watcher"))
@@ -42,37 +179,149 @@ impl LocalFilesEventHandler {
         Ok(handle)
     }
 
-    pub fn handle_event(&self, event: notify::DebouncedEvent) {
+    pub fn handle_event(&self, event: notify::DebouncedEvent, transfer: &Transfer) {
         use notify::DebouncedEvent::*;
 
         debug!("[local_file] got {:?}", event);
 
-        let res = match event {
-            Create(path) => {
-                if path.is_dir() {
-                    debug!("path is directory, skipping (path={})", path.display());
-                    return;
+        let is_placeholder_event = match &event {
+            Create(path) | Write(path) | Remove(path) => LocalFSStore::is_placeholder(path),
+            Rename(old_path, new_path) => {
+                LocalFSStore::is_placeholder(old_path) || LocalFSStore::is_placeholder(new_path)
+            }
+            _ => false,
+        };
+        if is_placeholder_event {
+            debug!("[local_file] placeholders are never published, skipping");
+            return;
+        }
+        let event = match self.without_ignored_paths(event) {
+            None => {
+                debug!("[local_file] path is excluded, or written by another synchronization tool, skipping");
+                return;
+            }
+            Some(event) => event,
+        };
+        let no_upload = &self.upload_policy.no_upload;
+        let is_no_upload_event = match &event {
+            Create(path) | Write(path) | Remove(path) => no_upload.matches(path),
+            Rename(old_path, new_path) => {
+                no_upload.matches(old_path) || no_upload.matches(new_path)
+            }
+            _ => false,
+        };
+        if is_no_upload_event {
+            debug!("[local_file] path is excluded from uploads, skipping");
+            return;
+        }
+
+        if self.transfers.is_held() {
+            // the current state of the paths is published once resumed
+            match event {
+                Create(path) | Write(path) | Remove(path) => {
+                    self.transfers.defer(RetryDirection::Upload, path)
                 }
-                self.get_file_content_and_hash(&path)
-                    .and_then(|(content, hash)| {
-                        self.store.new_file(self.unique_id, path, &*content, hash)
-                    })
+                Rename(old_path, new_path) => {
+                    self.transfers.defer(RetryDirection::Upload, old_path);
+                    self.transfers.defer(RetryDirection::Upload, new_path);
+                }
+                _ => (),
             }
-            Write(path) => {
-                if path.is_dir() {
+            return;
+        }
+
+        let is_content_event =
+            matches!(&event, Create(path) | Write(path) if !LocalFSStore::is_directory(path));
+        if is_content_event && self.transfers.is_paused() {
+            if let Create(path) | Write(path) = event {
+                self.transfers.defer(RetryDirection::Upload, path);
+            }
+            return;
+        }
+        match &event {
+            // the content of the old path may not have been uploaded
+            Rename(_, new_path)
+                if self.transfers.is_paused() && LocalFSStore::is_file(new_path) =>
+            {
+                self.transfers
+                    .defer(RetryDirection::Upload, new_path.clone())
+            }
+            _ => (),
+        }
+
+        let paths: Vec<PathBuf> = match &event {
+            Create(path) | Write(path) | Remove(path) => vec![path.clone()],
+            Rename(old_path, new_path) => vec![old_path.clone(), new_path.clone()],
+            _ => Vec::new(),
+        };
+        for path in paths.iter() {
+            self.upload_policy
+                .events
+                .emit(SyncEvent::LocalChanged { path: path.clone() });
+        }
+
+        let is_new_file = matches!(&event, Create(_));
+        let res = match event {
+            Create(path) | Write(path) if transfer.is_cancelled() => {
+                debug!(
+                    "[local_file] a newer content of {} is to be uploaded, skipping",
+                    path.display()
+                );
+                return;
+            }
+            Create(path) | Write(path) => {
+                if LocalFSStore::is_directory(&path) {
                     debug!("path is directory, skipping (path={})", path.display());
                     return;
                 }
-                self.get_file_content_and_hash(&path)
-                    .and_then(|(content, hash)| {
-                        self.store
-                            .modified_file(self.unique_id, path, &*content, hash)
-                    })
-            }
-            Remove(path) => self.store.removed_file(self.unique_id, path),
-            Rename(old_path, new_path) => {
-                self.store.renamed_file(self.unique_id, old_path, new_path)
+                let _transfer = self.transfers.transferring();
+                match self.get_file_content_and_hash(&path) {
+                    // reading and compressing a large file takes a while
+                    Ok(_) if transfer.is_cancelled() => {
+                        debug!(
+                            "[local_file] {} was written again while reading it, skipping",
+                            path.display()
+                        );
+                        return;
+                    }
+                    // rewritten with the same bytes, or written by an apply
+                    Ok((_, hash)) if self.is_published(&path, hash) => {
+                        debug!(
+                            "[local_file] the content of {} is the one last synchronized, skipping",
+                            path.display()
+                        );
+                        self.retries.succeeded(RetryDirection::Upload, &path);
+                        return;
+                    }
+                    Ok((content, hash)) if is_new_file => self
+                        .store
+                        .new_file(self.unique_id, path.clone(), &content, hash)
+                        .map(|()| self.upload_policy.synced_hashes.record(&path, hash)),
+                    Ok((content, hash)) => self
+                        .store
+                        .modified_file(self.unique_id, path.clone(), &content, hash)
+                        .map(|()| self.upload_policy.synced_hashes.record(&path, hash)),
+                    Err(error) => Err(error),
+                }
             }
+            Remove(path) => self
+                .store
+                .removed_file(self.unique_id, path.clone())
+                .map(|()| {
+                    self.upload_policy.synced_hashes.forget(&path);
+                    self.upload_policy.events.emit(SyncEvent::Removed {
+                        path,
+                        emitter_id: self.unique_id,
+                    })
+                }),
+            Rename(old_path, new_path) => self
+                .store
+                .renamed_file(self.unique_id, old_path.clone(), new_path.clone())
+                .map(|()| {
+                    self.upload_policy
+                        .synced_hashes
+                        .rename(&old_path, &new_path)
+                }),
             NoticeWrite(_path) => Ok(()),  // do nothing
             NoticeRemove(_path) => Ok(()), // do nothing
             Chmod(_) => Ok(()),            // do nothing
@@ -83,29 +332,324 @@ impl LocalFilesEventHandler {
             Error(error, path) => Err(anyhow!("Error: {} on path {:?}", error, path)),
         };
 
-        if let Err(error) = res {
-            error!("Error when handling event: {:?}", error)
+        match res {
+            Ok(()) => {
+                for path in paths {
+                    self.retries.succeeded(RetryDirection::Upload, &path);
+                    self.upload_policy
+                        .events
+                        .emit(SyncEvent::Published { path });
+                }
+            }
+            Err(error) => {
+                for path in paths.iter() {
+                    self.upload_policy.events.emit(SyncEvent::PublishFailed {
+                        path: path.clone(),
+                        error: format!("{:#}", error),
+                    });
+                }
+                self.errors
+                    .error(format!("Error when handling event: {:?}", error));
+                for path in paths {
+                    self.retries.schedule(RetryDirection::Upload, path);
+                }
+            }
+        }
+    }
+
+    /// The event as seen without the ignored files: a rename from one of them creates the file,
+    /// and a rename to one of them removes it
+    fn without_ignored_paths(
+        &self,
+        event: notify::DebouncedEvent,
+    ) -> Option<notify::DebouncedEvent> {
+        use notify::DebouncedEvent::*;
+
+        let is_ignored =
+            |path: &Path| self.upload_policy.is_ignored(path) || self.is_unpublished_link(path);
+        match event {
+            Create(path) | Write(path) | Remove(path) if is_ignored(&path) => None,
+            Rename(old_path, new_path) => match (is_ignored(&old_path), is_ignored(&new_path)) {
+                (true, true) => None,
+                (true, false) => Some(Create(new_path)),
+                (false, true) => Some(Remove(old_path)),
+                (false, false) => Some(Rename(old_path, new_path)),
+            },
+            event => Some(event),
+        }
+    }
+
+    /// Whether the symlink policy keeps the path from being published: a skipped link, or a
+    /// file reached through a linked directory of a watched root. The watchers follow the links
+    /// to the directories whatever the policy.
+    fn is_unpublished_link(&self, path: &Path) -> bool {
+        let symlinks = LocalFSStore::symlinks();
+        if symlinks == Symlinks::Follow {
+            return false;
+        }
+        if symlinks == Symlinks::Skip && LocalFSStore::is_symlink(path) {
+            return true;
+        }
+        let watched_paths = self
+            .watched_paths
+            .read()
+            .expect("watched paths lock poisoned");
+        // the roots themselves may be links, as /tmp on macOS
+        let root = watched_paths
+            .iter()
+            .map(|watched_path| watched_path.path.as_path())
+            .filter(|root| path.starts_with(root))
+            .max_by_key(|root| root.components().count());
+        match root {
+            None => false,
+            Some(root) => path
+                .ancestors()
+                .skip(1)
+                .take_while(|directory| *directory != root)
+                .any(LocalFSStore::is_symlink),
+        }
+    }
+
+    /// Publish again the current state of the paths whose upload failed or was deferred
+    fn upload_again(&self, paths: Vec<PathBuf>) {
+        for path in paths {
+            debug!("[local_file] uploading again {}", path.display());
+            if LocalFSStore::is_file(&path) && self.transfers.is_paused() {
+                self.retries.succeeded(RetryDirection::Upload, &path);
+                self.transfers.defer(RetryDirection::Upload, path);
+                continue;
+            }
+            let res = if LocalFSStore::is_file(&path) {
+                let _transfer = self.transfers.transferring();
+                self.get_file_content_and_hash(&path)
+                    .and_then(|(content, hash)| {
+                        self.store
+                            .new_file(self.unique_id, path.clone(), &content, hash)
+                            .map(|()| self.upload_policy.synced_hashes.record(&path, hash))
+                    })
+            } else if !LocalFSStore::exists(&path) {
+                self.store
+                    .removed_file(self.unique_id, path.clone())
+                    .map(|()| self.upload_policy.synced_hashes.forget(&path))
+            } else {
+                Ok(())
+            };
+
+            match res {
+                Ok(()) => {
+                    self.retries.succeeded(RetryDirection::Upload, &path);
+                    self.upload_policy
+                        .events
+                        .emit(SyncEvent::Published { path });
+                }
+                Err(error) => {
+                    self.upload_policy.events.emit(SyncEvent::PublishFailed {
+                        path: path.clone(),
+                        error: format!("{:#}", error),
+                    });
+                    self.errors.error(format!(
+                        "Error when uploading again {}: {:?}",
+                        path.display(),
+                        error
+                    ));
+                    self.retries.schedule(RetryDirection::Upload, path);
+                }
+            }
         }
     }
 
     fn start_watching(&self) -> Result<()> {
-        let (tx, event_channel) = channel();
-        let mut watcher: RecommendedWatcher =
-            Watcher::new(tx, Duration::from_millis(self.event_bounce_ms))
-                .context("unable to create the fs watcher")?;
-        for path in self.paths_to_watch.iter() {
-            debug!("[local_file] watching {:?}", path);
-            watcher
-                .watch(path, RecursiveMode::Recursive)
-                .context("fs watcher is unable to setup")?;
+        let (sender, watcher_events) = channel();
+        let (scheduled_sender, event_channel) = channel();
+        TransferScheduler::new().forward(watcher_events, scheduled_sender)?;
+        let mut watchers = Watchers {
+            sender,
+            by_setting: BTreeMap::new(),
+            watched_paths: Vec::new(),
+            roots: Vec::new(),
+        };
+
+        // a failing root must not prevent the others from being synchronized
+        let failed_roots = self.follow_watched_paths(&mut watchers)?;
+        if watchers.roots.is_empty() {
+            bail!("fs watcher is unable to setup: no path could be watched");
         }
+        info!(
+            "watching {} paths ({} failed to register)",
+            watchers.roots.len(),
+            failed_roots
+        );
 
         loop {
-            match event_channel.recv() {
-                Ok(event) => self.handle_event(event),
+            match event_channel.recv_timeout(RETRY_TICK) {
+                Ok(ScheduledEvent { event, transfer }) => self.handle_event(event, &transfer),
+                Err(RecvTimeoutError::Timeout) => (),
                 Err(e) => panic!("FATAL ERROR with the channel: {:?}", e),
             }
+            self.upload_again(self.retries.take_due(RetryDirection::Upload));
+            self.upload_again(self.transfers.take_deferred(RetryDirection::Upload));
+            if let Err(error) = self.follow_watched_paths(&mut watchers) {
+                error!("unable to watch the new watched paths. Error: {:?}", error);
+            }
+            self.errors.flush();
+        }
+    }
+
+    /// Watch the roots of the watched paths which are not watched yet, and stop watching the
+    /// ones which are not watched paths anymore. Returns the number of roots which could not be
+    /// watched.
+    fn follow_watched_paths(&self, watchers: &mut Watchers) -> Result<usize> {
+        let watched_paths = self
+            .watched_paths
+            .read()
+            .expect("watched paths lock poisoned")
+            .clone();
+        if watched_paths == watchers.watched_paths {
+            return Ok(0);
+        }
+        let events = &self.upload_policy.events;
+
+        // the paths covered by a root which cannot be watched are watched on their own
+        let mut failed_paths: Vec<PathBuf> = Vec::new();
+        let mut attempted_roots: Vec<WatchedPath> = Vec::new();
+        let roots = loop {
+            let remaining_paths: Vec<WatchedPath> = watched_paths
+                .iter()
+                .filter(|watched_path| !failed_paths.contains(&canonical_path(&watched_path.path)))
+                .cloned()
+                .collect();
+            let roots = Self::dedupe_nested_roots(&remaining_paths);
+            let added_roots: Vec<WatchedPath> = roots
+                .iter()
+                .filter(|root| !watchers.roots.contains(root) && !attempted_roots.contains(root))
+                .cloned()
+                .collect();
+            if added_roots.is_empty() {
+                break roots;
+            }
+            for root in added_roots {
+                debug!("[local_file] watching {:?}", root);
+                attempted_roots.push(root.clone());
+                let watcher = match watchers
+                    .by_setting
+                    .entry((root.event_bounce_ms, root.poll_interval))
+                {
+                    Entry::Occupied(entry) => entry.into_mut(),
+                    Entry::Vacant(entry) => entry.insert(
+                        FsWatcher::new(watchers.sender.clone(), &root)
+                            .context("unable to create the fs watcher")?,
+                    ),
+                };
+                let mode = if root.recursive {
+                    RecursiveMode::Recursive
+                } else {
+                    RecursiveMode::NonRecursive
+                };
+                if let Err(error) = watcher.watch(&root.path, mode) {
+                    error!(
+                        "fs watcher is unable to watch {}. Error: {:?}",
+                        root.path.display(),
+                        error
+                    );
+                    failed_paths.push(canonical_path(&root.path));
+                } else {
+                    events.emit(SyncEvent::Watching {
+                        path: root.path.clone(),
+                        watcher: root.watcher_name().to_string(),
+                    });
+                }
+            }
+        };
+
+        for root in watchers.roots.iter().filter(|root| !roots.contains(root)) {
+            if let Some(watcher) = watchers
+                .by_setting
+                .get_mut(&(root.event_bounce_ms, root.poll_interval))
+            {
+                // it fails when the root could not be watched
+                if let Err(error) = watcher.unwatch(&root.path) {
+                    debug!(
+                        "[local_file] unable to unwatch {}: {:?}",
+                        root.path.display(),
+                        error
+                    );
+                }
+            }
+            info!("not watching {} anymore", root.path.display());
+        }
+        for removed_path in watchers.watched_paths.iter().filter(|watched_path| {
+            !watched_paths
+                .iter()
+                .any(|new_path| new_path.path == watched_path.path)
+        }) {
+            events.emit(SyncEvent::Unwatched {
+                path: removed_path.path.clone(),
+            });
+        }
+        // the new paths already watched through a parent
+        for watched_path in watched_paths.iter().filter(|watched_path| {
+            !roots.iter().any(|root| root.path == watched_path.path)
+                && !failed_paths.contains(&canonical_path(&watched_path.path))
+                && !watchers
+                    .watched_paths
+                    .iter()
+                    .any(|old_path| old_path.path == watched_path.path)
+        }) {
+            // watched by its innermost parent
+            let path = canonical_path(&watched_path.path);
+            let watcher = roots
+                .iter()
+                .filter(|root| path.starts_with(canonical_path(&root.path)))
+                .max_by_key(|root| root.path.components().count())
+                .map_or(WATCHER_NAME, WatchedPath::watcher_name);
+            events.emit(SyncEvent::Watching {
+                path: watched_path.path.clone(),
+                watcher: watcher.to_string(),
+            });
         }
+
+        watchers.watched_paths = watched_paths;
+        watchers.roots = roots;
+        Ok(failed_paths.len())
+    }
+
+    /// Remove duplicated paths and paths already covered by a recursive watch on one of their
+    /// parents, compared once canonicalized as `./a` and `/abs/a` are the same directory. A
+    /// covered path gets the events of its parent, bounced as its parent's.
+    fn dedupe_nested_roots(watched_paths: &[WatchedPath]) -> Vec<WatchedPath> {
+        let mut sorted_paths: Vec<(PathBuf, WatchedPath)> = watched_paths
+            .iter()
+            .map(|watched_path| (canonical_path(&watched_path.path), watched_path.clone()))
+            .collect();
+        // a path watched recursively covers the same path watched alone
+        sorted_paths.sort_by(|(a_path, a), (b_path, b)| {
+            a_path.cmp(b_path).then(b.recursive.cmp(&a.recursive))
+        });
+
+        let mut roots: Vec<(PathBuf, WatchedPath)> = Vec::with_capacity(sorted_paths.len());
+        for (path, watched_path) in sorted_paths {
+            let covering_root = roots.iter().find(|(root_path, root)| {
+                *root_path == path || (root.recursive && path.starts_with(root_path))
+            });
+            match covering_root {
+                Some((_, root)) => {
+                    debug!(
+                        "[local_file] {} is already watched through {}",
+                        watched_path.path.display(),
+                        root.path.display()
+                    );
+                }
+                None => roots.push((path, watched_path)),
+            }
+        }
+        roots.into_iter().map(|(_, root)| root).collect()
+    }
+
+    /// Whether this content of the file is the one this instance last published or applied.
+    /// Unlike the remote hash, it does not follow the changes of the peers: a local edit back to
+    /// an older content, made after a peer changed the file, is published.
+    fn is_published(&self, path: &Path, hash: u64) -> bool {
+        self.upload_policy.synced_hashes.is_synced(path, hash)
     }
 
     fn get_file_content_and_hash(&self, path: &Path) -> Result<(Vec<u8>, u64), anyhow::Error> {
@@ -115,3 +659,8 @@ impl LocalFilesEventHandler {
         Ok((contents, hash))
     }
 }
+
+/// The path canonicalized, or as given when it does not exist
+fn canonical_path(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+}
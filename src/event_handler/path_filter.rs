@@ -0,0 +1,148 @@
+use anyhow::Context;
+use glob::{MatchOptions, Pattern};
+use ignore::gitignore::Gitignore;
+use ignore::{Match, WalkBuilder};
+use log::{debug, warn};
+use std::path::{Path, PathBuf};
+
+/// A set of globs matched against the absolute paths of the files.
+/// Relative globs (not starting with `/`) can match at any depth, so `secrets/**` matches
+/// `/home/user/project/secrets/key.pem`.
+#[derive(Debug, Clone, Default)]
+pub struct PathFilter {
+    patterns: Vec<Pattern>,
+    /// The ignore files, the deepest first, and `.ignore` before `.gitignore` in a directory
+    ignore_files: Vec<Gitignore>,
+}
+
+/// Files listing the paths ignored in their directory, by order of precedence, as ripgrep reads
+/// them
+pub const IGNORE_FILES: &[&str] = &[".ignore", ".gitignore"];
+
+/// Temporary and conflict files of syncthing and rsync, which may synchronize the same tree
+pub const FOREIGN_ARTIFACTS: &[&str] = &[
+    ".syncthing.*.tmp",
+    "~syncthing~*.tmp",
+    "*.sync-conflict-*",
+    ".~tmp~/**",
+];
+
+/// Swap, backup and probe files of the editors, and the metadata files of the file managers,
+/// which are of no use on the other machines
+pub const EDITOR_ARTIFACTS: &[&str] = &[
+    "*.swp",
+    "*.swo",
+    "*~",
+    // vim checks that it may write in the directory by creating this file
+    "4913",
+    ".DS_Store",
+    "Thumbs.db",
+];
+
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+impl PathFilter {
+    pub fn new(globs: &[String]) -> Result<PathFilter, anyhow::Error> {
+        let patterns = globs
+            .iter()
+            .map(|glob| {
+                let anchored_glob = if glob.starts_with('/') {
+                    glob.clone()
+                } else {
+                    format!("**/{}", glob)
+                };
+                Pattern::new(&anchored_glob).with_context(|| format!("invalid glob: {}", glob))
+            })
+            .collect::<Result<Vec<Pattern>, anyhow::Error>>()?;
+        Ok(PathFilter {
+            patterns,
+            ignore_files: Vec::new(),
+        })
+    }
+
+    /// The filter also matching the paths ignored by the ignore files under these roots. The
+    /// files are read once: the ones written later are not followed. The directories they ignore
+    /// are not searched for other ignore files.
+    pub fn with_ignore_files(mut self, roots: &[PathBuf]) -> PathFilter {
+        let mut ignore_file_paths = Vec::new();
+        for root in roots {
+            let root = root.canonicalize().unwrap_or_else(|_| root.clone());
+            let walk = WalkBuilder::new(&root)
+                .standard_filters(false)
+                .git_ignore(true)
+                .ignore(true)
+                .require_git(false)
+                .filter_entry(|entry| entry.file_name() != ".git")
+                .build();
+            for entry in walk {
+                let path = match entry {
+                    Ok(entry) => entry.into_path(),
+                    Err(error) => {
+                        debug!("[path_filter] unable to search for ignore files: {}", error);
+                        continue;
+                    }
+                };
+                let is_ignore_file = path
+                    .file_name()
+                    .is_some_and(|name| IGNORE_FILES.iter().any(|file| name == *file));
+                if is_ignore_file && path.is_file() {
+                    ignore_file_paths.push(path);
+                }
+            }
+        }
+        ignore_file_paths.sort_by_key(|path| {
+            let precedence = path
+                .file_name()
+                .and_then(|name| IGNORE_FILES.iter().position(|file| name == *file));
+            (std::cmp::Reverse(path.components().count()), precedence)
+        });
+        ignore_file_paths.dedup();
+        for path in ignore_file_paths {
+            let (ignore_file, error) = Gitignore::new(&path);
+            if let Some(error) = error {
+                warn!("invalid lines in {}: {}", path.display(), error);
+            }
+            debug!("[path_filter] ignoring the paths of {}", path.display());
+            self.ignore_files.push(ignore_file);
+        }
+        self
+    }
+
+    /// Whether the path is one of the paths to keep, when the filter lists them: all the paths
+    /// are kept by an empty filter. The directories are kept, as their files may be.
+    pub fn includes(&self, path: &Path) -> bool {
+        self.is_empty() || path.is_dir() || self.matches(path)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty() && self.ignore_files.is_empty()
+    }
+
+    pub fn matches(&self, path: &Path) -> bool {
+        self.patterns
+            .iter()
+            .any(|pattern| pattern.matches_path_with(path, MATCH_OPTIONS))
+            || self.is_ignored_by_files(path)
+    }
+
+    /// The deepest ignore file deciding about the path wins, as a whitelist (`!path`) may
+    /// include again a path ignored by a parent directory
+    fn is_ignored_by_files(&self, path: &Path) -> bool {
+        let is_dir = path.is_dir();
+        for ignore_file in self.ignore_files.iter() {
+            if !path.starts_with(ignore_file.path()) {
+                continue;
+            }
+            match ignore_file.matched_path_or_any_parents(path, is_dir) {
+                Match::None => (),
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+            }
+        }
+        false
+    }
+}
@@ -1,38 +1,311 @@
-use crate::client::redis_client::{RedisClient, RedisPublishPayload};
+use crate::client::event_bus::{EventBus, EventMessage};
+use crate::client::redis_client::RedisPublishPayload;
 use crate::event_handler::file_events::{self, FileEvents};
-use crate::store::local_fs_store::LocalFSStore;
-use crate::store::redis_store::RedisStore;
-use anyhow::Context;
-use log::{debug, error, info};
-use std::path::PathBuf;
+use crate::event_handler::path_filter::PathFilter;
+use crate::event_handler::retry_scheduler::{RetryDirection, RetryScheduler};
+use crate::event_handler::sync_events::{SyncEvent, SyncEvents};
+use crate::event_handler::template::Templates;
+use crate::event_handler::transfer_gate::TransferGate;
+use crate::logs::ErrorAggregator;
+use crate::store::audit_store::AuditStore;
+use crate::store::content_store::CONTENT_KEY_PREFIX;
+use crate::store::fleet_semaphore::FleetSemaphore;
+use crate::store::kill_switch::KillSwitch;
+use crate::store::local_fs_store::{LocalFSStore, Symlinks};
+use crate::store::local_hash_cache::LocalHashCache;
+use crate::store::namespace::Namespace;
+use crate::store::presence_store::{PresenceCache, PresenceStore};
+use crate::store::root_mapping::RootMapping;
+use crate::store::sync_store::SyncStore;
+use crate::store::synced_hashes::SyncedHashes;
+use crate::store::write_batch::WriteBatch;
+use anyhow::{bail, Context};
+use log::{debug, error, info, warn};
+use rand::seq::SliceRandom;
+use rand::Rng;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-pub struct RemoteFilesEventHandler {
-    client: RedisClient,
-    store: RedisStore,
+/// Window in which the pub/sub connection losses are counted
+const DISCONNECTIONS_WINDOW: Duration = Duration::from_secs(300);
+/// Number of connection losses in the window above which the pub/sub is considered unreliable
+const MAX_DISCONNECTIONS_IN_WINDOW: usize = 3;
+/// Delay between two reconciliations when the pub/sub is unreliable
+const DEGRADED_RECONCILE_INTERVAL: Duration = Duration::from_secs(30);
+const RECONNECTION_DELAY: Duration = Duration::from_secs(1);
+/// Maximum delay before checking the due retries when there is no message
+const RETRY_TICK: Duration = Duration::from_secs(1);
+/// Emitter of the events built from keyspace notifications, which do not tell who changed the key
+const UNKNOWN_EMITTER_ID: u64 = 0;
+/// Identical errors are logged once per window
+const ERRORS_AGGREGATION_WINDOW: Duration = Duration::from_secs(60);
+/// A content is rejected at most once per interval, so that a content the store keeps corrupting
+/// does not bounce forever between the peers
+const CONTENT_REJECTION_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Default)]
+struct PubSubHealth {
+    disconnections: VecDeque<Instant>,
+    degraded: bool,
+}
+
+impl PubSubHealth {
+    fn update_degraded_state(&mut self) {
+        let now = Instant::now();
+        while self
+            .disconnections
+            .front()
+            .is_some_and(|at| now.duration_since(*at) > DISCONNECTIONS_WINDOW)
+        {
+            self.disconnections.pop_front();
+        }
+
+        let should_degrade = self.disconnections.len() >= MAX_DISCONNECTIONS_IN_WINDOW;
+        if should_degrade == self.degraded {
+            return;
+        }
+        self.degraded = should_degrade;
+        if self.degraded {
+            warn!(
+                "redis pub/sub is unreliable ({} disconnections in the last {}s). Degrading to periodic reconciliation every {}s.",
+                self.disconnections.len(),
+                DISCONNECTIONS_WINDOW.as_secs(),
+                DEGRADED_RECONCILE_INTERVAL.as_secs()
+            );
+        } else {
+            info!("redis pub/sub is stable again. Leaving periodic reconciliation.");
+        }
+    }
+}
+
+/// Where the remote events come from
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum EventSource {
+    /// Events published by the peers on the `file_event` channel
+    #[default]
+    Channel,
+    /// Redis keyspace notifications of the `content:` keys, so that the changes made
+    /// to the store by other tools are applied too
+    KeyspaceNotifications,
+}
+
+/// Which remote events are applied on the local fs, and how
+#[derive(Debug, Clone, Default)]
+pub struct ApplyPolicy {
+    pub event_source: EventSource,
+    /// Namespace of the channel and of the keys the events come from
+    pub namespace: Namespace,
+    /// Paths never synchronized, excluded from applies whatever `no_apply` is. Shared, as a
+    /// reload of the configuration changes it.
+    pub exclude: Arc<RwLock<PathFilter>>,
+    /// When not empty, the only files synchronized
+    pub only: PathFilter,
+    /// Remote paths never applied locally
+    pub no_apply: PathFilter,
+    /// Same as `no_apply`, from the shared configuration. Ignored when `no_apply` is not empty.
+    pub shared_no_apply: Arc<RwLock<PathFilter>>,
+    /// When not empty, only the events of the peers having all these tags are applied
+    pub apply_from_tags: BTreeMap<String, String>,
+    /// Tags of this instance, matched against the audience the peers restrict their events to
+    pub tags: BTreeMap<String, String>,
+    /// Set while every live peer answers the requests for missing content
+    pub peers_answer_missing: Arc<AtomicBool>,
+    /// Set while every live peer answers the content rejections
+    pub peers_answer_rejections: Arc<AtomicBool>,
+    /// Write placeholders for the remote files excluded from applies
+    pub placeholders: bool,
+    /// Apply the remote events into this directory instead of the watched paths
+    pub shadow: Option<PathBuf>,
+    /// Hashes of the applied files, compared with the remote hashes
+    pub local_hashes: LocalHashCache,
+    /// Hashes last published or applied by this instance, so that the applies are not
+    /// published back
+    pub synced_hashes: SyncedHashes,
+    /// Local paths of the files of the store. The events of the files out of the remote root
+    /// are ignored.
+    pub roots: RootMapping,
+    /// Remote files rendered for this instance before being written
+    pub templates: Templates,
+    /// Staged rollout: hold the events during this delay before applying them, unless the
+    /// rollout is approved sooner. Canary instances apply them immediately.
+    pub rollout_soak: Option<Duration>,
+    /// When set, record the versions applied by this instance
+    pub audit: Option<AuditStore>,
+    /// Defers the downloads of the contents while the transfers are paused
+    pub transfers: TransferGate,
+    /// The applied changes not flushed to the disk yet
+    pub writes: Arc<Mutex<WriteBatch>>,
+    /// Nothing is applied while the fleet disables this instance
+    pub kill_switch: KillSwitch,
+    /// Subscribers of the remote changes and of their applies
+    pub events: SyncEvents,
+}
+
+impl ApplyPolicy {
+    /// Where the remote file is applied locally: the file itself, or its copy in the shadow directory
+    pub fn local_path(&self, path: &Path) -> Result<PathBuf, anyhow::Error> {
+        match &self.shadow {
+            None => Ok(path.to_path_buf()),
+            Some(shadow) => LocalFSStore::to_shadow(shadow, path).with_context(|| {
+                format!(
+                    "{} would be applied out of the shadow directory",
+                    path.display()
+                )
+            }),
+        }
+    }
+
+    pub fn is_excluded(&self, path: &Path) -> bool {
+        let is_excluded = self
+            .exclude
+            .read()
+            .expect("excluded paths lock poisoned")
+            .matches(path);
+        if is_excluded || !self.only.includes(path) {
+            return true;
+        }
+        if !self.no_apply.is_empty() {
+            return self.no_apply.matches(path);
+        }
+        self.shared_no_apply
+            .read()
+            .expect("shared no-apply lock poisoned")
+            .matches(path)
+    }
+}
+
+/// How the reconciliations are spread over time across the fleet, and how the local files are
+/// verified
+#[derive(Debug, Clone, Default)]
+pub struct ReconcilePolicy {
+    /// Maximum random delay before each reconciliation
+    pub jitter: Duration,
+    /// When set, limit the number of instances reconciling at the same time
+    pub semaphore: Option<FleetSemaphore>,
+    /// When set, verify a sample of the files at startup and after each reconnection
+    pub verify_sample: Option<SampleVerification>,
+}
+
+/// Verification of a random sample of the files against their remote content. The
+/// reconciliations only compare the local files with the remote hashes, which miss a content
+/// corrupted in the store, or a local file corrupted since it was compared.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SampleVerification {
+    /// Share of the files verified, in percent
+    pub percent: f64,
+    /// Share of the verified files which may mismatch without alert, in percent
+    pub alert_above_percent: f64,
+    /// Verify every file once an alert is raised
+    pub full_on_alert: bool,
+}
+
+pub struct RemoteFilesEventHandler<S: SyncStore> {
+    events: Arc<dyn EventBus>,
+    store: S,
     unique_id: u64,
+    /// None when the peers do not announce themselves
+    presence: Option<PresenceCache>,
+    apply_policy: ApplyPolicy,
+    reconcile_policy: ReconcilePolicy,
+    errors: ErrorAggregator,
+    retries: RetryScheduler,
+    /// Changes waiting for the end of the rollout soak, by order of reception. They are not
+    /// persisted: after a restart, the synchronization holds again the files changed within the
+    /// soak.
+    held_events: Mutex<VecDeque<HeldEvent>>,
+    /// Generation of the store at the last synchronization which applied every file
+    synchronized_generation: Mutex<Option<u64>>,
+    /// Generation of the last change received for each file since that synchronization
+    received_generations: Mutex<HashMap<PathBuf, u64>>,
+    /// Last time the content of each path was rejected
+    rejected_contents: Mutex<HashMap<PathBuf, Instant>>,
 }
 
-impl RemoteFilesEventHandler {
-    pub fn new(client: RedisClient, store: RedisStore, unique_id: u64) -> RemoteFilesEventHandler {
+struct HeldEvent {
+    received: Instant,
+    change: HeldChange,
+}
+
+enum HeldChange {
+    Event {
+        event_kind: String,
+        payload: RedisPublishPayload,
+    },
+    /// A file found changed by a synchronization, to apply with this remote hash
+    Reconciled { path: PathBuf, hash: u64 },
+}
+
+impl<S: SyncStore> RemoteFilesEventHandler<S> {
+    pub fn new(
+        events: Arc<dyn EventBus>,
+        store: S,
+        unique_id: u64,
+        presence: Option<PresenceStore>,
+        apply_policy: ApplyPolicy,
+        reconcile_policy: ReconcilePolicy,
+        retries: RetryScheduler,
+    ) -> RemoteFilesEventHandler<S> {
         RemoteFilesEventHandler {
-            client,
+            events,
             store,
             unique_id,
+            presence: presence.map(PresenceCache::new),
+            apply_policy,
+            reconcile_policy,
+            errors: ErrorAggregator::new(ERRORS_AGGREGATION_WINDOW),
+            retries,
+            held_events: Mutex::new(VecDeque::new()),
+            synchronized_generation: Mutex::new(None),
+            received_generations: Mutex::new(HashMap::new()),
+            rejected_contents: Mutex::new(HashMap::new()),
         }
     }
 
     pub fn synchronize_local_files_with_remote(&self) -> Result<(), anyhow::Error> {
+        if self.apply_policy.kill_switch.is_disabled() {
+            // synchronized once enabled again
+            info!("this instance is disabled by the fleet, not synchronizing");
+            return Ok(());
+        }
+        let permit = match &self.reconcile_policy.semaphore {
+            None => None,
+            Some(semaphore) => Some(semaphore.acquire()?),
+        };
         debug!("[remote_file] synchronizing all remote files to local fs");
 
+        // read before the listing: a change made during the synchronization must not be skipped
+        let generation = self.store.get_generation()?;
+        let mut is_complete = true;
+        let soaking_changes = self
+            .soaking_changes()
+            .context("when synchronizing local files with remote files")?;
         let remote_files = self
             .store
             .get_all_remote_files()
             .context("when synchronizing local files with remote files")?;
 
         for path in remote_files {
+            if let Some(permit) = &permit {
+                permit.keep_alive();
+            }
             debug!("[remote_file] retreiving {}...", path);
             let path = PathBuf::from(path);
+            if self.apply_policy.is_excluded(&path) {
+                debug!("[remote_file] path is excluded from applies. Skipping file.");
+                if let Err(error) = self.write_placeholder(&path) {
+                    self.errors.error(format!(
+                        "unable to write placeholder of {}. Error: {:?}",
+                        &path.display(),
+                        error
+                    ));
+                    is_complete = false;
+                }
+                continue;
+            }
             // XXX remote hash reading is non-fatal. Anything could be in redis.
             // local hash reading is also non-fatal. Maybe the file is not there. We will try to write it to see.
             // In any case, hust use a dummy default value
@@ -43,45 +316,105 @@ impl RemoteFilesEventHandler {
                     info!("non-fatal error when fetching the remote hash. Using dummy value. Error: {:?}", err);
                     0
                 });
-            let local_hash = LocalFSStore::local_hash(&path).unwrap_or_else(|err| {
-                info!(
+            let local_path = match self.apply_policy.local_path(&path) {
+                Err(error) => {
+                    error!("{:#}, skipping it", error);
+                    continue;
+                }
+                Ok(local_path) => local_path,
+            };
+            let local_hash = self
+                .apply_policy
+                .local_hashes
+                .local_hash(&local_path)
+                .unwrap_or_else(|err| {
+                    info!(
                     "non-fatal error when fetching the local hash. Using dummy value. Error: {:?}",
                     err
                 );
-                1
-            });
+                    1
+                });
 
             if remote_hash == local_hash {
                 debug!("[remote_file] local hash matches remote hash. Skipping file.");
+                self.apply_policy
+                    .synced_hashes
+                    .record(&local_path, remote_hash);
+                self.record_applied(&path);
+                continue;
+            }
+            if let Some(changed_at) = soaking_changes.get(&path) {
+                self.hold_reconciled(path, remote_hash, *changed_at);
+                is_complete = false;
+                continue;
+            }
+            if self.apply_policy.transfers.is_paused() {
+                self.apply_policy
+                    .transfers
+                    .defer(RetryDirection::Apply, path);
+                is_complete = false;
                 continue;
             }
 
+            let _transfer = self.apply_policy.transfers.transferring();
             let contents = match self.store.get_remote_file_content(&path) {
                 Err(error) => {
-                    error!(
+                    self.apply_failed(&path, &error);
+                    self.errors.error(format!(
                         "unable to retreive file {} from remote storage. Error: {:?}",
                         &path.display(),
                         error
-                    );
+                    ));
+                    is_complete = false;
+                    continue;
+                }
+                Ok(None) => {
+                    self.request_missing_content(path);
+                    is_complete = false;
                     continue;
                 }
-                Ok(content) => content,
+                Ok(Some(content)) => content,
             };
 
-            if let Err(error) = LocalFSStore::write_file(&path, contents) {
-                error!(
+            if let Err(error) = self.write_applied_file(&path, contents) {
+                self.apply_failed(&path, &error);
+                self.errors.error(format!(
                     "unable to write file {} on local storage ! Error: {:?}",
                     &path.display(),
                     error
-                );
+                ));
+                is_complete = false;
                 continue;
             }
+            self.record_applied(&path);
         }
 
+        if let Err(error) = self.flush_writes() {
+            self.errors.error(format!("{:?}", error));
+            is_complete = false;
+        }
+        if is_complete {
+            *self
+                .synchronized_generation
+                .lock()
+                .expect("synchronized generation lock poisoned") = generation;
+            // the older changes are applied now
+            if let Some(generation) = generation {
+                self.received_generations
+                    .lock()
+                    .expect("received generations lock poisoned")
+                    .retain(|_, received| *received > generation);
+            }
+        }
         debug!("[remote_file] synchronization complete");
         Ok(())
     }
 
+    /// The errors of the handler, as logged
+    pub fn errors(&self) -> ErrorAggregator {
+        self.errors.clone()
+    }
+
     pub fn watch_events(self) -> Result<JoinHandle<()>, anyhow::Error> {
         let handle = std::thread::Builder::new()
             .name(String::from("remote file events thread"))
@@ -95,46 +428,488 @@ impl RemoteFilesEventHandler {
     }
 
     fn start_watching(&self) -> Result<(), anyhow::Error> {
-        debug!("[remote_file] subscribing to redis...");
-        let mut connection = self
-            .client
-            .take_connection()
-            .context("unable to take connection to Redis server")?;
-        let mut pubsub: r2d2_redis::redis::PubSub = connection.as_pubsub();
-        pubsub
-            .psubscribe(file_events::FILE_EVENT)
-            .context("unable to subscribe to redis channels `files:*`")?;
-
+        if self.apply_policy.event_source == EventSource::KeyspaceNotifications {
+            self.warn_if_keyspace_notifications_disabled();
+        }
+        let mut health = PubSubHealth::default();
         loop {
-            let msg = pubsub.get_message()?;
-            let event_kind = msg.get_channel_name();
+            if let Err(error) = self.listen_to_events(&mut health) {
+                warn!(
+                    "lost the connection to the event bus. Reconnecting... Error: {:?}",
+                    error
+                );
+                health.disconnections.push_back(Instant::now());
+                std::thread::sleep(RECONNECTION_DELAY);
+            }
+        }
+    }
+
+    /// Listen to the pub/sub until the connection is lost.
+    /// When the pub/sub is unreliable, the remote files are also reconciled periodically.
+    /// The read timeout wakes the loop up regularly to run the due retries and reconciliations.
+    fn listen_to_events(&self, health: &mut PubSubHealth) -> Result<(), anyhow::Error> {
+        let channel_pattern = match self.apply_policy.event_source {
+            EventSource::Channel => self.apply_policy.namespace.key(file_events::FILE_EVENT),
+            // keyspace notifications of the content keys
+            EventSource::KeyspaceNotifications => self
+                .events
+                .keyspace_pattern(
+                    &self
+                        .apply_policy
+                        .namespace
+                        .key(&format!("{}*", CONTENT_KEY_PREFIX)),
+                )
+                .context("the event bus has no keyspace notifications")?,
+        };
+        let mut last_reconcile = Instant::now();
+        let mut is_subscribed = false;
+        self.events
+            .listen(&channel_pattern, RETRY_TICK, &mut |msg| {
+                if self.apply_policy.kill_switch.is_disabled() {
+                    return Ok(());
+                }
+                if self.apply_policy.kill_switch.take_reenabled() {
+                    // the local files may have been corrupted meanwhile
+                    *self
+                        .synchronized_generation
+                        .lock()
+                        .expect("synchronized generation lock poisoned") = None;
+                    self.store.invalidate_all_cached_hashes();
+                    self.reconcile();
+                }
+                if !is_subscribed && !health.disconnections.is_empty() {
+                    // events may have been published while we were disconnected
+                    self.store.invalidate_all_cached_hashes();
+                    self.reconcile();
+                }
+                if !is_subscribed {
+                    self.verify_sample();
+                }
+                is_subscribed = true;
+
+                self.apply_again(self.retries.take_due(RetryDirection::Apply));
+                self.apply_again(
+                    self.apply_policy
+                        .transfers
+                        .take_deferred(RetryDirection::Apply),
+                );
+                let is_held = self.apply_policy.transfers.is_held();
+                if !is_held {
+                    self.apply_held_events(false);
+                }
+                health.update_degraded_state();
+                if health.degraded
+                    && !is_held
+                    && last_reconcile.elapsed() >= DEGRADED_RECONCILE_INTERVAL
+                {
+                    self.reconcile();
+                    last_reconcile = Instant::now();
+                }
+                if let Some(msg) = msg {
+                    self.handle_message(msg);
+                }
+                // the applies are flushed by batches, at most one tick after they were made
+                if self.writes().is_due() {
+                    if let Err(error) = self.flush_writes() {
+                        self.errors.error(format!("{:?}", error));
+                    }
+                }
+                self.errors.flush();
+                Ok(())
+            })
+            .with_context(|| format!("unable to listen to the channels `{}`", channel_pattern))
+    }
 
-            let payload_res: Result<RedisPublishPayload, rmp_serde::decode::Error> =
-                rmp_serde::from_slice(msg.get_payload_bytes());
+    /// Whether the event of this generation arrives after a newer change of one of its files,
+    /// the pub/sub not keeping the order across the reconnections. A late event is dropped: its
+    /// files which were not changed since are made to match the store. Our own events are
+    /// received too, so that the late events of the peers never overwrite our changes.
+    fn is_late(&self, generation: u64, paths: &[PathBuf]) -> bool {
+        let synchronized_generation = *self
+            .synchronized_generation
+            .lock()
+            .expect("synchronized generation lock poisoned");
+        if synchronized_generation.is_some_and(|synchronized| generation <= synchronized) {
+            debug!(
+                "[remote_file] the change {} was applied by the last synchronization. Skipping message.",
+                generation
+            );
+            return true;
+        }
+        let mut received_generations = self
+            .received_generations
+            .lock()
+            .expect("received generations lock poisoned");
+        let (superseded, not_superseded): (Vec<&PathBuf>, Vec<&PathBuf>) =
+            paths.iter().partition(|path| {
+                received_generations
+                    .get(*path)
+                    .is_some_and(|received| *received > generation)
+            });
+        if superseded.is_empty() {
+            for path in paths {
+                received_generations.insert(path.clone(), generation);
+            }
+            return false;
+        }
+        info!(
+            "dropping the change {} of {:?}, received after a newer change",
+            generation, superseded
+        );
+        for path in not_superseded {
+            self.retries.schedule(RetryDirection::Apply, path.clone());
+        }
+        true
+    }
 
-            let payload = match payload_res {
+    fn handle_message(&self, msg: EventMessage) {
+        debug!("[remote_file] got message on channel '{}'", msg.channel);
+        let (event_kind, payload) = match self.apply_policy.event_source {
+            EventSource::Channel => match RedisPublishPayload::from_bytes(&msg.payload) {
                 Err(error) => {
                     debug!(
                         "error when decoding message. Skipping message. Detailed error: {:?}",
                         error
                     );
-                    continue;
+                    return;
                 }
-                Ok(payload) => payload,
-            };
-            debug!(
-                "[remote_file] got message on channel '{}': {:?}",
-                event_kind, payload
+                Ok(payload) => {
+                    match payload.map_paths(|path| self.apply_policy.roots.to_local(path)) {
+                        None => {
+                            debug!("[remote_file] the event is out of the remote root. Skipping message.");
+                            return;
+                        }
+                        Some(RedisPublishPayload::Stamped(generation, payload)) => {
+                            if self.is_late(generation, &payload.get_changed_paths()) {
+                                return;
+                            }
+                            (file_events::FILE_EVENT, *payload)
+                        }
+                        Some(payload) => (file_events::FILE_EVENT, payload),
+                    }
+                }
+            },
+            EventSource::KeyspaceNotifications => {
+                match self.keyspace_notification_to_payload(
+                    &msg.channel,
+                    &String::from_utf8_lossy(&msg.payload),
+                ) {
+                    None => return,
+                    Some(payload) => (file_events::FILE_EVENT, payload),
+                }
+            }
+        };
+        debug!("[remote_file] decoded payload: {:?}", payload);
+
+        if payload.get_emitter_id() == self.unique_id {
+            debug!("[remote_file] skipping event as we are the emitter");
+            return;
+        }
+        for path in payload.get_changed_paths().iter() {
+            self.store.invalidate_cached_hash(path);
+        }
+        match payload {
+            RedisPublishPayload::PullRequested(emitter_id, request_id, instance_ids, paths) => {
+                if instance_ids.contains(&self.unique_id) {
+                    self.pull(emitter_id, request_id, paths);
+                }
+                return;
+            }
+            // for the instance waiting for them
+            RedisPublishPayload::PullAcknowledged(_, _, _) => return,
+            _ => (),
+        }
+        if self.apply_policy.transfers.is_held() {
+            self.hold_event(payload);
+            return;
+        }
+        match (&payload, self.apply_policy.rollout_soak) {
+            (RedisPublishPayload::RolloutApproved(_), _) => {
+                info!("rollout approved, applying the held events");
+                self.apply_held_events(true);
+            }
+            // missing contents are asked by the peers, not a change to roll out
+            (RedisPublishPayload::ContentMissing(_, _), _)
+            | (RedisPublishPayload::ContentRejected(_, _, _), _)
+            | (_, None) => self.process_event(event_kind, payload),
+            (_, Some(_)) => {
+                debug!("[remote_file] holding the event until the end of the rollout soak");
+                self.held_events
+                    .lock()
+                    .expect("held events lock poisoned")
+                    .push_back(HeldEvent {
+                        received: Instant::now(),
+                        change: HeldChange::Event {
+                            event_kind: event_kind.to_string(),
+                            payload,
+                        },
+                    });
+            }
+        }
+    }
+
+    fn process_event(&self, event_kind: &str, payload: RedisPublishPayload) {
+        let paths = payload.get_changed_paths();
+        let is_change = !matches!(
+            payload,
+            RedisPublishPayload::ContentMissing(_, _)
+                | RedisPublishPayload::ContentRejected(_, _, _)
+        );
+        if is_change {
+            let emitter_id = payload.get_emitter_id();
+            let mut unpublished = self.retries.pending(RetryDirection::Upload);
+            unpublished.extend(self.apply_policy.transfers.pending(RetryDirection::Upload));
+            for path in paths.iter() {
+                self.apply_policy.events.emit(SyncEvent::RemoteChanged {
+                    path: path.clone(),
+                    emitter_id,
+                });
+                if unpublished.contains(path) {
+                    self.apply_policy.events.emit(SyncEvent::Conflict {
+                        path: path.clone(),
+                        emitter_id,
+                    });
+                }
+            }
+            if let RedisPublishPayload::RemovedFile(_, path) = &payload {
+                self.apply_policy.events.emit(SyncEvent::Removed {
+                    path: path.clone(),
+                    emitter_id,
+                });
+            }
+        }
+        let handling_result = self.handle_event(event_kind, payload);
+        match handling_result {
+            Ok(()) => {
+                for path in paths {
+                    self.retries.succeeded(RetryDirection::Apply, &path);
+                }
+            }
+            Err(error) => {
+                for path in paths.iter() {
+                    self.apply_failed(path, &error);
+                }
+                self.errors
+                    .error(format!("Error when handling event: {:?}", error));
+                for path in paths {
+                    self.retry_apply(path, &error);
+                }
+            }
+        }
+    }
+
+    /// Apply the held events whose soak is over, or all of them when the rollout is approved
+    fn apply_held_events(&self, is_approved: bool) {
+        let soak = match self.apply_policy.rollout_soak {
+            None => return,
+            Some(soak) => soak,
+        };
+        let ready_events: Vec<HeldEvent> = {
+            let mut held_events = self.held_events.lock().expect("held events lock poisoned");
+            let ready_count = held_events
+                .iter()
+                .take_while(|event| is_approved || event.received.elapsed() >= soak)
+                .count();
+            held_events.drain(..ready_count).collect()
+        };
+
+        for event in ready_events {
+            match event.change {
+                HeldChange::Event {
+                    event_kind,
+                    payload,
+                } => {
+                    if self.is_superseded(&payload) {
+                        debug!(
+                            "[remote_file] the file changed again since the event, the next event will apply it: {:?}",
+                            payload
+                        );
+                        continue;
+                    }
+                    self.process_event(&event_kind, payload);
+                }
+                HeldChange::Reconciled { path, hash } => {
+                    if self
+                        .store
+                        .get_remote_file_hash(&path)
+                        .is_ok_and(|remote_hash| remote_hash != hash)
+                    {
+                        debug!(
+                            "[remote_file] {} changed again since the synchronization, the next event will apply it",
+                            path.display()
+                        );
+                        continue;
+                    }
+                    self.apply_again(vec![path]);
+                }
+            }
+        }
+    }
+
+    /// The files changed within the rollout soak, and since its last approval, with the unix
+    /// time of their change. A synchronization holds them as it holds their events.
+    fn soaking_changes(&self) -> Result<HashMap<PathBuf, u64>, anyhow::Error> {
+        let soak = match self.apply_policy.rollout_soak {
+            None => return Ok(HashMap::new()),
+            Some(soak) => soak,
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or_default();
+        let approved_at = self
+            .store
+            .get_rollout_approval()
+            .context("unable to get the rollout approval")?;
+        let since = now
+            .saturating_sub(soak.as_secs())
+            .max(approved_at.unwrap_or_default());
+        Ok(self
+            .store
+            .get_changed_since(since)
+            .context("unable to get the files changed within the rollout soak")?
+            .into_iter()
+            .collect())
+    }
+
+    /// Hold a file found changed by a synchronization until the end of the soak of its change
+    fn hold_reconciled(&self, path: PathBuf, hash: u64, changed_at: u64) {
+        debug!(
+            "[remote_file] {} changed within the rollout soak, holding it",
+            path.display()
+        );
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or_default();
+        let received = Instant::now()
+            .checked_sub(Duration::from_secs(now.saturating_sub(changed_at)))
+            .unwrap_or_else(Instant::now);
+        let mut held_events = self.held_events.lock().expect("held events lock poisoned");
+        let is_held = held_events.iter().any(|event| match &event.change {
+            HeldChange::Reconciled {
+                path: held_path,
+                hash: held_hash,
+            } => *held_path == path && *held_hash == hash,
+            HeldChange::Event { .. } => false,
+        });
+        if is_held {
+            return;
+        }
+        let position = held_events
+            .iter()
+            .position(|event| event.received > received)
+            .unwrap_or(held_events.len());
+        held_events.insert(
+            position,
+            HeldEvent {
+                received,
+                change: HeldChange::Reconciled { path, hash },
+            },
+        );
+    }
+
+    /// The content fetched is always the latest one. When it changed since the event, applying
+    /// it would skip the soak of the newer change.
+    fn is_superseded(&self, payload: &RedisPublishPayload) -> bool {
+        match payload {
+            RedisPublishPayload::NewFile(_, hash, path)
+            | RedisPublishPayload::ModifiedFile(_, hash, path)
+            | RedisPublishPayload::InlineNewFile(_, hash, path, _)
+            | RedisPublishPayload::InlineModifiedFile(_, hash, path, _) => self
+                .store
+                .get_remote_file_hash(path)
+                .is_ok_and(|remote_hash| remote_hash != *hash),
+            _ => false,
+        }
+    }
+
+    /// Redis sends no keyspace notification by default. Changing the setting is left to the
+    /// administrator, as it has a cost for every client of the server.
+    fn warn_if_keyspace_notifications_disabled(&self) {
+        let flags = match self.events.keyspace_notification_flags() {
+            Err(error) => {
+                warn!(
+                    "unable to check that the keyspace notifications are enabled. Error: {:?}",
+                    error
+                );
+                return;
+            }
+            Ok(flags) => flags.unwrap_or_default(),
+        };
+        let has_flag = |flag| flags.contains(flag) || (flag != 'K' && flags.contains('A'));
+        if !['K', '$', 'g', 'x', 'e'].iter().all(|flag| has_flag(*flag)) {
+            warn!(
+                "redis keyspace notifications are not fully enabled (notify-keyspace-events={:?}). \
+                 Some changes will be missed. Run `CONFIG SET notify-keyspace-events K$gxe` on the server.",
+                flags
             );
+        }
+    }
 
-            if payload.get_emitter_id() == self.unique_id {
-                debug!("[remote_file] skipping event as we are the emitter");
-                continue;
+    /// Convert the notification of an operation on a content key to the equivalent event.
+    /// Returns None for the operations which need nothing to be done.
+    fn keyspace_notification_to_payload(
+        &self,
+        channel: &str,
+        operation: &str,
+    ) -> Option<RedisPublishPayload> {
+        // channel is `__keyspace@<db>__:[<namespace>:]content:<path>`
+        let key = channel.split_once("__:")?.1;
+        let path = self.apply_policy.roots.to_local(Path::new(
+            self.apply_policy
+                .namespace
+                .strip(key)?
+                .strip_prefix(CONTENT_KEY_PREFIX)?,
+        ))?;
+        debug!(
+            "[remote_file] keyspace operation {} on {}",
+            operation,
+            path.display()
+        );
+
+        match operation {
+            "set" | "rename_to" => {
+                // the tools writing the contents may leave their hash missing or outdated: the
+                // content written is read to hash it, and carried by the event so that it is
+                // applied without being fetched again
+                self.store.invalidate_cached_hash(&path);
+                let contents = match self.store.get_remote_file_content(&path) {
+                    Ok(Some(contents)) => contents,
+                    // removed since the notification
+                    Ok(None) => return None,
+                    Err(error) => {
+                        self.errors.error(format!(
+                            "unable to read the content of {} notified. Error: {:?}",
+                            path.display(),
+                            error
+                        ));
+                        return None;
+                    }
+                };
+                Some(RedisPublishPayload::InlineNewFile(
+                    UNKNOWN_EMITTER_ID,
+                    LocalFSStore::hash_content(&contents),
+                    path,
+                    LocalFSStore::compress(&contents),
+                ))
             }
-            let handling_result = self.handle_event(event_kind, payload);
-            if let Err(error) = handling_result {
-                error!("Error when handling event: {:?}", error)
+            // our own removals and renames are notified too
+            "del" | "rename_from" if self.store.take_own_removal(&path) => {
+                debug!(
+                    "[remote_file] {} was removed by this instance",
+                    path.display()
+                );
+                None
             }
+            "del" | "rename_from" => {
+                Some(RedisPublishPayload::RemovedFile(UNKNOWN_EMITTER_ID, path))
+            }
+            "expired" | "evicted" => Some(RedisPublishPayload::ContentMissing(
+                UNKNOWN_EMITTER_ID,
+                path,
+            )),
+            _ => None,
         }
     }
 
@@ -143,37 +918,111 @@ impl RemoteFilesEventHandler {
         event_kind: &str,
         payload: RedisPublishPayload,
     ) -> Result<(), anyhow::Error> {
+        let emitter_id = payload.get_emitter_id();
         let event = file_events::FileEvents::from_str_and_payload(event_kind, payload)
             .context("unable to convert the event to a known file event")?;
 
+        // requests for missing content are answered whatever the emitter is
+        let is_apply = !matches!(
+            event,
+            FileEvents::ContentMissing(_) | FileEvents::ContentRejected(_, _)
+        );
+        if is_apply && !self.is_emitter_accepted(emitter_id)? {
+            debug!("[remote_file] emitter does not have the required tags. Doing nothing.");
+            return Ok(());
+        }
+
         let res = match event {
-            FileEvents::New(path, remote_hash) | FileEvents::Modified(path, remote_hash) => {
-                let local_hash = LocalFSStore::local_hash(&path).with_context(|| {
-                    format!(
-                        "unable to compute hash of file for comparison. Path: {}",
-                        &path.display()
+            FileEvents::New(path, _, _) | FileEvents::Modified(path, _, _)
+                if self.apply_policy.is_excluded(&path) =>
+            {
+                debug!(
+                    "[remote_file] path is excluded from applies. (path={})",
+                    path.display()
+                );
+                self.write_placeholder(&path)
+            }
+            FileEvents::Removed(path) if self.apply_policy.is_excluded(&path) => {
+                LocalFSStore::remove_placeholder(&self.apply_policy.local_path(&path)?)
+            }
+            FileEvents::New(path, remote_hash, inline_content)
+            | FileEvents::Modified(path, remote_hash, inline_content) => {
+                let local_path = self.apply_policy.local_path(&path)?;
+                // a file missing locally has no hash to compare
+                let local_hash = if LocalFSStore::exists(&local_path) {
+                    Some(
+                        self.apply_policy
+                            .local_hashes
+                            .local_hash(&local_path)
+                            .with_context(|| {
+                                format!(
+                                    "unable to compute hash of file for comparison. Path: {}",
+                                    &local_path.display()
+                                )
+                            })?,
                     )
-                })?;
+                } else {
+                    None
+                };
 
                 debug!(
-                    "[remote_file] local_hash = {} remote_hash = {}",
+                    "[remote_file] local_hash = {:?} remote_hash = {}",
                     local_hash, remote_hash
                 );
-                if local_hash == remote_hash {
+                if local_hash == Some(remote_hash) {
                     debug!("[remote_file] hash matches. Doing nothing.");
+                    self.apply_policy
+                        .synced_hashes
+                        .record(&local_path, remote_hash);
+                    self.record_applied(&path);
                     return Ok(());
                 }
 
-                let contents = self.store.get_remote_file_content(&path).with_context(|| {
-                    format!(
-                        "unable to get from redis file content of {}",
-                        &path.display()
-                    )
-                })?;
-                LocalFSStore::write_file(&path, contents)
+                match inline_content {
+                    None => self.fetch_remote_file(path),
+                    Some(compressed_content) => {
+                        self.apply_inline_content(path, remote_hash, &compressed_content)
+                    }
+                }
+            }
+            FileEvents::Removed(path) => {
+                let local_path = self.apply_policy.local_path(&path)?;
+                self.writes().remove_file(&local_path).map(|_| {
+                    self.apply_policy.synced_hashes.forget(&local_path);
+                    self.apply_policy.events.emit(SyncEvent::Applied { path })
+                })
             }
-            FileEvents::Removed(path) => LocalFSStore::remove_file(&path),
-            FileEvents::Renamed(old, new) => LocalFSStore::rename_file(&old, &new),
+            FileEvents::Renamed(old, new) => {
+                let (local_old, local_new) = (
+                    self.apply_policy.local_path(&old)?,
+                    self.apply_policy.local_path(&new)?,
+                );
+                match (
+                    self.apply_policy.is_excluded(&old),
+                    self.apply_policy.is_excluded(&new),
+                ) {
+                    (true, true) => LocalFSStore::remove_placeholder(&local_old)
+                        .and_then(|_| self.write_placeholder(&new)),
+                    // the file leaves the applied paths: it must not stay there under its old name
+                    (false, true) => self
+                        .writes()
+                        .remove_file(&local_old)
+                        .and_then(|_| self.write_placeholder(&new)),
+                    // the file enters the applied paths: we never had it locally
+                    (true, false) => LocalFSStore::remove_placeholder(&local_old)
+                        .and_then(|_| self.fetch_remote_file(new)),
+                    (false, false) => self.writes().rename_file(&local_old, &local_new).map(|_| {
+                        self.apply_policy
+                            .synced_hashes
+                            .rename(&local_old, &local_new);
+                        self.record_applied(&new)
+                    }),
+                }
+            }
+            FileEvents::ContentMissing(path) => {
+                self.upload_content_again(path, "the content is missing")
+            }
+            FileEvents::ContentRejected(path, reason) => self.upload_content_again(path, &reason),
         };
 
         if res.is_err() {
@@ -181,4 +1030,587 @@ impl RemoteFilesEventHandler {
         }
         Ok(())
     }
+
+    /// Whether the emitter has the tags this instance applies the events from, and this instance
+    /// the tags of the audience of the emitter
+    fn is_emitter_accepted(&self, emitter_id: u64) -> Result<bool, anyhow::Error> {
+        let presence = match self.presence.as_ref() {
+            None if self.apply_policy.apply_from_tags.is_empty() => return Ok(true),
+            None => bail!("the peers do not announce their tags"),
+            Some(presence) => presence,
+        };
+        let record = match presence
+            .get_presence(emitter_id)
+            .context("unable to get the emitter tags")?
+        {
+            None => {
+                debug!("[remote_file] emitter {} is unknown", emitter_id);
+                return Ok(self.apply_policy.apply_from_tags.is_empty());
+            }
+            Some(record) => record,
+        };
+        let has_tags = |tags: &BTreeMap<String, String>, required: &BTreeMap<String, String>| {
+            required
+                .iter()
+                .all(|(key, value)| tags.get(key) == Some(value))
+        };
+        Ok(has_tags(&record.tags, &self.apply_policy.apply_from_tags)
+            && has_tags(&self.apply_policy.tags, &record.audience))
+    }
+
+    fn write_placeholder(&self, path: &Path) -> Result<(), anyhow::Error> {
+        if !self.apply_policy.placeholders {
+            return Ok(());
+        }
+        let hash = self.store.get_remote_file_hash(path)?;
+        let compressed_size = self.store.get_remote_file_compressed_size(path)?;
+        LocalFSStore::write_placeholder(&self.apply_policy.local_path(path)?, hash, compressed_size)
+    }
+
+    fn fetch_remote_file(&self, path: PathBuf) -> Result<(), anyhow::Error> {
+        if self.apply_policy.transfers.is_paused() {
+            self.apply_policy
+                .transfers
+                .defer(RetryDirection::Apply, path);
+            return Ok(());
+        }
+        let _transfer = self.apply_policy.transfers.transferring();
+        let contents = self.store.get_remote_file_content(&path).with_context(|| {
+            format!(
+                "unable to get from redis file content of {}",
+                &path.display()
+            )
+        })?;
+        match contents {
+            None => {
+                self.request_missing_content(path);
+                Ok(())
+            }
+            Some(contents) => {
+                // the tools changing the contents notified by the keyspace may leave their hash
+                // outdated: the content is the reference
+                if self.apply_policy.event_source == EventSource::Channel {
+                    let remote_hash = self.store.get_remote_file_hash(&path)?;
+                    if LocalFSStore::hash_content(&contents) != remote_hash {
+                        let reason = format!("the content does not match the hash {}", remote_hash);
+                        self.reject_content(path.clone(), reason);
+                        bail!("content of {} does not match its hash", path.display());
+                    }
+                }
+                self.write_applied_file(&path, contents)?;
+                self.record_applied(&path);
+                Ok(())
+            }
+        }
+    }
+
+    /// Write the content carried by the event, without fetching it from the store
+    fn apply_inline_content(
+        &self,
+        path: PathBuf,
+        hash: u64,
+        compressed_content: &[u8],
+    ) -> Result<(), anyhow::Error> {
+        let contents = LocalFSStore::decompress(compressed_content)
+            .with_context(|| format!("invalid inline content for {}", path.display()))?;
+        if LocalFSStore::hash_content(&contents) != hash {
+            debug!(
+                "[remote_file] inline content of {} does not match its hash, fetching it",
+                path.display()
+            );
+            return self.fetch_remote_file(path);
+        }
+        debug!(
+            "[remote_file] applying the inline content of {}",
+            path.display()
+        );
+        self.write_applied_file(&path, contents)?;
+        self.record_applied(&path);
+        Ok(())
+    }
+
+    /// Record the remote version of the file as applied by this instance. A failure does not
+    /// fail the apply: the file is on the disk anyway.
+    fn record_applied(&self, path: &Path) {
+        self.apply_policy.events.emit(SyncEvent::Applied {
+            path: path.to_path_buf(),
+        });
+        // recorded under the path of the store, the same for all the instances
+        let (audit, remote_path) = match (
+            &self.apply_policy.audit,
+            &self.apply_policy.shadow,
+            self.apply_policy.roots.to_remote(path),
+        ) {
+            // the shadow copies are not in use
+            (Some(audit), None, Some(remote_path)) => (audit, remote_path),
+            _ => return,
+        };
+        let result = self
+            .store
+            .get_remote_file_hash(path)
+            .and_then(|hash| audit.record_applied(&remote_path, hash));
+        if let Err(error) = result {
+            self.errors.error(format!(
+                "unable to record the version of {} applied. Error: {:?}",
+                path.display(),
+                error
+            ));
+        }
+    }
+
+    fn apply_failed(&self, path: &Path, error: &anyhow::Error) {
+        self.apply_policy.events.emit(SyncEvent::ApplyFailed {
+            path: path.to_path_buf(),
+            error: format!("{:#}", error),
+        });
+    }
+
+    /// Write the remote content of a file locally, rendered when it is a template
+    fn write_applied_file(&self, path: &Path, contents: Vec<u8>) -> Result<(), anyhow::Error> {
+        let local_path = self.apply_policy.local_path(path)?;
+        let symlinks = LocalFSStore::symlinks();
+        if symlinks == Symlinks::Skip && LocalFSStore::is_symlink(&local_path) {
+            debug!("[remote_file] local file is a skipped symlink. Doing nothing.");
+            return Ok(());
+        }
+        if let Some(target) = LocalFSStore::link_target(&contents) {
+            if symlinks != Symlinks::CopyLink {
+                warn!(
+                    "{} is a symlink copied by a peer, not applied without --symlinks copy-link",
+                    path.display()
+                );
+                return Ok(());
+            }
+            let hash = LocalFSStore::hash_content(&contents);
+            return self
+                .writes()
+                .write_link(&local_path, &target)
+                .map(|()| self.apply_policy.synced_hashes.record(&local_path, hash));
+        }
+        // writing through the link would change the file it points to
+        if symlinks == Symlinks::CopyLink && LocalFSStore::is_symlink(&local_path) {
+            self.writes().remove_file(&local_path)?;
+        }
+        if !self.apply_policy.templates.is_template(path) {
+            let hash = LocalFSStore::hash_content(&contents);
+            return self
+                .writes()
+                .write_file(&local_path, contents)
+                .map(|()| self.apply_policy.synced_hashes.record(&local_path, hash));
+        }
+        let rendered = self.apply_policy.templates.render(path, &contents)?;
+        // the remote hash is the one of the template, so it never matches the rendered file
+        if LocalFSStore::local_hash(&local_path).ok() == Some(LocalFSStore::hash_content(&rendered))
+        {
+            debug!("[remote_file] rendered template is unchanged. Doing nothing.");
+            return Ok(());
+        }
+        self.writes().write_file(&local_path, rendered)
+    }
+
+    fn writes(&self) -> std::sync::MutexGuard<'_, WriteBatch> {
+        self.apply_policy
+            .writes
+            .lock()
+            .expect("write batch lock poisoned")
+    }
+
+    /// Flush the applied changes to the disk
+    fn flush_writes(&self) -> Result<(), anyhow::Error> {
+        self.writes()
+            .flush()
+            .context("unable to flush the applied changes to the disk")
+    }
+
+    /// Defer the event while the user holds the synchronization: the remote state of its paths
+    /// is applied once resumed, and the contents asked by the peers are uploaded then
+    fn hold_event(&self, payload: RedisPublishPayload) {
+        let transfers = &self.apply_policy.transfers;
+        match payload {
+            RedisPublishPayload::ContentMissing(_, path)
+            | RedisPublishPayload::ContentRejected(_, path, _) => {
+                transfers.defer(RetryDirection::Upload, path)
+            }
+            RedisPublishPayload::RolloutApproved(_) => {
+                debug!("[remote_file] the held events are applied once resumed")
+            }
+            payload => {
+                for path in payload.get_changed_paths() {
+                    transfers.defer(RetryDirection::Apply, path);
+                }
+            }
+        }
+    }
+
+    /// Apply again the remote state of the paths whose apply failed or was deferred
+    fn apply_again(&self, paths: Vec<PathBuf>) {
+        for path in paths {
+            debug!("[remote_file] applying again {}", path.display());
+            match self.apply_remote_state(&path) {
+                Ok(()) => self.retries.succeeded(RetryDirection::Apply, &path),
+                Err(error) => {
+                    self.apply_failed(&path, &error);
+                    self.errors.error(format!(
+                        "Error when applying again {}: {:?}",
+                        path.display(),
+                        error
+                    ));
+                    self.retry_apply(path, &error);
+                }
+            }
+        }
+    }
+
+    /// A file open in another program is retried until the program releases it
+    fn retry_apply(&self, path: PathBuf, error: &anyhow::Error) {
+        if LocalFSStore::is_sharing_violation(error) {
+            self.retries.schedule_locked(RetryDirection::Apply, path);
+        } else {
+            self.retries.schedule(RetryDirection::Apply, path);
+        }
+    }
+
+    /// Apply the remote state of the paths a peer asked for now, without waiting for the rollout,
+    /// then acknowledge it once they are written to the disk, with the paths which failed
+    fn pull(&self, emitter_id: u64, request_id: u64, paths: Vec<PathBuf>) {
+        info!(
+            "instance {} asked to pull {} paths",
+            emitter_id,
+            paths.len()
+        );
+        let transfers = &self.apply_policy.transfers;
+        let mut failures = Vec::new();
+        // the paths of the store are acknowledged, as requested
+        for path in paths.iter() {
+            let local_path = match self.apply_policy.roots.to_local(path) {
+                None => {
+                    failures.push((path.clone(), String::from("out of the remote root")));
+                    continue;
+                }
+                Some(local_path) => local_path,
+            };
+            if transfers.is_held() {
+                failures.push((path.clone(), String::from("paused by the user")));
+                transfers.defer(RetryDirection::Apply, local_path);
+                continue;
+            }
+            self.store.invalidate_cached_hash(&local_path);
+            let is_stored = self.store.get_remote_file_hash(&local_path).is_ok();
+            match self.apply_remote_state(&local_path) {
+                Ok(()) if is_stored => self.retries.succeeded(RetryDirection::Apply, &local_path),
+                Ok(()) => failures.push((path.clone(), String::from("not in the store"))),
+                Err(error) => {
+                    self.apply_failed(&local_path, &error);
+                    failures.push((path.clone(), format!("{:#}", error)));
+                    self.retry_apply(local_path, &error);
+                }
+            }
+        }
+        if let Err(error) = self.flush_writes() {
+            failures = paths
+                .into_iter()
+                .map(|path| (path, format!("{:#}", error)))
+                .collect();
+        }
+        let acknowledgement =
+            RedisPublishPayload::PullAcknowledged(self.unique_id, request_id, failures);
+        if let Err(error) = self.events.publish(
+            &self.apply_policy.namespace.key(file_events::FILE_EVENT),
+            acknowledgement,
+        ) {
+            self.errors.error(format!(
+                "unable to acknowledge the pull requested by instance {}. Error: {:?}",
+                emitter_id, error
+            ));
+        }
+    }
+
+    /// Make the local file match the remote one, whatever the event which failed to apply
+    fn apply_remote_state(&self, path: &Path) -> Result<(), anyhow::Error> {
+        if self.apply_policy.is_excluded(path) {
+            return Ok(());
+        }
+        if self.store.get_remote_file_content(path)?.is_some() {
+            return self.fetch_remote_file(path.to_path_buf());
+        }
+        // without content nor hash, the file does not exist anymore on the remote store
+        let local_path = self.apply_policy.local_path(path)?;
+        if self.store.get_remote_file_hash(path).is_ok() {
+            self.request_missing_content(path.to_path_buf());
+            Ok(())
+        } else if LocalFSStore::exists(&local_path) {
+            self.writes()
+                .remove_file(&local_path)
+                .map(|()| self.apply_policy.synced_hashes.forget(&local_path))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Verify a random sample of the files, and all of them when too many mismatch and the
+    /// policy says so. The mismatching files are repaired.
+    fn verify_sample(&self) {
+        let verification = match self.reconcile_policy.verify_sample {
+            None => return,
+            Some(verification) => verification,
+        };
+        if self.apply_policy.transfers.is_paused() {
+            debug!("[remote_file] transfers are paused, not verifying the files");
+            return;
+        }
+        // their repair would skip the rollout soak
+        let soaking_changes = match self.soaking_changes() {
+            Err(error) => {
+                error!("unable to list the files to verify. Error: {:?}", error);
+                return;
+            }
+            Ok(soaking_changes) => soaking_changes,
+        };
+        let paths: Vec<PathBuf> = match self.store.get_all_remote_files() {
+            Err(error) => {
+                error!("unable to list the files to verify. Error: {:?}", error);
+                return;
+            }
+            Ok(remote_files) => remote_files
+                .into_iter()
+                .map(PathBuf::from)
+                // the templates are rendered differently by each instance
+                .filter(|path| {
+                    !self.apply_policy.is_excluded(path)
+                        && !self.apply_policy.templates.is_template(path)
+                        && !soaking_changes.contains_key(path)
+                })
+                .collect(),
+        };
+        let sample_size = (paths.len() as f64 * verification.percent / 100.0).ceil() as usize;
+        let sample: Vec<&PathBuf> = paths
+            .choose_multiple(&mut rand::thread_rng(), sample_size)
+            .collect();
+        let mismatched = self.verify_files(&sample);
+        info!(
+            "{} files of {} verified against their remote content, {} mismatched",
+            sample.len(),
+            paths.len(),
+            mismatched
+        );
+        let mismatched_percent = mismatched as f64 * 100.0 / sample.len().max(1) as f64;
+        if mismatched == 0 || mismatched_percent <= verification.alert_above_percent {
+            return;
+        }
+        error!(
+            "{:.1}% of the verified files mismatched their remote content, above {}%: the local files or the store may be corrupted",
+            mismatched_percent, verification.alert_above_percent
+        );
+        self.apply_policy.events.emit(SyncEvent::VerificationAlert {
+            verified: sample.len(),
+            mismatched,
+        });
+        if verification.full_on_alert {
+            let all_paths: Vec<&PathBuf> = paths.iter().collect();
+            let mismatched = self.verify_files(&all_paths);
+            info!(
+                "all the {} files verified against their remote content, {} mismatched",
+                all_paths.len(),
+                mismatched
+            );
+        }
+    }
+
+    /// Returns how many files mismatched their remote content
+    fn verify_files(&self, paths: &[&PathBuf]) -> usize {
+        let mut mismatched = 0;
+        for path in paths {
+            match self.verify_file(path) {
+                Ok(true) => (),
+                Ok(false) => mismatched += 1,
+                Err(error) => self.errors.error(format!(
+                    "unable to verify {}. Error: {:?}",
+                    path.display(),
+                    error
+                )),
+            }
+        }
+        if let Err(error) = self.flush_writes() {
+            self.errors.error(format!("{:?}", error));
+        }
+        mismatched
+    }
+
+    /// Whether the local file matches the remote content, which matches the remote hash. The
+    /// contents missing or corrupted in the store are asked to the peers, the local files
+    /// differing are written again.
+    fn verify_file(&self, path: &Path) -> Result<bool, anyhow::Error> {
+        let contents = match self.store.get_remote_file_content(path)? {
+            None => {
+                self.request_missing_content(path.to_path_buf());
+                return Ok(false);
+            }
+            Some(contents) => contents,
+        };
+        let remote_hash = self.store.get_remote_file_hash(path)?;
+        let content_hash = LocalFSStore::hash_content(&contents);
+        if content_hash != remote_hash {
+            let reason = format!("the content does not match the hash {}", remote_hash);
+            self.reject_content(path.to_path_buf(), reason);
+            return Ok(false);
+        }
+        let local_path = self.apply_policy.local_path(path)?;
+        if LocalFSStore::local_hash(&local_path).ok() == Some(content_hash) {
+            return Ok(true);
+        }
+        warn!(
+            "{} does not match its remote content, writing it again",
+            local_path.display()
+        );
+        self.write_applied_file(path, contents)?;
+        self.record_applied(path);
+        Ok(false)
+    }
+
+    fn reconcile(&self) {
+        if self.reconcile_policy.jitter > Duration::from_secs(0) {
+            let delay = self
+                .reconcile_policy
+                .jitter
+                .mul_f64(rand::thread_rng().gen::<f64>());
+            debug!(
+                "[remote_file] waiting {}ms before reconciling",
+                delay.as_millis()
+            );
+            std::thread::sleep(delay);
+        }
+        if self.is_synchronized() {
+            debug!("[remote_file] nothing changed on the store since the last synchronization");
+            return;
+        }
+        if let Err(error) = self.synchronize_local_files_with_remote() {
+            error!(
+                "unable to reconcile local files with remote. Error: {:?}",
+                error
+            );
+        }
+    }
+
+    /// True when the store did not change since the last complete synchronization.
+    /// The changes made by other tools do not change the generation, so the keyspace mode always reconciles.
+    fn is_synchronized(&self) -> bool {
+        if self.apply_policy.event_source == EventSource::KeyspaceNotifications {
+            return false;
+        }
+        let synchronized_generation = *self
+            .synchronized_generation
+            .lock()
+            .expect("synchronized generation lock poisoned");
+        match self.store.get_generation() {
+            Ok(Some(generation)) => synchronized_generation == Some(generation),
+            Ok(None) => false,
+            Err(error) => {
+                debug!(
+                    "[remote_file] unable to get the store generation: {:?}",
+                    error
+                );
+                false
+            }
+        }
+    }
+
+    fn request_missing_content(&self, path: PathBuf) {
+        warn!(
+            "content of {} is missing on the remote store (evicted ?). Asking peers to upload it again.",
+            &path.display()
+        );
+        if !self.peers_support(&self.apply_policy.peers_answer_missing) {
+            info!("some peers do not support missing content requests: not requesting it. The file is fetched again at the next reconciliation.");
+            return;
+        }
+        if let Err(error) = self.store.request_missing_content(self.unique_id, path) {
+            error!("unable to request missing content. Error: {:?}", error);
+        }
+    }
+
+    /// Ask the peers to upload again a content which does not match its hash. The store may have
+    /// lost or corrupted a write.
+    fn reject_content(&self, path: PathBuf, reason: String) {
+        {
+            let mut rejected_contents = self
+                .rejected_contents
+                .lock()
+                .expect("rejected contents lock poisoned");
+            let is_rejected_recently = rejected_contents
+                .get(&path)
+                .is_some_and(|rejected| rejected.elapsed() < CONTENT_REJECTION_INTERVAL);
+            if is_rejected_recently {
+                debug!(
+                    "[remote_file] content of {} already rejected recently",
+                    path.display()
+                );
+                return;
+            }
+            rejected_contents.retain(|_, rejected| rejected.elapsed() < CONTENT_REJECTION_INTERVAL);
+            rejected_contents.insert(path.clone(), Instant::now());
+        }
+        warn!(
+            "content of {} on the remote store is unusable: {}. Asking peers to upload it again.",
+            &path.display(),
+            reason
+        );
+        let result = if self.peers_support(&self.apply_policy.peers_answer_rejections) {
+            self.store.reject_content(self.unique_id, path, reason)
+        } else if self.peers_support(&self.apply_policy.peers_answer_missing) {
+            // answered the same way, by uploading the content again
+            debug!("[remote_file] some peers do not support content rejections: requesting the content as missing");
+            self.store.request_missing_content(self.unique_id, path)
+        } else {
+            info!("some peers do not support content rejections: not rejecting it. The file is fetched again at the next reconciliation.");
+            return;
+        };
+        if let Err(error) = result {
+            error!("unable to reject the content. Error: {:?}", error);
+        }
+    }
+
+    /// Whether every live peer supports a capability, as last negotiated. Without presence, the
+    /// peers are not known and are assumed to support it.
+    fn peers_support(&self, supported: &AtomicBool) -> bool {
+        self.presence.is_none() || supported.load(Ordering::SeqCst)
+    }
+
+    /// Upload again the content of a file when a peer reports it missing or unusable, but only if
+    /// our local copy is the one referenced by the remote hash: we do not want to overwrite a newer version.
+    fn upload_content_again(&self, path: PathBuf, reason: &str) -> Result<(), anyhow::Error> {
+        if !LocalFSStore::is_file(&path) {
+            debug!("[remote_file] we do not hold the reported file. Doing nothing.");
+            return Ok(());
+        }
+        // our copy may be outdated by the deferred applies. The peer asks again at its next reconciliation.
+        if self.apply_policy.transfers.is_paused() {
+            debug!("[remote_file] transfers are paused. Not uploading the reported file.");
+            return Ok(());
+        }
+        let _transfer = self.apply_policy.transfers.transferring();
+
+        let remote_hash = self
+            .store
+            .get_remote_file_hash(&path)
+            .context("unable to get the remote hash of the reported file")?;
+        let (contents, local_hash) = LocalFSStore::local_file_content_compressed(&path)
+            .context("unable to read the local copy of the reported file")?;
+        if local_hash != remote_hash {
+            debug!("[remote_file] local copy of the reported file is not the remote version. Doing nothing.");
+            return Ok(());
+        }
+        // every peer holding the file received the report
+        if !self.store.claim_upload_again(&path, self.unique_id)? {
+            debug!("[remote_file] another peer uploads the reported file. Doing nothing.");
+            return Ok(());
+        }
+
+        info!(
+            "uploading again the content of {} as a peer reported: {}",
+            &path.display(),
+            reason
+        );
+        self.store
+            .modified_file(self.unique_id, path, &contents, local_hash)
+    }
 }
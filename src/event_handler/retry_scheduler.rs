@@ -0,0 +1,143 @@
+use log::{debug, error, warn};
+use rand::Rng;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(300);
+const MAX_ATTEMPTS: u32 = 10;
+/// A file open in another program is retried at least this often, until it is released
+const MAX_LOCKED_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum RetryDirection {
+    /// Publish the local state of the path to the store
+    Upload,
+    /// Apply the remote state of the path to the local fs
+    Apply,
+}
+
+/// Retries of the paths whose upload or apply failed, with a jittered exponential backoff.
+/// Cloning it gives a handle on the same retries, so that both handlers share them.
+#[derive(Debug, Clone, Default)]
+pub struct RetryScheduler {
+    retries: Arc<Mutex<HashMap<(RetryDirection, PathBuf), ScheduledRetry>>>,
+}
+
+#[derive(Debug)]
+struct ScheduledRetry {
+    attempts: u32,
+    due: Instant,
+}
+
+impl RetryScheduler {
+    pub fn new() -> RetryScheduler {
+        RetryScheduler::default()
+    }
+
+    /// Schedule a new attempt for the path, or give up when it failed too many times
+    pub fn schedule(&self, direction: RetryDirection, path: PathBuf) {
+        self.schedule_attempt(direction, path, false)
+    }
+
+    /// Schedule a new attempt for a path whose file is open in another program. It is never
+    /// given up: the file may stay open for hours.
+    pub fn schedule_locked(&self, direction: RetryDirection, path: PathBuf) {
+        self.schedule_attempt(direction, path, true)
+    }
+
+    fn schedule_attempt(&self, direction: RetryDirection, path: PathBuf, is_locked: bool) {
+        let mut retries = self.retries.lock().expect("retry scheduler lock poisoned");
+        let attempts = retries
+            .get(&(direction, path.clone()))
+            .map_or(0, |retry| retry.attempts)
+            + 1;
+        if attempts > MAX_ATTEMPTS && !is_locked {
+            error!(
+                "giving up {:?} of {} after {} attempts",
+                direction,
+                path.display(),
+                MAX_ATTEMPTS
+            );
+            retries.remove(&(direction, path));
+            return;
+        }
+
+        let delay = RetryScheduler::backoff(attempts);
+        let delay = if is_locked {
+            let delay = delay.min(MAX_LOCKED_RETRY_DELAY);
+            warn!(
+                "{:?} of {} failed as the file is open in another program, retrying in {}ms (attempt {})",
+                direction,
+                path.display(),
+                delay.as_millis(),
+                attempts
+            );
+            delay
+        } else {
+            warn!(
+                "{:?} of {} failed, retrying in {}ms (attempt {}/{})",
+                direction,
+                path.display(),
+                delay.as_millis(),
+                attempts,
+                MAX_ATTEMPTS
+            );
+            delay
+        };
+        retries.insert(
+            (direction, path),
+            ScheduledRetry {
+                attempts,
+                due: Instant::now() + delay,
+            },
+        );
+    }
+
+    pub fn succeeded(&self, direction: RetryDirection, path: &Path) {
+        let mut retries = self.retries.lock().expect("retry scheduler lock poisoned");
+        if retries.remove(&(direction, path.to_path_buf())).is_some() {
+            debug!(
+                "[retry_scheduler] {:?} of {} succeeded",
+                direction,
+                path.display()
+            );
+        }
+    }
+
+    /// The paths whose retry is due. They are not due again until they are scheduled again.
+    pub fn take_due(&self, direction: RetryDirection) -> Vec<PathBuf> {
+        let mut retries = self.retries.lock().expect("retry scheduler lock poisoned");
+        let now = Instant::now();
+        retries
+            .iter_mut()
+            .filter(|((retry_direction, _), retry)| {
+                *retry_direction == direction && retry.due <= now
+            })
+            .map(|((_, path), retry)| {
+                retry.due = now + MAX_RETRY_DELAY;
+                path.clone()
+            })
+            .collect()
+    }
+
+    /// The paths waiting for a retry, due or not
+    pub fn pending(&self, direction: RetryDirection) -> Vec<PathBuf> {
+        let retries = self.retries.lock().expect("retry scheduler lock poisoned");
+        retries
+            .keys()
+            .filter(|(retry_direction, _)| *retry_direction == direction)
+            .map(|(_, path)| path.clone())
+            .collect()
+    }
+
+    /// Exponential backoff with a +/- 50% jitter, so that many failing paths are not retried all at once
+    fn backoff(attempts: u32) -> Duration {
+        let delay = FIRST_RETRY_DELAY
+            .checked_mul(1 << (attempts - 1).min(16))
+            .map_or(MAX_RETRY_DELAY, |delay| delay.min(MAX_RETRY_DELAY));
+        delay.mul_f64(rand::thread_rng().gen_range(0.5, 1.5))
+    }
+}
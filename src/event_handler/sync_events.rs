@@ -0,0 +1,166 @@
+use anyhow::Context;
+use log::error;
+use serde::Serialize;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// What happened to the synchronized paths, as seen by the handlers
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum SyncEvent {
+    /// A watched path is watched with this watcher backend
+    Watching {
+        path: PathBuf,
+        watcher: String,
+    },
+    /// A path removed from the watched paths
+    Unwatched {
+        path: PathBuf,
+    },
+    /// A local change to publish
+    LocalChanged {
+        path: PathBuf,
+    },
+    /// The local change is in the store
+    Published {
+        path: PathBuf,
+    },
+    PublishFailed {
+        path: PathBuf,
+        error: String,
+    },
+    /// A change published by a peer
+    RemoteChanged {
+        path: PathBuf,
+        emitter_id: u64,
+    },
+    /// The local file matches the remote one
+    Applied {
+        path: PathBuf,
+    },
+    ApplyFailed {
+        path: PathBuf,
+        error: String,
+    },
+    /// Too many of the files verified mismatched their remote content
+    VerificationAlert {
+        verified: usize,
+        mismatched: usize,
+    },
+    /// A file removed by an instance, this one included
+    Removed {
+        path: PathBuf,
+        emitter_id: u64,
+    },
+    /// A change published by a peer while the local change of the path is not published yet.
+    /// The last one published wins.
+    Conflict {
+        path: PathBuf,
+        emitter_id: u64,
+    },
+    /// An instance started announcing its presence on the namespace
+    PeerJoined {
+        instance_id: u64,
+        name: Option<String>,
+    },
+    /// An instance stopped announcing its presence
+    PeerLeft {
+        instance_id: u64,
+        name: Option<String>,
+    },
+}
+
+/// Subscriber of the synchronization events. It is called on the thread of the handler, so it
+/// must not block.
+pub trait EventSink: Send + Sync {
+    fn handle(&self, event: &SyncEvent);
+}
+
+/// The events of the handlers, dispatched to every subscriber
+#[derive(Clone, Default)]
+pub struct SyncEvents {
+    sinks: Arc<RwLock<Vec<Arc<dyn EventSink>>>>,
+}
+
+impl std::fmt::Debug for SyncEvents {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SyncEvents")
+            .field(
+                "sinks",
+                &self.sinks.read().expect("sinks lock poisoned").len(),
+            )
+            .finish()
+    }
+}
+
+impl SyncEvents {
+    pub fn new() -> SyncEvents {
+        SyncEvents::default()
+    }
+
+    pub fn subscribe(&self, sink: Arc<dyn EventSink>) {
+        self.sinks.write().expect("sinks lock poisoned").push(sink);
+    }
+
+    pub fn emit(&self, event: SyncEvent) {
+        for sink in self.sinks.read().expect("sinks lock poisoned").iter() {
+            sink.handle(&event);
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct TimedEvent<'a> {
+    at: u64,
+    #[serde(flatten)]
+    event: &'a SyncEvent,
+}
+
+/// Writes the events as newline-delimited JSON, for the scripts following the synchronization
+pub struct NdjsonSink {
+    output: Mutex<Box<dyn Write + Send>>,
+}
+
+impl NdjsonSink {
+    /// Append to this file, or write to the standard output for `-`
+    pub fn open(path: &Path) -> Result<NdjsonSink, anyhow::Error> {
+        let output: Box<dyn Write + Send> = if path == Path::new("-") {
+            Box::new(std::io::stdout())
+        } else {
+            Box::new(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .with_context(|| format!("unable to open {}", path.display()))?,
+            )
+        };
+        Ok(NdjsonSink {
+            output: Mutex::new(output),
+        })
+    }
+}
+
+impl EventSink for NdjsonSink {
+    fn handle(&self, event: &SyncEvent) {
+        let at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.as_secs());
+        // one write per line, so that the lines of several instances appending to the same file
+        // are not mixed
+        let result = serde_json::to_vec(&TimedEvent { at, event })
+            .map_err(anyhow::Error::from)
+            .and_then(|mut line| {
+                line.push(b'\n');
+                let mut output = self.output.lock().expect("ndjson output lock poisoned");
+                output.write_all(&line)?;
+                Ok(output.flush()?)
+            });
+        if let Err(error) = result {
+            error!("unable to write the event {:?}. Error: {:?}", event, error);
+        }
+    }
+}
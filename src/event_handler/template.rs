@@ -0,0 +1,83 @@
+use crate::event_handler::path_filter::PathFilter;
+use anyhow::{bail, Context};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+const TEMPLATE_START: &str = "{{";
+const TEMPLATE_END: &str = "}}";
+const TAG_VARIABLE_PREFIX: &str = "tag:";
+
+/// Remote files rendered for this instance when they are applied, e.g. `listen {{hostname}}:80`.
+/// Variables: `{{hostname}}`, and `{{tag:<key>}}` for the tags of this instance.
+#[derive(Debug, Clone, Default)]
+pub struct Templates {
+    paths: PathFilter,
+    hostname: String,
+    tags: BTreeMap<String, String>,
+}
+
+impl Templates {
+    pub fn new(
+        paths: PathFilter,
+        tags: BTreeMap<String, String>,
+    ) -> Result<Templates, anyhow::Error> {
+        let hostname = if paths.is_empty() {
+            String::new()
+        } else {
+            read_hostname()?
+        };
+        Ok(Templates {
+            paths,
+            hostname,
+            tags,
+        })
+    }
+
+    pub fn is_template(&self, path: &Path) -> bool {
+        self.paths.matches(path)
+    }
+
+    /// Replace every variable of the template. Unknown variables are an error, so that a
+    /// typo cannot distribute a broken file.
+    pub fn render(&self, path: &Path, template: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+        let mut remaining = std::str::from_utf8(template)
+            .with_context(|| format!("template {} is not valid UTF-8", path.display()))?;
+        let mut rendered = String::with_capacity(remaining.len());
+        while let Some(start) = remaining.find(TEMPLATE_START) {
+            rendered.push_str(&remaining[..start]);
+            let after_start = &remaining[start + TEMPLATE_START.len()..];
+            let end = match after_start.find(TEMPLATE_END) {
+                None => bail!("unclosed {} in template {}", TEMPLATE_START, path.display()),
+                Some(end) => end,
+            };
+            let variable = after_start[..end].trim();
+            rendered.push_str(
+                self.variable_value(variable)
+                    .with_context(|| format!("unable to render template {}", path.display()))?,
+            );
+            remaining = &after_start[end + TEMPLATE_END.len()..];
+        }
+        rendered.push_str(remaining);
+        Ok(rendered.into_bytes())
+    }
+
+    fn variable_value(&self, variable: &str) -> Result<&str, anyhow::Error> {
+        if variable == "hostname" {
+            return Ok(&self.hostname);
+        }
+        match variable.strip_prefix(TAG_VARIABLE_PREFIX) {
+            Some(key) => match self.tags.get(key) {
+                None => bail!("this instance has no tag {}", key),
+                Some(value) => Ok(value),
+            },
+            None => bail!("unknown template variable {}", variable),
+        }
+    }
+}
+
+pub fn read_hostname() -> Result<String, anyhow::Error> {
+    let hostname = std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .or_else(|_| std::fs::read_to_string("/etc/hostname"))
+        .context("unable to read the hostname of this machine")?;
+    Ok(hostname.trim().to_string())
+}
@@ -0,0 +1,259 @@
+use crate::event_handler::retry_scheduler::RetryDirection;
+use anyhow::Context;
+use log::{debug, info};
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+const CONDITIONS_POLL_INTERVAL: Duration = Duration::from_secs(30);
+const POWER_SUPPLY_DIRECTORY: &str = "/sys/class/power_supply";
+
+/// When the transfers of file contents are paused. The metadata events (removals, renames)
+/// are still synchronized.
+#[derive(Debug, Clone, Default)]
+pub struct PausePolicy {
+    /// Pause while NetworkManager reports the connection as metered
+    pub on_metered_connection: bool,
+    /// Pause while the battery is discharging below this percentage
+    pub below_battery_percent: Option<u8>,
+}
+
+impl PausePolicy {
+    pub fn is_enabled(&self) -> bool {
+        self.on_metered_connection || self.below_battery_percent.is_some()
+    }
+
+    /// Why the transfers should be paused now, or None
+    fn pause_reason(&self) -> Option<String> {
+        if let Some(min_percent) = self.below_battery_percent {
+            if let Some(percent) = discharging_battery_percent() {
+                if percent < min_percent {
+                    return Some(format!("battery at {}%", percent));
+                }
+            }
+        }
+        if self.on_metered_connection && is_connection_metered() {
+            return Some(String::from("metered connection"));
+        }
+        None
+    }
+}
+
+/// Pauses the transfers of file contents, and keeps the paths whose transfer was deferred
+/// until they are resumed. The user may also hold the synchronization, during heavy local
+/// operations: then no event is processed, the changed paths are synchronized once resumed.
+/// Cloning it gives a handle on the same state, so that both handlers share it.
+#[derive(Debug, Clone, Default)]
+pub struct TransferGate {
+    state: Arc<Mutex<GateState>>,
+}
+
+#[derive(Debug, Default)]
+struct GateState {
+    /// None when the transfers are allowed by the conditions
+    pause_reason: Option<String>,
+    /// Held by the user
+    is_held: bool,
+    deferred: BTreeSet<(RetryDirection, PathBuf)>,
+    /// Contents being uploaded or downloaded
+    active_transfers: usize,
+    last_transfer_end: Option<Instant>,
+}
+
+impl TransferGate {
+    pub fn new() -> TransferGate {
+        TransferGate::default()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, GateState> {
+        self.state.lock().expect("transfer gate lock poisoned")
+    }
+
+    pub fn pause_reason(&self) -> Option<String> {
+        let state = self.lock();
+        if state.is_held {
+            return Some(String::from("paused by the user"));
+        }
+        state.pause_reason.clone()
+    }
+
+    pub fn is_paused(&self) -> bool {
+        let state = self.lock();
+        state.is_held || state.pause_reason.is_some()
+    }
+
+    /// Whether the user holds the synchronization: the events are deferred, whatever they are
+    pub fn is_held(&self) -> bool {
+        self.lock().is_held
+    }
+
+    pub fn set_held(&self, is_held: bool) {
+        let mut state = self.lock();
+        if state.is_held == is_held {
+            return;
+        }
+        if is_held {
+            info!("pausing the synchronization: the changes are synchronized once it is resumed");
+        } else {
+            info!(
+                "resuming the synchronization ({} changes deferred)",
+                state.deferred.len()
+            );
+        }
+        state.is_held = is_held;
+    }
+
+    /// Transfer the path once the transfers are resumed
+    pub fn defer(&self, direction: RetryDirection, path: PathBuf) {
+        debug!(
+            "[transfer_gate] transfers are paused, deferring {:?} of {}",
+            direction,
+            path.display()
+        );
+        self.lock().deferred.insert((direction, path));
+    }
+
+    /// The deferred paths, once the transfers are resumed. Empty while they are paused.
+    pub fn take_deferred(&self, direction: RetryDirection) -> Vec<PathBuf> {
+        let mut state = self.lock();
+        if state.is_held || state.pause_reason.is_some() {
+            return Vec::new();
+        }
+        let taken: Vec<(RetryDirection, PathBuf)> = state
+            .deferred
+            .iter()
+            .filter(|(deferred_direction, _)| *deferred_direction == direction)
+            .cloned()
+            .collect();
+        for key in taken.iter() {
+            state.deferred.remove(key);
+        }
+        taken.into_iter().map(|(_, path)| path).collect()
+    }
+
+    /// The deferred paths, left deferred
+    pub fn pending(&self, direction: RetryDirection) -> Vec<PathBuf> {
+        self.lock()
+            .deferred
+            .iter()
+            .filter(|(deferred_direction, _)| *deferred_direction == direction)
+            .map(|(_, path)| path.clone())
+            .collect()
+    }
+
+    /// Count a transfer of content until the returned guard is dropped
+    pub fn transferring(&self) -> TransferActivity {
+        self.lock().active_transfers += 1;
+        TransferActivity { gate: self.clone() }
+    }
+
+    /// Whether a content is being transferred, or was in the last `delay`
+    pub fn is_transferring(&self, delay: Duration) -> bool {
+        let state = self.lock();
+        state.active_transfers > 0
+            || state
+                .last_transfer_end
+                .is_some_and(|end| end.elapsed() < delay)
+    }
+
+    fn set_pause_reason(&self, pause_reason: Option<String>) {
+        let mut state = self.lock();
+        if state.pause_reason == pause_reason {
+            return;
+        }
+        match &pause_reason {
+            Some(reason) => info!("pausing the file transfers: {}", reason),
+            None => info!(
+                "resuming the file transfers ({} deferred)",
+                state.deferred.len()
+            ),
+        }
+        state.pause_reason = pause_reason;
+    }
+
+    /// Check the conditions of the policy now, then periodically until the process exits
+    pub fn watch_conditions(self, policy: PausePolicy) -> Result<JoinHandle<()>, anyhow::Error> {
+        self.set_pause_reason(policy.pause_reason());
+        let handle = std::thread::Builder::new()
+            .name(String::from("transfer conditions watcher"))
+            .spawn(move || loop {
+                std::thread::sleep(CONDITIONS_POLL_INTERVAL);
+                debug!("[transfer_gate] checking the transfer conditions");
+                self.set_pause_reason(policy.pause_reason());
+            })
+            .context("unable to create transfer conditions watcher thread")?;
+        Ok(handle)
+    }
+}
+
+/// A transfer of content in progress
+pub struct TransferActivity {
+    gate: TransferGate,
+}
+
+impl Drop for TransferActivity {
+    fn drop(&mut self) {
+        let mut state = self.gate.lock();
+        state.active_transfers -= 1;
+        state.last_transfer_end = Some(Instant::now());
+    }
+}
+
+/// Average charge of the batteries, when they are discharging
+fn discharging_battery_percent() -> Option<u8> {
+    let read = |path: PathBuf| std::fs::read_to_string(path).map(|value| value.trim().to_string());
+    let mut percents = Vec::new();
+    for entry in std::fs::read_dir(POWER_SUPPLY_DIRECTORY).ok()?.flatten() {
+        let supply = entry.path();
+        if read(supply.join("type")).ok().as_deref() != Some("Battery")
+            || read(supply.join("status")).ok().as_deref() != Some("Discharging")
+        {
+            continue;
+        }
+        if let Some(percent) = read(supply.join("capacity"))
+            .ok()
+            .and_then(|capacity| capacity.parse::<u32>().ok())
+        {
+            percents.push(percent);
+        }
+    }
+    if percents.is_empty() {
+        return None;
+    }
+    Some((percents.iter().sum::<u32>() / percents.len() as u32).min(100) as u8)
+}
+
+/// Asks NetworkManager through D-Bus. Without it, the connection is not considered metered.
+fn is_connection_metered() -> bool {
+    let output = Command::new("busctl")
+        .args([
+            "--system",
+            "get-property",
+            "org.freedesktop.NetworkManager",
+            "/org/freedesktop/NetworkManager",
+            "org.freedesktop.NetworkManager",
+            "Metered",
+        ])
+        .output();
+    match output {
+        Ok(output) if output.status.success() => {
+            // `u <NMMetered>`: 1 is yes, 3 is guessed yes
+            let metered = String::from_utf8_lossy(&output.stdout);
+            matches!(metered.split_whitespace().nth(1), Some("1") | Some("3"))
+        }
+        Ok(output) => {
+            debug!(
+                "[transfer_gate] unable to get the metered state from NetworkManager: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            false
+        }
+        Err(error) => {
+            debug!("[transfer_gate] unable to run busctl: {}", error);
+            false
+        }
+    }
+}
@@ -0,0 +1,156 @@
+use anyhow::Context;
+use log::debug;
+use notify::DebouncedEvent;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+/// Generations of the local changes of the paths, so that an upload superseded by a newer
+/// content of its file is cancelled instead of being published, then overwritten right away.
+/// Cloning it gives a handle on the same generations.
+#[derive(Debug, Clone, Default)]
+pub struct TransferScheduler {
+    state: Arc<Mutex<SchedulerState>>,
+}
+
+#[derive(Debug, Default)]
+struct SchedulerState {
+    next_generation: u64,
+    paths: HashMap<PathBuf, PathGenerations>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct PathGenerations {
+    /// The last change of the path
+    latest: u64,
+    /// The first content change since the last removal or rename of the path: the uploads
+    /// from this one are cancelled by the newer contents. The older ones are not, as the
+    /// removal or the rename relies on them.
+    cancellable_from: u64,
+}
+
+/// A change of a path, from the watcher to the upload
+#[derive(Debug)]
+pub struct TransferToken {
+    state: Arc<Mutex<SchedulerState>>,
+    path: PathBuf,
+    generation: u64,
+}
+
+/// The tokens of the paths changed by a local event
+#[derive(Debug)]
+pub struct Transfer {
+    tokens: Vec<TransferToken>,
+}
+
+/// A local event, with its transfer
+#[derive(Debug)]
+pub struct ScheduledEvent {
+    pub event: DebouncedEvent,
+    pub transfer: Transfer,
+}
+
+impl TransferScheduler {
+    pub fn new() -> TransferScheduler {
+        TransferScheduler::default()
+    }
+
+    /// Record a change of the path. A new content cancels the uploads of the previous ones.
+    pub fn schedule(&self, path: &Path, is_content: bool) -> TransferToken {
+        let mut state = self.state.lock().expect("transfer scheduler lock poisoned");
+        state.next_generation += 1;
+        let generation = state.next_generation;
+        let cancellable_from = match state.paths.get(path) {
+            Some(previous) if is_content && previous.cancellable_from <= previous.latest => {
+                previous.cancellable_from
+            }
+            _ if is_content => generation,
+            // nothing before a removal or a rename can be cancelled
+            _ => generation + 1,
+        };
+        state.paths.insert(
+            path.to_path_buf(),
+            PathGenerations {
+                latest: generation,
+                cancellable_from,
+            },
+        );
+        TransferToken {
+            state: self.state.clone(),
+            path: path.to_path_buf(),
+            generation,
+        }
+    }
+
+    /// Give the events of the watchers their tokens as soon as they are received, while the
+    /// previous ones are uploaded. Ends once the watchers or the handler are gone.
+    pub fn forward(
+        self,
+        events: Receiver<DebouncedEvent>,
+        scheduled_events: Sender<ScheduledEvent>,
+    ) -> Result<JoinHandle<()>, anyhow::Error> {
+        let handle = std::thread::Builder::new()
+            .name(String::from("transfer scheduler"))
+            .spawn(move || {
+                for event in events {
+                    let tokens = match &event {
+                        DebouncedEvent::Create(path) | DebouncedEvent::Write(path) => {
+                            vec![self.schedule(path, true)]
+                        }
+                        DebouncedEvent::Remove(path) => vec![self.schedule(path, false)],
+                        DebouncedEvent::Rename(old_path, new_path) => vec![
+                            self.schedule(old_path, false),
+                            self.schedule(new_path, false),
+                        ],
+                        _ => Vec::new(),
+                    };
+                    if scheduled_events
+                        .send(ScheduledEvent {
+                            event,
+                            transfer: Transfer { tokens },
+                        })
+                        .is_err()
+                    {
+                        return;
+                    }
+                }
+                debug!("[transfer_scheduler] the watchers are gone");
+            })
+            .context("unable to create transfer scheduler thread")?;
+        Ok(handle)
+    }
+}
+
+impl TransferToken {
+    /// Whether a newer content of the path is to be uploaded instead
+    pub fn is_cancelled(&self) -> bool {
+        let state = self.state.lock().expect("transfer scheduler lock poisoned");
+        state.paths.get(&self.path).is_some_and(|generations| {
+            generations.latest > self.generation && generations.cancellable_from <= self.generation
+        })
+    }
+}
+
+impl Drop for TransferToken {
+    /// The last change of the path is handled: forget the path
+    fn drop(&mut self) {
+        let mut state = self.state.lock().expect("transfer scheduler lock poisoned");
+        if state
+            .paths
+            .get(&self.path)
+            .is_some_and(|generations| generations.latest == self.generation)
+        {
+            state.paths.remove(&self.path);
+        }
+    }
+}
+
+impl Transfer {
+    /// Whether the event uploads a content which a newer one of its file supersedes. The
+    /// removals and the renames are never cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.tokens.iter().any(TransferToken::is_cancelled)
+    }
+}
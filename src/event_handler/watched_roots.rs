@@ -0,0 +1,117 @@
+use crate::event_handler::sync_events::{EventSink, SyncEvent};
+use crate::store::presence_store::RootStatus;
+use crate::store::root_mapping::RootMapping;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The watched paths, and their state as told by the synchronization events. Cloning it gives a
+/// handle on the same state, so that the presence announces what the subscriber records.
+#[derive(Debug, Clone)]
+pub struct WatchedRoots {
+    /// Paths as given, and their state under their canonical path, as the events give it
+    roots: Arc<Mutex<Vec<(PathBuf, RootStatus)>>>,
+    /// Where the roots are in the store
+    mapping: RootMapping,
+}
+
+impl WatchedRoots {
+    pub fn new(paths: &[PathBuf], mapping: RootMapping) -> WatchedRoots {
+        let roots = paths
+            .iter()
+            .map(|path| (path.clone(), WatchedRoots::status(path, &mapping)))
+            .collect();
+        WatchedRoots {
+            roots: Arc::new(Mutex::new(roots)),
+            mapping,
+        }
+    }
+
+    fn status(path: &Path, mapping: &RootMapping) -> RootStatus {
+        let canonical_path = std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        RootStatus {
+            remote_path: mapping
+                .to_remote(&canonical_path)
+                .or_else(|| mapping.to_remote(path)),
+            path: canonical_path,
+            ..RootStatus::default()
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<(PathBuf, RootStatus)>> {
+        self.roots.lock().expect("watched roots lock poisoned")
+    }
+
+    /// The paths as given
+    pub fn paths(&self) -> Vec<PathBuf> {
+        self.lock().iter().map(|(path, _)| path.clone()).collect()
+    }
+
+    /// The roots under a path watched with this watcher, nested roots included. The path is
+    /// added when it was not watched yet.
+    fn set_watched(&self, watched_path: &Path, watcher: &str) {
+        let mut roots = self.lock();
+        if !roots.iter().any(|(path, _)| path == watched_path) {
+            let status = WatchedRoots::status(watched_path, &self.mapping);
+            roots.push((watched_path.to_path_buf(), status));
+        }
+        for (path, status) in roots.iter_mut() {
+            if path.starts_with(watched_path) {
+                status.watcher = Some(watcher.to_string());
+            }
+        }
+    }
+
+    fn record_event(&self, path: &Path) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.as_secs());
+        self.update(path, |status| status.last_event_at = Some(now));
+    }
+
+    fn record_error(&self, path: &Path, error: String) {
+        self.update(path, |status| {
+            status.errors += 1;
+            status.last_error = Some(error);
+        });
+    }
+
+    /// Update the innermost root of the path, if any
+    fn update(&self, path: &Path, update: impl FnOnce(&mut RootStatus)) {
+        let mut roots = self.lock();
+        let innermost_root = roots
+            .iter_mut()
+            .map(|(_, status)| status)
+            .filter(|status| path.starts_with(&status.path))
+            .max_by_key(|status| status.path.components().count());
+        if let Some(status) = innermost_root {
+            update(status);
+        }
+    }
+
+    /// The state of the roots, counting the pending uploads from these paths
+    pub fn statuses(&self, pending_uploads: &[PathBuf]) -> Vec<RootStatus> {
+        self.lock()
+            .iter()
+            .map(|(_, status)| RootStatus {
+                pending_uploads: pending_uploads
+                    .iter()
+                    .filter(|path| path.starts_with(&status.path))
+                    .count() as u64,
+                ..status.clone()
+            })
+            .collect()
+    }
+}
+
+impl EventSink for WatchedRoots {
+    fn handle(&self, event: &SyncEvent) {
+        match event {
+            SyncEvent::Watching { path, watcher } => self.set_watched(path, watcher),
+            SyncEvent::Unwatched { path } => self.lock().retain(|(root, _)| root != path),
+            SyncEvent::LocalChanged { path } => self.record_event(path),
+            SyncEvent::PublishFailed { path, error } => self.record_error(path, error.clone()),
+            _ => (),
+        }
+    }
+}
@@ -1,8 +1,16 @@
+use anyhow::Context;
 use fern::colors::{Color, ColoredLevelConfig};
-use log::debug;
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-pub fn setup_logs(is_debug: bool) {
+/// Log to the standard output, or append to the file without colors
+pub fn setup_logs(is_debug: bool, log_file: Option<&Path>) -> Result<(), anyhow::Error> {
     let colors = ColoredLevelConfig::new().error(Color::Red);
+    let is_colored = log_file.is_none();
 
     let base_config = if is_debug {
         fern::Dispatch::new().level(log::LevelFilter::Debug)
@@ -10,13 +18,24 @@ pub fn setup_logs(is_debug: bool) {
         fern::Dispatch::new().level(log::LevelFilter::Info)
     };
 
+    let output: fern::Output = match log_file {
+        None => std::io::stdout().into(),
+        Some(path) => fern::log_file(path)
+            .with_context(|| format!("unable to open the log file {}", path.display()))?
+            .into(),
+    };
     base_config
-        .chain(std::io::stdout())
+        .chain(output)
         .format(move |out, message, record| {
+            let level = if is_colored {
+                // This will color the log level only, not the whole line. Just a touch.
+                colors.color(record.level()).to_string()
+            } else {
+                record.level().to_string()
+            };
             out.finish(format_args!(
                 "[{}]{} {}",
-                // This will color the log level only, not the whole line. Just a touch.
-                colors.color(record.level()),
+                level,
                 chrono::Utc::now().format("[%Y-%m-%d %H:%M:%S.%3f %z]"),
                 message
             ))
@@ -24,5 +43,102 @@ pub fn setup_logs(is_debug: bool) {
         .apply()
         .expect("Unable to set logs !");
 
-    debug!("[logs] logs set !")
+    debug!("[logs] logs set !");
+    Ok(())
+}
+
+/// Logs identical errors once per window, then how many times they were repeated,
+/// so that a broken path does not flood the logs. Cloning it gives a handle on the same errors.
+#[derive(Clone)]
+pub struct ErrorAggregator {
+    window: Duration,
+    errors: Arc<Mutex<HashMap<String, AggregatedError>>>,
+}
+
+struct AggregatedError {
+    window_start: Instant,
+    repetitions: usize,
+}
+
+/// An error logged in the current window, and how many times it was repeated since
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct SuppressedError {
+    pub message: String,
+    pub repetitions: usize,
+    /// Unix time of the logged occurrence
+    pub since: u64,
+}
+
+impl ErrorAggregator {
+    pub fn new(window: Duration) -> ErrorAggregator {
+        ErrorAggregator {
+            window,
+            errors: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn error(&self, message: String) {
+        let mut errors = self.lock();
+        self.report_repetitions(&mut errors);
+
+        match errors.get_mut(&message) {
+            Some(aggregated) => aggregated.repetitions += 1,
+            None => {
+                error!("{}", message);
+                errors.insert(
+                    message,
+                    AggregatedError {
+                        window_start: Instant::now(),
+                        repetitions: 0,
+                    },
+                );
+            }
+        }
+    }
+
+    /// Report the errors which stopped repeating. Called regularly, so that the last
+    /// repetitions are reported without waiting for another error.
+    pub fn flush(&self) {
+        self.report_repetitions(&mut self.lock());
+    }
+
+    /// The errors logged in the current window, by order of occurrence
+    pub fn suppressed(&self) -> Vec<SuppressedError> {
+        let errors = self.lock();
+        let now = SystemTime::now();
+        let mut suppressed: Vec<SuppressedError> = errors
+            .iter()
+            .map(|(message, aggregated)| SuppressedError {
+                message: message.clone(),
+                repetitions: aggregated.repetitions,
+                since: (now - aggregated.window_start.elapsed())
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |since| since.as_secs()),
+            })
+            .collect();
+        suppressed.sort_by_key(|error| error.since);
+        suppressed
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, AggregatedError>> {
+        self.errors.lock().expect("error aggregator lock poisoned")
+    }
+
+    fn report_repetitions(&self, errors: &mut HashMap<String, AggregatedError>) {
+        let window = self.window;
+        errors.retain(|message, aggregated| {
+            if aggregated.window_start.elapsed() < window {
+                return true;
+            }
+            if aggregated.repetitions > 0 {
+                error!(
+                    "{} (repeated {} times in the last {}s)",
+                    message,
+                    aggregated.repetitions,
+                    window.as_secs()
+                );
+            }
+            false
+        });
+    }
 }
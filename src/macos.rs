@@ -0,0 +1,162 @@
+use crate::event_handler::transfer_gate::TransferGate;
+use anyhow::{bail, Context};
+use log::{debug, info, warn};
+use std::path::Path;
+use std::process::{Child, Command};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+const ACTIVITY_POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// The assertion is kept this long after the last transfer, so that the transfers of a batch of
+/// changes are not interrupted between two of them
+const IDLE_DELAY: Duration = Duration::from_secs(30);
+
+/// Write the LaunchAgent of the user running the synchronization with the arguments given
+/// here, then load it. The agent is started at login and restarted when it exits.
+pub fn install_launch_agent(label: &str) -> Result<(), anyhow::Error> {
+    let home = std::env::var_os("HOME").context("unable to find the home directory: no HOME")?;
+    let mut program_arguments = vec![std::env::current_exe()
+        .context("unable to find the path of the executable")?
+        .into_os_string()];
+    let arguments: Vec<_> = std::env::args_os().skip(1).collect();
+    let install_position = arguments
+        .iter()
+        .rposition(|argument| argument == "install-launchd")
+        .context("unable to find `install-launchd` in the arguments")?;
+    program_arguments.extend(arguments.into_iter().take(install_position));
+    program_arguments.push("watch".into());
+    let working_directory =
+        std::env::current_dir().context("unable to find the current directory")?;
+    let log_path = Path::new(&home)
+        .join("Library/Logs")
+        .join(format!("{}.log", label));
+
+    let mut plist = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <!DOCTYPE plist PUBLIC \"-//Apple//DTD PLIST 1.0//EN\" \"http://www.apple.com/DTDs/PropertyList-1.0.dtd\">\n\
+         <plist version=\"1.0\">\n\
+         <dict>\n",
+    );
+    plist.push_str(&plist_entry("Label", &plist_string(label)));
+    let mut arguments_array = String::from("<array>\n");
+    for argument in program_arguments.iter() {
+        let argument = argument
+            .to_str()
+            .with_context(|| format!("the argument {:?} is not valid unicode", argument))?;
+        arguments_array.push_str(&format!("        {}\n", plist_string(argument)));
+    }
+    arguments_array.push_str("    </array>");
+    plist.push_str(&plist_entry("ProgramArguments", &arguments_array));
+    plist.push_str(&plist_entry(
+        "WorkingDirectory",
+        &plist_string(&path_to_str(&working_directory)?),
+    ));
+    plist.push_str(&plist_entry("RunAtLoad", "<true/>"));
+    plist.push_str(&plist_entry("KeepAlive", "<true/>"));
+    // not throttled as the background agents are, which delays the events
+    plist.push_str(&plist_entry("ProcessType", &plist_string("Interactive")));
+    let log_path = plist_string(&path_to_str(&log_path)?);
+    plist.push_str(&plist_entry("StandardOutPath", &log_path));
+    plist.push_str(&plist_entry("StandardErrorPath", &log_path));
+    plist.push_str("</dict>\n</plist>\n");
+
+    let agents_directory = Path::new(&home).join("Library/LaunchAgents");
+    std::fs::create_dir_all(&agents_directory).with_context(|| {
+        format!(
+            "unable to create the LaunchAgents directory {}",
+            agents_directory.display()
+        )
+    })?;
+    let plist_path = agents_directory.join(format!("{}.plist", label));
+    std::fs::write(&plist_path, plist)
+        .with_context(|| format!("unable to write the agent {}", plist_path.display()))?;
+    debug!("[macos] agent written to {}", plist_path.display());
+
+    // an agent installed before keeps running its previous arguments until unloaded
+    let _ = Command::new("launchctl")
+        .arg("unload")
+        .arg(&plist_path)
+        .output();
+    let output = Command::new("launchctl")
+        .args(["load", "-w"])
+        .arg(&plist_path)
+        .output()
+        .context("unable to run launchctl")?;
+    if !output.status.success() {
+        bail!(
+            "unable to load the agent {}: {}",
+            plist_path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    info!("agent {} installed and loaded", label);
+    Ok(())
+}
+
+fn plist_entry(key: &str, value: &str) -> String {
+    format!("    <key>{}</key>\n    {}\n", key, value)
+}
+
+fn plist_string(value: &str) -> String {
+    format!(
+        "<string>{}</string>",
+        value
+            .replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+    )
+}
+
+fn path_to_str(path: &Path) -> Result<String, anyhow::Error> {
+    path.to_str()
+        .map(String::from)
+        .with_context(|| format!("the path {} is not valid unicode", path.display()))
+}
+
+/// Prevent the idle sleep of the system while contents are transferred, so that a transfer is
+/// not interrupted and the events are still received. The assertion is held by a `caffeinate`
+/// bound to this process, which releases it if the process dies.
+pub fn prevent_idle_sleep(
+    transfer_gates: Vec<TransferGate>,
+) -> Result<JoinHandle<()>, anyhow::Error> {
+    let handle = std::thread::Builder::new()
+        .name(String::from("idle sleep preventer"))
+        .spawn(move || {
+            let mut assertion: Option<Child> = None;
+            loop {
+                std::thread::sleep(ACTIVITY_POLL_INTERVAL);
+                let is_transferring = transfer_gates
+                    .iter()
+                    .any(|transfers| transfers.is_transferring(IDLE_DELAY));
+                match assertion.take() {
+                    None if is_transferring => {
+                        debug!("[macos] transferring, preventing the idle sleep");
+                        match hold_assertion() {
+                            Ok(child) => assertion = Some(child),
+                            Err(error) => {
+                                warn!("the idle sleep is not prevented: {:?}", error);
+                                return;
+                            }
+                        }
+                    }
+                    Some(mut child) if !is_transferring => {
+                        debug!("[macos] no more transfers, allowing the idle sleep");
+                        let _ = child.kill();
+                        let _ = child.wait();
+                    }
+                    held => assertion = held,
+                }
+            }
+        })
+        .context("unable to create idle sleep preventer thread")?;
+    Ok(handle)
+}
+
+/// Until the child is killed: -i prevents the idle sleep of the system, -m the one of the disks
+fn hold_assertion() -> Result<Child, anyhow::Error> {
+    Command::new("caffeinate")
+        .args(["-i", "-m", "-w"])
+        .arg(std::process::id().to_string())
+        .spawn()
+        .context("unable to run caffeinate")
+}
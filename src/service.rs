@@ -0,0 +1,271 @@
+use crate::client::probe_server::Readiness;
+use crate::event_handler::transfer_gate::TransferGate;
+use crate::{Command, ServiceCommand};
+use anyhow::Context;
+use crossbeam_channel::Receiver;
+use log::{error, info};
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use windows_service::service::{
+    ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl, ServiceExitCode,
+    ServiceInfo, ServiceStartType, ServiceState, ServiceStatus, ServiceType,
+};
+use windows_service::service_control_handler::{
+    self, ServiceControlHandlerResult, ServiceStatusHandle,
+};
+use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+use windows_service::{define_windows_service, service_dispatcher};
+
+const SERVICE_NAME: &str = "fs-synchronizer";
+const SERVICE_TYPE: ServiceType = ServiceType::OWN_PROCESS;
+/// Given to the service control manager on top of the --termination-grace-secs, before it
+/// considers the service as hung
+const STOP_WAIT_MARGIN: Duration = Duration::from_secs(10);
+
+/// The controls received by the service, until the synchronization follows them
+static CONTROLS: Mutex<Option<ServiceControls>> = Mutex::new(None);
+
+define_windows_service!(ffi_service_main, service_main);
+
+/// Install, uninstall, start the service, or run as the service
+pub fn run_command(command: &ServiceCommand) -> Result<(), anyhow::Error> {
+    match command {
+        ServiceCommand::Install => install(),
+        ServiceCommand::Uninstall => uninstall(),
+        ServiceCommand::Start => start(),
+        // blocks until the service is stopped
+        ServiceCommand::Run => service_dispatcher::start(SERVICE_NAME, ffi_service_main)
+            .context("unable to reach the service control manager: `service run` is started by it"),
+    }
+}
+
+/// The service runs the executable with the arguments of `service install`, as `service run`
+fn install() -> Result<(), anyhow::Error> {
+    let mut launch_arguments: Vec<OsString> = std::env::args_os().skip(1).collect();
+    let install_position = launch_arguments
+        .windows(2)
+        .rposition(|arguments| arguments[0] == "service" && arguments[1] == "install")
+        .context("unable to find `service install` in the arguments")?;
+    launch_arguments.splice(
+        install_position..install_position + 2,
+        [OsString::from("service"), OsString::from("run")],
+    );
+    let manager = ServiceManager::local_computer(
+        None::<&str>,
+        ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE,
+    )
+    .context("unable to connect to the service control manager")?;
+    let service = manager
+        .create_service(
+            &ServiceInfo {
+                name: OsString::from(SERVICE_NAME),
+                display_name: OsString::from(SERVICE_NAME),
+                service_type: SERVICE_TYPE,
+                start_type: ServiceStartType::AutoStart,
+                error_control: ServiceErrorControl::Normal,
+                executable_path: std::env::current_exe()
+                    .context("unable to find the path of the executable")?,
+                launch_arguments,
+                dependencies: Vec::new(),
+                // the local system account
+                account_name: None,
+                account_password: None,
+            },
+            ServiceAccess::CHANGE_CONFIG,
+        )
+        .context("unable to install the service")?;
+    service
+        .set_description("Synchronize the files with the other instances")
+        .context("unable to describe the service")?;
+    info!("service {} installed", SERVICE_NAME);
+    Ok(())
+}
+
+fn uninstall() -> Result<(), anyhow::Error> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)
+        .context("unable to connect to the service control manager")?;
+    let service = manager
+        .open_service(
+            SERVICE_NAME,
+            ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE,
+        )
+        .context("unable to open the service")?;
+    if service.query_status()?.current_state != ServiceState::Stopped {
+        service.stop().context("unable to stop the service")?;
+    }
+    service.delete().context("unable to remove the service")?;
+    info!("service {} removed once stopped", SERVICE_NAME);
+    Ok(())
+}
+
+fn start() -> Result<(), anyhow::Error> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)
+        .context("unable to connect to the service control manager")?;
+    manager
+        .open_service(SERVICE_NAME, ServiceAccess::START)
+        .context("unable to open the service")?
+        .start::<OsString>(&[])
+        .context("unable to start the service")?;
+    info!("service {} started", SERVICE_NAME);
+    Ok(())
+}
+
+fn service_main(_arguments: Vec<OsString>) {
+    if let Err(error) = run_service() {
+        error!("the service failed: {:?}", error);
+    }
+}
+
+/// Watch the paths with the arguments of the command line, as `watch` does
+fn run_service() -> Result<(), anyhow::Error> {
+    let (sender, receiver) = crossbeam_channel::unbounded();
+    let status = service_control_handler::register(SERVICE_NAME, move |control| match control {
+        ServiceControl::Stop
+        | ServiceControl::Shutdown
+        | ServiceControl::Pause
+        | ServiceControl::Continue => {
+            // the synchronization may be stopped before it followed the controls
+            let _ = sender.send(control);
+            ServiceControlHandlerResult::NoError
+        }
+        ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+        _ => ServiceControlHandlerResult::NotImplemented,
+    })
+    .context("unable to register the service control handler")?;
+    // the controls received during the first synchronization are followed after it
+    set_status(status, ServiceState::Running, ServiceExitCode::Win32(0))?;
+    *CONTROLS.lock().expect("service controls lock poisoned") =
+        Some(ServiceControls { receiver, status });
+
+    let mut cli_arguments = crate::parse_arguments()?;
+    cli_arguments.command = Some(Command::Watch);
+    // the stop exits the process, this returns when the synchronization failed
+    let result = crate::run(cli_arguments);
+    let exit_code = match &result {
+        Ok(()) => ServiceExitCode::Win32(0),
+        Err(_) => ServiceExitCode::ServiceSpecific(1),
+    };
+    set_status(status, ServiceState::Stopped, exit_code)?;
+    result
+}
+
+fn set_status(
+    status: ServiceStatusHandle,
+    state: ServiceState,
+    exit_code: ServiceExitCode,
+) -> Result<(), anyhow::Error> {
+    set_pending_status(status, state, exit_code, Duration::default())
+}
+
+/// `wait_hint` is the time the pending state may last
+fn set_pending_status(
+    status: ServiceStatusHandle,
+    state: ServiceState,
+    exit_code: ServiceExitCode,
+    wait_hint: Duration,
+) -> Result<(), anyhow::Error> {
+    let controls_accepted = match state {
+        ServiceState::Running | ServiceState::Paused => {
+            ServiceControlAccept::STOP
+                | ServiceControlAccept::SHUTDOWN
+                | ServiceControlAccept::PAUSE_CONTINUE
+        }
+        _ => ServiceControlAccept::empty(),
+    };
+    status
+        .set_service_status(ServiceStatus {
+            service_type: SERVICE_TYPE,
+            current_state: state,
+            controls_accepted,
+            exit_code,
+            checkpoint: 0,
+            wait_hint,
+            process_id: None,
+        })
+        .with_context(|| format!("unable to report the service as {:?}", state))
+}
+
+/// What the services console asks to the service
+pub struct ServiceControls {
+    receiver: Receiver<ServiceControl>,
+    status: ServiceStatusHandle,
+}
+
+impl ServiceControls {
+    /// The controls of the service, when the process runs as the service
+    pub fn take() -> Option<ServiceControls> {
+        CONTROLS
+            .lock()
+            .expect("service controls lock poisoned")
+            .take()
+    }
+
+    /// Pause and resume the synchronization of every instance, as SIGUSR1 and SIGUSR2 do, and
+    /// stop it as SIGTERM does. The system shutting down does not wait for the grace delay.
+    pub fn follow(
+        self,
+        transfer_gates: Vec<TransferGate>,
+        readiness: Readiness,
+        termination_grace: Duration,
+        pidfile: Option<PathBuf>,
+    ) -> Result<JoinHandle<()>, anyhow::Error> {
+        let handle = std::thread::Builder::new()
+            .name(String::from("service control"))
+            .spawn(move || {
+                for control in self.receiver.iter() {
+                    let result = match control {
+                        ServiceControl::Pause | ServiceControl::Continue => {
+                            let is_held = control == ServiceControl::Pause;
+                            for transfers in transfer_gates.iter() {
+                                transfers.set_held(is_held);
+                            }
+                            let state = if is_held {
+                                ServiceState::Paused
+                            } else {
+                                ServiceState::Running
+                            };
+                            set_status(self.status, state, ServiceExitCode::Win32(0))
+                        }
+                        _ => {
+                            readiness.set_terminating();
+                            let grace = if control == ServiceControl::Shutdown {
+                                Duration::default()
+                            } else {
+                                termination_grace
+                            };
+                            if let Err(error) = set_pending_status(
+                                self.status,
+                                ServiceState::StopPending,
+                                ServiceExitCode::Win32(0),
+                                grace + STOP_WAIT_MARGIN,
+                            ) {
+                                error!("{:?}", error);
+                            }
+                            info!("terminating in {}s", grace.as_secs());
+                            std::thread::sleep(grace);
+                            info!("terminating");
+                            if let Some(pidfile) = &pidfile {
+                                crate::remove_pidfile(pidfile);
+                            }
+                            if let Err(error) = set_status(
+                                self.status,
+                                ServiceState::Stopped,
+                                ServiceExitCode::Win32(0),
+                            ) {
+                                error!("{:?}", error);
+                            }
+                            std::process::exit(0);
+                        }
+                    };
+                    if let Err(error) = result {
+                        error!("{:?}", error);
+                    }
+                }
+            })
+            .context("service control thread creation")?;
+        Ok(handle)
+    }
+}
@@ -0,0 +1,136 @@
+use crate::client::redis_client::RedisClient;
+use crate::store::local_fs_store::LocalFSStore;
+use crate::store::namespace::Namespace;
+use anyhow::Context;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Prefix of the hashes of the applied versions of each file
+const APPLIED_KEY_PREFIX: &str = "applied:";
+
+/// When an instance applied a version of a file
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct AppliedRecord {
+    /// Seconds since the epoch
+    pub applied_at: u64,
+    /// Id of the running process, changing at every restart
+    pub instance_id: u64,
+}
+
+/// A version of a file held by an instance
+#[derive(Debug, Clone, PartialEq)]
+pub struct AppliedVersion {
+    pub hash: u64,
+    pub instance_name: String,
+    pub record: AppliedRecord,
+}
+
+/// Records, for every version of a file, which instances applied it and when.
+/// Each instance records a version once: the time is the one of its first apply.
+/// The records outlive the files, so that the history of the removed files stays available.
+#[derive(Debug, Clone)]
+pub struct AuditStore {
+    client: RedisClient,
+    namespace: Namespace,
+    /// Stable name of this instance, e.g. its hostname
+    instance_name: String,
+    instance_id: u64,
+}
+
+impl AuditStore {
+    pub fn new(
+        client: RedisClient,
+        namespace: Namespace,
+        instance_name: String,
+        instance_id: u64,
+    ) -> AuditStore {
+        AuditStore {
+            client,
+            namespace,
+            instance_name,
+            instance_id,
+        }
+    }
+
+    /// Record that this instance holds the given version of the file
+    pub fn record_applied(&self, path: &Path, hash: u64) -> Result<(), anyhow::Error> {
+        let record = AppliedRecord {
+            applied_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_secs())
+                .unwrap_or_default(),
+            instance_id: self.instance_id,
+        };
+        let serialized_record = rmp_serde::to_vec(&record)
+            .expect("messagepack serialization of AppliedRecord should never fail");
+        let is_new = self
+            .client
+            .hset_if_not_exists(
+                &self.to_applied_key(path),
+                &format!("{}:{}", LocalFSStore::format_hash(hash), self.instance_name),
+                &serialized_record,
+            )
+            .context("unable to send the redis command to record the applied version")?;
+        if is_new {
+            debug!(
+                "[audit] recorded version {} of {}",
+                LocalFSStore::format_hash(hash),
+                path.display()
+            );
+        }
+        Ok(())
+    }
+
+    /// Every version of the file applied by the instances, the most recent first
+    pub fn applied_versions(&self, path: &Path) -> Result<Vec<AppliedVersion>, anyhow::Error> {
+        let fields = self
+            .client
+            .hgetall(&self.to_applied_key(path))
+            .context("unable to get the applied versions from the redis server")?;
+        let mut versions = Vec::with_capacity(fields.len());
+        for (field, serialized_record) in fields {
+            // the hash is hex, so the name is everything after the first `:`. The versions
+            // recorded before `migrate-hashes` are decimal, and never match a current hash.
+            let (hash, instance_name) = match field
+                .split_once(':')
+                .and_then(|(hash, name)| Some((LocalFSStore::parse_hash(hash).ok()?, name)))
+            {
+                None => continue,
+                Some(hash_and_name) => hash_and_name,
+            };
+            let record = rmp_serde::from_slice(&serialized_record)
+                .context("unable to decode the applied record")?;
+            versions.push(AppliedVersion {
+                hash,
+                instance_name: instance_name.to_string(),
+                record,
+            });
+        }
+        versions.sort_by_key(|version| std::cmp::Reverse(version.record.applied_at));
+        Ok(versions)
+    }
+
+    /// Every path having a recorded version, including the removed files
+    pub fn recorded_paths(&self) -> Result<Vec<PathBuf>, anyhow::Error> {
+        let applied_keys = self
+            .client
+            .scan_match(&self.namespace.key(&format!("{}*", APPLIED_KEY_PREFIX)))
+            .context("unable to list the applied versions")?;
+        Ok(applied_keys
+            .iter()
+            .filter_map(|key| {
+                self.namespace
+                    .strip(key)?
+                    .strip_prefix(APPLIED_KEY_PREFIX)
+                    .map(PathBuf::from)
+            })
+            .collect())
+    }
+
+    fn to_applied_key(&self, path: &Path) -> String {
+        self.namespace
+            .key(&format!("{}{}", APPLIED_KEY_PREFIX, path.to_string_lossy()))
+    }
+}
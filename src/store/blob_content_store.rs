@@ -0,0 +1,548 @@
+use crate::client::http_client::{HttpClient, HttpResponse};
+use crate::client::redis_client::RedisClient;
+use crate::store::content_store::{ContentStore, CONTENT_KEY_PREFIX};
+use crate::store::local_fs_store::LocalFSStore;
+use crate::store::namespace::Namespace;
+use anyhow::{anyhow, bail, Context};
+use log::{debug, error};
+use std::fmt::Debug;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+/// Prefix of the content keys values, followed by the id of the blob
+const BLOB_POINTER_PREFIX: &[u8] = b"blob:";
+
+/// Object storage holding the blobs, addressed by an opaque id
+pub trait BlobStore: Debug + Send + Sync {
+    fn backend_name(&self) -> &'static str;
+
+    fn put(&self, id: &str, blob: &[u8]) -> Result<(), anyhow::Error>;
+
+    /// Returns None when there is no such blob
+    fn get(&self, id: &str) -> Result<Option<Vec<u8>>, anyhow::Error>;
+
+    /// Deleting a missing blob is not an error
+    fn delete(&self, id: &str) -> Result<(), anyhow::Error>;
+
+    /// Returns 0 when there is no such blob
+    fn size(&self, id: &str) -> Result<u64, anyhow::Error>;
+}
+
+/// Contents stored as blobs out of Redis. The `content:` key of a file only holds a pointer
+/// to its blob, so that large repositories do not blow up the memory of Redis.
+/// Each version of a file gets a new blob, deleted once it is replaced.
+#[derive(Debug)]
+pub struct BlobContentStore {
+    client: RedisClient,
+    namespace: Namespace,
+    blobs: Box<dyn BlobStore>,
+}
+
+impl BlobContentStore {
+    pub fn new(
+        client: RedisClient,
+        namespace: Namespace,
+        blobs: Box<dyn BlobStore>,
+    ) -> BlobContentStore {
+        BlobContentStore {
+            client,
+            namespace,
+            blobs,
+        }
+    }
+
+    fn to_content_key(&self, path: &str) -> String {
+        self.namespace
+            .key(&format!("{}{}", CONTENT_KEY_PREFIX, path))
+    }
+
+    fn to_blob_id(pointer: &[u8]) -> Result<String, anyhow::Error> {
+        let blob_id = pointer
+            .strip_prefix(BLOB_POINTER_PREFIX)
+            .ok_or_else(|| anyhow!("the content key does not hold a blob pointer"))?;
+        Ok(String::from_utf8_lossy(blob_id).into_owned())
+    }
+
+    fn get_blob_id(&self, path: &str) -> Result<Option<String>, anyhow::Error> {
+        match self
+            .client
+            .get_if_exists(&self.to_content_key(path))
+            .context("unable to read the blob pointer from redis server")?
+        {
+            None => Ok(None),
+            Some(pointer) => BlobContentStore::to_blob_id(&pointer).map(Some),
+        }
+    }
+
+    /// The replaced blob is not referenced anymore. Failing to delete it only wastes space.
+    fn delete_replaced_blob(&self, path: &str, old_pointer: Option<Vec<u8>>) {
+        let old_blob_id = match old_pointer.map(|pointer| BlobContentStore::to_blob_id(&pointer)) {
+            None | Some(Err(_)) => return,
+            Some(Ok(old_blob_id)) => old_blob_id,
+        };
+        debug!(
+            "[blob_content_store] deleting blob {} of {}",
+            old_blob_id, path
+        );
+        if let Err(error) = self.blobs.delete(&old_blob_id) {
+            error!(
+                "unable to delete the blob {} replaced in {}: {:?}",
+                old_blob_id, path, error
+            );
+        }
+    }
+}
+
+impl ContentStore for BlobContentStore {
+    fn backend_name(&self) -> &'static str {
+        self.blobs.backend_name()
+    }
+
+    fn set_content(&self, path: &str, content: &[u8]) -> Result<(), anyhow::Error> {
+        let blob_id = format!("{:032x}", rand::random::<u128>());
+        debug!("[blob_content_store] writing {} to blob {}", path, blob_id);
+        self.blobs
+            .put(&blob_id, content)
+            .with_context(|| format!("unable to write the blob of {}", path))?;
+
+        let mut pointer = BLOB_POINTER_PREFIX.to_vec();
+        pointer.extend_from_slice(blob_id.as_bytes());
+        // swapped atomically, so that concurrent writers each delete a different old blob
+        let old_pointer = self
+            .client
+            .get_set(&self.to_content_key(path), &pointer)
+            .context("unable to write the blob pointer to redis server")?;
+        self.delete_replaced_blob(path, old_pointer);
+        Ok(())
+    }
+
+    fn get_content(&self, path: &str) -> Result<Option<Vec<u8>>, anyhow::Error> {
+        match self.get_blob_id(path)? {
+            None => Ok(None),
+            Some(blob_id) => self
+                .blobs
+                .get(&blob_id)
+                .with_context(|| format!("unable to read the blob of {}", path)),
+        }
+    }
+
+    /// Only the pointer moves, the blob stays the same
+    fn rename_content(&self, old_path: &str, new_path: &str) -> Result<(), anyhow::Error> {
+        self.client.rename(
+            &self.to_content_key(old_path),
+            &self.to_content_key(new_path),
+        )
+    }
+
+    fn remove_content(&self, path: &str) -> Result<(), anyhow::Error> {
+        let content_key = self.to_content_key(path);
+        let old_pointer = self
+            .client
+            .get_if_exists(&content_key)
+            .context("unable to read the blob pointer from redis server")?;
+        self.client.remove(&content_key)?;
+        self.delete_replaced_blob(path, old_pointer);
+        Ok(())
+    }
+
+    fn content_size(&self, path: &str) -> Result<u64, anyhow::Error> {
+        match self.get_blob_id(path)? {
+            None => Ok(0),
+            Some(blob_id) => self.blobs.size(&blob_id),
+        }
+    }
+}
+
+/// Blobs stored as files in a local directory, typically a shared mount
+#[derive(Debug)]
+pub struct FsBlobStore {
+    directory: PathBuf,
+}
+
+impl FsBlobStore {
+    /// Parse a `file:///directory` url
+    pub fn new(url: &str) -> Result<FsBlobStore, anyhow::Error> {
+        let url = url::Url::parse(url).context("invalid blob store url")?;
+        let directory = url
+            .to_file_path()
+            .map_err(|_| anyhow!("blob store url must be file:///directory, got {}", url))?;
+        if !directory.is_dir() {
+            bail!(
+                "blob store directory {} does not exist",
+                directory.display()
+            );
+        }
+        Ok(FsBlobStore { directory })
+    }
+
+    /// Blobs are spread in subdirectories, to keep the directories small
+    fn to_blob_path(&self, id: &str) -> PathBuf {
+        self.directory.join(id.get(..2).unwrap_or(id)).join(id)
+    }
+}
+
+impl BlobStore for FsBlobStore {
+    fn backend_name(&self) -> &'static str {
+        "fs-blobs"
+    }
+
+    fn put(&self, id: &str, blob: &[u8]) -> Result<(), anyhow::Error> {
+        let blob_path = self.to_blob_path(id);
+        LocalFSStore::ensure_directory_exists(&blob_path)?;
+        // written aside then renamed, so that a reader never sees a partial blob
+        let partial_path = blob_path.with_extension("partial");
+        std::fs::write(&partial_path, blob)
+            .with_context(|| format!("unable to write {}", partial_path.display()))?;
+        std::fs::rename(&partial_path, &blob_path)
+            .with_context(|| format!("unable to write {}", blob_path.display()))
+    }
+
+    fn get(&self, id: &str) -> Result<Option<Vec<u8>>, anyhow::Error> {
+        let blob_path = self.to_blob_path(id);
+        match std::fs::read(&blob_path) {
+            Err(error) if error.kind() == ErrorKind::NotFound => Ok(None),
+            Err(error) => {
+                Err(error).with_context(|| format!("unable to read {}", blob_path.display()))
+            }
+            Ok(blob) => Ok(Some(blob)),
+        }
+    }
+
+    fn delete(&self, id: &str) -> Result<(), anyhow::Error> {
+        let blob_path = self.to_blob_path(id);
+        match std::fs::remove_file(&blob_path) {
+            Err(error) if error.kind() != ErrorKind::NotFound => {
+                Err(error).with_context(|| format!("unable to remove {}", blob_path.display()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn size(&self, id: &str) -> Result<u64, anyhow::Error> {
+        let blob_path = self.to_blob_path(id);
+        match std::fs::metadata(&blob_path) {
+            Err(error) if error.kind() == ErrorKind::NotFound => Ok(0),
+            Err(error) => {
+                Err(error).with_context(|| format!("unable to stat {}", blob_path.display()))
+            }
+            Ok(metadata) => Ok(metadata.len()),
+        }
+    }
+}
+
+/// Credentials of the S3 API, read from the usual AWS environment variables
+pub struct S3Credentials {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub session_token: Option<String>,
+}
+
+impl S3Credentials {
+    pub fn from_env() -> Result<S3Credentials, anyhow::Error> {
+        Ok(S3Credentials {
+            access_key_id: std::env::var("AWS_ACCESS_KEY_ID")
+                .context("AWS_ACCESS_KEY_ID is required by the s3 content store")?,
+            secret_access_key: std::env::var("AWS_SECRET_ACCESS_KEY")
+                .context("AWS_SECRET_ACCESS_KEY is required by the s3 content store")?,
+            session_token: std::env::var("AWS_SESSION_TOKEN").ok(),
+        })
+    }
+}
+
+/// Blobs stored as objects of a S3 compatible bucket, addressed in path style
+pub struct S3BlobStore {
+    http: HttpClient,
+    bucket: String,
+    /// Prefix of the object keys, empty or ending with `/`
+    prefix: String,
+    region: String,
+    credentials: S3Credentials,
+}
+
+impl Debug for S3BlobStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("S3BlobStore")
+            .field("http", &self.http)
+            .field("bucket", &self.bucket)
+            .field("prefix", &self.prefix)
+            .field("region", &self.region)
+            .finish()
+    }
+}
+
+impl S3BlobStore {
+    /// Parse a `s3://bucket/prefix` url. The endpoint defaults to the AWS one of the region.
+    pub fn new(
+        url: &str,
+        endpoint: Option<String>,
+        region: String,
+        credentials: S3Credentials,
+    ) -> Result<S3BlobStore, anyhow::Error> {
+        let url = url::Url::parse(url).context("invalid blob store url")?;
+        let bucket = url
+            .host_str()
+            .ok_or_else(|| anyhow!("s3 url has no bucket: {}", url))?
+            .to_string();
+        // decoded, as the object paths are encoded when they are sent
+        let path = percent_encoding::percent_decode_str(url.path())
+            .decode_utf8()
+            .context("s3 url prefix is not valid UTF-8")?;
+        let prefix = match path.trim_matches('/') {
+            "" => String::new(),
+            prefix => format!("{}/", prefix),
+        };
+        let endpoint = endpoint.unwrap_or_else(|| format!("https://s3.{}.amazonaws.com", region));
+        Ok(S3BlobStore {
+            http: HttpClient::new(&endpoint).context("invalid s3 endpoint")?,
+            bucket,
+            prefix,
+            region,
+            credentials,
+        })
+    }
+
+    fn to_object_path(&self, id: &str) -> String {
+        uri_encode(&format!("/{}/{}{}", self.bucket, self.prefix, id))
+    }
+
+    /// Send a request signed with AWS signature version 4
+    fn signed_request(
+        &self,
+        method: &str,
+        id: &str,
+        body: &[u8],
+    ) -> Result<HttpResponse, anyhow::Error> {
+        let path = self.to_object_path(id);
+        let amz_date = chrono::Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+        let payload_hash = hex_sha256(body);
+
+        let mut headers = vec![
+            ("host", self.http.host_header()),
+            ("x-amz-content-sha256", payload_hash.clone()),
+            ("x-amz-date", amz_date.clone()),
+        ];
+        if let Some(session_token) = &self.credentials.session_token {
+            headers.push(("x-amz-security-token", session_token.clone()));
+        }
+        let (canonical_request, signed_headers) =
+            canonical_request(method, &path, &headers, &payload_hash);
+        let (scope, signature) = sign(
+            &canonical_request,
+            &amz_date,
+            &self.region,
+            "s3",
+            &self.credentials.secret_access_key,
+        );
+
+        // the Host header is added by the http client
+        headers.remove(0);
+        headers.push((
+            "Authorization",
+            format!(
+                "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                self.credentials.access_key_id, scope, signed_headers, signature
+            ),
+        ));
+        self.http.request(method, &path, &headers, body)
+    }
+
+    fn ensure_success(response: &HttpResponse, id: &str) -> Result<(), anyhow::Error> {
+        if !response.is_success() {
+            bail!(
+                "s3 answered {} for blob {}: {}",
+                response.status,
+                id,
+                String::from_utf8_lossy(&response.body).trim()
+            );
+        }
+        Ok(())
+    }
+}
+
+impl BlobStore for S3BlobStore {
+    fn backend_name(&self) -> &'static str {
+        "s3"
+    }
+
+    fn put(&self, id: &str, blob: &[u8]) -> Result<(), anyhow::Error> {
+        let response = self.signed_request("PUT", id, blob)?;
+        S3BlobStore::ensure_success(&response, id)
+    }
+
+    fn get(&self, id: &str) -> Result<Option<Vec<u8>>, anyhow::Error> {
+        let response = self.signed_request("GET", id, b"")?;
+        if response.status == 404 {
+            return Ok(None);
+        }
+        S3BlobStore::ensure_success(&response, id)?;
+        Ok(Some(response.body))
+    }
+
+    fn delete(&self, id: &str) -> Result<(), anyhow::Error> {
+        let response = self.signed_request("DELETE", id, b"")?;
+        if response.status == 404 {
+            return Ok(());
+        }
+        S3BlobStore::ensure_success(&response, id)
+    }
+
+    fn size(&self, id: &str) -> Result<u64, anyhow::Error> {
+        let response = self.signed_request("HEAD", id, b"")?;
+        if response.status == 404 {
+            return Ok(0);
+        }
+        S3BlobStore::ensure_success(&response, id)?;
+        response
+            .header("content-length")
+            .and_then(|length| length.parse().ok())
+            .ok_or_else(|| anyhow!("s3 did not give the size of blob {}", id))
+    }
+}
+
+/// The canonical request of SigV4, and the names of its signed headers. The headers are sorted
+/// by name, in lower case.
+fn canonical_request(
+    method: &str,
+    path: &str,
+    headers: &[(&str, String)],
+    payload_hash: &str,
+) -> (String, String) {
+    let signed_headers = headers
+        .iter()
+        .map(|(name, _)| *name)
+        .collect::<Vec<&str>>()
+        .join(";");
+    let canonical_headers: String = headers
+        .iter()
+        .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+        .collect();
+    let canonical_request = format!(
+        "{}\n{}\n\n{}\n{}\n{}",
+        method, path, canonical_headers, signed_headers, payload_hash
+    );
+    (canonical_request, signed_headers)
+}
+
+/// The credential scope of the request and its SigV4 signature, for an `amz_date` formatted
+/// as `20130524T000000Z`
+fn sign(
+    canonical_request: &str,
+    amz_date: &str,
+    region: &str,
+    service: &str,
+    secret_access_key: &str,
+) -> (String, String) {
+    let date = &amz_date[..8];
+    let scope = format!("{}/{}/{}/aws4_request", date, region, service);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        amz_date,
+        scope,
+        hex_sha256(canonical_request.as_bytes())
+    );
+    let signing_key = [region, service, "aws4_request"].iter().fold(
+        hmac_sha256(
+            format!("AWS4{}", secret_access_key).as_bytes(),
+            date.as_bytes(),
+        ),
+        |key, part| hmac_sha256(&key, part.as_bytes()),
+    );
+    let signature = hex(&hmac_sha256(&signing_key, string_to_sign.as_bytes()));
+    (scope, signature)
+}
+
+/// Percent-encode everything but the unreserved characters and `/`, as expected by SigV4
+fn uri_encode(path: &str) -> String {
+    path.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn hex_sha256(data: &[u8]) -> String {
+    hex(ring::digest::digest(&ring::digest::SHA256, data).as_ref())
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, key);
+    ring::hmac::sign(&key, data).as_ref().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET_ACCESS_KEY: &str = "wJalrXUtnFEMI/K7MDENG/bPxRfiCYEXAMPLEKEY";
+
+    /// `get-vanilla` of the AWS SigV4 test suite
+    #[test]
+    fn signs_the_aws_test_suite_request() {
+        let headers = [
+            ("host", String::from("example.amazonaws.com")),
+            ("x-amz-date", String::from("20150830T123600Z")),
+        ];
+        let (canonical_request, signed_headers) =
+            canonical_request("GET", "/", &headers, &hex_sha256(b""));
+        assert_eq!(
+            canonical_request,
+            "GET\n/\n\nhost:example.amazonaws.com\nx-amz-date:20150830T123600Z\n\nhost;x-amz-date\ne3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(signed_headers, "host;x-amz-date");
+
+        let (scope, signature) = sign(
+            &canonical_request,
+            "20150830T123600Z",
+            "us-east-1",
+            "service",
+            "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+        );
+        assert_eq!(scope, "20150830/us-east-1/service/aws4_request");
+        assert_eq!(
+            signature,
+            "5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31"
+        );
+    }
+
+    /// The GET Object example of the S3 SigV4 documentation
+    #[test]
+    fn signs_the_s3_documentation_request() {
+        let headers = [
+            ("host", String::from("examplebucket.s3.amazonaws.com")),
+            ("range", String::from("bytes=0-9")),
+            ("x-amz-content-sha256", hex_sha256(b"")),
+            ("x-amz-date", String::from("20130524T000000Z")),
+        ];
+        let (canonical_request, signed_headers) =
+            canonical_request("GET", "/test.txt", &headers, &hex_sha256(b""));
+        assert_eq!(signed_headers, "host;range;x-amz-content-sha256;x-amz-date");
+
+        let (scope, signature) = sign(
+            &canonical_request,
+            "20130524T000000Z",
+            "us-east-1",
+            "s3",
+            SECRET_ACCESS_KEY,
+        );
+        assert_eq!(scope, "20130524/us-east-1/s3/aws4_request");
+        assert_eq!(
+            signature,
+            "f0e8bdb87c964420e857bd35b5d6ed310bd44f0170aba48dd91039c6036bdb41"
+        );
+    }
+
+    #[test]
+    fn encodes_the_object_paths() {
+        assert_eq!(
+            uri_encode("/bucket/dir/a file+é~.txt"),
+            "/bucket/dir/a%20file%2B%C3%A9~.txt"
+        );
+    }
+}
@@ -0,0 +1,92 @@
+use crate::client::redis_client::RedisClient;
+use crate::store::namespace::Namespace;
+use anyhow::Context;
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+const SHARED_CONFIG_KEY: &str = "config:shared";
+const SHARED_CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Configuration fragment shared by every instance of the namespace, applied without restart.
+/// A setting given on the command line always wins over the shared one.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
+pub struct SharedConfig {
+    /// Globs of remote paths never applied locally, as --no-apply
+    #[serde(default)]
+    pub no_apply: Vec<String>,
+}
+
+impl SharedConfig {
+    /// Read a fragment written as JSON, e.g. `{"no_apply": ["*.tmp"]}`
+    pub fn from_json_file(path: &Path) -> Result<SharedConfig, anyhow::Error> {
+        let file = std::fs::File::open(path)
+            .with_context(|| format!("unable to open {}", path.display()))?;
+        serde_json::from_reader(file)
+            .with_context(|| format!("invalid shared configuration in {}", path.display()))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ConfigStore {
+    client: RedisClient,
+    namespace: Namespace,
+}
+
+impl ConfigStore {
+    pub fn new(client: RedisClient, namespace: Namespace) -> ConfigStore {
+        ConfigStore { client, namespace }
+    }
+
+    pub fn publish_shared_config(&self, config: &SharedConfig) -> Result<(), anyhow::Error> {
+        let serialized_config = rmp_serde::to_vec(config)
+            .expect("messagepack serialization of SharedConfig should never fail");
+        self.client
+            .set(&self.namespace.key(SHARED_CONFIG_KEY), &serialized_config)
+            .context("unable to send the redis command to publish the shared configuration")?;
+        info!("shared configuration published: {:?}", config);
+        Ok(())
+    }
+
+    /// Returns the default configuration when none was published
+    pub fn get_shared_config(&self) -> Result<SharedConfig, anyhow::Error> {
+        let serialized_config = match self
+            .client
+            .get_if_exists(&self.namespace.key(SHARED_CONFIG_KEY))
+            .context("unable to get the shared configuration from the redis server")?
+        {
+            None => return Ok(SharedConfig::default()),
+            Some(serialized_config) => serialized_config,
+        };
+        rmp_serde::from_slice(&serialized_config)
+            .context("unable to decode the shared configuration")
+    }
+
+    /// Poll the shared configuration until the process exits, and call `on_change` with
+    /// every new version. `current` is the version already applied.
+    pub fn watch_shared_config(
+        self,
+        mut current: SharedConfig,
+        on_change: impl Fn(&SharedConfig) + Send + 'static,
+    ) -> Result<JoinHandle<()>, anyhow::Error> {
+        let handle = std::thread::Builder::new()
+            .name(String::from("shared config watcher"))
+            .spawn(move || loop {
+                std::thread::sleep(SHARED_CONFIG_POLL_INTERVAL);
+                debug!("[config] polling the shared configuration");
+                match self.get_shared_config() {
+                    Err(error) => error!("unable to get the shared configuration: {:?}", error),
+                    Ok(config) if config != current => {
+                        info!("shared configuration changed: {:?}", config);
+                        on_change(&config);
+                        current = config;
+                    }
+                    Ok(_) => (),
+                }
+            })
+            .context("unable to create shared config watcher thread")?;
+        Ok(handle)
+    }
+}
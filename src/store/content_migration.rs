@@ -0,0 +1,202 @@
+use crate::client::redis_client::RedisClient;
+use crate::store::content_store::CONTENT_KEY_PREFIX;
+use crate::store::fleet_semaphore::FleetPermit;
+use crate::store::local_fs_store::{Compression, LocalFSStore};
+use crate::store::namespace::Namespace;
+use anyhow::Context;
+use log::{debug, error, info};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Name of the semaphore letting one migration run at a time in the namespace
+pub const MIGRATION_SEMAPHORE: &str = "content-migration";
+/// Delay between two progress reports
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How fast the contents are migrated
+#[derive(Debug, Clone)]
+pub struct MigrationPolicy {
+    /// Number of contents migrated at the same time
+    pub parallel: usize,
+    /// Maximum number of contents checked per second, across the workers. 0 for no limit.
+    pub max_rate: u32,
+}
+
+#[derive(Debug, Default)]
+struct Progress {
+    checked: AtomicU64,
+    rewritten: AtomicU64,
+    failed: AtomicU64,
+    /// Sizes of the rewritten contents, before and after
+    size_before: AtomicU64,
+    size_after: AtomicU64,
+}
+
+impl Progress {
+    fn report(&self) -> MigrationReport {
+        MigrationReport {
+            checked: self.checked.load(Ordering::SeqCst),
+            rewritten: self.rewritten.load(Ordering::SeqCst),
+            failed: self.failed.load(Ordering::SeqCst),
+            size_before: self.size_before.load(Ordering::SeqCst),
+            size_after: self.size_after.load(Ordering::SeqCst),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MigrationReport {
+    pub checked: u64,
+    pub rewritten: u64,
+    pub failed: u64,
+    pub size_before: u64,
+    pub size_after: u64,
+}
+
+impl MigrationReport {
+    /// Negative when the new codec compresses less
+    pub fn saved_bytes(&self) -> i64 {
+        self.size_before as i64 - self.size_after as i64
+    }
+}
+
+/// Rewrites the contents stored in redis with another codec, in place. The contents already
+/// compressed with it are skipped, so that running the migration again resumes it. A content
+/// changed by an instance during its migration is left as the instance wrote it.
+pub struct ContentMigration {
+    client: RedisClient,
+    namespace: Namespace,
+    compression: Compression,
+    policy: MigrationPolicy,
+}
+
+impl ContentMigration {
+    pub fn new(
+        client: RedisClient,
+        namespace: Namespace,
+        compression: Compression,
+        policy: MigrationPolicy,
+    ) -> ContentMigration {
+        ContentMigration {
+            client,
+            namespace,
+            compression,
+            policy,
+        }
+    }
+
+    /// Migrate the contents of these paths, reporting the progress regularly
+    pub fn run(
+        &self,
+        paths: &[String],
+        permit: &FleetPermit<'_>,
+    ) -> Result<MigrationReport, anyhow::Error> {
+        let progress = Progress::default();
+        let next_slot = Mutex::new(Instant::now());
+        let (sender, receiver) = crossbeam_channel::bounded::<&str>(self.policy.parallel);
+        std::thread::scope(|scope| -> Result<(), anyhow::Error> {
+            for _ in 0..self.policy.parallel.max(1) {
+                let receiver = receiver.clone();
+                let (progress, next_slot) = (&progress, &next_slot);
+                std::thread::Builder::new()
+                    .name(String::from("content migration"))
+                    .spawn_scoped(scope, move || {
+                        for path in receiver {
+                            self.wait_for_slot(next_slot);
+                            self.migrate(path, progress);
+                        }
+                    })
+                    .context("unable to create content migration thread")?;
+            }
+            drop(receiver);
+            let mut last_report = Instant::now();
+            for path in paths {
+                permit.keep_alive();
+                if sender.send(path).is_err() {
+                    break;
+                }
+                if last_report.elapsed() >= PROGRESS_INTERVAL {
+                    let report = progress.report();
+                    info!(
+                        "{}/{} contents checked, {} rewritten, {} failed, {} bytes saved",
+                        report.checked,
+                        paths.len(),
+                        report.rewritten,
+                        report.failed,
+                        report.saved_bytes()
+                    );
+                    last_report = Instant::now();
+                }
+            }
+            // the workers stop once the paths sent are done
+            drop(sender);
+            Ok(())
+        })?;
+        Ok(progress.report())
+    }
+
+    fn wait_for_slot(&self, next_slot: &Mutex<Instant>) {
+        if self.policy.max_rate == 0 {
+            return;
+        }
+        let slot = {
+            let mut next_slot = next_slot.lock().expect("migration rate lock poisoned");
+            let slot = (*next_slot).max(Instant::now());
+            *next_slot = slot + Duration::from_secs(1) / self.policy.max_rate;
+            slot
+        };
+        let now = Instant::now();
+        if slot > now {
+            std::thread::sleep(slot - now);
+        }
+    }
+
+    fn migrate(&self, path: &str, progress: &Progress) {
+        progress.checked.fetch_add(1, Ordering::SeqCst);
+        match self.rewrite(path) {
+            Ok(None) => (),
+            Ok(Some((size_before, size_after))) => {
+                progress.rewritten.fetch_add(1, Ordering::SeqCst);
+                progress
+                    .size_before
+                    .fetch_add(size_before, Ordering::SeqCst);
+                progress.size_after.fetch_add(size_after, Ordering::SeqCst);
+            }
+            Err(error) => {
+                progress.failed.fetch_add(1, Ordering::SeqCst);
+                error!("unable to migrate the content of {}: {:?}", path, error);
+            }
+        }
+    }
+
+    /// Returns the sizes before and after, or None when the content is left as it is
+    fn rewrite(&self, path: &str) -> Result<Option<(u64, u64)>, anyhow::Error> {
+        let key = self
+            .namespace
+            .key(&format!("{}{}", CONTENT_KEY_PREFIX, path));
+        let content = match self.client.get_if_exists(&key)? {
+            // removed, or moved to the cold tier
+            None => return Ok(None),
+            Some(content) => content,
+        };
+        match Compression::of(&content) {
+            Some(compression) if compression != self.compression => (),
+            // already migrated, or not a compressed content, like the reference to a secret
+            _ => return Ok(None),
+        }
+        let migrated_content =
+            LocalFSStore::compress_with(&LocalFSStore::decompress(&content)?, self.compression);
+        if !self
+            .client
+            .compare_and_set(&key, &content, &migrated_content)?
+        {
+            debug!(
+                "[content_migration] {} changed during its migration, left as it is",
+                path
+            );
+            return Ok(None);
+        }
+        Ok(Some((content.len() as u64, migrated_content.len() as u64)))
+    }
+}
@@ -0,0 +1,80 @@
+use crate::client::redis_client::RedisClient;
+use crate::store::namespace::Namespace;
+use anyhow::Context;
+use std::fmt::Debug;
+
+/// Prefix of the redis keys holding the contents, when they are stored in redis
+pub const CONTENT_KEY_PREFIX: &str = "content:";
+
+/// Where the compressed contents of the files are kept.
+/// The hashes, the list of files and the events always go through Redis.
+pub trait ContentStore: Debug + Send + Sync {
+    /// Short name of the backend, shared by all the instances of a namespace
+    fn backend_name(&self) -> &'static str;
+
+    fn set_content(&self, path: &str, content: &[u8]) -> Result<(), anyhow::Error>;
+
+    /// Returns None when there is no content for this path
+    fn get_content(&self, path: &str) -> Result<Option<Vec<u8>>, anyhow::Error>;
+
+    fn rename_content(&self, old_path: &str, new_path: &str) -> Result<(), anyhow::Error>;
+
+    fn remove_content(&self, path: &str) -> Result<(), anyhow::Error>;
+
+    /// Size of the content as stored, i.e. compressed
+    fn content_size(&self, path: &str) -> Result<u64, anyhow::Error>;
+
+    /// Whether the content may be carried by the change events, which every peer receives
+    fn is_inlinable(&self, _path: &str) -> bool {
+        true
+    }
+}
+
+/// Contents stored in Redis, next to the hashes, under the `content:` keys
+#[derive(Debug, Clone)]
+pub struct RedisContentStore {
+    client: RedisClient,
+    namespace: Namespace,
+}
+
+impl RedisContentStore {
+    pub fn new(client: RedisClient, namespace: Namespace) -> RedisContentStore {
+        RedisContentStore { client, namespace }
+    }
+
+    fn to_content_key(&self, path: &str) -> String {
+        self.namespace
+            .key(&format!("{}{}", CONTENT_KEY_PREFIX, path))
+    }
+}
+
+impl ContentStore for RedisContentStore {
+    fn backend_name(&self) -> &'static str {
+        "redis"
+    }
+
+    fn set_content(&self, path: &str, content: &[u8]) -> Result<(), anyhow::Error> {
+        self.client.set(&self.to_content_key(path), content)
+    }
+
+    fn get_content(&self, path: &str) -> Result<Option<Vec<u8>>, anyhow::Error> {
+        self.client
+            .get_if_exists(&self.to_content_key(path))
+            .context("unable to read compressed file content from redis server")
+    }
+
+    fn rename_content(&self, old_path: &str, new_path: &str) -> Result<(), anyhow::Error> {
+        self.client.rename(
+            &self.to_content_key(old_path),
+            &self.to_content_key(new_path),
+        )
+    }
+
+    fn remove_content(&self, path: &str) -> Result<(), anyhow::Error> {
+        self.client.remove(&self.to_content_key(path))
+    }
+
+    fn content_size(&self, path: &str) -> Result<u64, anyhow::Error> {
+        self.client.strlen(&self.to_content_key(path))
+    }
+}
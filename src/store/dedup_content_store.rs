@@ -0,0 +1,295 @@
+use crate::client::redis_client::RedisClient;
+use crate::store::content_store::{ContentStore, CONTENT_KEY_PREFIX};
+use crate::store::local_fs_store::LocalFSStore;
+use crate::store::namespace::Namespace;
+use anyhow::{bail, Context};
+use log::debug;
+use std::collections::HashMap;
+
+/// Prefix of the keys holding the blobs, followed by the BLAKE3 hash of the uncompressed content,
+/// so that the peers compressing it differently share its blob. The `content:` key of a file
+/// holds the name of its blob key, without the namespace.
+const BLOB_KEY_PREFIX: &str = "dedup-blob:";
+/// Size of the pointers: the prefix and the hex BLAKE3 hash
+const POINTER_SIZE: u64 = BLOB_KEY_PREFIX.len() as u64 + 64;
+/// Hash of the blob key names -> number of `content:` keys pointing to them
+const BLOB_REFS_KEY: &str = "dedup-blob-refs";
+/// A blob may be deleted by the last file pointing to it between two steps of a write or a read
+const MAX_ATTEMPTS: usize = 3;
+
+/// Lua function dropping a reference to a blob, and the blob along with the last one.
+/// The contents stored before the deduplication are not counted, and left alone.
+macro_rules! release_function {
+    () => {
+        r"
+local function release(refs_key, namespace, pointer)
+    if pointer and redis.call('HEXISTS', refs_key, pointer) == 1
+        and redis.call('HINCRBY', refs_key, pointer, -1) <= 0 then
+        redis.call('HDEL', refs_key, pointer)
+        redis.call('DEL', namespace .. pointer)
+    end
+end
+"
+    };
+}
+
+/// Point the content key to an existing blob. Returns -1 when the blob does not exist.
+/// KEYS[1]: content key, KEYS[2]: blob refs hash, KEYS[3]: blob key
+/// ARGV: blob key name, namespace prefix
+const LINK_SCRIPT: &str = concat!(
+    release_function!(),
+    r"
+if redis.call('EXISTS', KEYS[3]) == 0 then
+    return -1
+end
+local replaced = redis.call('GET', KEYS[1])
+redis.call('SET', KEYS[1], ARGV[1])
+if replaced ~= ARGV[1] then
+    redis.call('HINCRBY', KEYS[2], ARGV[1], 1)
+    release(KEYS[2], ARGV[2], replaced)
+end
+return 1
+"
+);
+
+/// Move the content key, releasing the blob of the content it replaces.
+/// KEYS[1]: old content key, KEYS[2]: new content key, KEYS[3]: blob refs hash
+/// ARGV: namespace prefix
+const RENAME_SCRIPT: &str = concat!(
+    release_function!(),
+    r"
+local replaced = redis.call('GET', KEYS[2])
+redis.call('RENAME', KEYS[1], KEYS[2])
+if KEYS[1] ~= KEYS[2] then
+    release(KEYS[3], ARGV[1], replaced)
+end
+return 1
+"
+);
+
+/// Remove the content key, releasing its blob.
+/// KEYS[1]: content key, KEYS[2]: blob refs hash
+/// ARGV: namespace prefix
+const REMOVE_SCRIPT: &str = concat!(
+    release_function!(),
+    r"
+local removed = redis.call('GET', KEYS[1])
+redis.call('DEL', KEYS[1])
+release(KEYS[2], ARGV[1], removed)
+return 1
+"
+);
+
+/// A stored blob
+#[derive(Debug, Clone)]
+pub struct BlobUsage {
+    /// Name of its key, without the namespace
+    pub name: String,
+    pub size: u64,
+    /// Number of files pointing to it
+    pub references: u64,
+}
+
+/// Contents stored in Redis once per distinct content: the `content:` key of a file only names
+/// the blob holding it, keyed by its hash and counted by reference. The copies of a tree are
+/// stored once, and uploading the copy of a stored content only writes the pointer.
+///
+/// A blob uploaded by an instance dying before pointing a file to it is never deleted.
+#[derive(Debug, Clone)]
+pub struct DedupContentStore {
+    client: RedisClient,
+    namespace: Namespace,
+}
+
+impl DedupContentStore {
+    pub fn new(client: RedisClient, namespace: Namespace) -> DedupContentStore {
+        DedupContentStore { client, namespace }
+    }
+
+    fn to_content_key(&self, path: &str) -> String {
+        self.namespace
+            .key(&format!("{}{}", CONTENT_KEY_PREFIX, path))
+    }
+
+    /// Name of the blob key of a compressed content, without the namespace
+    fn to_blob_key_name(compressed_content: &[u8]) -> Result<String, anyhow::Error> {
+        let content = LocalFSStore::decompress(compressed_content)?;
+        Ok(format!(
+            "{}{}",
+            BLOB_KEY_PREFIX,
+            blake3::hash(&content).to_hex()
+        ))
+    }
+
+    fn is_pointer(stored: &[u8]) -> bool {
+        stored.starts_with(BLOB_KEY_PREFIX.as_bytes())
+    }
+
+    /// Every blob, with its size and the number of files pointing to it
+    pub fn blobs(&self) -> Result<Vec<BlobUsage>, anyhow::Error> {
+        let references: HashMap<String, u64> = self
+            .client
+            .hgetall(&self.namespace.key(BLOB_REFS_KEY))
+            .context("unable to get the blob references from redis server")?
+            .into_iter()
+            .map(|(blob_key_name, references)| {
+                let references = String::from_utf8_lossy(&references).parse().unwrap_or(0);
+                (blob_key_name, references)
+            })
+            .collect();
+        let blob_keys = self
+            .client
+            .scan_match(&self.namespace.key(&format!("{}*", BLOB_KEY_PREFIX)))
+            .context("unable to list the blobs")?;
+        let mut blobs = Vec::with_capacity(blob_keys.len());
+        for blob_key in blob_keys {
+            let name = match self.namespace.strip(&blob_key) {
+                None => continue,
+                Some(name) => name.to_string(),
+            };
+            blobs.push(BlobUsage {
+                size: self.client.strlen(&blob_key)?,
+                references: references.get(&name).copied().unwrap_or(0),
+                name,
+            });
+        }
+        Ok(blobs)
+    }
+
+    fn link(&self, path: &str, blob_key_name: &str) -> Result<bool, anyhow::Error> {
+        let is_linked = self
+            .client
+            .eval(
+                LINK_SCRIPT,
+                &[
+                    &self.to_content_key(path),
+                    &self.namespace.key(BLOB_REFS_KEY),
+                    &self.namespace.key(blob_key_name),
+                ],
+                &[blob_key_name.to_string(), self.namespace.key("")],
+            )
+            .context("unable to point the content key to its blob")?
+            != -1;
+        Ok(is_linked)
+    }
+}
+
+impl ContentStore for DedupContentStore {
+    fn backend_name(&self) -> &'static str {
+        "redis-dedup"
+    }
+
+    fn set_content(&self, path: &str, content: &[u8]) -> Result<(), anyhow::Error> {
+        let blob_key_name = DedupContentStore::to_blob_key_name(content)?;
+        for _ in 0..MAX_ATTEMPTS {
+            if self.link(path, &blob_key_name)? {
+                return Ok(());
+            }
+            debug!(
+                "[dedup_content_store] uploading {} of {}",
+                blob_key_name, path
+            );
+            self.client
+                .set_if_not_exists(&self.namespace.key(&blob_key_name), content)
+                .context("unable to write the blob to redis server")?;
+        }
+        bail!(
+            "the blob {} of {} kept being deleted while written",
+            blob_key_name,
+            path
+        )
+    }
+
+    fn get_content(&self, path: &str) -> Result<Option<Vec<u8>>, anyhow::Error> {
+        for _ in 0..MAX_ATTEMPTS {
+            let blob_key_name = match self
+                .client
+                .get_if_exists(&self.to_content_key(path))
+                .context("unable to read the blob pointer from redis server")?
+            {
+                None => return Ok(None),
+                // stored before the deduplication
+                Some(stored) if !DedupContentStore::is_pointer(&stored) => return Ok(Some(stored)),
+                Some(pointer) => String::from_utf8_lossy(&pointer).into_owned(),
+            };
+            if let Some(content) = self
+                .client
+                .get_if_exists(&self.namespace.key(&blob_key_name))
+                .context("unable to read compressed file content from redis server")?
+            {
+                return Ok(Some(content));
+            }
+            // the file was written or removed meanwhile, and was the last one on this blob
+            debug!(
+                "[dedup_content_store] {} of {} deleted while read",
+                blob_key_name, path
+            );
+        }
+        bail!("the blob of {} kept being deleted while read", path)
+    }
+
+    /// Only the pointer moves, the blob stays the same
+    fn rename_content(&self, old_path: &str, new_path: &str) -> Result<(), anyhow::Error> {
+        self.client
+            .eval(
+                RENAME_SCRIPT,
+                &[
+                    &self.to_content_key(old_path),
+                    &self.to_content_key(new_path),
+                    &self.namespace.key(BLOB_REFS_KEY),
+                ],
+                &[self.namespace.key("")],
+            )
+            .context("unable to rename the blob pointer")?;
+        Ok(())
+    }
+
+    fn remove_content(&self, path: &str) -> Result<(), anyhow::Error> {
+        self.client
+            .eval(
+                REMOVE_SCRIPT,
+                &[
+                    &self.to_content_key(path),
+                    &self.namespace.key(BLOB_REFS_KEY),
+                ],
+                &[self.namespace.key("")],
+            )
+            .context("unable to remove the blob pointer")?;
+        Ok(())
+    }
+
+    fn content_size(&self, path: &str) -> Result<u64, anyhow::Error> {
+        let content_key = self.to_content_key(path);
+        let stored_size = self.client.strlen(&content_key)?;
+        // only the pointers are worth reading
+        if stored_size != POINTER_SIZE {
+            return Ok(stored_size);
+        }
+        match self.client.get_if_exists(&content_key)? {
+            Some(pointer) if DedupContentStore::is_pointer(&pointer) => self
+                .client
+                .strlen(&self.namespace.key(&String::from_utf8_lossy(&pointer))),
+            _ => Ok(stored_size),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::local_fs_store::Compression;
+
+    #[test]
+    fn keys_the_blobs_by_the_uncompressed_content() {
+        let content = b"the same content, compressed by two peers";
+        let blob_key_names: Vec<String> = [Compression::Snappy, Compression::Zstd]
+            .iter()
+            .map(|compression| {
+                let compressed_content = LocalFSStore::compress_with(content, *compression);
+                DedupContentStore::to_blob_key_name(&compressed_content).unwrap()
+            })
+            .collect();
+        assert_eq!(blob_key_names[0], blob_key_names[1]);
+        assert_eq!(blob_key_names[0].len() as u64, POINTER_SIZE);
+    }
+}
@@ -0,0 +1,164 @@
+use crate::store::local_fs_store::LocalFSStore;
+use crate::store::sync_store::SyncStore;
+use anyhow::{anyhow, Context};
+use log::debug;
+use std::path::{Path, PathBuf};
+
+/// Mirror the watched files into another directory (local or network mounted), uncompressed.
+/// The files of all the watched roots are mirrored at the root of the target directory.
+#[derive(Debug, Clone)]
+pub struct DirStore {
+    target: PathBuf,
+    roots: Vec<PathBuf>,
+}
+
+impl DirStore {
+    pub fn new(target: PathBuf, roots: &[PathBuf]) -> DirStore {
+        let roots = roots
+            .iter()
+            .map(|root| root.canonicalize().unwrap_or_else(|_| root.clone()))
+            .collect();
+        DirStore { target, roots }
+    }
+
+    fn to_mirror_path(&self, path: &Path) -> Result<PathBuf, anyhow::Error> {
+        let root = self
+            .roots
+            .iter()
+            .find(|root| path.starts_with(root))
+            .ok_or_else(|| anyhow!("{} is not in a watched path", path.display()))?;
+        let relative_path = path.strip_prefix(root).expect("path starts with its root");
+
+        // when a single file is watched, it is its own root
+        if relative_path.as_os_str().is_empty() {
+            let file_name = root
+                .file_name()
+                .ok_or_else(|| anyhow!("cannot mirror {}", root.display()))?;
+            Ok(self.target.join(file_name))
+        } else {
+            Ok(self.target.join(relative_path))
+        }
+    }
+
+    fn write_mirror_file(&self, path: &Path, content: &[u8]) -> Result<(), anyhow::Error> {
+        let mirror_path = self.to_mirror_path(path)?;
+        let contents = LocalFSStore::decompress(content)?;
+        LocalFSStore::write_file(&mirror_path, &contents)
+    }
+
+    fn list_mirror_files(directory: &Path, files: &mut Vec<PathBuf>) -> Result<(), anyhow::Error> {
+        let entries = std::fs::read_dir(directory)
+            .with_context(|| format!("unable to list directory {}", directory.display()))?;
+        for entry in entries {
+            let path = entry.context("unable to read directory entry")?.path();
+            if path.is_dir() {
+                DirStore::list_mirror_files(&path, files)?;
+            } else {
+                files.push(path);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl SyncStore for DirStore {
+    fn new_file(
+        &self,
+        _emitter_id: u64,
+        path: PathBuf,
+        content: &[u8],
+        _hash: u64,
+    ) -> Result<(), anyhow::Error> {
+        self.write_mirror_file(&path, content)
+    }
+
+    fn modified_file(
+        &self,
+        _emitter_id: u64,
+        path: PathBuf,
+        content: &[u8],
+        _hash: u64,
+    ) -> Result<(), anyhow::Error> {
+        self.write_mirror_file(&path, content)
+    }
+
+    fn renamed_file(
+        &self,
+        _emitter_id: u64,
+        old_path: PathBuf,
+        new_path: PathBuf,
+    ) -> Result<(), anyhow::Error> {
+        LocalFSStore::rename_file(
+            &self.to_mirror_path(&old_path)?,
+            &self.to_mirror_path(&new_path)?,
+        )
+    }
+
+    fn removed_file(&self, _emitter_id: u64, path: PathBuf) -> Result<(), anyhow::Error> {
+        LocalFSStore::remove_file(&self.to_mirror_path(&path)?)
+    }
+
+    fn request_missing_content(
+        &self,
+        _emitter_id: u64,
+        path: PathBuf,
+    ) -> Result<(), anyhow::Error> {
+        debug!(
+            "[dir_store] no peer to request the missing content of {} from",
+            path.display()
+        );
+        Ok(())
+    }
+
+    fn reject_content(
+        &self,
+        _emitter_id: u64,
+        path: PathBuf,
+        _reason: String,
+    ) -> Result<(), anyhow::Error> {
+        debug!(
+            "[dir_store] no peer to request the content of {} from",
+            path.display()
+        );
+        Ok(())
+    }
+
+    /// The mirrored files, as paths of the first watched root
+    fn get_all_remote_files(&self) -> Result<Vec<String>, anyhow::Error> {
+        let root = match self.roots.first() {
+            None => return Ok(Vec::new()),
+            Some(root) => root,
+        };
+        let mut mirror_files = Vec::new();
+        if self.target.exists() {
+            DirStore::list_mirror_files(&self.target, &mut mirror_files)?;
+        }
+        Ok(mirror_files
+            .iter()
+            .filter_map(|mirror_file| mirror_file.strip_prefix(&self.target).ok())
+            .map(|relative_path| root.join(relative_path).to_string_lossy().into_owned())
+            .collect())
+    }
+
+    fn get_remote_file_content(&self, path: &Path) -> Result<Option<Vec<u8>>, anyhow::Error> {
+        let mirror_path = self.to_mirror_path(path)?;
+        if !mirror_path.exists() {
+            return Ok(None);
+        }
+        let contents = std::fs::read(&mirror_path)
+            .with_context(|| format!("unable to read mirror file {}", mirror_path.display()))?;
+        Ok(Some(contents))
+    }
+
+    fn get_remote_file_hash(&self, path: &Path) -> Result<u64, anyhow::Error> {
+        LocalFSStore::local_hash(&self.to_mirror_path(path)?)
+    }
+
+    /// Mirror files are stored uncompressed
+    fn get_remote_file_compressed_size(&self, path: &Path) -> Result<u64, anyhow::Error> {
+        let mirror_path = self.to_mirror_path(path)?;
+        let metadata = std::fs::metadata(&mirror_path)
+            .with_context(|| format!("unable to stat mirror file {}", mirror_path.display()))?;
+        Ok(metadata.len())
+    }
+}
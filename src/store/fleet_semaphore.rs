@@ -0,0 +1,168 @@
+use crate::client::redis_client::RedisClient;
+use crate::store::namespace::Namespace;
+use anyhow::Context;
+use log::{debug, error, info};
+use rand::Rng;
+use std::cell::Cell;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Semaphore of the operations scanning the whole store: initial synchronization and reconciliations
+pub const BULK_OPERATIONS_SEMAPHORE: &str = "bulk-operations";
+
+/// A crashed holder releases its permit after this delay
+const PERMIT_LEASE: Duration = Duration::from_secs(600);
+/// Holders refresh their lease when it is half expired
+const PERMIT_RENEWAL_INTERVAL: Duration = Duration::from_secs(300);
+/// Delay between two acquisition attempts, randomized between the half and the full value
+const ACQUIRE_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Drop the expired holders, then take a permit if one is free.
+/// KEYS[1]: sorted set of holders, scored by acquisition (or renewal) time
+/// ARGV: now (ms), lease (ms), limit, holder
+const TRY_ACQUIRE_SCRIPT: &str = r"
+redis.call('ZREMRANGEBYSCORE', KEYS[1], '-inf', tonumber(ARGV[1]) - tonumber(ARGV[2]))
+if redis.call('ZSCORE', KEYS[1], ARGV[4]) then
+    redis.call('ZADD', KEYS[1], ARGV[1], ARGV[4])
+    return 1
+end
+if redis.call('ZCARD', KEYS[1]) < tonumber(ARGV[3]) then
+    redis.call('ZADD', KEYS[1], ARGV[1], ARGV[4])
+    return 1
+end
+return 0
+";
+
+/// Distributed semaphore limiting how many instances of the fleet run a heavy operation
+/// at the same time, so that they do not overload the shared Redis.
+#[derive(Debug, Clone)]
+pub struct FleetSemaphore {
+    client: RedisClient,
+    namespace: Namespace,
+    name: String,
+    limit: u32,
+    holder_id: u64,
+}
+
+/// Released when dropped. Long operations must call `keep_alive` regularly to keep it.
+pub struct FleetPermit<'a> {
+    semaphore: &'a FleetSemaphore,
+    last_renewal: Cell<Instant>,
+}
+
+impl FleetSemaphore {
+    pub fn new(
+        client: RedisClient,
+        namespace: Namespace,
+        name: &str,
+        limit: u32,
+        holder_id: u64,
+    ) -> FleetSemaphore {
+        FleetSemaphore {
+            client,
+            namespace,
+            name: name.to_string(),
+            limit,
+            holder_id,
+        }
+    }
+
+    /// Wait until a permit is free
+    pub fn acquire(&self) -> Result<FleetPermit<'_>, anyhow::Error> {
+        let mut is_waiting = false;
+        while !self.try_acquire()? {
+            if !is_waiting {
+                info!(
+                    "{} instances are already running {}. Waiting for our turn...",
+                    self.limit, self.name
+                );
+                is_waiting = true;
+            }
+            std::thread::sleep(
+                ACQUIRE_POLL_INTERVAL.mul_f64(rand::thread_rng().gen_range(0.5, 1.0)),
+            );
+        }
+        debug!("[fleet_semaphore] {} permit acquired", self.name);
+        Ok(FleetPermit {
+            semaphore: self,
+            last_renewal: Cell::new(Instant::now()),
+        })
+    }
+
+    /// Take a free permit, or renew the permit if we already hold it
+    fn try_acquire(&self) -> Result<bool, anyhow::Error> {
+        let now_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("system time is before the unix epoch")
+            .as_millis();
+        let acquired = self
+            .client
+            .eval(
+                TRY_ACQUIRE_SCRIPT,
+                &[&self.to_semaphore_key()],
+                &[
+                    now_ms.to_string(),
+                    PERMIT_LEASE.as_millis().to_string(),
+                    self.limit.to_string(),
+                    self.holder_id.to_string(),
+                ],
+            )
+            .with_context(|| {
+                format!(
+                    "unable to send the redis command to acquire the {} semaphore",
+                    self.name
+                )
+            })?;
+        Ok(acquired == 1)
+    }
+
+    fn release(&self) -> Result<(), anyhow::Error> {
+        self.client
+            .zrem(&self.to_semaphore_key(), &self.holder_id.to_string())
+            .with_context(|| {
+                format!(
+                    "unable to send the redis command to release the {} semaphore",
+                    self.name
+                )
+            })
+    }
+
+    fn to_semaphore_key(&self) -> String {
+        self.namespace.key(&format!("semaphore:{}", self.name))
+    }
+}
+
+impl FleetPermit<'_> {
+    /// Renew the lease of the permit when it is half expired. Cheap to call often.
+    pub fn keep_alive(&self) {
+        if self.last_renewal.get().elapsed() < PERMIT_RENEWAL_INTERVAL {
+            return;
+        }
+        debug!("[fleet_semaphore] renewing {} permit", self.semaphore.name);
+        self.last_renewal.set(Instant::now());
+        match self.semaphore.try_acquire() {
+            Ok(true) => (),
+            // the lease expired and the permit was given to another instance: finish anyway
+            Ok(false) => info!(
+                "lost the {} permit, the operation goes on without it",
+                self.semaphore.name
+            ),
+            Err(error) => error!(
+                "unable to renew the {} permit: {:?}",
+                self.semaphore.name, error
+            ),
+        }
+    }
+}
+
+impl Drop for FleetPermit<'_> {
+    fn drop(&mut self) {
+        debug!("[fleet_semaphore] releasing {} permit", self.semaphore.name);
+        // the lease expiration will release it anyway
+        if let Err(error) = self.semaphore.release() {
+            error!(
+                "unable to release the {} semaphore: {:?}",
+                self.semaphore.name, error
+            );
+        }
+    }
+}
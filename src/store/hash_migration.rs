@@ -0,0 +1,202 @@
+use crate::client::redis_client::RedisClient;
+use crate::store::content_migration::MigrationPolicy;
+use crate::store::fleet_semaphore::FleetPermit;
+use crate::store::local_fs_store::LocalFSStore;
+use crate::store::namespace::Namespace;
+use crate::store::redis_store::{RedisStore, HASH_KEY_PREFIX};
+use crate::store::sync_store::SyncStore;
+use anyhow::{bail, Context};
+use log::{error, info};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Name of the semaphore letting one migration of the hashes run at a time in the namespace
+pub const HASH_MIGRATION_SEMAPHORE: &str = "hash-migration";
+/// Delay between two progress reports
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Default)]
+struct Progress {
+    checked: AtomicU64,
+    rewritten: AtomicU64,
+    failed: AtomicU64,
+}
+
+impl Progress {
+    fn report(&self) -> HashMigrationReport {
+        HashMigrationReport {
+            checked: self.checked.load(Ordering::SeqCst),
+            rewritten: self.rewritten.load(Ordering::SeqCst),
+            failed: self.failed.load(Ordering::SeqCst),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct HashMigrationReport {
+    pub checked: u64,
+    pub rewritten: u64,
+    pub failed: u64,
+}
+
+/// Computes again the hash of every file from its stored content, with the current algorithm,
+/// and writes it in hex. The hashes already matching are skipped, so that running the
+/// migration again resumes it. The instances still running with the previous algorithm write
+/// the hashes they change meanwhile with it: these are left to the next run.
+pub struct HashMigration {
+    client: RedisClient,
+    namespace: Namespace,
+    store: RedisStore,
+    policy: MigrationPolicy,
+}
+
+impl HashMigration {
+    pub fn new(
+        client: RedisClient,
+        namespace: Namespace,
+        store: RedisStore,
+        policy: MigrationPolicy,
+    ) -> HashMigration {
+        HashMigration {
+            client,
+            namespace,
+            store,
+            policy,
+        }
+    }
+
+    /// Migrate the hashes of these paths, reporting the progress regularly
+    pub fn run(
+        &self,
+        paths: &[String],
+        permit: &FleetPermit<'_>,
+    ) -> Result<HashMigrationReport, anyhow::Error> {
+        let progress = Progress::default();
+        let next_slot = Mutex::new(Instant::now());
+        let (sender, receiver) = crossbeam_channel::bounded::<&str>(self.policy.parallel);
+        std::thread::scope(|scope| -> Result<(), anyhow::Error> {
+            for _ in 0..self.policy.parallel.max(1) {
+                let receiver = receiver.clone();
+                let (progress, next_slot) = (&progress, &next_slot);
+                std::thread::Builder::new()
+                    .name(String::from("hash migration"))
+                    .spawn_scoped(scope, move || {
+                        for path in receiver {
+                            self.wait_for_slot(next_slot);
+                            self.migrate(path, progress);
+                        }
+                    })
+                    .context("unable to create hash migration thread")?;
+            }
+            drop(receiver);
+            let mut last_report = Instant::now();
+            for path in paths {
+                permit.keep_alive();
+                if sender.send(path).is_err() {
+                    break;
+                }
+                if last_report.elapsed() >= PROGRESS_INTERVAL {
+                    let report = progress.report();
+                    info!(
+                        "{}/{} hashes checked, {} rewritten, {} failed",
+                        report.checked,
+                        paths.len(),
+                        report.rewritten,
+                        report.failed
+                    );
+                    last_report = Instant::now();
+                }
+            }
+            // the workers stop once the paths sent are done
+            drop(sender);
+            Ok(())
+        })?;
+        Ok(progress.report())
+    }
+
+    fn wait_for_slot(&self, next_slot: &Mutex<Instant>) {
+        if self.policy.max_rate == 0 {
+            return;
+        }
+        let slot = {
+            let mut next_slot = next_slot.lock().expect("migration rate lock poisoned");
+            let slot = (*next_slot).max(Instant::now());
+            *next_slot = slot + Duration::from_secs(1) / self.policy.max_rate;
+            slot
+        };
+        let now = Instant::now();
+        if slot > now {
+            std::thread::sleep(slot - now);
+        }
+    }
+
+    fn migrate(&self, path: &str, progress: &Progress) {
+        progress.checked.fetch_add(1, Ordering::SeqCst);
+        match self.rewrite(path) {
+            Ok(false) => (),
+            Ok(true) => {
+                progress.rewritten.fetch_add(1, Ordering::SeqCst);
+            }
+            Err(error) => {
+                progress.failed.fetch_add(1, Ordering::SeqCst);
+                error!("unable to migrate the hash of {}: {:?}", path, error);
+            }
+        }
+    }
+
+    /// Returns whether the hash was rewritten
+    fn rewrite(&self, path: &str) -> Result<bool, anyhow::Error> {
+        let key = self.namespace.key(&format!("{}{}", HASH_KEY_PREFIX, path));
+        let hash = match self.client.get_if_exists(&key)? {
+            // removed
+            None => return Ok(false),
+            Some(hash) => hash,
+        };
+        let content = self
+            .store
+            .get_remote_file_content(Path::new(path))?
+            .context("the file has no content to hash")?;
+        let migrated_hash = match migrated_hash(&hash, &content) {
+            None => return Ok(false),
+            Some(migrated_hash) => migrated_hash,
+        };
+        if !self
+            .client
+            .compare_and_set(&key, &hash, migrated_hash.as_bytes())?
+        {
+            bail!("changed during its migration by an instance using the previous algorithm");
+        }
+        Ok(true)
+    }
+}
+
+/// The hash to write for this content, None when the stored one is already migrated
+fn migrated_hash(stored_hash: &[u8], content: &[u8]) -> Option<String> {
+    let migrated_hash = LocalFSStore::format_hash(LocalFSStore::hash_content(content));
+    (stored_hash != migrated_hash.as_bytes()).then_some(migrated_hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skips_the_migrated_hashes() {
+        let content = b"content";
+        let hash = LocalFSStore::format_hash(LocalFSStore::hash_content(content));
+        assert_eq!(migrated_hash(hash.as_bytes(), content), None);
+        // decimal, as written by the versions before BLAKE3
+        assert_eq!(
+            migrated_hash(b"12345678901234567890", content),
+            Some(hash.clone())
+        );
+        assert_eq!(
+            migrated_hash(hash.as_bytes(), b"another content"),
+            Some(LocalFSStore::format_hash(LocalFSStore::hash_content(
+                b"another content"
+            )))
+        );
+    }
+}
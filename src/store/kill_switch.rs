@@ -0,0 +1,260 @@
+use crate::client::redis_client::RedisClient;
+use crate::store::namespace::Namespace;
+use crate::store::sync_store::SyncStore;
+use anyhow::Context;
+use log::{debug, error, info};
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// Set of the names of the instances disabled in the namespace
+const DISABLED_INSTANCES_KEY: &str = "meta:disabled-instances";
+/// Delay between two checks of whether this instance is disabled
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The instances disabled in the namespace by the `disable` command. They are named by their
+/// --owner-name, pod name or hostname, so that a restarted instance stays disabled.
+#[derive(Debug, Clone)]
+pub struct DisabledInstances {
+    client: RedisClient,
+    namespace: Namespace,
+}
+
+impl DisabledInstances {
+    pub fn new(client: RedisClient, namespace: Namespace) -> DisabledInstances {
+        DisabledInstances { client, namespace }
+    }
+
+    pub fn disable(&self, instance_name: &str) -> Result<(), anyhow::Error> {
+        self.client
+            .sadd(&self.namespace.key(DISABLED_INSTANCES_KEY), instance_name)
+            .context("unable to disable the instance")
+    }
+
+    /// Returns false when the instance was not disabled
+    pub fn enable(&self, instance_name: &str) -> Result<bool, anyhow::Error> {
+        self.client
+            .srem(&self.namespace.key(DISABLED_INSTANCES_KEY), instance_name)
+            .context("unable to enable the instance")
+    }
+
+    pub fn is_disabled(&self, instance_name: &str) -> Result<bool, anyhow::Error> {
+        self.client
+            .sismember(&self.namespace.key(DISABLED_INSTANCES_KEY), instance_name)
+            .context("unable to check whether the instance is disabled")
+    }
+
+    pub fn list(&self) -> Result<BTreeSet<String>, anyhow::Error> {
+        Ok(self
+            .client
+            .smembers(&self.namespace.key(DISABLED_INSTANCES_KEY))
+            .context("unable to list the disabled instances")?
+            .into_iter()
+            .collect())
+    }
+}
+
+/// Whether the fleet disabled this instance, as one machine may corrupt the tree: a disabled
+/// instance neither publishes its changes nor applies the remote ones. Cloning it gives a handle
+/// on the same state, so that both handlers share it. Never disabled by default.
+#[derive(Debug, Clone, Default)]
+pub struct KillSwitch {
+    is_disabled: Arc<AtomicBool>,
+    /// Enabled again since the last `take_reenabled`
+    is_reenabled: Arc<AtomicBool>,
+}
+
+impl KillSwitch {
+    pub fn new() -> KillSwitch {
+        KillSwitch::default()
+    }
+
+    pub fn is_disabled(&self) -> bool {
+        self.is_disabled.load(Ordering::SeqCst)
+    }
+
+    /// Whether the instance was enabled again since the last call, and missed changes meanwhile
+    pub fn take_reenabled(&self) -> bool {
+        self.is_reenabled.swap(false, Ordering::SeqCst)
+    }
+
+    fn set_disabled(&self, instance_name: &str, is_disabled: bool) {
+        if self.is_disabled.swap(is_disabled, Ordering::SeqCst) == is_disabled {
+            return;
+        }
+        if is_disabled {
+            error!(
+                "this instance ({}) is disabled by the fleet: the local changes are not published and the remote changes are not applied until it is enabled again",
+                instance_name
+            );
+        } else {
+            info!("this instance is enabled again by the fleet, synchronizing");
+            self.is_reenabled.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// Check whether this instance is disabled, then regularly until the process exits. The
+    /// instance stays as it is while the check fails.
+    pub fn watch(
+        self,
+        instances: DisabledInstances,
+        instance_name: String,
+    ) -> Result<JoinHandle<()>, anyhow::Error> {
+        match instances.is_disabled(&instance_name) {
+            Ok(is_disabled) => self.set_disabled(&instance_name, is_disabled),
+            Err(error) => error!("{:?}", error),
+        }
+        let handle = std::thread::Builder::new()
+            .name(String::from("kill switch watcher"))
+            .spawn(move || loop {
+                std::thread::sleep(CHECK_INTERVAL);
+                match instances.is_disabled(&instance_name) {
+                    Ok(is_disabled) => self.set_disabled(&instance_name, is_disabled),
+                    Err(error) => error!("{:?}", error),
+                }
+            })
+            .context("unable to create kill switch watcher thread")?;
+        Ok(handle)
+    }
+}
+
+/// Drops the local changes while the instance is disabled
+#[derive(Debug, Clone)]
+pub struct KillSwitchStore<S: SyncStore + Clone> {
+    store: S,
+    kill_switch: KillSwitch,
+}
+
+impl<S: SyncStore + Clone> KillSwitchStore<S> {
+    pub fn new(store: S, kill_switch: KillSwitch) -> KillSwitchStore<S> {
+        KillSwitchStore { store, kill_switch }
+    }
+
+    fn is_dropped(&self, path: &Path) -> bool {
+        let is_disabled = self.kill_switch.is_disabled();
+        if is_disabled {
+            debug!(
+                "[kill_switch] the instance is disabled, not publishing the change of {}",
+                path.display()
+            );
+        }
+        is_disabled
+    }
+}
+
+impl<S: SyncStore + Clone> SyncStore for KillSwitchStore<S> {
+    fn new_file(
+        &self,
+        emitter_id: u64,
+        path: PathBuf,
+        content: &[u8],
+        hash: u64,
+    ) -> Result<(), anyhow::Error> {
+        if self.is_dropped(&path) {
+            return Ok(());
+        }
+        self.store.new_file(emitter_id, path, content, hash)
+    }
+
+    fn modified_file(
+        &self,
+        emitter_id: u64,
+        path: PathBuf,
+        content: &[u8],
+        hash: u64,
+    ) -> Result<(), anyhow::Error> {
+        if self.is_dropped(&path) {
+            return Ok(());
+        }
+        self.store.modified_file(emitter_id, path, content, hash)
+    }
+
+    fn renamed_file(
+        &self,
+        emitter_id: u64,
+        old_path: PathBuf,
+        new_path: PathBuf,
+    ) -> Result<(), anyhow::Error> {
+        if self.is_dropped(&old_path) {
+            return Ok(());
+        }
+        self.store.renamed_file(emitter_id, old_path, new_path)
+    }
+
+    fn removed_file(&self, emitter_id: u64, path: PathBuf) -> Result<(), anyhow::Error> {
+        if self.is_dropped(&path) {
+            return Ok(());
+        }
+        self.store.removed_file(emitter_id, path)
+    }
+
+    fn request_missing_content(&self, emitter_id: u64, path: PathBuf) -> Result<(), anyhow::Error> {
+        if self.is_dropped(&path) {
+            return Ok(());
+        }
+        self.store.request_missing_content(emitter_id, path)
+    }
+
+    fn reject_content(
+        &self,
+        emitter_id: u64,
+        path: PathBuf,
+        reason: String,
+    ) -> Result<(), anyhow::Error> {
+        if self.is_dropped(&path) {
+            return Ok(());
+        }
+        self.store.reject_content(emitter_id, path, reason)
+    }
+
+    fn claim_upload_again(&self, path: &Path, claimer_id: u64) -> Result<bool, anyhow::Error> {
+        // the upload would be dropped: leave it to a peer
+        if self.is_dropped(path) {
+            return Ok(false);
+        }
+        self.store.claim_upload_again(path, claimer_id)
+    }
+
+    fn take_own_removal(&self, path: &Path) -> bool {
+        self.store.take_own_removal(path)
+    }
+
+    fn get_changed_since(&self, since: u64) -> Result<Vec<(PathBuf, u64)>, anyhow::Error> {
+        self.store.get_changed_since(since)
+    }
+
+    fn get_rollout_approval(&self) -> Result<Option<u64>, anyhow::Error> {
+        self.store.get_rollout_approval()
+    }
+
+    fn get_all_remote_files(&self) -> Result<Vec<String>, anyhow::Error> {
+        self.store.get_all_remote_files()
+    }
+
+    fn get_generation(&self) -> Result<Option<u64>, anyhow::Error> {
+        self.store.get_generation()
+    }
+
+    fn get_remote_file_content(&self, path: &Path) -> Result<Option<Vec<u8>>, anyhow::Error> {
+        self.store.get_remote_file_content(path)
+    }
+
+    fn get_remote_file_hash(&self, path: &Path) -> Result<u64, anyhow::Error> {
+        self.store.get_remote_file_hash(path)
+    }
+
+    fn invalidate_cached_hash(&self, path: &Path) {
+        self.store.invalidate_cached_hash(path)
+    }
+
+    fn invalidate_all_cached_hashes(&self) {
+        self.store.invalidate_all_cached_hashes()
+    }
+
+    fn get_remote_file_compressed_size(&self, path: &Path) -> Result<u64, anyhow::Error> {
+        self.store.get_remote_file_compressed_size(path)
+    }
+}
@@ -1,16 +1,154 @@
-use anyhow::Context;
-use log::debug;
-use std::collections::hash_map::DefaultHasher;
+use anyhow::{bail, Context};
+use log::{debug, info, warn};
 use std::fs::File;
-use std::hash::Hasher;
-use std::path::Path;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicI32, AtomicU8, Ordering};
+
+/// Extension appended to the placeholders of the remote files excluded from applies
+pub const PLACEHOLDER_EXTENSION: &str = "fssync-placeholder";
+/// Extension appended to the files written next to a file open in another program, then
+/// renamed over it
+pub const REPLACEMENT_EXTENSION: &str = "fssync-replacement";
+/// Windows errors of a file open in another program which does not share it
+#[cfg(windows)]
+const ERROR_SHARING_VIOLATION: i32 = 32;
+#[cfg(windows)]
+const ERROR_LOCK_VIOLATION: i32 = 33;
+/// First bytes of the compressed contents, telling the codecs apart
+const SNAPPY_FRAME_MAGIC: &[u8] = b"\xff\x06\x00\x00sNaPpY";
+const ZSTD_FRAME_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const LZ4_FRAME_MAGIC: &[u8] = &[0x04, 0x22, 0x4d, 0x18];
+/// First bytes of the contents stored without compression, so that they are told apart from
+/// the contents written before the codecs were recorded, which are all snappy
+const UNCOMPRESSED_MAGIC: &[u8] = b"fssync-uncompressed\0";
+/// First bytes of the contents published for the symlinks, followed by their target. The NUL
+/// byte keeps the text files from being taken for links.
+const LINK_MAGIC: &[u8] = b"fssync-symlink\0";
+/// Hash of the contents recorded in the namespace metadata: the first 64 bits of their BLAKE3
+/// hash, which every build on every platform computes the same. In hex, it is the beginning
+/// of the hash printed by `b3sum`.
+pub const HASH_ALGORITHM: &str = "blake3-64";
+/// Hash of the namespaces created before BLAKE3, which depended on the version of Rust. Their
+/// hashes are recomputed by `migrate-hashes`.
+pub const LEGACY_HASH_ALGORITHM: &str = "std-default-hasher";
+
+/// Codec of the contents compressed by this instance, set from the command line or the
+/// namespace metadata
+static COMPRESSION: AtomicU8 = AtomicU8::new(Compression::Snappy as u8);
+/// Level of the zstd compression, 0 being the default level of zstd. The other codecs have none.
+static ZSTD_LEVEL: AtomicI32 = AtomicI32::new(0);
+/// The negative levels trade size for speed
+pub const ZSTD_LEVELS: std::ops::RangeInclusive<i32> = -7..=22;
+/// Symlink policy of this instance, set from the command line
+static SYMLINKS: AtomicU8 = AtomicU8::new(Symlinks::Follow as u8);
+
+/// How the contents are compressed. Every codec is read whatever the namespace uses, so that
+/// the contents can be migrated from one to the other while the instances run.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Compression {
+    #[default]
+    Snappy,
+    Zstd,
+    Lz4,
+    /// For the contents already compressed, like archives or media
+    Uncompressed,
+}
+
+impl Compression {
+    /// Name recorded in the namespace metadata
+    pub fn name(self) -> &'static str {
+        match self {
+            Compression::Snappy => "snappy",
+            Compression::Zstd => "zstd",
+            Compression::Lz4 => "lz4",
+            Compression::Uncompressed => "none",
+        }
+    }
+
+    /// None when the content is not compressed by a known codec
+    pub fn of(compressed_content: &[u8]) -> Option<Compression> {
+        if compressed_content.starts_with(SNAPPY_FRAME_MAGIC) {
+            Some(Compression::Snappy)
+        } else if compressed_content.starts_with(ZSTD_FRAME_MAGIC) {
+            Some(Compression::Zstd)
+        } else if compressed_content.starts_with(LZ4_FRAME_MAGIC) {
+            Some(Compression::Lz4)
+        } else if compressed_content.starts_with(UNCOMPRESSED_MAGIC) {
+            Some(Compression::Uncompressed)
+        } else {
+            None
+        }
+    }
+}
+
+impl std::str::FromStr for Compression {
+    type Err = anyhow::Error;
+
+    fn from_str(compression: &str) -> Result<Compression, anyhow::Error> {
+        match compression {
+            "snappy" => Ok(Compression::Snappy),
+            "zstd" => Ok(Compression::Zstd),
+            "lz4" => Ok(Compression::Lz4),
+            "none" => Ok(Compression::Uncompressed),
+            _ => bail!(
+                "compression must be zstd, lz4, snappy or none, got {}",
+                compression
+            ),
+        }
+    }
+}
+
+/// How the symlinks of the watched trees are synchronized. The instances sharing a store should
+/// use the same policy: the links copied by one are not applied by the others.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Symlinks {
+    /// Publish the files the links point to, as if they were in the tree
+    #[default]
+    Follow,
+    /// Never publish the links, nor the files under the linked directories, and never overwrite
+    /// the local links
+    Skip,
+    /// Publish the links themselves, applied as links by the peers. The files under the linked
+    /// directories are not published.
+    CopyLink,
+}
+
+impl std::str::FromStr for Symlinks {
+    type Err = anyhow::Error;
+
+    fn from_str(symlinks: &str) -> Result<Symlinks, anyhow::Error> {
+        match symlinks {
+            "follow" => Ok(Symlinks::Follow),
+            "skip" => Ok(Symlinks::Skip),
+            "copy-link" => Ok(Symlinks::CopyLink),
+            _ => bail!(
+                "symlinks must be follow, skip or copy-link, got {}",
+                symlinks
+            ),
+        }
+    }
+}
+
+#[cfg(windows)]
+fn is_sharing_violation_code(code: Option<i32>) -> bool {
+    matches!(
+        code,
+        Some(ERROR_SHARING_VIOLATION) | Some(ERROR_LOCK_VIOLATION)
+    )
+}
+
+/// Only Windows refuses to write the files open in other programs
+#[cfg(not(windows))]
+fn is_sharing_violation_code(_code: Option<i32>) -> bool {
+    false
+}
 
 pub struct LocalFSStore;
 
 impl LocalFSStore {
     pub fn remove_file(path: &Path) -> Result<(), anyhow::Error> {
         debug!("[local_fs_store] removing file {}", &path.display());
-        std::fs::remove_file(&path)
+        std::fs::remove_file(path)
             .with_context(|| format!("unable to remove file {}", &path.display()))
     }
 
@@ -21,8 +159,8 @@ impl LocalFSStore {
             &new.display()
         );
 
-        LocalFSStore::ensure_directory_exists(&new)?;
-        std::fs::rename(&old, &new).with_context(|| {
+        LocalFSStore::ensure_directory_exists(new)?;
+        std::fs::rename(old, new).with_context(|| {
             format!(
                 "unable to rename file from {} to {}",
                 &old.display(),
@@ -31,14 +169,31 @@ impl LocalFSStore {
         })
     }
 
-    pub fn write_file(path: &Path, contents: Vec<u8>) -> Result<(), anyhow::Error> {
+    pub fn write_file(path: &Path, contents: &[u8]) -> Result<(), anyhow::Error> {
         debug!("[local_fs_store] writing file {}", &path.display());
 
-        LocalFSStore::ensure_directory_exists(&path)?;
-        std::fs::write(&path, contents)
+        LocalFSStore::ensure_directory_exists(path)?;
+        std::fs::write(path, contents)
             .with_context(|| format!("unable to write on local fs the file {}", &path.display()))
     }
 
+    /// Whether the error comes from a file open in another program, on Windows. The operation
+    /// succeeds once the program releases the file.
+    pub fn is_sharing_violation(error: &anyhow::Error) -> bool {
+        error
+            .chain()
+            .filter_map(|cause| cause.downcast_ref::<std::io::Error>())
+            .any(|error| is_sharing_violation_code(error.raw_os_error()))
+    }
+
+    /// The file written next to `path`, then renamed over it
+    pub fn replacement_path(path: &Path) -> PathBuf {
+        let mut replacement = path.as_os_str().to_owned();
+        replacement.push(".");
+        replacement.push(REPLACEMENT_EXTENSION);
+        PathBuf::from(replacement)
+    }
+
     pub fn ensure_directory_exists(path: &Path) -> Result<(), anyhow::Error> {
         let parent_directory: &Path = path.parent().context("new file cannot be /")?;
         if parent_directory.exists() {
@@ -53,30 +208,441 @@ impl LocalFSStore {
         }
     }
 
+    /// Compress the contents with this codec from now on
+    pub fn set_compression(compression: Compression) {
+        COMPRESSION.store(compression as u8, Ordering::SeqCst);
+    }
+
+    /// Compress with this zstd level from now on
+    pub fn set_compression_level(level: i32) {
+        ZSTD_LEVEL.store(level, Ordering::SeqCst);
+    }
+
+    pub fn compression() -> Compression {
+        match COMPRESSION.load(Ordering::SeqCst) {
+            codec if codec == Compression::Zstd as u8 => Compression::Zstd,
+            codec if codec == Compression::Lz4 as u8 => Compression::Lz4,
+            codec if codec == Compression::Uncompressed as u8 => Compression::Uncompressed,
+            _ => Compression::Snappy,
+        }
+    }
+
+    /// Follow, skip or copy the symlinks from now on
+    pub fn set_symlinks(symlinks: Symlinks) {
+        SYMLINKS.store(symlinks as u8, Ordering::SeqCst);
+    }
+
+    pub fn symlinks() -> Symlinks {
+        match SYMLINKS.load(Ordering::SeqCst) {
+            policy if policy == Symlinks::Skip as u8 => Symlinks::Skip,
+            policy if policy == Symlinks::CopyLink as u8 => Symlinks::CopyLink,
+            _ => Symlinks::Follow,
+        }
+    }
+
+    pub fn is_symlink(path: &Path) -> bool {
+        std::fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_symlink())
+    }
+
+    /// Whether the path is a link whose own content is published, instead of the file it
+    /// points to
+    fn is_copied_link(path: &Path) -> bool {
+        LocalFSStore::symlinks() == Symlinks::CopyLink && LocalFSStore::is_symlink(path)
+    }
+
+    /// Whether the path holds a content to publish: a file, or a copied link wherever it points
+    pub fn is_file(path: &Path) -> bool {
+        LocalFSStore::is_copied_link(path) || path.is_file()
+    }
+
+    /// Whether the path is a directory: a copied link is not, even when it points to one
+    pub fn is_directory(path: &Path) -> bool {
+        !LocalFSStore::is_copied_link(path) && path.is_dir()
+    }
+
+    /// Whether the path exists: a copied link does, even when its target does not
+    pub fn exists(path: &Path) -> bool {
+        LocalFSStore::is_copied_link(path) || path.exists()
+    }
+
+    /// The content published for a copied link
+    fn link_content(path: &Path) -> Result<Vec<u8>, anyhow::Error> {
+        let target = std::fs::read_link(path)
+            .with_context(|| format!("unable to read the link {}", path.display()))?;
+        let target = target
+            .to_str()
+            .with_context(|| format!("the target of the link {} is not UTF-8", path.display()))?;
+        Ok([LINK_MAGIC, target.as_bytes()].concat())
+    }
+
+    /// The target of the link, when the content is the one of a copied link
+    pub fn link_target(content: &[u8]) -> Option<PathBuf> {
+        let target = content.strip_prefix(LINK_MAGIC)?;
+        std::str::from_utf8(target).ok().map(PathBuf::from)
+    }
+
+    /// Replace the file with a link to the target
+    pub fn write_link(path: &Path, target: &Path) -> Result<(), anyhow::Error> {
+        debug!(
+            "[local_fs_store] linking {} to {}",
+            path.display(),
+            target.display()
+        );
+
+        LocalFSStore::ensure_directory_exists(path)?;
+        if std::fs::symlink_metadata(path).is_ok() {
+            LocalFSStore::remove_file(path)?;
+        }
+        #[cfg(unix)]
+        let linked = std::os::unix::fs::symlink(target, path);
+        #[cfg(windows)]
+        let linked = {
+            // windows tells the links to the directories apart
+            let parent_directory = path.parent().unwrap_or_else(|| Path::new(""));
+            if parent_directory.join(target).is_dir() {
+                std::os::windows::fs::symlink_dir(target, path)
+            } else {
+                std::os::windows::fs::symlink_file(target, path)
+            }
+        };
+        #[cfg(not(any(unix, windows)))]
+        let linked: std::io::Result<()> = Err(std::io::ErrorKind::Unsupported.into());
+        linked.with_context(|| format!("unable to link {} to {}", path.display(), target.display()))
+    }
+
     pub fn local_file_content_compressed(path: &Path) -> Result<(Vec<u8>, u64), anyhow::Error> {
+        if LocalFSStore::is_copied_link(path) {
+            let content = LocalFSStore::link_content(path)?;
+            return Ok((
+                LocalFSStore::compress(&content),
+                LocalFSStore::hash_content(&content),
+            ));
+        }
         let mut contents: Vec<u8> = Vec::with_capacity(8196);
         {
-            let mut compressing_writer = snap::write::FrameEncoder::new(&mut contents);
             let mut file = File::open(path)
                 .with_context(|| format!("unable to open file {}", path.display()))?;
-
-            std::io::copy(&mut file, &mut compressing_writer)
-                .with_context(|| format!("unable to read file {}", path.display()))?;
+            match LocalFSStore::compression() {
+                Compression::Snappy => {
+                    let mut compressing_writer = snap::write::FrameEncoder::new(&mut contents);
+                    std::io::copy(&mut file, &mut compressing_writer)
+                        .with_context(|| format!("unable to read file {}", path.display()))?;
+                }
+                Compression::Zstd => zstd::stream::copy_encode(
+                    &mut file,
+                    &mut contents,
+                    ZSTD_LEVEL.load(Ordering::SeqCst),
+                )
+                .with_context(|| format!("unable to read file {}", path.display()))?,
+                Compression::Lz4 => {
+                    let mut compressing_writer = lz4_flex::frame::FrameEncoder::new(&mut contents);
+                    std::io::copy(&mut file, &mut compressing_writer)
+                        .with_context(|| format!("unable to read file {}", path.display()))?;
+                    compressing_writer
+                        .finish()
+                        .with_context(|| format!("unable to compress file {}", path.display()))?;
+                }
+                Compression::Uncompressed => {
+                    contents.extend_from_slice(UNCOMPRESSED_MAGIC);
+                    std::io::copy(&mut file, &mut contents)
+                        .with_context(|| format!("unable to read file {}", path.display()))?;
+                }
+            }
         }
         let hash = LocalFSStore::local_hash(path)?;
         Ok((contents, hash))
     }
 
+    pub fn compress(content: &[u8]) -> Vec<u8> {
+        LocalFSStore::compress_with(content, LocalFSStore::compression())
+    }
+
+    pub fn compress_with(content: &[u8], compression: Compression) -> Vec<u8> {
+        match compression {
+            Compression::Snappy => {
+                let mut compressed_content: Vec<u8> = Vec::with_capacity(content.len());
+                {
+                    let mut compressing_writer =
+                        snap::write::FrameEncoder::new(&mut compressed_content);
+                    std::io::Write::write_all(&mut compressing_writer, content)
+                        .expect("compression in memory should never fail");
+                }
+                compressed_content
+            }
+            Compression::Zstd => {
+                zstd::stream::encode_all(content, ZSTD_LEVEL.load(Ordering::SeqCst))
+                    .expect("compression in memory should never fail")
+            }
+            Compression::Lz4 => {
+                let mut compressing_writer =
+                    lz4_flex::frame::FrameEncoder::new(Vec::with_capacity(content.len()));
+                std::io::Write::write_all(&mut compressing_writer, content)
+                    .expect("compression in memory should never fail");
+                compressing_writer
+                    .finish()
+                    .expect("compression in memory should never fail")
+            }
+            Compression::Uncompressed => [UNCOMPRESSED_MAGIC, content].concat(),
+        }
+    }
+
+    /// Whatever the codec of the content
+    pub fn decompress(compressed_content: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+        match Compression::of(compressed_content) {
+            Some(Compression::Zstd) => {
+                return zstd::stream::decode_all(compressed_content)
+                    .context("error when decoding compressed content")
+            }
+            Some(Compression::Lz4) => {
+                let mut contents: Vec<u8> = Vec::with_capacity(8196);
+                let mut decompressing_reader =
+                    lz4_flex::frame::FrameDecoder::new(compressed_content);
+                std::io::copy(&mut decompressing_reader, &mut contents)
+                    .context("error when decoding compressed content")?;
+                return Ok(contents);
+            }
+            Some(Compression::Uncompressed) => {
+                return Ok(compressed_content[UNCOMPRESSED_MAGIC.len()..].to_vec())
+            }
+            // the contents written before the codecs were recorded are snappy
+            Some(Compression::Snappy) | None => (),
+        }
+        let mut contents: Vec<u8> = Vec::with_capacity(8196);
+        let mut decompressing_writer = snap::read::FrameDecoder::new(compressed_content);
+        std::io::copy(&mut decompressing_writer, &mut contents)
+            .context("error when decoding compressed content")?;
+        Ok(contents)
+    }
+
     pub fn local_hash(path: &Path) -> Result<u64, anyhow::Error> {
-        let mut hasher = DefaultHasher::default();
-        let contents = std::fs::read(&path).context("unable to read file for hashing")?;
-        hasher.write(&*contents);
-        Ok(hasher.finish())
+        if LocalFSStore::is_copied_link(path) {
+            return Ok(LocalFSStore::hash_content(&LocalFSStore::link_content(
+                path,
+            )?));
+        }
+        let mut hasher = blake3::Hasher::new();
+        let mut file = File::open(path).context("unable to read file for hashing")?;
+        std::io::copy(&mut file, &mut hasher).context("unable to read file for hashing")?;
+        Ok(LocalFSStore::truncate_hash(hasher.finalize()))
     }
 
     pub fn hash_content(content: &[u8]) -> u64 {
-        let mut hasher = DefaultHasher::default();
-        hasher.write(&*content);
-        hasher.finish()
+        LocalFSStore::truncate_hash(blake3::hash(content))
+    }
+
+    fn truncate_hash(hash: blake3::Hash) -> u64 {
+        let mut first_bytes = [0; 8];
+        first_bytes.copy_from_slice(&hash.as_bytes()[..8]);
+        u64::from_be_bytes(first_bytes)
+    }
+
+    /// The hash as stored in redis and shown to the user
+    pub fn format_hash(hash: u64) -> String {
+        format!("{:016x}", hash)
+    }
+
+    /// Only the hashes as formatted by `format_hash`: the decimal hashes of the previous
+    /// versions would be read as other hashes. The few of them having exactly 16 digits cannot
+    /// be told apart.
+    pub fn parse_hash(hash: &str) -> Result<u64, anyhow::Error> {
+        if hash.len() != 16 || !hash.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            bail!("invalid hash {:?}, expected 16 hexadecimal digits", hash);
+        }
+        u64::from_str_radix(hash, 16).with_context(|| format!("invalid hash {:?}", hash))
+    }
+
+    pub fn placeholder_path(path: &Path) -> PathBuf {
+        let mut placeholder = path.as_os_str().to_owned();
+        placeholder.push(".");
+        placeholder.push(PLACEHOLDER_EXTENSION);
+        PathBuf::from(placeholder)
+    }
+
+    pub fn is_placeholder(path: &Path) -> bool {
+        path.extension()
+            .is_some_and(|extension| extension == PLACEHOLDER_EXTENSION)
+    }
+
+    /// Write a small file telling that `path` exists remotely but is not applied locally
+    pub fn write_placeholder(
+        path: &Path,
+        hash: u64,
+        compressed_size: u64,
+    ) -> Result<(), anyhow::Error> {
+        let contents = format!(
+            "This file exists on the remote store but is excluded from applies on this machine.\n\
+             path: {}\n\
+             hash: {}\n\
+             compressed size: {} bytes\n",
+            path.display(),
+            LocalFSStore::format_hash(hash),
+            compressed_size
+        );
+        LocalFSStore::write_file(&LocalFSStore::placeholder_path(path), contents.as_bytes())
+    }
+
+    /// Remove the placeholder of `path`, if any
+    pub fn remove_placeholder(path: &Path) -> Result<(), anyhow::Error> {
+        let placeholder = LocalFSStore::placeholder_path(path);
+        if placeholder.exists() {
+            LocalFSStore::remove_file(&placeholder)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Where a file is applied in the shadow directory: its absolute path under the shadow, the
+    /// drive being a directory on Windows. None for the paths which would escape the shadow,
+    /// as the `..` written by a peer.
+    pub fn to_shadow(shadow: &Path, path: &Path) -> Option<PathBuf> {
+        let mut shadow_path = shadow.to_path_buf();
+        for component in path.components() {
+            match component {
+                Component::Normal(name) => shadow_path.push(name),
+                Component::RootDir | Component::CurDir => (),
+                Component::Prefix(prefix) => match prefix.kind() {
+                    std::path::Prefix::Disk(drive) | std::path::Prefix::VerbatimDisk(drive) => {
+                        shadow_path.push(String::from(drive as char))
+                    }
+                    // the network shares have no drive to map them to
+                    _ => return None,
+                },
+                Component::ParentDir => return None,
+            }
+        }
+        Some(shadow_path)
+    }
+
+    /// The real path of a file of the shadow directory, the reverse of `to_shadow`
+    pub fn from_shadow(shadow: &Path, shadow_path: &Path) -> Option<PathBuf> {
+        let relative_path = shadow_path.strip_prefix(shadow).ok()?;
+        if !relative_path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return None;
+        }
+        #[cfg(windows)]
+        {
+            let mut components = relative_path.components();
+            let drive = components.next()?.as_os_str().to_str()?;
+            if drive.len() != 1 || !drive.chars().all(|letter| letter.is_ascii_alphabetic()) {
+                return None;
+            }
+            Some(PathBuf::from(format!("{}:\\", drive)).join(components.as_path()))
+        }
+        #[cfg(not(windows))]
+        Some(Path::new("/").join(relative_path))
+    }
+
+    /// Copy into the real tree the files of the shadow directory which are new or different,
+    /// and returns how many were copied. Removals are not promoted: the shadow does not
+    /// record them, and it lacks the files uploaded by this instance.
+    pub fn promote_shadow(shadow: &Path) -> Result<usize, anyhow::Error> {
+        let mut promoted_count = 0;
+        let mut directories = vec![shadow.to_path_buf()];
+        while let Some(directory) = directories.pop() {
+            let entries = std::fs::read_dir(&directory)
+                .with_context(|| format!("unable to list {}", directory.display()))?;
+            for entry in entries {
+                let shadow_path = entry
+                    .with_context(|| format!("unable to list {}", directory.display()))?
+                    .path();
+                // the links are not followed, as they may point out of the shadow
+                let file_type = std::fs::symlink_metadata(&shadow_path)
+                    .with_context(|| format!("unable to read {}", shadow_path.display()))?
+                    .file_type();
+                if file_type.is_dir() {
+                    directories.push(shadow_path);
+                    continue;
+                }
+                if file_type.is_symlink() && shadow_path.is_dir() {
+                    warn!(
+                        "{} is a link to a directory, not promoted",
+                        shadow_path.display()
+                    );
+                    continue;
+                }
+                if LocalFSStore::is_placeholder(&shadow_path) {
+                    continue;
+                }
+
+                let real_path = match LocalFSStore::from_shadow(shadow, &shadow_path) {
+                    None => {
+                        warn!(
+                            "{} is not a path of the tree, not promoted",
+                            shadow_path.display()
+                        );
+                        continue;
+                    }
+                    Some(real_path) => real_path,
+                };
+                let shadow_hash = LocalFSStore::local_hash(&shadow_path)?;
+                let change = match LocalFSStore::local_hash(&real_path) {
+                    Err(_) => "A",
+                    Ok(real_hash) if real_hash != shadow_hash => "M",
+                    Ok(_) => continue,
+                };
+
+                info!("{} {}", change, real_path.display());
+                LocalFSStore::ensure_directory_exists(&real_path)?;
+                std::fs::copy(&shadow_path, &real_path).with_context(|| {
+                    format!(
+                        "unable to copy {} to {}",
+                        shadow_path.display(),
+                        real_path.display()
+                    )
+                })?;
+                promoted_count += 1;
+            }
+        }
+        Ok(promoted_count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_formatted_hashes() {
+        for hash in [0, 42, LocalFSStore::hash_content(b"content"), u64::MAX] {
+            assert_eq!(
+                LocalFSStore::parse_hash(&LocalFSStore::format_hash(hash)).unwrap(),
+                hash
+            );
+        }
+    }
+
+    #[test]
+    fn refuses_the_legacy_hashes() {
+        // decimal, as written by the versions before BLAKE3
+        assert!(LocalFSStore::parse_hash("12345678901234567890").is_err());
+        assert!(LocalFSStore::parse_hash("123456789").is_err());
+        assert!(LocalFSStore::parse_hash("+23456789abcdef0").is_err());
+        assert!(LocalFSStore::parse_hash("").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn keeps_the_applies_in_the_shadow() {
+        let shadow = Path::new("/var/shadow");
+        let shadow_path = LocalFSStore::to_shadow(shadow, Path::new("/home/user/notes.txt"));
+        assert_eq!(
+            shadow_path.as_deref(),
+            Some(Path::new("/var/shadow/home/user/notes.txt"))
+        );
+        assert_eq!(
+            LocalFSStore::from_shadow(shadow, &shadow_path.unwrap()).as_deref(),
+            Some(Path::new("/home/user/notes.txt"))
+        );
+        assert_eq!(
+            LocalFSStore::to_shadow(shadow, Path::new("/home/../../etc/passwd")),
+            None
+        );
+        assert_eq!(
+            LocalFSStore::from_shadow(shadow, Path::new("/var/other/etc/passwd")),
+            None
+        );
     }
 }
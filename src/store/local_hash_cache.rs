@@ -0,0 +1,91 @@
+use crate::store::local_fs_store::LocalFSStore;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// A file modified this recently may be written again without its modification time changing,
+/// as the file systems store it with a coarse resolution
+const RACY_DELAY: Duration = Duration::from_secs(2);
+
+/// Hashes of the local files, so that the remote changes matching the local files are skipped
+/// without reading them again. A hash is used while its file has the size and the times it was
+/// computed for. Cloning it gives a handle on the same hashes.
+#[derive(Debug, Clone, Default)]
+pub struct LocalHashCache {
+    hashes: Arc<Mutex<HashMap<PathBuf, CachedHash>>>,
+    /// Without it, the files are hashed every time
+    is_enabled: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct FileVersion {
+    len: u64,
+    modified: SystemTime,
+    /// Change time of the inode, in nanoseconds: unlike the modification time, the tools
+    /// copying a file (`cp -p`, `rsync -t`) cannot set it back
+    changed: i64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct CachedHash {
+    version: FileVersion,
+    hash: u64,
+}
+
+impl FileVersion {
+    fn of(path: &Path) -> std::io::Result<FileVersion> {
+        let metadata = std::fs::metadata(path)?;
+        #[cfg(unix)]
+        let changed = {
+            use std::os::unix::fs::MetadataExt;
+            metadata.ctime() * 1_000_000_000 + metadata.ctime_nsec()
+        };
+        #[cfg(not(unix))]
+        let changed = 0;
+        Ok(FileVersion {
+            len: metadata.len(),
+            modified: metadata.modified()?,
+            changed,
+        })
+    }
+}
+
+impl LocalHashCache {
+    pub fn new(is_enabled: bool) -> LocalHashCache {
+        LocalHashCache {
+            hashes: Arc::new(Mutex::new(HashMap::new())),
+            is_enabled,
+        }
+    }
+
+    /// Same as `LocalFSStore::local_hash`, from the cache when the file did not change since
+    pub fn local_hash(&self, path: &Path) -> Result<u64, anyhow::Error> {
+        let version = match FileVersion::of(path) {
+            // the target of a link changes without the times of the link changing
+            Ok(version) if self.is_enabled && !LocalFSStore::is_symlink(path) => version,
+            // the errors are the ones of the hashing
+            _ => return LocalFSStore::local_hash(path),
+        };
+        let cached = self
+            .hashes
+            .lock()
+            .expect("local hash cache lock poisoned")
+            .get(path)
+            .copied();
+        if let Some(cached) = cached.filter(|cached| cached.version == version) {
+            return Ok(cached.hash);
+        }
+        let hash = LocalFSStore::local_hash(path)?;
+        let is_racy = SystemTime::now()
+            .duration_since(version.modified)
+            .map_or(true, |age| age < RACY_DELAY);
+        let mut hashes = self.hashes.lock().expect("local hash cache lock poisoned");
+        if is_racy {
+            hashes.remove(path);
+        } else {
+            hashes.insert(path.to_path_buf(), CachedHash { version, hash });
+        }
+        Ok(hash)
+    }
+}
@@ -0,0 +1,408 @@
+use crate::client::event_bus::EventBus;
+use crate::client::memory_event_bus::MemoryEventBus;
+use crate::client::redis_client::RedisPublishPayload;
+use crate::event_handler::file_events;
+use crate::store::local_fs_store::LocalFSStore;
+use crate::store::namespace::Namespace;
+use crate::store::sync_store::SyncStore;
+use anyhow::{anyhow, bail};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Hash, then compressed content
+type MemoryFile = (u64, Vec<u8>);
+
+#[derive(Debug, Default)]
+struct MemoryFiles {
+    files: BTreeMap<PathBuf, MemoryFile>,
+    generation: u64,
+}
+
+/// Files kept in memory, and events sent on a `MemoryEventBus`, so that the event handlers can
+/// be tested without a redis server. The clones share the files, like the instances share a
+/// redis server.
+#[derive(Debug, Clone)]
+pub struct MemoryStore {
+    files: Arc<Mutex<MemoryFiles>>,
+    events: MemoryEventBus,
+    namespace: Namespace,
+}
+
+impl MemoryStore {
+    pub fn new(events: MemoryEventBus, namespace: Namespace) -> MemoryStore {
+        MemoryStore {
+            files: Arc::new(Mutex::new(MemoryFiles::default())),
+            events,
+            namespace,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, MemoryFiles> {
+        self.files.lock().expect("memory store lock poisoned")
+    }
+
+    fn file(&self, path: &Path) -> Option<MemoryFile> {
+        self.lock().files.get(path).cloned()
+    }
+
+    /// Change the files, then send the event, as the other stores do
+    fn write(
+        &self,
+        change: impl FnOnce(&mut BTreeMap<PathBuf, MemoryFile>) -> Result<(), anyhow::Error>,
+        event: RedisPublishPayload,
+    ) -> Result<(), anyhow::Error> {
+        {
+            let mut files = self.lock();
+            change(&mut files.files)?;
+            files.generation += 1;
+        }
+        self.events
+            .publish(&self.namespace.key(file_events::FILE_EVENT), event)
+    }
+}
+
+impl SyncStore for MemoryStore {
+    fn new_file(
+        &self,
+        emitter_id: u64,
+        path: PathBuf,
+        content: &[u8],
+        hash: u64,
+    ) -> Result<(), anyhow::Error> {
+        let event = RedisPublishPayload::NewFile(emitter_id, hash, path.clone());
+        self.write(
+            |files| {
+                files.insert(path, (hash, content.to_vec()));
+                Ok(())
+            },
+            event,
+        )
+    }
+
+    fn modified_file(
+        &self,
+        emitter_id: u64,
+        path: PathBuf,
+        content: &[u8],
+        hash: u64,
+    ) -> Result<(), anyhow::Error> {
+        let event = RedisPublishPayload::ModifiedFile(emitter_id, hash, path.clone());
+        self.write(
+            |files| {
+                files.insert(path, (hash, content.to_vec()));
+                Ok(())
+            },
+            event,
+        )
+    }
+
+    /// A file replaced by the rename is dropped
+    fn renamed_file(
+        &self,
+        emitter_id: u64,
+        old_path: PathBuf,
+        new_path: PathBuf,
+    ) -> Result<(), anyhow::Error> {
+        let event =
+            RedisPublishPayload::RenamedFile(emitter_id, old_path.clone(), new_path.clone());
+        self.write(
+            |files| match files.remove(&old_path) {
+                None => bail!("no file {} to rename", old_path.display()),
+                Some(file) => {
+                    files.insert(new_path, file);
+                    Ok(())
+                }
+            },
+            event,
+        )
+    }
+
+    fn removed_file(&self, emitter_id: u64, path: PathBuf) -> Result<(), anyhow::Error> {
+        let event = RedisPublishPayload::RemovedFile(emitter_id, path.clone());
+        self.write(
+            |files| {
+                files.remove(&path);
+                Ok(())
+            },
+            event,
+        )
+    }
+
+    fn request_missing_content(&self, emitter_id: u64, path: PathBuf) -> Result<(), anyhow::Error> {
+        self.events.publish(
+            &self.namespace.key(file_events::FILE_EVENT),
+            RedisPublishPayload::ContentMissing(emitter_id, path),
+        )
+    }
+
+    fn reject_content(
+        &self,
+        emitter_id: u64,
+        path: PathBuf,
+        reason: String,
+    ) -> Result<(), anyhow::Error> {
+        self.events.publish(
+            &self.namespace.key(file_events::FILE_EVENT),
+            RedisPublishPayload::ContentRejected(emitter_id, path, reason),
+        )
+    }
+
+    fn get_all_remote_files(&self) -> Result<Vec<String>, anyhow::Error> {
+        Ok(self
+            .lock()
+            .files
+            .keys()
+            .map(|path| path.to_string_lossy().into_owned())
+            .collect())
+    }
+
+    fn get_generation(&self) -> Result<Option<u64>, anyhow::Error> {
+        Ok(Some(self.lock().generation))
+    }
+
+    fn get_remote_file_content(&self, path: &Path) -> Result<Option<Vec<u8>>, anyhow::Error> {
+        match self.file(path) {
+            None => Ok(None),
+            Some((_, compressed_content)) => {
+                LocalFSStore::decompress(&compressed_content).map(Some)
+            }
+        }
+    }
+
+    fn get_remote_file_hash(&self, path: &Path) -> Result<u64, anyhow::Error> {
+        self.file(path)
+            .map(|(hash, _)| hash)
+            .ok_or_else(|| anyhow!("no hash for {} in memory", path.display()))
+    }
+
+    fn get_remote_file_compressed_size(&self, path: &Path) -> Result<u64, anyhow::Error> {
+        Ok(self
+            .file(path)
+            .map_or(0, |(_, compressed_content)| compressed_content.len() as u64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event_handler::local_files_event_handler::{
+        LocalFilesEventHandler, UploadPolicy, WatchedPath,
+    };
+    use crate::event_handler::remote_files_event_handler::{
+        ApplyPolicy, ReconcilePolicy, RemoteFilesEventHandler,
+    };
+    use crate::event_handler::retry_scheduler::RetryScheduler;
+    use crate::event_handler::transfer_gate::TransferGate;
+    use crate::store::local_hash_cache::LocalHashCache;
+    use crate::store::root_mapping::{RootMappedStore, RootMapping};
+    use std::sync::RwLock;
+    use std::time::{Duration, Instant};
+
+    /// Delay for the watcher and the listener to start, and for a change to be applied
+    const STARTUP_DELAY: Duration = Duration::from_millis(500);
+    const APPLY_TIMEOUT: Duration = Duration::from_secs(10);
+    /// Files synchronized by the memory budget test, and the resident memory the test process
+    /// may use once they are, as documented in docs/low-memory.md
+    const MEMORY_BUDGET_FILES: usize = 2000;
+    const MEMORY_BUDGET_KB: u64 = 16 * 1024;
+
+    /// A watched directory whose changes are published to the store, and a directory where the
+    /// other instance applies them
+    struct Peers {
+        directory: PathBuf,
+        published: PathBuf,
+        applied: PathBuf,
+    }
+
+    impl Peers {
+        fn start(name: &str) -> Peers {
+            let directory = std::env::temp_dir().join(format!(
+                "fs-synchronizer-{}-{}",
+                name,
+                std::process::id()
+            ));
+            let _ = std::fs::remove_dir_all(&directory);
+            let (published, applied) = (directory.join("published"), directory.join("applied"));
+            std::fs::create_dir_all(&published).unwrap();
+            std::fs::create_dir_all(&applied).unwrap();
+            let published = published.canonicalize().unwrap();
+            let applied = applied.canonicalize().unwrap();
+
+            let events = MemoryEventBus::new();
+            let store = MemoryStore::new(events.clone(), Namespace::default());
+            let publisher_store = RootMappedStore::new(
+                store.clone(),
+                RootMapping::new(published.clone(), PathBuf::new()),
+            );
+            let watched_paths = vec![WatchedPath {
+                path: published.clone(),
+                recursive: true,
+                event_bounce_ms: 50,
+                poll_interval: None,
+            }];
+            LocalFilesEventHandler::new(
+                publisher_store,
+                1,
+                Arc::new(RwLock::new(watched_paths)),
+                UploadPolicy::default(),
+                RetryScheduler::new(),
+                TransferGate::new(),
+            )
+            .watch_events()
+            .unwrap();
+
+            let roots = RootMapping::new(applied.clone(), PathBuf::new());
+            RemoteFilesEventHandler::new(
+                Arc::new(events),
+                RootMappedStore::new(store, roots.clone()),
+                2,
+                None,
+                ApplyPolicy {
+                    roots,
+                    ..ApplyPolicy::default()
+                },
+                ReconcilePolicy::default(),
+                RetryScheduler::new(),
+            )
+            .watch_events()
+            .unwrap();
+            std::thread::sleep(STARTUP_DELAY);
+            Peers {
+                directory,
+                published,
+                applied,
+            }
+        }
+
+        /// Waits for the applied file to have this content, None when it must not exist
+        fn assert_applied(&self, name: &str, content: Option<&str>) {
+            let path = self.applied.join(name);
+            let started = Instant::now();
+            loop {
+                let applied_content = std::fs::read_to_string(&path).ok();
+                if applied_content.as_deref() == content {
+                    return;
+                }
+                if started.elapsed() > APPLY_TIMEOUT {
+                    panic!(
+                        "{} is {:?} instead of {:?}",
+                        path.display(),
+                        applied_content,
+                        content
+                    );
+                }
+                std::thread::sleep(Duration::from_millis(50));
+            }
+        }
+    }
+
+    impl Drop for Peers {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.directory);
+        }
+    }
+
+    #[test]
+    fn applies_the_new_files() {
+        let peers = Peers::start("new");
+        std::fs::write(peers.published.join("new.txt"), "created").unwrap();
+        peers.assert_applied("new.txt", Some("created"));
+    }
+
+    #[test]
+    fn applies_the_modified_files() {
+        let peers = Peers::start("modified");
+        std::fs::write(peers.published.join("modified.txt"), "first").unwrap();
+        peers.assert_applied("modified.txt", Some("first"));
+        std::fs::write(peers.published.join("modified.txt"), "second").unwrap();
+        peers.assert_applied("modified.txt", Some("second"));
+    }
+
+    #[test]
+    fn applies_the_removals() {
+        let peers = Peers::start("removed");
+        std::fs::write(peers.published.join("removed.txt"), "removed soon").unwrap();
+        peers.assert_applied("removed.txt", Some("removed soon"));
+        std::fs::remove_file(peers.published.join("removed.txt")).unwrap();
+        peers.assert_applied("removed.txt", None);
+    }
+
+    #[test]
+    fn applies_the_renames() {
+        let peers = Peers::start("renamed");
+        std::fs::write(peers.published.join("old.txt"), "renamed").unwrap();
+        peers.assert_applied("old.txt", Some("renamed"));
+        std::fs::rename(
+            peers.published.join("old.txt"),
+            peers.published.join("new.txt"),
+        )
+        .unwrap();
+        peers.assert_applied("new.txt", Some("renamed"));
+        peers.assert_applied("old.txt", None);
+    }
+
+    /// With the settings of --low-memory which apply to the handlers
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn synchronizes_within_the_memory_budget() {
+        let directory = std::env::temp_dir().join(format!(
+            "fs-synchronizer-memory-budget-{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).unwrap();
+        let events = MemoryEventBus::new();
+        let store = MemoryStore::new(events.clone(), Namespace::default());
+        for index in 0..MEMORY_BUDGET_FILES {
+            let content = format!("line {} of the file\n", index).repeat(100);
+            store
+                .new_file(
+                    1,
+                    PathBuf::from(format!("{}/{}.txt", index % 50, index)),
+                    &LocalFSStore::compress(content.as_bytes()),
+                    LocalFSStore::hash_content(content.as_bytes()),
+                )
+                .unwrap();
+        }
+        let roots = RootMapping::new(directory.clone(), PathBuf::new());
+        RemoteFilesEventHandler::new(
+            Arc::new(events),
+            RootMappedStore::new(store, roots.clone()),
+            2,
+            None,
+            ApplyPolicy {
+                roots,
+                local_hashes: LocalHashCache::new(false),
+                ..ApplyPolicy::default()
+            },
+            ReconcilePolicy::default(),
+            RetryScheduler::new(),
+        )
+        .synchronize_local_files_with_remote()
+        .unwrap();
+        let applied_count = (0..50)
+            .map(|index| {
+                std::fs::read_dir(directory.join(index.to_string()))
+                    .unwrap()
+                    .count()
+            })
+            .sum::<usize>();
+        let _ = std::fs::remove_dir_all(&directory);
+        assert_eq!(applied_count, MEMORY_BUDGET_FILES);
+
+        let status = std::fs::read_to_string("/proc/self/status").unwrap();
+        let resident_kb: u64 = status
+            .lines()
+            .find_map(|line| line.strip_prefix("VmRSS:"))
+            .and_then(|resident| resident.trim().trim_end_matches("kB").trim().parse().ok())
+            .unwrap();
+        assert!(
+            resident_kb <= MEMORY_BUDGET_KB,
+            "{} kB resident after synchronizing {} files, above the budget of {} kB",
+            resident_kb,
+            MEMORY_BUDGET_FILES,
+            MEMORY_BUDGET_KB
+        );
+    }
+}
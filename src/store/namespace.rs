@@ -0,0 +1,41 @@
+use anyhow::bail;
+
+/// Prefix of all the keys and channels of a sync domain, so that independent domains
+/// can share one redis server. The default namespace has no prefix.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Namespace {
+    prefix: String,
+}
+
+impl Namespace {
+    /// The name is used in key patterns, so only alphanumerics, `-` and `_` are allowed
+    pub fn new(name: Option<&str>) -> Result<Namespace, anyhow::Error> {
+        let name = match name {
+            None => return Ok(Namespace::default()),
+            Some(name) => name,
+        };
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            bail!(
+                "invalid namespace `{}`: only alphanumerics, `-` and `_` are allowed",
+                name
+            );
+        }
+        Ok(Namespace {
+            prefix: format!("{}:", name),
+        })
+    }
+
+    /// The key (or channel) in this namespace
+    pub fn key(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+
+    /// The key without the namespace prefix, or None when it is not in this namespace
+    pub fn strip<'a>(&self, key: &'a str) -> Option<&'a str> {
+        key.strip_prefix(self.prefix.as_str())
+    }
+}
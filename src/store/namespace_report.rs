@@ -0,0 +1,273 @@
+use crate::client::redis_client::RedisClient;
+use crate::store::audit_store::AuditStore;
+use crate::store::content_store::CONTENT_KEY_PREFIX;
+use crate::store::dedup_content_store::DedupContentStore;
+use crate::store::namespace::Namespace;
+use crate::store::presence_store::PresenceStore;
+use crate::store::redis_store::RedisStore;
+use crate::store::sync_store::SyncStore;
+use anyhow::Context;
+use log::debug;
+use std::collections::{BTreeSet, HashSet};
+use std::path::{Path, PathBuf};
+
+/// The default --cold-min-size: the contents worth moving to a cold tier
+const LARGE_CONTENT_SIZE: u64 = 1048576;
+/// Paths listed for each finding, the others are only counted
+const MAX_LISTED_PATHS: usize = 10;
+
+/// Usage of a namespace, and what is left behind in it by the instances running for months
+#[derive(Debug, Default)]
+pub struct NamespaceReport {
+    pub content_backend: String,
+    pub file_count: usize,
+    /// The `content:` keys of the --redis-url redis. Only the pointers are counted for the
+    /// contents stored elsewhere, or deduplicated.
+    pub content_key_count: usize,
+    pub content_key_size: u64,
+    /// Content keys of at least LARGE_CONTENT_SIZE bytes
+    pub large_content_count: usize,
+    pub large_content_size: u64,
+    pub blob_count: usize,
+    pub blob_size: u64,
+    /// Content keys of no file: the removals interrupted after the file, or the uploads in
+    /// progress
+    pub stray_contents: Vec<(String, u64)>,
+    /// Files without a content key: the uploads interrupted after the file, or in progress
+    pub missing_contents: Vec<String>,
+    /// Deduplicated blobs no file points to, and their size
+    pub unreferenced_blobs: Vec<(String, u64)>,
+    /// Removed files whose applied versions are still recorded by the audit
+    pub removed_file_histories: Vec<PathBuf>,
+    /// Versions recorded by the audit which are not the current version of their file
+    pub old_version_count: usize,
+    pub live_instance_count: usize,
+    /// Files under none of the paths watched by the live instances
+    pub unwatched_files: Vec<String>,
+}
+
+impl NamespaceReport {
+    pub fn collect(
+        client: &RedisClient,
+        namespace: &Namespace,
+        store: &RedisStore,
+        audit: &AuditStore,
+        presence: &PresenceStore,
+    ) -> Result<NamespaceReport, anyhow::Error> {
+        let content_backend = store
+            .get_namespace_metadata()
+            .context("the namespace has no metadata: start an instance on it first")?
+            .content_backend;
+        let files: BTreeSet<String> = store.get_all_remote_files()?.into_iter().collect();
+        let mut report = NamespaceReport {
+            content_backend,
+            file_count: files.len(),
+            ..NamespaceReport::default()
+        };
+
+        debug!("[namespace_report] reading the content keys");
+        let content_keys = client
+            .scan_match(&namespace.key(&format!("{}*", CONTENT_KEY_PREFIX)))
+            .context("unable to list the content keys")?;
+        let mut content_paths = HashSet::with_capacity(content_keys.len());
+        for content_key in content_keys {
+            let path = match namespace
+                .strip(&content_key)
+                .and_then(|key| key.strip_prefix(CONTENT_KEY_PREFIX))
+            {
+                None => continue,
+                Some(path) => path.to_string(),
+            };
+            let size = client.strlen(&content_key)?;
+            report.content_key_count += 1;
+            report.content_key_size += size;
+            if size >= LARGE_CONTENT_SIZE {
+                report.large_content_count += 1;
+                report.large_content_size += size;
+            }
+            if !files.contains(&path) {
+                report.stray_contents.push((path.clone(), size));
+            }
+            content_paths.insert(path);
+        }
+        // the other backends keep the contents, or some of them, out of the content keys
+        if matches!(
+            report.content_backend.as_str(),
+            "redis" | "redis-dedup" | "fs-blobs" | "s3"
+        ) {
+            report.missing_contents = files
+                .iter()
+                .filter(|path| !content_paths.contains(*path))
+                .cloned()
+                .collect();
+        }
+
+        if report.content_backend == "redis-dedup" {
+            debug!("[namespace_report] reading the deduplicated blobs");
+            for blob in DedupContentStore::new(client.clone(), namespace.clone()).blobs()? {
+                report.blob_count += 1;
+                report.blob_size += blob.size;
+                if blob.references == 0 {
+                    report.unreferenced_blobs.push((blob.name, blob.size));
+                }
+            }
+        }
+
+        debug!("[namespace_report] reading the audit records");
+        for path in audit.recorded_paths()? {
+            let path_as_str = path.to_string_lossy();
+            if !files.contains(path_as_str.as_ref()) {
+                report.removed_file_histories.push(path);
+                continue;
+            }
+            let current_hash = store.get_remote_file_hash(&path)?;
+            let old_versions: HashSet<u64> = audit
+                .applied_versions(&path)?
+                .into_iter()
+                .map(|version| version.hash)
+                .filter(|hash| *hash != current_hash)
+                .collect();
+            report.old_version_count += old_versions.len();
+        }
+
+        debug!("[namespace_report] reading the live instances");
+        let instances = presence.live_instances()?;
+        report.live_instance_count = instances.len();
+        let watched_paths: Vec<PathBuf> = instances
+            .into_iter()
+            .flat_map(|(_, record)| record.roots)
+            .map(|root| root.path)
+            .collect();
+        report.unwatched_files = files
+            .into_iter()
+            .filter(|path| {
+                !watched_paths
+                    .iter()
+                    .any(|watched_path| Path::new(path).starts_with(watched_path))
+            })
+            .collect();
+        Ok(report)
+    }
+
+    /// Print the usage, then the findings along with what to do about them
+    pub fn print(&self) {
+        println!("content backend: {}", self.content_backend);
+        println!("{} files", self.file_count);
+        println!(
+            "{} content keys, {} bytes",
+            self.content_key_count, self.content_key_size
+        );
+        if self.content_backend == "redis-dedup" {
+            println!(
+                "{} deduplicated blobs, {} bytes",
+                self.blob_count, self.blob_size
+            );
+        }
+        println!("{} live instances", self.live_instance_count);
+
+        let mut advice_count = 0;
+        let mut advise = |finding: String, advice: &str, paths: Vec<&str>| {
+            advice_count += 1;
+            println!();
+            println!("{}", finding);
+            for path in paths.iter().take(MAX_LISTED_PATHS) {
+                println!("  {}", path);
+            }
+            if paths.len() > MAX_LISTED_PATHS {
+                println!("  ... and {} more", paths.len() - MAX_LISTED_PATHS);
+            }
+            println!("  -> {}", advice);
+        };
+        if !self.stray_contents.is_empty() {
+            advise(
+                format!(
+                    "{} content keys of no file, {} bytes",
+                    self.stray_contents.len(),
+                    self.stray_contents.iter().map(|(_, size)| size).sum::<u64>()
+                ),
+                "left by interrupted removals. Run the report again: the ones still listed can be deleted.",
+                self.stray_contents
+                    .iter()
+                    .map(|(path, _)| path.as_str())
+                    .collect(),
+            );
+        }
+        if !self.missing_contents.is_empty() {
+            advise(
+                format!("{} files without content", self.missing_contents.len()),
+                "left by interrupted uploads: the peers cannot apply them. Touch them on an instance holding them, or remove them.",
+                self.missing_contents.iter().map(String::as_str).collect(),
+            );
+        }
+        if !self.unreferenced_blobs.is_empty() {
+            advise(
+                format!(
+                    "{} deduplicated blobs of no file, {} bytes",
+                    self.unreferenced_blobs.len(),
+                    self.unreferenced_blobs
+                        .iter()
+                        .map(|(_, size)| size)
+                        .sum::<u64>()
+                ),
+                "left by interrupted uploads. Run the report again: the ones still listed can be deleted.",
+                self.unreferenced_blobs
+                    .iter()
+                    .map(|(name, _)| name.as_str())
+                    .collect(),
+            );
+        }
+        if !self.removed_file_histories.is_empty() {
+            advise(
+                format!(
+                    "{} removed files still have their applied versions recorded",
+                    self.removed_file_histories.len()
+                ),
+                "the --audit records outlive the files. Delete the `applied:` keys of the histories no longer needed.",
+                self.removed_file_histories
+                    .iter()
+                    .filter_map(|path| path.to_str())
+                    .collect(),
+            );
+        }
+        if self.old_version_count > 0 {
+            advise(
+                format!(
+                    "{} versions older than the current ones are recorded",
+                    self.old_version_count
+                ),
+                "the --audit records every version applied. Run it only on the instances whose history is needed.",
+                Vec::new(),
+            );
+        }
+        if self.live_instance_count == 0 {
+            advise(
+                String::from("no live instance"),
+                "no instance holds the files locally: start one before the store is cleaned up.",
+                Vec::new(),
+            );
+        } else if !self.unwatched_files.is_empty() {
+            advise(
+                format!(
+                    "{} files are under no path watched by a live instance",
+                    self.unwatched_files.len()
+                ),
+                "no live instance holds them locally. Remove them, or start an instance watching them.",
+                self.unwatched_files.iter().map(String::as_str).collect(),
+            );
+        }
+        if self.content_backend == "redis" && self.large_content_count > 0 {
+            advise(
+                format!(
+                    "{} contents of at least {} bytes are in redis, {} bytes",
+                    self.large_content_count, LARGE_CONTENT_SIZE, self.large_content_size
+                ),
+                "--cold-tier-url would move the idle ones out of redis.",
+                Vec::new(),
+            );
+        }
+        if advice_count == 0 {
+            println!();
+            println!("nothing to clean up");
+        }
+    }
+}
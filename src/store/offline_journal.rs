@@ -0,0 +1,331 @@
+use crate::store::sync_store::SyncStore;
+use anyhow::{bail, Context};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// Delay before the local changes try again to replay the journal, while the store is unreachable
+const REPLAY_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// A local change the store could not receive
+#[derive(Debug, Deserialize, Serialize)]
+enum JournaledChange {
+    /// Path, hash, then compressed content
+    Created(PathBuf, u64, Vec<u8>),
+    /// Path, hash, then compressed content
+    Modified(PathBuf, u64, Vec<u8>),
+    /// Old path, then new path
+    Renamed(PathBuf, PathBuf),
+    Removed(PathBuf),
+}
+
+impl JournaledChange {
+    fn describe(&self) -> String {
+        match self {
+            JournaledChange::Created(path, _, _) => format!("creation of {}", path.display()),
+            JournaledChange::Modified(path, _, _) => {
+                format!("modification of {}", path.display())
+            }
+            JournaledChange::Renamed(old_path, new_path) => {
+                format!("rename of {} to {}", old_path.display(), new_path.display())
+            }
+            JournaledChange::Removed(path) => format!("removal of {}", path.display()),
+        }
+    }
+}
+
+/// Local SQLite database keeping, in order, the local changes made while the store is unreachable,
+/// so that they survive a restart
+#[derive(Debug)]
+pub struct OfflineJournal {
+    connection: rusqlite::Connection,
+    last_replay_failure: Option<Instant>,
+}
+
+impl OfflineJournal {
+    pub fn open(path: &Path) -> Result<OfflineJournal, anyhow::Error> {
+        let connection = rusqlite::Connection::open(path)
+            .with_context(|| format!("unable to open the offline journal {}", path.display()))?;
+        connection
+            .execute_batch(
+                "CREATE TABLE IF NOT EXISTS changes (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    change BLOB NOT NULL
+                )",
+            )
+            .context("unable to create the offline journal")?;
+        Ok(OfflineJournal {
+            connection,
+            last_replay_failure: None,
+        })
+    }
+
+    fn pending(&self) -> Result<u64, anyhow::Error> {
+        self.connection
+            .query_row("SELECT COUNT(*) FROM changes", [], |row| row.get(0))
+            .context("unable to count the changes of the offline journal")
+    }
+
+    fn append(&self, change: &JournaledChange) -> Result<(), anyhow::Error> {
+        debug!("[offline_journal] recording the {}", change.describe());
+        let serialized_change = rmp_serde::to_vec(change)
+            .expect("messagepack serialization of JournaledChange should never fail");
+        self.connection
+            .execute(
+                "INSERT INTO changes (change) VALUES (?1)",
+                [serialized_change],
+            )
+            .context("unable to record the change in the offline journal")?;
+        Ok(())
+    }
+
+    fn oldest(&self) -> Result<Option<(i64, JournaledChange)>, anyhow::Error> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT id, change FROM changes ORDER BY id LIMIT 1")?;
+        let mut rows = statement.query([])?;
+        let row = match rows.next()? {
+            None => return Ok(None),
+            Some(row) => row,
+        };
+        let serialized_change: Vec<u8> = row.get(1)?;
+        let change = rmp_serde::from_slice(&serialized_change)
+            .context("unable to decode a change of the offline journal")?;
+        Ok(Some((row.get(0)?, change)))
+    }
+
+    fn remove(&self, id: i64) -> Result<(), anyhow::Error> {
+        self.connection
+            .execute("DELETE FROM changes WHERE id = ?1", [id])
+            .context("unable to remove the change from the offline journal")?;
+        Ok(())
+    }
+}
+
+/// Records the local changes in the offline journal while the store is unreachable, and replays
+/// them in order once it is back. Without journal, the changes go straight to the store.
+///
+/// The journal is replayed before the generation of the store is read, so that a synchronization
+/// never overwrites the local changes made offline.
+#[derive(Debug, Clone)]
+pub struct JournaledStore<S: SyncStore + Clone> {
+    store: S,
+    journal: Option<Arc<Mutex<OfflineJournal>>>,
+    /// Emitter of the replayed changes: the journal may have been recorded by a previous run
+    emitter_id: u64,
+}
+
+impl<S: SyncStore + Clone> JournaledStore<S> {
+    pub fn new(store: S, journal: Option<OfflineJournal>, emitter_id: u64) -> JournaledStore<S> {
+        JournaledStore {
+            store,
+            journal: journal.map(|journal| Arc::new(Mutex::new(journal))),
+            emitter_id,
+        }
+    }
+
+    fn lock(journal: &Mutex<OfflineJournal>) -> MutexGuard<'_, OfflineJournal> {
+        journal.lock().expect("offline journal lock poisoned")
+    }
+
+    /// An error with the store may be a refused change: the store is unreachable when it cannot
+    /// even give its generation
+    fn is_store_unreachable(&self) -> bool {
+        self.store.get_generation().is_err()
+    }
+
+    fn send(&self, emitter_id: u64, change: &JournaledChange) -> Result<(), anyhow::Error> {
+        match change {
+            JournaledChange::Created(path, hash, content) => {
+                self.store
+                    .new_file(emitter_id, path.clone(), content, *hash)
+            }
+            JournaledChange::Modified(path, hash, content) => {
+                self.store
+                    .modified_file(emitter_id, path.clone(), content, *hash)
+            }
+            JournaledChange::Renamed(old_path, new_path) => {
+                self.store
+                    .renamed_file(emitter_id, old_path.clone(), new_path.clone())
+            }
+            JournaledChange::Removed(path) => self.store.removed_file(emitter_id, path.clone()),
+        }
+    }
+
+    /// Send the change, after the ones still in the journal
+    fn write(&self, emitter_id: u64, change: JournaledChange) -> Result<(), anyhow::Error> {
+        let journal = match &self.journal {
+            None => return self.send(emitter_id, &change),
+            Some(journal) => journal,
+        };
+        let mut journal = JournaledStore::<S>::lock(journal);
+        let is_replayed = journal
+            .last_replay_failure
+            .is_none_or(|failure| failure.elapsed() >= REPLAY_RETRY_DELAY);
+        if is_replayed {
+            self.replay(&mut journal);
+        }
+        if journal.pending()? > 0 {
+            return journal.append(&change);
+        }
+        match self.send(emitter_id, &change) {
+            Err(error) if self.is_store_unreachable() => {
+                warn!(
+                    "the store is unreachable, the {} is recorded in the offline journal. Error: {:?}",
+                    change.describe(),
+                    error
+                );
+                journal.last_replay_failure = Some(Instant::now());
+                journal.append(&change)
+            }
+            result => result,
+        }
+    }
+
+    /// Send the journaled changes in order, until the store cannot be reached.
+    /// A change the store refuses is dropped, so that it does not block the next ones.
+    fn replay(&self, journal: &mut OfflineJournal) {
+        loop {
+            let (id, change) = match journal.oldest() {
+                Err(error) => {
+                    error!("unable to read the offline journal: {:?}", error);
+                    return;
+                }
+                Ok(None) => return,
+                Ok(Some(oldest)) => oldest,
+            };
+            debug!("[offline_journal] replaying the {}", change.describe());
+            if let Err(error) = self.send(self.emitter_id, &change) {
+                if self.is_store_unreachable() {
+                    debug!("[offline_journal] the store is still unreachable");
+                    journal.last_replay_failure = Some(Instant::now());
+                    return;
+                }
+                error!(
+                    "the store refused the {} recorded offline, dropping it. Error: {:?}",
+                    change.describe(),
+                    error
+                );
+            }
+            if let Err(error) = journal.remove(id) {
+                error!("{:?}", error);
+                return;
+            }
+            if let Ok(0) = journal.pending() {
+                info!("the changes made offline are sent to the store");
+                journal.last_replay_failure = None;
+            }
+        }
+    }
+}
+
+impl<S: SyncStore + Clone> SyncStore for JournaledStore<S> {
+    fn new_file(
+        &self,
+        emitter_id: u64,
+        path: PathBuf,
+        content: &[u8],
+        hash: u64,
+    ) -> Result<(), anyhow::Error> {
+        self.write(
+            emitter_id,
+            JournaledChange::Created(path, hash, content.to_vec()),
+        )
+    }
+
+    fn modified_file(
+        &self,
+        emitter_id: u64,
+        path: PathBuf,
+        content: &[u8],
+        hash: u64,
+    ) -> Result<(), anyhow::Error> {
+        self.write(
+            emitter_id,
+            JournaledChange::Modified(path, hash, content.to_vec()),
+        )
+    }
+
+    fn renamed_file(
+        &self,
+        emitter_id: u64,
+        old_path: PathBuf,
+        new_path: PathBuf,
+    ) -> Result<(), anyhow::Error> {
+        self.write(emitter_id, JournaledChange::Renamed(old_path, new_path))
+    }
+
+    fn removed_file(&self, emitter_id: u64, path: PathBuf) -> Result<(), anyhow::Error> {
+        self.write(emitter_id, JournaledChange::Removed(path))
+    }
+
+    fn request_missing_content(&self, emitter_id: u64, path: PathBuf) -> Result<(), anyhow::Error> {
+        self.store.request_missing_content(emitter_id, path)
+    }
+
+    fn reject_content(
+        &self,
+        emitter_id: u64,
+        path: PathBuf,
+        reason: String,
+    ) -> Result<(), anyhow::Error> {
+        self.store.reject_content(emitter_id, path, reason)
+    }
+
+    fn claim_upload_again(&self, path: &Path, claimer_id: u64) -> Result<bool, anyhow::Error> {
+        self.store.claim_upload_again(path, claimer_id)
+    }
+
+    fn take_own_removal(&self, path: &Path) -> bool {
+        self.store.take_own_removal(path)
+    }
+
+    fn get_changed_since(&self, since: u64) -> Result<Vec<(PathBuf, u64)>, anyhow::Error> {
+        self.store.get_changed_since(since)
+    }
+
+    fn get_rollout_approval(&self) -> Result<Option<u64>, anyhow::Error> {
+        self.store.get_rollout_approval()
+    }
+
+    fn get_all_remote_files(&self) -> Result<Vec<String>, anyhow::Error> {
+        self.store.get_all_remote_files()
+    }
+
+    fn get_generation(&self) -> Result<Option<u64>, anyhow::Error> {
+        if let Some(journal) = &self.journal {
+            let mut journal = JournaledStore::<S>::lock(journal);
+            self.replay(&mut journal);
+            let pending = journal.pending()?;
+            if pending > 0 {
+                bail!(
+                    "{} local changes made offline are not sent to the store yet",
+                    pending
+                );
+            }
+        }
+        self.store.get_generation()
+    }
+
+    fn get_remote_file_content(&self, path: &Path) -> Result<Option<Vec<u8>>, anyhow::Error> {
+        self.store.get_remote_file_content(path)
+    }
+
+    fn get_remote_file_hash(&self, path: &Path) -> Result<u64, anyhow::Error> {
+        self.store.get_remote_file_hash(path)
+    }
+
+    fn invalidate_cached_hash(&self, path: &Path) {
+        self.store.invalidate_cached_hash(path)
+    }
+
+    fn invalidate_all_cached_hashes(&self) {
+        self.store.invalidate_all_cached_hashes()
+    }
+
+    fn get_remote_file_compressed_size(&self, path: &Path) -> Result<u64, anyhow::Error> {
+        self.store.get_remote_file_compressed_size(path)
+    }
+}
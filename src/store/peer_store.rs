@@ -0,0 +1,125 @@
+use crate::client::event_bus::EventBus;
+use crate::client::peer_client::PeerClient;
+use crate::client::redis_client::RedisPublishPayload;
+use crate::event_handler::file_events;
+use crate::store::local_fs_store::LocalFSStore;
+use crate::store::namespace::Namespace;
+use crate::store::sync_store::SyncStore;
+use anyhow::{anyhow, Context};
+use std::path::{Path, PathBuf};
+
+/// Synchronization without central store: the files of the peers are the remote files.
+/// The changes are only announced, and the peers fetch the contents from our local files.
+#[derive(Debug, Clone)]
+pub struct PeerStore {
+    client: PeerClient,
+    namespace: Namespace,
+}
+
+impl PeerStore {
+    pub fn new(client: PeerClient, namespace: Namespace) -> PeerStore {
+        PeerStore { client, namespace }
+    }
+
+    fn publish(&self, payload: RedisPublishPayload) -> Result<(), anyhow::Error> {
+        self.client
+            .publish(&self.namespace.key(file_events::FILE_EVENT), payload)
+            .context("unable to publish the event to the peers")
+    }
+}
+
+impl SyncStore for PeerStore {
+    fn new_file(
+        &self,
+        emitter_id: u64,
+        path: PathBuf,
+        _content: &[u8],
+        hash: u64,
+    ) -> Result<(), anyhow::Error> {
+        self.publish(RedisPublishPayload::NewFile(emitter_id, hash, path))
+    }
+
+    fn modified_file(
+        &self,
+        emitter_id: u64,
+        path: PathBuf,
+        _content: &[u8],
+        hash: u64,
+    ) -> Result<(), anyhow::Error> {
+        self.publish(RedisPublishPayload::ModifiedFile(emitter_id, hash, path))
+    }
+
+    fn renamed_file(
+        &self,
+        emitter_id: u64,
+        old_path: PathBuf,
+        new_path: PathBuf,
+    ) -> Result<(), anyhow::Error> {
+        self.publish(RedisPublishPayload::RenamedFile(
+            emitter_id, old_path, new_path,
+        ))
+    }
+
+    fn removed_file(&self, emitter_id: u64, path: PathBuf) -> Result<(), anyhow::Error> {
+        self.publish(RedisPublishPayload::RemovedFile(emitter_id, path))
+    }
+
+    fn request_missing_content(&self, emitter_id: u64, path: PathBuf) -> Result<(), anyhow::Error> {
+        self.publish(RedisPublishPayload::ContentMissing(emitter_id, path))
+    }
+
+    fn reject_content(
+        &self,
+        emitter_id: u64,
+        path: PathBuf,
+        reason: String,
+    ) -> Result<(), anyhow::Error> {
+        self.publish(RedisPublishPayload::ContentRejected(
+            emitter_id, path, reason,
+        ))
+    }
+
+    /// The files held by the reachable peers
+    fn get_all_remote_files(&self) -> Result<Vec<String>, anyhow::Error> {
+        Ok(self
+            .client
+            .list_files()
+            .context("unable to list the files of the peers")?
+            .iter()
+            .map(|path| path.to_string_lossy().into_owned())
+            .collect())
+    }
+
+    fn get_remote_file_content(&self, path: &Path) -> Result<Option<Vec<u8>>, anyhow::Error> {
+        match self
+            .client
+            .get_file(path)
+            .with_context(|| format!("unable to get {} from the peers", path.display()))?
+        {
+            None => Ok(None),
+            Some((_, compressed_content)) => {
+                LocalFSStore::decompress(&compressed_content).map(Some)
+            }
+        }
+    }
+
+    fn get_remote_file_hash(&self, path: &Path) -> Result<u64, anyhow::Error> {
+        self.client
+            .file_hash(path)
+            .with_context(|| {
+                format!(
+                    "unable to get the hash of {} from the peers",
+                    path.display()
+                )
+            })?
+            .ok_or_else(|| anyhow!("no peer holds {}", path.display()))
+    }
+
+    fn get_remote_file_compressed_size(&self, path: &Path) -> Result<u64, anyhow::Error> {
+        self.client
+            .get_file(path)
+            .with_context(|| format!("unable to get {} from the peers", path.display()))?
+            .map(|(_, compressed_content)| compressed_content.len() as u64)
+            .ok_or_else(|| anyhow!("no peer holds {}", path.display()))
+    }
+}
@@ -0,0 +1,402 @@
+use crate::client::event_bus::EventBus;
+use crate::client::postgres_client::PostgresClient;
+use crate::client::redis_client::RedisPublishPayload;
+use crate::event_handler::file_events;
+use crate::store::hash_migration::HashMigrationReport;
+use crate::store::local_fs_store::{self, LocalFSStore};
+use crate::store::namespace::Namespace;
+use crate::store::sync_store::SyncStore;
+use anyhow::{anyhow, bail, Context};
+use log::error;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// $1: namespace
+const INCREMENT_GENERATION_QUERY: &str = r"
+INSERT INTO fs_synchronizer_generations (namespace, generation) VALUES ($1, 1)
+ON CONFLICT (namespace)
+DO UPDATE SET generation = fs_synchronizer_generations.generation + 1
+";
+
+/// Files stored in postgres: the hash and the content of a file are one row, changed in the
+/// same transaction as the generation and the event, which the peers only see once committed.
+/// The hashes are stored as BIGINT, i.e. reinterpreted as signed. The algorithm hashing them is
+/// recorded per namespace, the namespaces of the previous versions having none.
+#[derive(Debug, Clone)]
+pub struct PostgresStore {
+    client: PostgresClient,
+    namespace: Namespace,
+}
+
+impl PostgresStore {
+    pub fn new(client: PostgresClient, namespace: Namespace) -> PostgresStore {
+        PostgresStore { client, namespace }
+    }
+
+    /// Value of the namespace column: the prefix of the keys of the namespace
+    fn namespace_column(&self) -> String {
+        self.namespace.key("")
+    }
+
+    fn to_str(path: &Path) -> Result<&str, anyhow::Error> {
+        path.to_str().ok_or_else(|| {
+            anyhow!(
+                "path is not valid UTF-8 string. Unable to synchronize this file. Path: {:?}",
+                path.display()
+            )
+        })
+    }
+
+    /// Refuse the namespaces whose files are hashed with another algorithm, and record the
+    /// current one on the new namespaces
+    pub fn check_hash_algorithm(&self) -> Result<(), anyhow::Error> {
+        let namespace = self.namespace_column();
+        let (hash_algorithm, has_files) = self
+            .client
+            .with_client(|client| {
+                let hash_algorithm = client
+                    .query_opt(
+                        "SELECT hash_algorithm FROM fs_synchronizer_namespaces \
+                         WHERE namespace = $1",
+                        &[&namespace],
+                    )?
+                    .map(|row| row.try_get::<_, String>(0))
+                    .transpose()?;
+                let has_files = client
+                    .query_opt(
+                        "SELECT 1 FROM fs_synchronizer_files WHERE namespace = $1 LIMIT 1",
+                        &[&namespace],
+                    )?
+                    .is_some();
+                Ok((hash_algorithm, has_files))
+            })
+            .context("unable to get the hash algorithm of the namespace")?;
+        match hash_algorithm {
+            Some(hash_algorithm) if hash_algorithm == local_fs_store::HASH_ALGORITHM => Ok(()),
+            Some(hash_algorithm) => bail!(
+                "the namespace is incompatible with this instance: hash algorithm {} != {}",
+                hash_algorithm,
+                local_fs_store::HASH_ALGORITHM
+            ),
+            // written by the previous versions
+            None if has_files => bail!(
+                "the files of the namespace are hashed with {}: run `migrate-hashes` first",
+                local_fs_store::LEGACY_HASH_ALGORITHM
+            ),
+            None => self.set_namespace_hash_algorithm(),
+        }
+    }
+
+    /// Make the namespace usable by the instances hashing with the current algorithm, once the
+    /// hashes of its files are migrated to it
+    pub fn set_namespace_hash_algorithm(&self) -> Result<(), anyhow::Error> {
+        let namespace = self.namespace_column();
+        self.client
+            .with_client(|client| {
+                client.execute(
+                    "INSERT INTO fs_synchronizer_namespaces (namespace, hash_algorithm) \
+                     VALUES ($1, $2) \
+                     ON CONFLICT (namespace) DO UPDATE SET hash_algorithm = EXCLUDED.hash_algorithm",
+                    &[&namespace, &local_fs_store::HASH_ALGORITHM],
+                )?;
+                Ok(())
+            })
+            .context("unable to record the hash algorithm of the namespace")
+    }
+
+    /// Same as `HashMigration::run`, one file at a time on the shared connection
+    pub fn migrate_hashes(&self, max_rate: u32) -> Result<HashMigrationReport, anyhow::Error> {
+        let paths = self.get_all_remote_files()?;
+        let mut report = HashMigrationReport {
+            checked: 0,
+            rewritten: 0,
+            failed: 0,
+        };
+        let mut next_slot = Instant::now();
+        for path in paths {
+            if max_rate > 0 {
+                std::thread::sleep(next_slot.saturating_duration_since(Instant::now()));
+                next_slot = next_slot.max(Instant::now()) + Duration::from_secs(1) / max_rate;
+            }
+            report.checked += 1;
+            match self.rewrite_hash(&path) {
+                Ok(false) => (),
+                Ok(true) => report.rewritten += 1,
+                Err(error) => {
+                    report.failed += 1;
+                    error!("unable to migrate the hash of {}: {:?}", path, error);
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    /// Returns whether the hash was rewritten
+    fn rewrite_hash(&self, path: &str) -> Result<bool, anyhow::Error> {
+        let namespace = self.namespace_column();
+        let row = self.client.with_client(|client| {
+            Ok(client.query_opt(
+                "SELECT hash, content FROM fs_synchronizer_files \
+                 WHERE namespace = $1 AND path = $2",
+                &[&namespace, &path],
+            )?)
+        })?;
+        let (hash, compressed_content): (i64, Vec<u8>) = match row {
+            // removed
+            None => return Ok(false),
+            Some(row) => (
+                row.try_get(0).context("invalid hash in postgres")?,
+                row.try_get(1).context("invalid content in postgres")?,
+            ),
+        };
+        let content = LocalFSStore::decompress(&compressed_content)?;
+        let migrated_hash = LocalFSStore::hash_content(&content) as i64;
+        if hash == migrated_hash {
+            return Ok(false);
+        }
+        let updated = self.client.with_client(|client| {
+            Ok(client.execute(
+                "UPDATE fs_synchronizer_files SET hash = $4 \
+                 WHERE namespace = $1 AND path = $2 AND hash = $3",
+                &[&namespace, &path, &hash, &migrated_hash],
+            )?)
+        })?;
+        if updated == 0 {
+            bail!("changed during its migration by an instance using the previous algorithm");
+        }
+        Ok(true)
+    }
+
+    fn set_file(
+        &self,
+        path: &Path,
+        content: &[u8],
+        hash: u64,
+        event: RedisPublishPayload,
+    ) -> Result<(), anyhow::Error> {
+        let path_as_str = PostgresStore::to_str(path)?;
+        let namespace = self.namespace_column();
+        self.client.with_client(|client| {
+            let mut transaction = client.transaction()?;
+            transaction.execute(
+                "INSERT INTO fs_synchronizer_files (namespace, path, hash, content) \
+                 VALUES ($1, $2, $3, $4) \
+                 ON CONFLICT (namespace, path) \
+                 DO UPDATE SET hash = EXCLUDED.hash, content = EXCLUDED.content",
+                &[&namespace, &path_as_str, &(hash as i64), &content],
+            )?;
+            transaction.execute(INCREMENT_GENERATION_QUERY, &[&namespace])?;
+            PostgresClient::notify(
+                &mut transaction,
+                &self.namespace.key(file_events::FILE_EVENT),
+                event,
+            )?;
+            transaction.commit()?;
+            Ok(())
+        })
+    }
+}
+
+impl SyncStore for PostgresStore {
+    fn new_file(
+        &self,
+        emitter_id: u64,
+        path: PathBuf,
+        content: &[u8],
+        hash: u64,
+    ) -> Result<(), anyhow::Error> {
+        let event = RedisPublishPayload::NewFile(emitter_id, hash, path.clone());
+        self.set_file(&path, content, hash, event)
+            .context("unable to send the postgres queries to set new file")
+    }
+
+    fn modified_file(
+        &self,
+        emitter_id: u64,
+        path: PathBuf,
+        content: &[u8],
+        hash: u64,
+    ) -> Result<(), anyhow::Error> {
+        let event = RedisPublishPayload::ModifiedFile(emitter_id, hash, path.clone());
+        self.set_file(&path, content, hash, event)
+            .context("unable to send the postgres queries to modify the file")
+    }
+
+    /// A file replaced by the rename is dropped
+    fn renamed_file(
+        &self,
+        emitter_id: u64,
+        old_path: PathBuf,
+        new_path: PathBuf,
+    ) -> Result<(), anyhow::Error> {
+        let (old_path_as_str, new_path_as_str) = (
+            PostgresStore::to_str(&old_path)?,
+            PostgresStore::to_str(&new_path)?,
+        );
+        let namespace = self.namespace_column();
+        let event =
+            RedisPublishPayload::RenamedFile(emitter_id, old_path.clone(), new_path.clone());
+        self.client
+            .with_client(|client| {
+                let mut transaction = client.transaction()?;
+                transaction.execute(
+                    "DELETE FROM fs_synchronizer_files WHERE namespace = $1 AND path = $2",
+                    &[&namespace, &new_path_as_str],
+                )?;
+                transaction.execute(
+                    "UPDATE fs_synchronizer_files SET path = $3 \
+                     WHERE namespace = $1 AND path = $2",
+                    &[&namespace, &old_path_as_str, &new_path_as_str],
+                )?;
+                transaction.execute(INCREMENT_GENERATION_QUERY, &[&namespace])?;
+                PostgresClient::notify(
+                    &mut transaction,
+                    &self.namespace.key(file_events::FILE_EVENT),
+                    event,
+                )?;
+                transaction.commit()?;
+                Ok(())
+            })
+            .context("unable to send the postgres queries to rename file")
+    }
+
+    fn removed_file(&self, emitter_id: u64, path: PathBuf) -> Result<(), anyhow::Error> {
+        let path_as_str = PostgresStore::to_str(&path)?;
+        let namespace = self.namespace_column();
+        let event = RedisPublishPayload::RemovedFile(emitter_id, path.clone());
+        self.client
+            .with_client(|client| {
+                let mut transaction = client.transaction()?;
+                transaction.execute(
+                    "DELETE FROM fs_synchronizer_files WHERE namespace = $1 AND path = $2",
+                    &[&namespace, &path_as_str],
+                )?;
+                transaction.execute(INCREMENT_GENERATION_QUERY, &[&namespace])?;
+                PostgresClient::notify(
+                    &mut transaction,
+                    &self.namespace.key(file_events::FILE_EVENT),
+                    event,
+                )?;
+                transaction.commit()?;
+                Ok(())
+            })
+            .context("unable to send the postgres queries to remove file")
+    }
+
+    fn request_missing_content(&self, emitter_id: u64, path: PathBuf) -> Result<(), anyhow::Error> {
+        let event = RedisPublishPayload::ContentMissing(emitter_id, path);
+        self.client
+            .publish(&self.namespace.key(file_events::FILE_EVENT), event)
+            .context("unable to send the postgres query to request missing content")
+    }
+
+    fn reject_content(
+        &self,
+        emitter_id: u64,
+        path: PathBuf,
+        reason: String,
+    ) -> Result<(), anyhow::Error> {
+        let event = RedisPublishPayload::ContentRejected(emitter_id, path, reason);
+        self.client
+            .publish(&self.namespace.key(file_events::FILE_EVENT), event)
+            .context("unable to send the postgres query to reject the content")
+    }
+
+    fn get_all_remote_files(&self) -> Result<Vec<String>, anyhow::Error> {
+        let namespace = self.namespace_column();
+        self.client
+            .with_client(|client| {
+                Ok(client.query(
+                    "SELECT path FROM fs_synchronizer_files WHERE namespace = $1",
+                    &[&namespace],
+                )?)
+            })
+            .context("unable to send the postgres query to list all the files")?
+            .iter()
+            .map(|row| row.try_get(0).context("invalid path in postgres"))
+            .collect()
+    }
+
+    fn get_generation(&self) -> Result<Option<u64>, anyhow::Error> {
+        let namespace = self.namespace_column();
+        let generation: Option<i64> = self
+            .client
+            .with_client(|client| {
+                Ok(client.query_opt(
+                    "SELECT generation FROM fs_synchronizer_generations WHERE namespace = $1",
+                    &[&namespace],
+                )?)
+            })
+            .context("unable to get the generation from the postgres server")?
+            .map(|row| row.try_get(0))
+            .transpose()
+            .context("invalid generation in postgres")?;
+        // no file was ever changed
+        Ok(Some(generation.unwrap_or(0) as u64))
+    }
+
+    fn get_remote_file_content(&self, path: &Path) -> Result<Option<Vec<u8>>, anyhow::Error> {
+        let path_as_str = PostgresStore::to_str(path)?;
+        let namespace = self.namespace_column();
+        let compressed_content: Option<Vec<u8>> = self
+            .client
+            .with_client(|client| {
+                Ok(client.query_opt(
+                    "SELECT content FROM fs_synchronizer_files WHERE namespace = $1 AND path = $2",
+                    &[&namespace, &path_as_str],
+                )?)
+            })
+            .context("unable to read compressed file content from postgres server")?
+            .map(|row| row.try_get(0))
+            .transpose()
+            .context("invalid content in postgres")?;
+        match compressed_content {
+            None => Ok(None),
+            Some(compressed_content) => LocalFSStore::decompress(&compressed_content).map(Some),
+        }
+    }
+
+    fn get_remote_file_hash(&self, path: &Path) -> Result<u64, anyhow::Error> {
+        let path_as_str = PostgresStore::to_str(path)?;
+        let namespace = self.namespace_column();
+        let row = self
+            .client
+            .with_client(|client| {
+                Ok(client.query_opt(
+                    "SELECT hash FROM fs_synchronizer_files WHERE namespace = $1 AND path = $2",
+                    &[&namespace, &path_as_str],
+                )?)
+            })
+            .with_context(|| {
+                format!(
+                    "unable to get on postgres server the hash of file {}",
+                    path.display()
+                )
+            })?;
+        match row {
+            None => bail!("no hash for {} in postgres", path.display()),
+            Some(row) => Ok(row
+                .try_get::<_, i64>(0)
+                .context("invalid hash in postgres")? as u64),
+        }
+    }
+
+    fn get_remote_file_compressed_size(&self, path: &Path) -> Result<u64, anyhow::Error> {
+        let path_as_str = PostgresStore::to_str(path)?;
+        let namespace = self.namespace_column();
+        let size: Option<i32> = self
+            .client
+            .with_client(|client| {
+                Ok(client.query_opt(
+                    "SELECT octet_length(content) FROM fs_synchronizer_files \
+                     WHERE namespace = $1 AND path = $2",
+                    &[&namespace, &path_as_str],
+                )?)
+            })
+            .with_context(|| format!("unable to get the size of file {}", path.display()))?
+            .map(|row| row.try_get(0))
+            .transpose()
+            .context("invalid size in postgres")?;
+        Ok(size.unwrap_or(0) as u64)
+    }
+}
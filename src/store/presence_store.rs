@@ -0,0 +1,344 @@
+use crate::client::redis_client::RedisClient;
+use crate::event_handler::sync_events::{SyncEvent, SyncEvents};
+use crate::logs::SuppressedError;
+use crate::store::namespace::Namespace;
+use anyhow::Context;
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// The peer answers to the requests for missing content by uploading it again
+pub const CAPABILITY_CONTENT_MISSING: &str = "content-missing";
+
+/// The peer reports the contents fetched which do not match their hash, and answers to these
+/// reports by uploading the content again
+pub const CAPABILITY_CONTENT_REJECTED: &str = "content-rejected";
+
+/// The peer reads the compressed event payloads
+pub const CAPABILITY_COMPRESSED_PAYLOADS: &str = "compressed-payloads";
+
+/// The peer reads the events stamped with the generation of their change
+pub const CAPABILITY_GENERATION_STAMPS: &str = "generation-stamps";
+
+/// The peer applies the contents carried in the change events, instead of ignoring the events
+pub const CAPABILITY_INLINE_CONTENTS: &str = "inline-contents";
+
+/// Protocol features supported by this build
+pub const SUPPORTED_CAPABILITIES: &[&str] = &[
+    CAPABILITY_CONTENT_MISSING,
+    CAPABILITY_CONTENT_REJECTED,
+    CAPABILITY_COMPRESSED_PAYLOADS,
+    CAPABILITY_GENERATION_STAMPS,
+    CAPABILITY_INLINE_CONTENTS,
+];
+
+/// What an instance advertises about itself to its peers
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
+pub struct PresenceRecord {
+    /// Arbitrary key=value tags given on the command line
+    pub tags: BTreeMap<String, String>,
+    /// Protocol features supported by the instance. Empty for the versions before the negotiation.
+    #[serde(default)]
+    pub capabilities: BTreeSet<String>,
+    /// Why the transfers of contents are paused, None when they are not
+    #[serde(default)]
+    pub transfers_paused: Option<String>,
+    /// State of each watched path. Empty for the versions before it was announced.
+    #[serde(default)]
+    pub roots: Vec<RootStatus>,
+    /// Stable name of the instance, e.g. its pod name. None for the versions before it was
+    /// announced.
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Tags a peer must have to apply the events of the instance. Empty when every peer applies
+    /// them, as the versions before it was announced do.
+    #[serde(default)]
+    pub audience: BTreeMap<String, String>,
+    /// The errors logged by the instance in the last aggregation window. Empty for the versions
+    /// before it was announced.
+    #[serde(default)]
+    pub errors: Vec<SuppressedError>,
+}
+
+/// State of a watched path, so that the unhealthy ones can be told apart in multi-root setups
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
+pub struct RootStatus {
+    pub path: PathBuf,
+    /// Path of the root in the store, under the remote root. None for the versions before it
+    /// was announced.
+    #[serde(default)]
+    pub remote_path: Option<PathBuf>,
+    /// Name of the fs watcher, None when the path could not be watched
+    pub watcher: Option<String>,
+    /// Unix time of the last local change under the path
+    pub last_event_at: Option<u64>,
+    /// Local changes waiting for a retry, or for the transfers to resume
+    pub pending_uploads: u64,
+    /// Failed uploads since the start
+    pub errors: u64,
+    pub last_error: Option<String>,
+}
+
+impl PresenceRecord {
+    pub fn new(
+        name: String,
+        tags: BTreeMap<String, String>,
+        audience: BTreeMap<String, String>,
+    ) -> PresenceRecord {
+        PresenceRecord {
+            tags,
+            capabilities: SUPPORTED_CAPABILITIES
+                .iter()
+                .map(|capability| capability.to_string())
+                .collect(),
+            transfers_paused: None,
+            roots: Vec::new(),
+            name: Some(name),
+            audience,
+            errors: Vec::new(),
+        }
+    }
+
+    pub fn supports(&self, capability: &str) -> bool {
+        self.capabilities.contains(capability)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PresenceStore {
+    client: RedisClient,
+    namespace: Namespace,
+}
+
+/// The presence record disappears when the instance stops refreshing it
+const PRESENCE_TTL_SECS: usize = 30;
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+/// The records change at the heartbeats: reading them again sooner brings nothing
+const PRESENCE_CACHE_TTL: Duration = HEARTBEAT_INTERVAL;
+/// An unknown instance may have just started: it is looked up again sooner
+const UNKNOWN_PRESENCE_CACHE_TTL: Duration = Duration::from_secs(1);
+
+impl PresenceStore {
+    pub fn new(client: RedisClient, namespace: Namespace) -> PresenceStore {
+        PresenceStore { client, namespace }
+    }
+
+    pub fn announce(&self, instance_id: u64, record: &PresenceRecord) -> Result<(), anyhow::Error> {
+        let serialized_record = rmp_serde::to_vec(record)
+            .expect("messagepack serialization of PresenceRecord should never fail");
+        self.client
+            .set_with_expiration(
+                &self.to_presence_key(instance_id),
+                &serialized_record,
+                PRESENCE_TTL_SECS,
+            )
+            .context("unable to send the redis command to announce the instance")
+    }
+
+    /// Returns None when the instance is not alive (or never announced itself)
+    pub fn get_presence(&self, instance_id: u64) -> Result<Option<PresenceRecord>, anyhow::Error> {
+        let serialized_record = match self
+            .client
+            .get_if_exists(&self.to_presence_key(instance_id))
+            .context("unable to get the presence record from the redis server")?
+        {
+            None => return Ok(None),
+            Some(serialized_record) => serialized_record,
+        };
+        let record = rmp_serde::from_slice(&serialized_record)
+            .context("unable to decode the presence record")?;
+        Ok(Some(record))
+    }
+
+    /// The presence records of all the alive instances, including this one
+    pub fn live_instances(&self) -> Result<Vec<(u64, PresenceRecord)>, anyhow::Error> {
+        let presence_keys = self
+            .client
+            .scan_match(&self.namespace.key("presence:*"))
+            .context("unable to list the presence records")?;
+
+        let mut instances = Vec::with_capacity(presence_keys.len());
+        for key in presence_keys {
+            let instance_id: u64 = match self
+                .namespace
+                .strip(&key)
+                .and_then(|key| key.strip_prefix("presence:"))
+                .and_then(|instance_id| instance_id.parse().ok())
+            {
+                None => continue,
+                Some(instance_id) => instance_id,
+            };
+            // the record may have expired since the listing
+            if let Some(record) = self.get_presence(instance_id)? {
+                instances.push((instance_id, record));
+            }
+        }
+        Ok(instances)
+    }
+
+    /// Refresh the presence record of this instance until the process exits, with the
+    /// state given by `current_record` at each heartbeat
+    pub fn announce_periodically(
+        self,
+        instance_id: u64,
+        current_record: impl Fn() -> PresenceRecord + Send + 'static,
+    ) -> Result<JoinHandle<()>, anyhow::Error> {
+        let handle = std::thread::Builder::new()
+            .name(String::from("presence heartbeat"))
+            .spawn(move || loop {
+                debug!("[presence] announcing instance {}", instance_id);
+                if let Err(error) = self.announce(instance_id, &current_record()) {
+                    error!("unable to announce the instance presence: {:?}", error);
+                }
+                std::thread::sleep(HEARTBEAT_INTERVAL);
+            })
+            .context("unable to create presence heartbeat thread")?;
+        Ok(handle)
+    }
+
+    /// Keep each flag set while every other live instance supports its capability, until the
+    /// process exits. The live instances are listed once per heartbeat for all the capabilities.
+    /// The flags are set before returning, so that the startup synchronization uses them.
+    pub fn follow_capabilities(
+        self,
+        instance_id: u64,
+        capabilities: Vec<(&'static str, Arc<AtomicBool>)>,
+    ) -> Result<JoinHandle<()>, anyhow::Error> {
+        self.negotiate_capabilities(instance_id, &capabilities);
+        let handle = std::thread::Builder::new()
+            .name(String::from("capability negotiation"))
+            .spawn(move || loop {
+                std::thread::sleep(HEARTBEAT_INTERVAL);
+                self.negotiate_capabilities(instance_id, &capabilities);
+            })
+            .context("unable to create capability negotiation thread")?;
+        Ok(handle)
+    }
+
+    fn negotiate_capabilities(
+        &self,
+        instance_id: u64,
+        capabilities: &[(&'static str, Arc<AtomicBool>)],
+    ) {
+        let instances = match self.live_instances() {
+            Ok(instances) => instances,
+            Err(error) => {
+                error!("unable to check the peers capabilities: {:?}", error);
+                return;
+            }
+        };
+        for (capability, supported) in capabilities.iter() {
+            let is_supported = instances
+                .iter()
+                .filter(|(peer_id, _)| *peer_id != instance_id)
+                .all(|(_, record)| record.supports(capability));
+            if supported.swap(is_supported, Ordering::SeqCst) != is_supported {
+                info!(
+                    "the {} capability is {} by every live peer",
+                    capability,
+                    if is_supported {
+                        "supported"
+                    } else {
+                        "not supported"
+                    }
+                );
+            }
+        }
+    }
+
+    /// Emit the instances joining and leaving the namespace until the process exits. The ones
+    /// live when it starts are not reported.
+    pub fn follow_peers(
+        self,
+        instance_id: u64,
+        events: SyncEvents,
+    ) -> Result<JoinHandle<()>, anyhow::Error> {
+        let handle = std::thread::Builder::new()
+            .name(String::from("peer follower"))
+            .spawn(move || {
+                let mut known_peers: Option<BTreeMap<u64, Option<String>>> = None;
+                loop {
+                    match self.live_instances() {
+                        Ok(instances) => {
+                            let live_peers: BTreeMap<u64, Option<String>> = instances
+                                .into_iter()
+                                .filter(|(peer_id, _)| *peer_id != instance_id)
+                                .map(|(peer_id, record)| (peer_id, record.name))
+                                .collect();
+                            if let Some(known_peers) = &known_peers {
+                                for (peer_id, name) in live_peers.iter() {
+                                    if !known_peers.contains_key(peer_id) {
+                                        events.emit(SyncEvent::PeerJoined {
+                                            instance_id: *peer_id,
+                                            name: name.clone(),
+                                        });
+                                    }
+                                }
+                                for (peer_id, name) in known_peers.iter() {
+                                    if !live_peers.contains_key(peer_id) {
+                                        events.emit(SyncEvent::PeerLeft {
+                                            instance_id: *peer_id,
+                                            name: name.clone(),
+                                        });
+                                    }
+                                }
+                            }
+                            known_peers = Some(live_peers);
+                        }
+                        Err(error) => error!("unable to list the live peers: {:?}", error),
+                    }
+                    std::thread::sleep(HEARTBEAT_INTERVAL);
+                }
+            })
+            .context("unable to create peer follower thread")?;
+        Ok(handle)
+    }
+
+    fn to_presence_key(&self, instance_id: u64) -> String {
+        self.namespace.key(&format!("presence:{}", instance_id))
+    }
+}
+
+/// Each record with the time it was read, None when the instance was not alive
+type CachedRecords = HashMap<u64, (Instant, Option<PresenceRecord>)>;
+
+/// The presence records read recently, so that the events filtered by their emitter do not
+/// read a record each
+#[derive(Debug, Clone)]
+pub struct PresenceCache {
+    store: PresenceStore,
+    records: Arc<Mutex<CachedRecords>>,
+}
+
+impl PresenceCache {
+    pub fn new(store: PresenceStore) -> PresenceCache {
+        PresenceCache {
+            store,
+            records: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Returns None when the instance is not alive (or never announced itself)
+    pub fn get_presence(&self, instance_id: u64) -> Result<Option<PresenceRecord>, anyhow::Error> {
+        let mut records = self.records.lock().expect("presence cache lock poisoned");
+        if let Some((read_at, record)) = records.get(&instance_id) {
+            let ttl = if record.is_some() {
+                PRESENCE_CACHE_TTL
+            } else {
+                UNKNOWN_PRESENCE_CACHE_TTL
+            };
+            if read_at.elapsed() < ttl {
+                return Ok(record.clone());
+            }
+        }
+        let record = self.store.get_presence(instance_id)?;
+        records.retain(|_, (read_at, _)| read_at.elapsed() < PRESENCE_CACHE_TTL);
+        records.insert(instance_id, (Instant::now(), record.clone()));
+        Ok(record)
+    }
+}
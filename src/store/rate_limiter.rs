@@ -0,0 +1,47 @@
+use log::debug;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Paces the transfers to a number of bytes per second on average, across the clones of the
+/// limiter. Only the gaps between the transfers are paced: a transfer is never split, as a
+/// content read in parts could mix two versions of it. A large content goes at the speed of the
+/// network, and the next transfers wait for as long as it would have taken at the limit.
+#[derive(Debug, Clone, Default)]
+pub struct RateLimiter {
+    /// 0 for no limit
+    bytes_per_second: u64,
+    /// When the next transfer may start
+    next_slot: Arc<Mutex<Option<Instant>>>,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_second: u64) -> RateLimiter {
+        RateLimiter {
+            bytes_per_second,
+            next_slot: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Wait for the turn of a transfer of this size
+    pub fn wait(&self, size: usize) {
+        if self.bytes_per_second == 0 {
+            return;
+        }
+        let now = Instant::now();
+        let slot = {
+            let mut next_slot = self.next_slot.lock().expect("rate limiter lock poisoned");
+            let slot = next_slot.map_or(now, |next_slot| next_slot.max(now));
+            let duration = Duration::from_secs_f64(size as f64 / self.bytes_per_second as f64);
+            *next_slot = Some(slot + duration);
+            slot
+        };
+        if slot > now {
+            debug!(
+                "[rate_limiter] holding a transfer of {} bytes for {:?}",
+                size,
+                slot - now
+            );
+            std::thread::sleep(slot - now);
+        }
+    }
+}
@@ -1,28 +1,548 @@
+use crate::client::event_bus::EventBus;
 use crate::client::redis_client::{RedisClient, RedisPublishPayload};
 use crate::event_handler::file_events;
+use crate::store::content_store::ContentStore;
+use crate::store::local_fs_store::{self, Compression, LocalFSStore};
+use crate::store::namespace::Namespace;
+use crate::store::rate_limiter::RateLimiter;
+use crate::store::sync_store::SyncStore;
 use anyhow::{bail, Context};
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 #[derive(Debug, Clone)]
 pub struct RedisStore {
     client: RedisClient,
+    content: Arc<dyn ContentStore>,
+    /// Where the change events are published
+    events: Arc<dyn EventBus>,
+    namespace: Namespace,
+    /// Remote hashes already read or written by this instance, shared by the clones of the store
+    hash_cache: Arc<Mutex<HashMap<PathBuf, u64>>>,
+    /// The hashes are read from redis each time when false, to save the memory of the cache
+    cache_hashes: bool,
+    /// Name under which this instance owns its authoritative prefixes. Empty when it owns none.
+    owner_name: String,
+    /// Compressed contents up to this size are carried by the events. 0 disables it.
+    inline_content_max_size: u64,
+    /// Pace the contents written to and read from the content store
+    upload_rate: RateLimiter,
+    download_rate: RateLimiter,
+    /// Whether the events carry the generation of their change, which the peers of the previous
+    /// versions cannot read
+    stamp_generations: Arc<AtomicBool>,
+    /// Whether the events carry the small contents, which the peers of the previous versions
+    /// cannot read
+    inline_contents: Arc<AtomicBool>,
+    /// Contents removed by this instance, with the time of each removal, until the keyspace
+    /// notifications of the removals are taken
+    own_removals: Arc<Mutex<HashMap<PathBuf, Vec<Instant>>>>,
 }
 
 const SET_OF_ALL_FILES_NAME: &str = "all_files";
+/// Prefix of the hash of each file, in hex
+pub const HASH_KEY_PREFIX: &str = "hash:";
+const NAMESPACE_METADATA_KEY: &str = "meta:namespace";
+/// Incremented by every change of the files metadata
+const GENERATION_KEY: &str = "meta:generation";
+/// Generation of the last change of each file, so that the late events can be told apart
+const PATH_GENERATIONS_KEY: &str = "meta:path_generations";
+
+/// Unix time of the last change of each file, so that the reconciliations of the staged
+/// instances hold the recent changes as they hold their events
+const PATH_CHANGE_TIMES_KEY: &str = "meta:path_change_times";
+/// Unix time of the last approval of the staged rollout
+const ROLLOUT_APPROVAL_KEY: &str = "meta:rollout_approval";
+
+/// Owner of each authoritative prefix, claimed by the instances at startup
+const OWNERS_KEY: &str = "meta:owners";
+/// Prefix of the claims of the uploads again of the reported contents
+const UPLOAD_AGAIN_CLAIM_PREFIX: &str = "upload-again:";
+/// A report is answered once in this delay. The reporter asks again at its next reconciliation
+/// if the claimer failed.
+const UPLOAD_AGAIN_CLAIM_SECONDS: usize = 60;
+/// The removals are forgotten after this delay, as their notifications never come when the
+/// keyspace notifications are disabled
+const OWN_REMOVAL_TTL: Duration = Duration::from_secs(60);
+
+// The metadata of a file (its hash, and its membership in the set of all files) is changed
+// atomically by these scripts. A path is in the set if and only if its hash exists, so that
+// concurrent changes of the peers cannot leave phantom or missing entries.
+// They refuse to change the paths under a prefix owned by another instance.
+
+/// Defines `foreign_owner(path)`: the owner of the path when it is not us, or nil.
+/// KEYS[#KEYS]: owners. ARGV[#ARGV]: our owner name.
+macro_rules! foreign_owner_function {
+    () => {
+        r"
+local function foreign_owner(path)
+    local owners = redis.call('HGETALL', KEYS[#KEYS])
+    for i = 1, #owners, 2 do
+        if owners[i + 1] ~= ARGV[#ARGV] and string.sub(path, 1, #owners[i]) == owners[i] then
+            return owners[i + 1]
+        end
+    end
+    return nil
+end
+"
+    };
+}
+
+/// Returns the generation of the change.
+/// KEYS: hash, all files, generation, path generations, owners. ARGV: hash value, path, owner name
+const SET_FILE_METADATA_SCRIPT: &str = concat!(
+    foreign_owner_function!(),
+    r"
+local owner = foreign_owner(ARGV[2])
+if owner then
+    return redis.error_reply(ARGV[2] .. ' is owned by ' .. owner)
+end
+redis.call('SET', KEYS[1], ARGV[1])
+redis.call('SADD', KEYS[2], ARGV[2])
+local generation = redis.call('INCR', KEYS[3])
+redis.call('HSET', KEYS[4], ARGV[2], generation)
+return generation
+"
+);
+
+/// Returns the generation of the change.
+/// KEYS: hash, all files, generation, path generations, owners. ARGV: path, owner name
+const REMOVE_FILE_METADATA_SCRIPT: &str = concat!(
+    foreign_owner_function!(),
+    r"
+local owner = foreign_owner(ARGV[1])
+if owner then
+    return redis.error_reply(ARGV[1] .. ' is owned by ' .. owner)
+end
+redis.call('DEL', KEYS[1])
+redis.call('SREM', KEYS[2], ARGV[1])
+redis.call('HDEL', KEYS[4], ARGV[1])
+return redis.call('INCR', KEYS[3])
+"
+);
+
+/// Returns the generation of the change.
+/// KEYS: old hash, new hash, all files, generation, path generations, owners.
+/// ARGV: old path, new path, owner name
+const RENAME_FILE_METADATA_SCRIPT: &str = concat!(
+    foreign_owner_function!(),
+    r"
+for i = 1, 2 do
+    local owner = foreign_owner(ARGV[i])
+    if owner then
+        return redis.error_reply(ARGV[i] .. ' is owned by ' .. owner)
+    end
+end
+if redis.call('EXISTS', KEYS[1]) == 1 then
+    redis.call('RENAME', KEYS[1], KEYS[2])
+end
+redis.call('SREM', KEYS[3], ARGV[1])
+redis.call('HDEL', KEYS[5], ARGV[1])
+local generation = redis.call('INCR', KEYS[4])
+if redis.call('EXISTS', KEYS[2]) == 1 then
+    redis.call('SADD', KEYS[3], ARGV[2])
+    redis.call('HSET', KEYS[5], ARGV[2], generation)
+end
+return generation
+"
+);
+
+/// Claim the prefixes, unless one of them overlaps a prefix of another owner.
+/// KEYS: owners. ARGV: owner name, then the prefixes
+const CLAIM_PREFIXES_SCRIPT: &str = r"
+local owners = redis.call('HGETALL', KEYS[1])
+for i = 2, #ARGV do
+    for j = 1, #owners, 2 do
+        local prefix, owner = owners[j], owners[j + 1]
+        if owner ~= ARGV[1] and (string.sub(ARGV[i], 1, #prefix) == prefix
+                or string.sub(prefix, 1, #ARGV[i]) == ARGV[i]) then
+            return redis.error_reply(ARGV[i] .. ' overlaps ' .. prefix .. ' owned by ' .. owner)
+        end
+    end
+end
+for i = 2, #ARGV do
+    redis.call('HSET', KEYS[1], ARGV[i], ARGV[1])
+end
+return #ARGV - 1
+";
+
+/// Settings shared by every instance of the namespace. Peers disagreeing on them would
+/// produce garbled data, so they are written once and checked by every joining instance.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct NamespaceMetadata {
+    pub schema_version: u32,
+    pub compression: String,
+    pub hash_algorithm: String,
+    pub encryption: bool,
+    /// Backend of the file contents
+    #[serde(default = "NamespaceMetadata::default_content_backend")]
+    pub content_backend: String,
+    /// Informative only, not checked
+    pub created_by: String,
+}
+
+impl NamespaceMetadata {
+    pub const SCHEMA_VERSION: u32 = 1;
+
+    /// The settings used by this build
+    pub fn current(
+        created_by: String,
+        content_backend: &str,
+        compression: Compression,
+    ) -> NamespaceMetadata {
+        NamespaceMetadata {
+            schema_version: NamespaceMetadata::SCHEMA_VERSION,
+            compression: compression.name().to_string(),
+            hash_algorithm: String::from(local_fs_store::HASH_ALGORITHM),
+            encryption: false,
+            content_backend: content_backend.to_string(),
+            created_by,
+        }
+    }
+
+    /// Namespaces created before the content backends always stored contents in redis
+    fn default_content_backend() -> String {
+        String::from("redis")
+    }
+
+    pub fn incompatibilities(&self, other: &NamespaceMetadata) -> Vec<String> {
+        let mut incompatibilities = Vec::new();
+        if self.schema_version != other.schema_version {
+            incompatibilities.push(format!(
+                "schema version {} != {}",
+                self.schema_version, other.schema_version
+            ));
+        }
+        // every codec is read, and the contents are compressed with the namespace one
+        if self.compression.parse::<Compression>().is_err() {
+            incompatibilities.push(format!("unknown compression {}", self.compression));
+        }
+        if self.hash_algorithm == local_fs_store::LEGACY_HASH_ALGORITHM
+            && other.hash_algorithm == local_fs_store::HASH_ALGORITHM
+        {
+            incompatibilities.push(format!(
+                "hash algorithm {} != {}, run `migrate-hashes` first",
+                self.hash_algorithm, other.hash_algorithm
+            ));
+        } else if self.hash_algorithm != other.hash_algorithm {
+            incompatibilities.push(format!(
+                "hash algorithm {} != {}",
+                self.hash_algorithm, other.hash_algorithm
+            ));
+        }
+        if self.encryption != other.encryption {
+            incompatibilities.push(format!(
+                "encryption {} != {}",
+                self.encryption, other.encryption
+            ));
+        }
+        if self.content_backend != other.content_backend {
+            incompatibilities.push(format!(
+                "content backend {} != {}",
+                self.content_backend, other.content_backend
+            ));
+        }
+        incompatibilities
+    }
+}
 
 impl RedisStore {
-    pub fn new(client: RedisClient) -> RedisStore {
-        RedisStore { client }
+    pub fn new(
+        client: RedisClient,
+        content: Arc<dyn ContentStore>,
+        events: Arc<dyn EventBus>,
+        namespace: Namespace,
+        owner_name: Option<String>,
+        inline_content_max_size: u64,
+        cache_hashes: bool,
+    ) -> RedisStore {
+        RedisStore {
+            client,
+            content,
+            events,
+            namespace,
+            hash_cache: Arc::new(Mutex::new(HashMap::new())),
+            cache_hashes,
+            owner_name: owner_name.unwrap_or_default(),
+            inline_content_max_size,
+            upload_rate: RateLimiter::default(),
+            download_rate: RateLimiter::default(),
+            stamp_generations: Arc::new(AtomicBool::new(false)),
+            inline_contents: Arc::new(AtomicBool::new(false)),
+            own_removals: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Limit the bandwidth of the contents uploaded and downloaded, so that a bulk
+    /// synchronization does not saturate the network or the store
+    pub fn with_rate_limits(
+        mut self,
+        upload_rate: RateLimiter,
+        download_rate: RateLimiter,
+    ) -> RedisStore {
+        self.upload_rate = upload_rate;
+        self.download_rate = download_rate;
+        self
+    }
+
+    /// Stamp the events with the generation of their change while `peers_support` is set, so
+    /// that the peers drop the events arriving after a newer change of their files
+    pub fn with_generation_stamps(mut self, peers_support: Arc<AtomicBool>) -> RedisStore {
+        self.stamp_generations = peers_support;
+        self
+    }
+
+    /// Carry the contents up to the --inline-content-max-size in the events while
+    /// `peers_support` is set
+    pub fn with_inline_contents(mut self, peers_support: Arc<AtomicBool>) -> RedisStore {
+        self.inline_contents = peers_support;
+        self
+    }
+
+    /// Generation of the last change of the file, None when it is not in the store or was
+    /// changed by a version which did not record it
+    pub fn get_path_generation(&self, path: &Path) -> Result<Option<u64>, anyhow::Error> {
+        self.client
+            .hget(
+                &self.namespace.key(PATH_GENERATIONS_KEY),
+                &path.to_string_lossy(),
+            )
+            .context("unable to get the generation of the file from the redis server")?
+            .map(|generation| {
+                generation
+                    .parse()
+                    .context("unable to parse redis value to a correct generation")
+            })
+            .transpose()
+    }
+
+    /// Become the only instance allowed to change the paths starting with these prefixes.
+    /// The other instances only pull them. Claiming again our own prefixes is a no-op.
+    pub fn claim_authoritative_prefixes(&self, prefixes: &[String]) -> Result<(), anyhow::Error> {
+        if self.owner_name.is_empty() {
+            bail!("an owner name is required to claim authoritative prefixes");
+        }
+        if prefixes.iter().any(|prefix| prefix.is_empty()) {
+            bail!("authoritative prefixes cannot be empty");
+        }
+        let mut args = vec![self.owner_name.clone()];
+        args.extend(prefixes.iter().cloned());
+        self.client
+            .eval(
+                CLAIM_PREFIXES_SCRIPT,
+                &[&self.namespace.key(OWNERS_KEY)],
+                &args,
+            )
+            .context("unable to claim the authoritative prefixes")?;
+        info!(
+            "{} owns the authoritative prefixes {:?}",
+            self.owner_name, prefixes
+        );
+        Ok(())
+    }
+
+    /// Write the namespace metadata if this is the first instance using the namespace,
+    /// otherwise ensure that the existing one is compatible with ours. Returns the metadata of
+    /// the namespace.
+    pub fn ensure_namespace_metadata(
+        &self,
+        metadata: &NamespaceMetadata,
+    ) -> Result<NamespaceMetadata, anyhow::Error> {
+        let serialized_metadata = rmp_serde::to_vec(metadata)
+            .expect("messagepack serialization of NamespaceMetadata should never fail");
+        let is_created = self
+            .client
+            .set_if_not_exists(
+                &self.namespace.key(NAMESPACE_METADATA_KEY),
+                &serialized_metadata,
+            )
+            .context("unable to send the redis command to create the namespace metadata")?;
+        if is_created {
+            info!("namespace metadata created: {:?}", metadata);
+            return Ok(metadata.clone());
+        }
+
+        let remote_metadata = self.get_namespace_metadata()?;
+        debug!("[redis_store] namespace metadata: {:?}", remote_metadata);
+
+        let incompatibilities = remote_metadata.incompatibilities(metadata);
+        if !incompatibilities.is_empty() {
+            bail!(
+                "this instance is incompatible with the namespace created by {}: {}",
+                remote_metadata.created_by,
+                incompatibilities.join(", ")
+            );
+        }
+        Ok(remote_metadata)
+    }
+
+    pub fn get_namespace_metadata(&self) -> Result<NamespaceMetadata, anyhow::Error> {
+        let serialized_metadata = self
+            .client
+            .get(&self.namespace.key(NAMESPACE_METADATA_KEY))
+            .context("unable to get the namespace metadata")?;
+        rmp_serde::from_slice(&serialized_metadata).context(
+            "unable to decode the namespace metadata. Was it written by an incompatible version ?",
+        )
+    }
+
+    /// Make the instances compress the contents with this codec once they restart. The
+    /// instances of the versions knowing only snappy refuse to start on a zstd namespace.
+    pub fn set_namespace_compression(&self, compression: Compression) -> Result<(), anyhow::Error> {
+        let mut metadata = self
+            .get_namespace_metadata()
+            .context("the namespace has no metadata: start an instance on it first")?;
+        metadata.compression = compression.name().to_string();
+        let serialized_metadata = rmp_serde::to_vec(&metadata)
+            .expect("messagepack serialization of NamespaceMetadata should never fail");
+        self.client
+            .set(
+                &self.namespace.key(NAMESPACE_METADATA_KEY),
+                &serialized_metadata,
+            )
+            .context("unable to update the namespace metadata")
+    }
+
+    /// Make the namespace usable by the instances hashing with the current algorithm, once the
+    /// hashes of its files are migrated to it
+    pub fn set_namespace_hash_algorithm(&self) -> Result<(), anyhow::Error> {
+        let mut metadata = self
+            .get_namespace_metadata()
+            .context("the namespace has no metadata: start an instance on it first")?;
+        metadata.hash_algorithm = String::from(local_fs_store::HASH_ALGORITHM);
+        let serialized_metadata = rmp_serde::to_vec(&metadata)
+            .expect("messagepack serialization of NamespaceMetadata should never fail");
+        self.client
+            .set(
+                &self.namespace.key(NAMESPACE_METADATA_KEY),
+                &serialized_metadata,
+            )
+            .context("unable to update the namespace metadata")
+    }
+
+    /// Tell the staged instances to apply the events they are holding. The approval is recorded
+    /// for the instances which restart meanwhile.
+    pub fn approve_rollout(&self, emitter_id: u64) -> Result<(), anyhow::Error> {
+        self.client
+            .set(
+                &self.namespace.key(ROLLOUT_APPROVAL_KEY),
+                unix_time().to_string().as_bytes(),
+            )
+            .context("unable to record the rollout approval")?;
+        self.events
+            .publish(
+                &self.namespace.key(file_events::FILE_EVENT),
+                RedisPublishPayload::RolloutApproved(emitter_id),
+            )
+            .context("unable to send the redis command to approve the rollout")
+    }
+
+    fn cached_hashes(&self) -> std::sync::MutexGuard<'_, HashMap<PathBuf, u64>> {
+        self.hash_cache.lock().expect("hash cache lock poisoned")
+    }
+
+    fn own_removals(&self) -> std::sync::MutexGuard<'_, HashMap<PathBuf, Vec<Instant>>> {
+        self.own_removals
+            .lock()
+            .expect("own removals lock poisoned")
+    }
+
+    /// Record a removal of the content of a file, made before the removal so that its
+    /// notification cannot arrive first
+    fn record_own_removal(&self, path: &Path) {
+        let mut own_removals = self.own_removals();
+        own_removals.retain(|_, removals| {
+            removals.retain(|removed| removed.elapsed() < OWN_REMOVAL_TTL);
+            !removals.is_empty()
+        });
+        own_removals
+            .entry(path.to_path_buf())
+            .or_default()
+            .push(Instant::now());
+    }
+
+    fn record_change_time(&self, path: &str) -> Result<(), anyhow::Error> {
+        self.client.hset(
+            &self.namespace.key(PATH_CHANGE_TIMES_KEY),
+            path,
+            unix_time().to_string().as_bytes(),
+        )
+    }
+
+    fn cache_hash(&self, path: PathBuf, hash: u64) {
+        if self.cache_hashes {
+            self.cached_hashes().insert(path, hash);
+        }
+    }
+
+    /// Publish a change, with the generation it got in the store when every live peer reads it
+    fn publish_change(
+        &self,
+        generation: u64,
+        payload: RedisPublishPayload,
+    ) -> Result<(), anyhow::Error> {
+        let payload = if self.stamp_generations.load(Ordering::SeqCst) {
+            RedisPublishPayload::Stamped(generation, Box::new(payload))
+        } else {
+            payload
+        };
+        self.events
+            .publish(&self.namespace.key(file_events::FILE_EVENT), payload)
+    }
+
+    /// Set the hash and the membership of a new or modified file, and returns the generation of
+    /// the change
+    fn set_file_metadata(&self, path: &str, hash: u64) -> Result<u64, anyhow::Error> {
+        self.client
+            .eval(
+                SET_FILE_METADATA_SCRIPT,
+                &[
+                    &self.to_hash_key(path),
+                    &self.namespace.key(SET_OF_ALL_FILES_NAME),
+                    &self.namespace.key(GENERATION_KEY),
+                    &self.namespace.key(PATH_GENERATIONS_KEY),
+                    &self.namespace.key(OWNERS_KEY),
+                ],
+                &[
+                    LocalFSStore::format_hash(hash),
+                    path.to_string(),
+                    self.owner_name.clone(),
+                ],
+            )
+            .map(|generation| generation as u64)
+    }
+
+    /// The content to carry in the event, when it is small enough for the peers to skip the fetch
+    fn inline_content(&self, path: &str, content: &[u8]) -> Option<Vec<u8>> {
+        let is_inlined = self.inline_content_max_size > 0
+            && content.len() as u64 <= self.inline_content_max_size
+            && self.inline_contents.load(Ordering::SeqCst)
+            && self.content.is_inlinable(path);
+        if is_inlined {
+            Some(content.to_vec())
+        } else {
+            None
+        }
     }
 
-    pub fn new_file(
+    fn to_hash_key(&self, path: &str) -> String {
+        self.namespace.key(&format!("{}{}", HASH_KEY_PREFIX, path))
+    }
+}
+
+impl SyncStore for RedisStore {
+    fn new_file(
         &self,
         emitter_id: u64,
         path: PathBuf,
         content: &[u8],
         hash: u64,
     ) -> Result<(), anyhow::Error> {
-        let publish_value = RedisPublishPayload::NewFile(emitter_id, hash, path.clone());
         let path_as_str = match path.to_str() {
             None => bail!(
                 "path is not valid UTF-8 string. Unable to synchronize this file. Path: {:?}",
@@ -30,26 +550,33 @@ impl RedisStore {
             ),
             Some(path_as_str) => path_as_str,
         };
-        self.client
-            .in_transaction(|| {
-                self.client
-                    .set(&self.to_hash_key(path_as_str), hash.to_string().as_bytes())?;
-                self.client
-                    .set(&self.to_content_key(path_as_str), &content)?;
-                self.client.sadd(SET_OF_ALL_FILES_NAME, path_as_str)?;
-                self.client.publish(file_events::FILE_EVENT, publish_value)
+        let publish_value = match self.inline_content(path_as_str, content) {
+            None => RedisPublishPayload::NewFile(emitter_id, hash, path.clone()),
+            Some(content) => {
+                RedisPublishPayload::InlineNewFile(emitter_id, hash, path.clone(), content)
+            }
+        };
+        // the hash first, so that the keyspace notification of the content sees the new hash,
+        // and the event last, so that the peers find the content
+        self.set_file_metadata(path_as_str, hash)
+            .and_then(|generation| {
+                self.upload_rate.wait(content.len());
+                self.content.set_content(path_as_str, content)?;
+                self.record_change_time(path_as_str)?;
+                self.publish_change(generation, publish_value)
             })
-            .context("unable to send redis commands to set new file")
+            .context("unable to send redis commands to set new file")?;
+        self.cache_hash(path, hash);
+        Ok(())
     }
 
-    pub fn modified_file(
+    fn modified_file(
         &self,
         emitter_id: u64,
         path: PathBuf,
         content: &[u8],
         hash: u64,
     ) -> Result<(), anyhow::Error> {
-        let publish_value = RedisPublishPayload::ModifiedFile(emitter_id, hash, path.clone());
         let path_as_str = match path.to_str() {
             None => bail!(
                 "path is not valid UTF-8 string. Unable to synchronize this file. Path: {:?}",
@@ -57,19 +584,26 @@ impl RedisStore {
             ),
             Some(path_as_str) => path_as_str,
         };
+        let publish_value = match self.inline_content(path_as_str, content) {
+            None => RedisPublishPayload::ModifiedFile(emitter_id, hash, path.clone()),
+            Some(content) => {
+                RedisPublishPayload::InlineModifiedFile(emitter_id, hash, path.clone(), content)
+            }
+        };
 
-        self.client
-            .in_transaction(|| {
-                self.client
-                    .set(&self.to_hash_key(path_as_str), hash.to_string().as_bytes())?;
-                self.client
-                    .set(&self.to_content_key(path_as_str), &content)?;
-                self.client.publish(file_events::FILE_EVENT, publish_value)
+        self.set_file_metadata(path_as_str, hash)
+            .and_then(|generation| {
+                self.upload_rate.wait(content.len());
+                self.content.set_content(path_as_str, content)?;
+                self.record_change_time(path_as_str)?;
+                self.publish_change(generation, publish_value)
             })
-            .context("unable to send the redis commands to modify the file")
+            .context("unable to send the redis commands to modify the file")?;
+        self.cache_hash(path, hash);
+        Ok(())
     }
 
-    pub fn renamed_file(
+    fn renamed_file(
         &self,
         emitter_id: u64,
         old_path: PathBuf,
@@ -86,23 +620,41 @@ impl RedisStore {
         };
 
         self.client
-            .in_transaction(|| {
-                self.client.rename(
+            .eval(
+                RENAME_FILE_METADATA_SCRIPT,
+                &[
                     &self.to_hash_key(old_path_as_str),
                     &self.to_hash_key(new_path_as_str),
-                )?;
-                self.client.rename(
-                    &self.to_content_key(old_path_as_str),
-                    &self.to_content_key(new_path_as_str),
-                )?;
+                    &self.namespace.key(SET_OF_ALL_FILES_NAME),
+                    &self.namespace.key(GENERATION_KEY),
+                    &self.namespace.key(PATH_GENERATIONS_KEY),
+                    &self.namespace.key(OWNERS_KEY),
+                ],
+                &[
+                    old_path_as_str.to_string(),
+                    new_path_as_str.to_string(),
+                    self.owner_name.clone(),
+                ],
+            )
+            .and_then(|generation| {
+                self.record_own_removal(&old_path);
+                self.content
+                    .rename_content(old_path_as_str, new_path_as_str)?;
                 self.client
-                    .smove(SET_OF_ALL_FILES_NAME, old_path_as_str, new_path_as_str)?;
-                self.client.publish(file_events::FILE_EVENT, publish_value)
+                    .hdel(&self.namespace.key(PATH_CHANGE_TIMES_KEY), old_path_as_str)?;
+                self.record_change_time(new_path_as_str)?;
+                self.publish_change(generation as u64, publish_value)
             })
-            .context("unable to sned the redis commands to rename file")
+            .context("unable to sned the redis commands to rename file")?;
+        let mut cached_hashes = self.cached_hashes();
+        match cached_hashes.remove(&old_path) {
+            Some(hash) => cached_hashes.insert(new_path, hash),
+            None => cached_hashes.remove(&new_path),
+        };
+        Ok(())
     }
 
-    pub fn removed_file(&self, emitter_id: u64, path: PathBuf) -> Result<(), anyhow::Error> {
+    fn removed_file(&self, emitter_id: u64, path: PathBuf) -> Result<(), anyhow::Error> {
         let publish_value = RedisPublishPayload::RemovedFile(emitter_id, path.clone());
         let path_as_str = match path.to_str() {
             None => bail!(
@@ -112,36 +664,138 @@ impl RedisStore {
             Some(path_as_str) => path_as_str,
         };
         self.client
-            .in_transaction(|| {
-                self.client.remove(&self.to_hash_key(path_as_str))?;
-                self.client.remove(&self.to_content_key(path_as_str))?;
-                self.client.srem(SET_OF_ALL_FILES_NAME, path_as_str)?;
-                self.client.publish(file_events::FILE_EVENT, publish_value)
+            .eval(
+                REMOVE_FILE_METADATA_SCRIPT,
+                &[
+                    &self.to_hash_key(path_as_str),
+                    &self.namespace.key(SET_OF_ALL_FILES_NAME),
+                    &self.namespace.key(GENERATION_KEY),
+                    &self.namespace.key(PATH_GENERATIONS_KEY),
+                    &self.namespace.key(OWNERS_KEY),
+                ],
+                &[path_as_str.to_string(), self.owner_name.clone()],
+            )
+            .and_then(|generation| {
+                self.record_own_removal(&path);
+                self.content.remove_content(path_as_str)?;
+                self.client
+                    .hdel(&self.namespace.key(PATH_CHANGE_TIMES_KEY), path_as_str)?;
+                self.publish_change(generation as u64, publish_value)
             })
-            .context("unable to send the redis commands to remove file")
+            .context("unable to send the redis commands to remove file")?;
+        self.cached_hashes().remove(&path);
+        Ok(())
+    }
+
+    fn request_missing_content(&self, emitter_id: u64, path: PathBuf) -> Result<(), anyhow::Error> {
+        let publish_value = RedisPublishPayload::ContentMissing(emitter_id, path);
+        self.events
+            .publish(&self.namespace.key(file_events::FILE_EVENT), publish_value)
+            .context("unable to send the redis command to request missing content")
     }
 
-    pub fn get_all_remote_files(&self) -> Result<Vec<String>, anyhow::Error> {
+    fn reject_content(
+        &self,
+        emitter_id: u64,
+        path: PathBuf,
+        reason: String,
+    ) -> Result<(), anyhow::Error> {
+        let publish_value = RedisPublishPayload::ContentRejected(emitter_id, path, reason);
+        self.events
+            .publish(&self.namespace.key(file_events::FILE_EVENT), publish_value)
+            .context("unable to send the redis command to reject the content")
+    }
+
+    fn get_changed_since(&self, since: u64) -> Result<Vec<(PathBuf, u64)>, anyhow::Error> {
+        let change_times = self
+            .client
+            .hgetall(&self.namespace.key(PATH_CHANGE_TIMES_KEY))
+            .context("unable to get the change times of the files")?;
+        Ok(change_times
+            .into_iter()
+            .filter_map(|(path, changed_at)| {
+                let changed_at: u64 = String::from_utf8_lossy(&changed_at).parse().ok()?;
+                (changed_at > since).then(|| (PathBuf::from(path), changed_at))
+            })
+            .collect())
+    }
+
+    fn get_rollout_approval(&self) -> Result<Option<u64>, anyhow::Error> {
+        let approval = self
+            .client
+            .get_if_exists(&self.namespace.key(ROLLOUT_APPROVAL_KEY))
+            .context("unable to get the rollout approval")?;
+        Ok(approval.and_then(|approved_at| String::from_utf8_lossy(&approved_at).parse().ok()))
+    }
+
+    fn take_own_removal(&self, path: &Path) -> bool {
+        let mut own_removals = self.own_removals();
+        let removals = match own_removals.get_mut(path) {
+            None => return false,
+            Some(removals) => removals,
+        };
+        removals.retain(|removed| removed.elapsed() < OWN_REMOVAL_TTL);
+        let is_own_removal = !removals.is_empty();
+        if is_own_removal {
+            removals.remove(0);
+        }
+        if removals.is_empty() {
+            own_removals.remove(path);
+        }
+        is_own_removal
+    }
+
+    fn claim_upload_again(&self, path: &Path, claimer_id: u64) -> Result<bool, anyhow::Error> {
         self.client
-            .smembers(SET_OF_ALL_FILES_NAME)
+            .set_if_not_exists_with_expiration(
+                &self.namespace.key(&format!(
+                    "{}{}",
+                    UPLOAD_AGAIN_CLAIM_PREFIX,
+                    path.to_string_lossy()
+                )),
+                claimer_id.to_string().as_bytes(),
+                UPLOAD_AGAIN_CLAIM_SECONDS,
+            )
+            .context("unable to send the redis command to claim the upload again")
+    }
+
+    fn get_all_remote_files(&self) -> Result<Vec<String>, anyhow::Error> {
+        self.client
+            .smembers(&self.namespace.key(SET_OF_ALL_FILES_NAME))
             .context("unable to send the redis command to list all the files")
     }
 
-    pub fn get_remote_file_content(&self, path: &Path) -> Result<Vec<u8>, anyhow::Error> {
-        let mut contents: Vec<u8> = Vec::with_capacity(8196);
+    fn get_generation(&self) -> Result<Option<u64>, anyhow::Error> {
+        let generation = match self
+            .client
+            .get_if_exists(&self.namespace.key(GENERATION_KEY))
+            .context("unable to get the generation from the redis server")?
         {
-            let compressed_content = self
-                .client
-                .get(&self.to_content_key(&path.to_string_lossy()))
-                .context("unable to read compressed file content from redis server")?;
-            let mut decompressing_writer = snap::read::FrameDecoder::new(&*compressed_content);
-            std::io::copy(&mut decompressing_writer, &mut contents)
-                .context("error when decoding compressed content")?;
-        }
-        Ok(contents)
+            // no file was ever changed
+            None => 0,
+            Some(raw_generation) => String::from_utf8_lossy(&raw_generation)
+                .parse()
+                .context("unable to parse redis value to a correct generation")?,
+        };
+        Ok(Some(generation))
+    }
+
+    fn get_remote_file_content(&self, path: &Path) -> Result<Option<Vec<u8>>, anyhow::Error> {
+        let compressed_content = match self.content.get_content(&path.to_string_lossy())? {
+            None => return Ok(None),
+            Some(compressed_content) => compressed_content,
+        };
+        // the size is only known once read: the next downloads wait for this one
+        self.download_rate.wait(compressed_content.len());
+        LocalFSStore::decompress(&compressed_content).map(Some)
     }
 
-    pub fn get_remote_file_hash(&self, path: &Path) -> Result<u64, anyhow::Error> {
+    fn get_remote_file_hash(&self, path: &Path) -> Result<u64, anyhow::Error> {
+        if let Some(hash) = self.cached_hashes().get(path) {
+            debug!("[redis_store] hash of {} found in cache", path.display());
+            return Ok(*hash);
+        }
+
         let raw_num = self
             .client
             .get(&self.to_hash_key(&path.to_string_lossy()))
@@ -151,18 +805,31 @@ impl RedisStore {
                     &path.display()
                 )
             })?;
-        let str_num = String::from_utf8_lossy(&raw_num);
-        let hash: u64 = str_num
-            .parse()
+        let hash = LocalFSStore::parse_hash(&String::from_utf8_lossy(&raw_num))
             .context("unable to parse redis value to a correct hash")?;
+        self.cache_hash(path.to_path_buf(), hash);
         Ok(hash)
     }
 
-    fn to_hash_key(&self, path: &str) -> String {
-        format!("hash:{}", path)
+    fn invalidate_cached_hash(&self, path: &Path) {
+        self.cached_hashes().remove(path);
     }
 
-    fn to_content_key(&self, path: &str) -> String {
-        format!("content:{}", path)
+    fn invalidate_all_cached_hashes(&self) {
+        debug!("[redis_store] clearing the hash cache");
+        self.cached_hashes().clear();
     }
+
+    fn get_remote_file_compressed_size(&self, path: &Path) -> Result<u64, anyhow::Error> {
+        self.content
+            .content_size(&path.to_string_lossy())
+            .with_context(|| format!("unable to get the size of file {}", &path.display()))
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
 }
@@ -0,0 +1,242 @@
+use crate::store::sync_store::SyncStore;
+use anyhow::Context;
+use std::path::{Component, Path, PathBuf};
+
+/// Where the local files are in the store: the paths under the local root are stored relative
+/// to it, under the remote root, so that machines with different layouts (e.g. different home
+/// directories) synchronize the same tree. The local paths are stored as they are by default.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RootMapping {
+    /// Local root, then remote root
+    roots: Option<(PathBuf, PathBuf)>,
+}
+
+impl RootMapping {
+    pub fn new(local_root: PathBuf, remote_root: PathBuf) -> RootMapping {
+        RootMapping {
+            roots: Some((local_root, remote_root)),
+        }
+    }
+
+    /// The path of a local file in the store, or None when it is not under the local root
+    pub fn to_remote(&self, local_path: &Path) -> Option<PathBuf> {
+        match &self.roots {
+            None => Some(local_path.to_path_buf()),
+            Some((local_root, remote_root)) => local_path
+                .strip_prefix(local_root)
+                .ok()
+                .map(|relative_path| remote_root.join(relative_path)),
+        }
+    }
+
+    /// The local path of a file of the store, or None when it is not under the remote root
+    pub fn to_local(&self, remote_path: &Path) -> Option<PathBuf> {
+        let (local_root, remote_root) = match &self.roots {
+            None => return Some(remote_path.to_path_buf()),
+            Some(roots) => roots,
+        };
+        let relative_path = remote_path.strip_prefix(remote_root).ok()?;
+        // an absolute path, or `..`, written by a peer would escape the local root
+        if !relative_path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+        {
+            return None;
+        }
+        Some(local_root.join(relative_path))
+    }
+}
+
+/// Given the local paths, stores the files under their path in the store, and lists the files
+/// of the store under their local path. The files out of the remote root are not listed.
+#[derive(Debug, Clone)]
+pub struct RootMappedStore<S: SyncStore + Clone> {
+    store: S,
+    roots: RootMapping,
+}
+
+impl<S: SyncStore + Clone> RootMappedStore<S> {
+    pub fn new(store: S, roots: RootMapping) -> RootMappedStore<S> {
+        RootMappedStore { store, roots }
+    }
+
+    fn remote_path(&self, path: &Path) -> Result<PathBuf, anyhow::Error> {
+        self.roots
+            .to_remote(path)
+            .with_context(|| format!("{} is not under the --local-root", path.display()))
+    }
+}
+
+impl<S: SyncStore + Clone> SyncStore for RootMappedStore<S> {
+    fn new_file(
+        &self,
+        emitter_id: u64,
+        path: PathBuf,
+        content: &[u8],
+        hash: u64,
+    ) -> Result<(), anyhow::Error> {
+        self.store
+            .new_file(emitter_id, self.remote_path(&path)?, content, hash)
+    }
+
+    fn modified_file(
+        &self,
+        emitter_id: u64,
+        path: PathBuf,
+        content: &[u8],
+        hash: u64,
+    ) -> Result<(), anyhow::Error> {
+        self.store
+            .modified_file(emitter_id, self.remote_path(&path)?, content, hash)
+    }
+
+    fn renamed_file(
+        &self,
+        emitter_id: u64,
+        old_path: PathBuf,
+        new_path: PathBuf,
+    ) -> Result<(), anyhow::Error> {
+        self.store.renamed_file(
+            emitter_id,
+            self.remote_path(&old_path)?,
+            self.remote_path(&new_path)?,
+        )
+    }
+
+    fn removed_file(&self, emitter_id: u64, path: PathBuf) -> Result<(), anyhow::Error> {
+        self.store
+            .removed_file(emitter_id, self.remote_path(&path)?)
+    }
+
+    fn request_missing_content(&self, emitter_id: u64, path: PathBuf) -> Result<(), anyhow::Error> {
+        self.store
+            .request_missing_content(emitter_id, self.remote_path(&path)?)
+    }
+
+    fn reject_content(
+        &self,
+        emitter_id: u64,
+        path: PathBuf,
+        reason: String,
+    ) -> Result<(), anyhow::Error> {
+        self.store
+            .reject_content(emitter_id, self.remote_path(&path)?, reason)
+    }
+
+    fn claim_upload_again(&self, path: &Path, claimer_id: u64) -> Result<bool, anyhow::Error> {
+        self.store
+            .claim_upload_again(&self.remote_path(path)?, claimer_id)
+    }
+
+    fn take_own_removal(&self, path: &Path) -> bool {
+        self.remote_path(path)
+            .is_ok_and(|remote_path| self.store.take_own_removal(&remote_path))
+    }
+
+    fn get_all_remote_files(&self) -> Result<Vec<String>, anyhow::Error> {
+        Ok(self
+            .store
+            .get_all_remote_files()?
+            .into_iter()
+            .filter_map(|path| self.roots.to_local(Path::new(&path)))
+            .map(|path| path.to_string_lossy().into_owned())
+            .collect())
+    }
+
+    fn get_changed_since(&self, since: u64) -> Result<Vec<(PathBuf, u64)>, anyhow::Error> {
+        Ok(self
+            .store
+            .get_changed_since(since)?
+            .into_iter()
+            .filter_map(|(path, changed_at)| {
+                self.roots
+                    .to_local(&path)
+                    .map(|local_path| (local_path, changed_at))
+            })
+            .collect())
+    }
+
+    fn get_rollout_approval(&self) -> Result<Option<u64>, anyhow::Error> {
+        self.store.get_rollout_approval()
+    }
+
+    fn get_generation(&self) -> Result<Option<u64>, anyhow::Error> {
+        self.store.get_generation()
+    }
+
+    fn get_remote_file_content(&self, path: &Path) -> Result<Option<Vec<u8>>, anyhow::Error> {
+        self.store.get_remote_file_content(&self.remote_path(path)?)
+    }
+
+    fn get_remote_file_hash(&self, path: &Path) -> Result<u64, anyhow::Error> {
+        self.store.get_remote_file_hash(&self.remote_path(path)?)
+    }
+
+    fn invalidate_cached_hash(&self, path: &Path) {
+        if let Some(remote_path) = self.roots.to_remote(path) {
+            self.store.invalidate_cached_hash(&remote_path)
+        }
+    }
+
+    fn invalidate_all_cached_hashes(&self) {
+        self.store.invalidate_all_cached_hashes()
+    }
+
+    fn get_remote_file_compressed_size(&self, path: &Path) -> Result<u64, anyhow::Error> {
+        self.store
+            .get_remote_file_compressed_size(&self.remote_path(path)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapping() -> RootMapping {
+        RootMapping::new(PathBuf::from("/home/alice"), PathBuf::from("/shared"))
+    }
+
+    #[test]
+    fn stores_the_local_paths_under_the_remote_root() {
+        assert_eq!(
+            mapping().to_remote(Path::new("/home/alice/docs/notes.txt")),
+            Some(PathBuf::from("/shared/docs/notes.txt"))
+        );
+        assert_eq!(mapping().to_remote(Path::new("/home/bob/notes.txt")), None);
+    }
+
+    #[test]
+    fn applies_the_remote_paths_under_the_local_root() {
+        assert_eq!(
+            mapping().to_local(Path::new("/shared/docs/notes.txt")),
+            Some(PathBuf::from("/home/alice/docs/notes.txt"))
+        );
+        assert_eq!(mapping().to_local(Path::new("/other/notes.txt")), None);
+    }
+
+    #[test]
+    fn refuses_the_remote_paths_escaping_the_local_root() {
+        assert_eq!(mapping().to_local(Path::new("/shared/../etc/passwd")), None);
+        assert_eq!(
+            mapping().to_local(Path::new("/shared/docs/../../etc/passwd")),
+            None
+        );
+        // the remote root is the empty path when only the local root is given
+        let mapping = RootMapping::new(PathBuf::from("/home/alice"), PathBuf::new());
+        assert_eq!(mapping.to_local(Path::new("/etc/passwd")), None);
+    }
+
+    #[test]
+    fn maps_the_paths_back_and_forth() {
+        let local_path = Path::new("/home/alice/docs/notes.txt");
+        let remote_path = mapping().to_remote(local_path).unwrap();
+        assert_eq!(
+            mapping().to_local(&remote_path).as_deref(),
+            Some(local_path)
+        );
+        assert_eq!(
+            RootMapping::default().to_local(local_path).as_deref(),
+            Some(local_path)
+        );
+    }
+}
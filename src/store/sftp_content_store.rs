@@ -0,0 +1,253 @@
+use crate::store::content_store::ContentStore;
+use anyhow::{anyhow, bail, Context};
+use log::{debug, info};
+use ssh2::{CheckResult, ErrorCode, KnownHostFileKind, Session, Sftp};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// SFTP status code of a missing file (`LIBSSH2_FX_NO_SUCH_FILE`)
+const SFTP_NO_SUCH_FILE: i32 = 2;
+const DEFAULT_SSH_PORT: u16 = 22;
+/// Timeout of every SSH operation, so that a dead server cannot block the handlers
+const SSH_TIMEOUT_MS: u32 = 30_000;
+
+/// Contents stored as files on a remote host, over SFTP.
+/// Each file is stored at its synchronized path, under the remote directory.
+/// The connection is opened on first use, and opened again after any error.
+pub struct SftpContentStore {
+    host: String,
+    port: u16,
+    user: String,
+    remote_directory: PathBuf,
+    /// Private key used to authenticate. The SSH agent is used when there is none.
+    identity: Option<PathBuf>,
+    sftp: Mutex<Option<Sftp>>,
+}
+
+impl std::fmt::Debug for SftpContentStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SftpContentStore")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("user", &self.user)
+            .field("remote_directory", &self.remote_directory)
+            .field("identity", &self.identity)
+            .finish()
+    }
+}
+
+impl SftpContentStore {
+    /// Parse a `sftp://user@host[:port]/remote/directory` url
+    pub fn new(url: &str, identity: Option<PathBuf>) -> Result<SftpContentStore, anyhow::Error> {
+        let url = url::Url::parse(url).context("invalid sftp url")?;
+        if url.scheme() != "sftp" {
+            bail!("content url must start with sftp://, got {}", url);
+        }
+        let host = url
+            .host_str()
+            .ok_or_else(|| anyhow!("sftp url has no host: {}", url))?
+            .to_string();
+        if url.username().is_empty() {
+            bail!("sftp url has no user: {}", url);
+        }
+
+        Ok(SftpContentStore {
+            host,
+            port: url.port().unwrap_or(DEFAULT_SSH_PORT),
+            user: url.username().to_string(),
+            remote_directory: PathBuf::from(url.path()),
+            identity,
+            sftp: Mutex::new(None),
+        })
+    }
+
+    fn connect(&self) -> Result<Sftp, anyhow::Error> {
+        debug!(
+            "[sftp_content_store] connecting to {}@{}:{}",
+            self.user, self.host, self.port
+        );
+        let tcp_stream = TcpStream::connect((self.host.as_str(), self.port))
+            .with_context(|| format!("unable to connect to {}:{}", self.host, self.port))?;
+        let mut session = Session::new().context("unable to create the SSH session")?;
+        session.set_timeout(SSH_TIMEOUT_MS);
+        session.set_tcp_stream(tcp_stream);
+        session.handshake().context("SSH handshake failed")?;
+        self.check_host_key(&session)?;
+
+        match &self.identity {
+            Some(identity) => session
+                .userauth_pubkey_file(&self.user, None, identity, None)
+                .with_context(|| {
+                    format!("SSH authentication with {} failed", identity.display())
+                })?,
+            None => session
+                .userauth_agent(&self.user)
+                .context("SSH authentication with the SSH agent failed")?,
+        }
+        if !session.authenticated() {
+            bail!("SSH authentication of {} failed", self.user);
+        }
+
+        let sftp = session
+            .sftp()
+            .context("unable to start the SFTP subsystem")?;
+        info!(
+            "connected to sftp://{}@{}:{}",
+            self.user, self.host, self.port
+        );
+        Ok(sftp)
+    }
+
+    /// The host must be known, as with OpenSSH's StrictHostKeyChecking
+    fn check_host_key(&self, session: &Session) -> Result<(), anyhow::Error> {
+        let known_hosts_path = default_known_hosts_path()?;
+        let mut known_hosts = session
+            .known_hosts()
+            .context("unable to initialize the known hosts")?;
+        known_hosts
+            .read_file(&known_hosts_path, KnownHostFileKind::OpenSSH)
+            .with_context(|| format!("unable to read {}", known_hosts_path.display()))?;
+        let (host_key, _) = session
+            .host_key()
+            .ok_or_else(|| anyhow!("the SSH server sent no host key"))?;
+
+        match known_hosts.check_port(&self.host, self.port, host_key) {
+            CheckResult::Match => Ok(()),
+            CheckResult::NotFound => bail!(
+                "host {} is not in {}. Connect once with ssh to add it.",
+                self.host,
+                known_hosts_path.display()
+            ),
+            CheckResult::Mismatch => {
+                bail!("HOST KEY MISMATCH for {}. Refusing to connect.", self.host)
+            }
+            CheckResult::Failure => bail!("unable to check the host key of {}", self.host),
+        }
+    }
+
+    /// Run an operation on the SFTP connection. The connection is dropped on error,
+    /// so that the next operation reconnects.
+    fn with_sftp<T>(
+        &self,
+        operation: impl FnOnce(&Sftp) -> Result<T, anyhow::Error>,
+    ) -> Result<T, anyhow::Error> {
+        let mut sftp = self.sftp.lock().expect("sftp connection lock poisoned");
+        if sftp.is_none() {
+            *sftp = Some(self.connect()?);
+        }
+        let res = operation(sftp.as_ref().expect("sftp connection was just set"));
+        if res.is_err() {
+            *sftp = None;
+        }
+        res
+    }
+
+    fn to_remote_path(&self, path: &str) -> PathBuf {
+        self.remote_directory.join(path.trim_start_matches('/'))
+    }
+
+    fn ensure_remote_directory_exists(sftp: &Sftp, path: &Path) -> Result<(), anyhow::Error> {
+        let parent_directory = path.parent().context("remote file cannot be /")?;
+        let missing_directories: Vec<&Path> = parent_directory
+            .ancestors()
+            .take_while(|directory| sftp.stat(directory).is_err())
+            .collect();
+        for directory in missing_directories.into_iter().rev() {
+            sftp.mkdir(directory, 0o755).with_context(|| {
+                format!("unable to create remote directory {}", directory.display())
+            })?;
+        }
+        Ok(())
+    }
+
+    fn is_not_found(error: &ssh2::Error) -> bool {
+        error.code() == ErrorCode::SFTP(SFTP_NO_SUCH_FILE)
+    }
+}
+
+fn default_known_hosts_path() -> Result<PathBuf, anyhow::Error> {
+    let home = std::env::var_os("HOME").context("HOME is not set, cannot find known_hosts")?;
+    Ok(PathBuf::from(home).join(".ssh").join("known_hosts"))
+}
+
+impl ContentStore for SftpContentStore {
+    fn backend_name(&self) -> &'static str {
+        "sftp"
+    }
+
+    fn set_content(&self, path: &str, content: &[u8]) -> Result<(), anyhow::Error> {
+        let remote_path = self.to_remote_path(path);
+        debug!("[sftp_content_store] writing {}", remote_path.display());
+        self.with_sftp(|sftp| {
+            SftpContentStore::ensure_remote_directory_exists(sftp, &remote_path)?;
+            let mut file = sftp
+                .create(&remote_path)
+                .with_context(|| format!("unable to create {}", remote_path.display()))?;
+            file.write_all(content)
+                .with_context(|| format!("unable to write {}", remote_path.display()))
+        })
+    }
+
+    fn get_content(&self, path: &str) -> Result<Option<Vec<u8>>, anyhow::Error> {
+        let remote_path = self.to_remote_path(path);
+        debug!("[sftp_content_store] reading {}", remote_path.display());
+        self.with_sftp(|sftp| {
+            let mut file = match sftp.open(&remote_path) {
+                Err(error) if SftpContentStore::is_not_found(&error) => return Ok(None),
+                Err(error) => {
+                    return Err(error)
+                        .with_context(|| format!("unable to open {}", remote_path.display()))
+                }
+                Ok(file) => file,
+            };
+            let mut content = Vec::new();
+            file.read_to_end(&mut content)
+                .with_context(|| format!("unable to read {}", remote_path.display()))?;
+            Ok(Some(content))
+        })
+    }
+
+    fn rename_content(&self, old_path: &str, new_path: &str) -> Result<(), anyhow::Error> {
+        let old_remote_path = self.to_remote_path(old_path);
+        let new_remote_path = self.to_remote_path(new_path);
+        debug!(
+            "[sftp_content_store] renaming {} to {}",
+            old_remote_path.display(),
+            new_remote_path.display()
+        );
+        self.with_sftp(|sftp| {
+            SftpContentStore::ensure_remote_directory_exists(sftp, &new_remote_path)?;
+            sftp.rename(&old_remote_path, &new_remote_path, None)
+                .with_context(|| {
+                    format!(
+                        "unable to rename {} to {}",
+                        old_remote_path.display(),
+                        new_remote_path.display()
+                    )
+                })
+        })
+    }
+
+    fn remove_content(&self, path: &str) -> Result<(), anyhow::Error> {
+        let remote_path = self.to_remote_path(path);
+        debug!("[sftp_content_store] removing {}", remote_path.display());
+        self.with_sftp(|sftp| match sftp.unlink(&remote_path) {
+            Err(error) if !SftpContentStore::is_not_found(&error) => {
+                Err(error).with_context(|| format!("unable to remove {}", remote_path.display()))
+            }
+            _ => Ok(()),
+        })
+    }
+
+    fn content_size(&self, path: &str) -> Result<u64, anyhow::Error> {
+        let remote_path = self.to_remote_path(path);
+        self.with_sftp(|sftp| {
+            let stat = sftp
+                .stat(&remote_path)
+                .with_context(|| format!("unable to stat {}", remote_path.display()))?;
+            Ok(stat.size.unwrap_or(0))
+        })
+    }
+}
@@ -0,0 +1,110 @@
+use crate::store::content_store::{ContentStore, RedisContentStore};
+use anyhow::{bail, Context};
+use log::debug;
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::path::Path;
+
+/// Points of each shard on the ring, so that the contents are spread evenly
+const VIRTUAL_NODES_PER_SHARD: usize = 128;
+
+/// Spreads the contents over several redis servers, so that the memory of one server does not
+/// cap the size of the synchronized tree. The hashes, the list of files and the events stay on
+/// the primary redis.
+///
+/// The files of a directory go to the same shard, picked by consistent hashing of the directory.
+/// Shards are identified by their position in the list: all the instances must list them in the
+/// same order, and new shards are appended, so that only the contents they take over move.
+#[derive(Debug)]
+pub struct ShardedContentStore {
+    shards: Vec<RedisContentStore>,
+    /// Point on the ring -> index of the shard
+    ring: BTreeMap<u64, usize>,
+}
+
+impl ShardedContentStore {
+    pub fn new(shards: Vec<RedisContentStore>) -> Result<ShardedContentStore, anyhow::Error> {
+        if shards.is_empty() {
+            bail!("at least one content shard is required");
+        }
+        let mut ring = BTreeMap::new();
+        for shard in 0..shards.len() {
+            for virtual_node in 0..VIRTUAL_NODES_PER_SHARD {
+                ring.insert(
+                    ShardedContentStore::ring_point(&format!("shard-{}#{}", shard, virtual_node)),
+                    shard,
+                );
+            }
+        }
+        Ok(ShardedContentStore { shards, ring })
+    }
+
+    /// Stable across builds and architectures, as all the instances must agree on it
+    fn ring_point(key: &str) -> u64 {
+        let digest = ring::digest::digest(&ring::digest::SHA256, key.as_bytes());
+        u64::from_be_bytes(
+            digest.as_ref()[..8]
+                .try_into()
+                .expect("SHA-256 digests are longer than 8 bytes"),
+        )
+    }
+
+    /// The shard of the directory of the file: the first point of the ring after its hash
+    fn shard_index(&self, path: &str) -> usize {
+        let directory = Path::new(path)
+            .parent()
+            .map(|directory| directory.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let point = ShardedContentStore::ring_point(&directory);
+        let (_, shard) = self
+            .ring
+            .range(point..)
+            .next()
+            .or_else(|| self.ring.iter().next())
+            .expect("the ring has points of every shard");
+        *shard
+    }
+
+    fn shard(&self, path: &str) -> &RedisContentStore {
+        &self.shards[self.shard_index(path)]
+    }
+}
+
+impl ContentStore for ShardedContentStore {
+    fn backend_name(&self) -> &'static str {
+        "sharded-redis"
+    }
+
+    fn set_content(&self, path: &str, content: &[u8]) -> Result<(), anyhow::Error> {
+        self.shard(path).set_content(path, content)
+    }
+
+    fn get_content(&self, path: &str) -> Result<Option<Vec<u8>>, anyhow::Error> {
+        self.shard(path).get_content(path)
+    }
+
+    /// A file moved to a directory of another shard is copied, then removed from the old one
+    fn rename_content(&self, old_path: &str, new_path: &str) -> Result<(), anyhow::Error> {
+        let (old_shard, new_shard) = (self.shard_index(old_path), self.shard_index(new_path));
+        if old_shard == new_shard {
+            return self.shards[old_shard].rename_content(old_path, new_path);
+        }
+        debug!(
+            "[sharded_content_store] moving content of {} from shard {} to shard {}",
+            old_path, old_shard, new_shard
+        );
+        let content = self.shards[old_shard]
+            .get_content(old_path)?
+            .with_context(|| format!("no content for {} to rename", old_path))?;
+        self.shards[new_shard].set_content(new_path, &content)?;
+        self.shards[old_shard].remove_content(old_path)
+    }
+
+    fn remove_content(&self, path: &str) -> Result<(), anyhow::Error> {
+        self.shard(path).remove_content(path)
+    }
+
+    fn content_size(&self, path: &str) -> Result<u64, anyhow::Error> {
+        self.shard(path).content_size(path)
+    }
+}
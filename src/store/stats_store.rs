@@ -0,0 +1,170 @@
+use crate::event_handler::sync_events::{EventSink, SyncEvent};
+use anyhow::Context;
+use log::error;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// The longest history read, far beyond the age of any statistics, so that the dates stay
+/// within the range of the calendar
+pub const MAX_HISTORY_DAYS: u64 = 100 * 365;
+
+/// The synchronization of one day, in UTC
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DailyStats {
+    /// `YYYY-MM-DD`
+    pub day: String,
+    pub local_changes: u64,
+    pub remote_changes: u64,
+    /// Sizes of the files, before their compression
+    pub bytes_published: u64,
+    pub bytes_applied: u64,
+    pub errors: u64,
+    /// Remote changes of the files whose local change was not published yet
+    pub conflicts: u64,
+}
+
+/// Local SQLite database of the daily statistics of the synchronization, for the capacity
+/// planning of the store and of the network. The instances sharing it add up their counters.
+pub struct StatsStore {
+    connection: Mutex<rusqlite::Connection>,
+}
+
+fn today() -> String {
+    chrono::Utc::now().format("%Y-%m-%d").to_string()
+}
+
+impl StatsStore {
+    pub fn open(path: &Path) -> Result<StatsStore, anyhow::Error> {
+        let connection = rusqlite::Connection::open(path)
+            .with_context(|| format!("unable to open the statistics {}", path.display()))?;
+        connection
+            .busy_timeout(Duration::from_secs(5))
+            .context("unable to set the timeout of the statistics")?;
+        // each event is a transaction: not synced to the disk one by one
+        connection
+            .execute_batch(
+                "PRAGMA journal_mode = WAL;
+                PRAGMA synchronous = NORMAL;
+                CREATE TABLE IF NOT EXISTS daily_stats (
+                    day TEXT PRIMARY KEY,
+                    local_changes INTEGER NOT NULL DEFAULT 0,
+                    remote_changes INTEGER NOT NULL DEFAULT 0,
+                    bytes_published INTEGER NOT NULL DEFAULT 0,
+                    bytes_applied INTEGER NOT NULL DEFAULT 0,
+                    errors INTEGER NOT NULL DEFAULT 0,
+                    conflicts INTEGER NOT NULL DEFAULT 0
+                )",
+            )
+            .context("unable to create the statistics")?;
+        // the statistics created before the conflicts were counted
+        let has_conflicts: bool = connection
+            .query_row(
+                "SELECT COUNT(*) > 0 FROM pragma_table_info('daily_stats') WHERE name = 'conflicts'",
+                [],
+                |row| row.get(0),
+            )
+            .context("unable to read the statistics columns")?;
+        if !has_conflicts {
+            connection
+                .execute(
+                    "ALTER TABLE daily_stats ADD COLUMN conflicts INTEGER NOT NULL DEFAULT 0",
+                    [],
+                )
+                .context("unable to add the conflicts to the statistics")?;
+        }
+        Ok(StatsStore {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    /// The statistics of the last days, the oldest first. The days without synchronization are
+    /// missing.
+    pub fn history(&self, days: u64) -> Result<Vec<DailyStats>, anyhow::Error> {
+        let days = days.min(MAX_HISTORY_DAYS);
+        let since = (chrono::Utc::now() - chrono::Duration::days(days as i64 - 1))
+            .format("%Y-%m-%d")
+            .to_string();
+        let connection = self.connection.lock().expect("statistics lock poisoned");
+        let mut statement = connection
+            .prepare(
+                "SELECT day, local_changes, remote_changes, bytes_published, bytes_applied, errors,
+                     conflicts
+                 FROM daily_stats WHERE day >= ?1 ORDER BY day",
+            )
+            .context("unable to read the statistics")?;
+        let rows = statement
+            .query_map([since], |row| {
+                Ok(DailyStats {
+                    day: row.get(0)?,
+                    local_changes: row.get(1)?,
+                    remote_changes: row.get(2)?,
+                    bytes_published: row.get(3)?,
+                    bytes_applied: row.get(4)?,
+                    errors: row.get(5)?,
+                    conflicts: row.get(6)?,
+                })
+            })
+            .context("unable to read the statistics")?;
+        rows.collect::<Result<Vec<DailyStats>, rusqlite::Error>>()
+            .context("unable to read the statistics")
+    }
+
+    /// Add the counters to the ones of their day
+    fn add(&self, stats: &DailyStats) -> Result<(), anyhow::Error> {
+        self.connection
+            .lock()
+            .expect("statistics lock poisoned")
+            .execute(
+                "INSERT INTO daily_stats
+                     (day, local_changes, remote_changes, bytes_published, bytes_applied, errors,
+                      conflicts)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+                 ON CONFLICT (day) DO UPDATE SET
+                     local_changes = local_changes + excluded.local_changes,
+                     remote_changes = remote_changes + excluded.remote_changes,
+                     bytes_published = bytes_published + excluded.bytes_published,
+                     bytes_applied = bytes_applied + excluded.bytes_applied,
+                     errors = errors + excluded.errors,
+                     conflicts = conflicts + excluded.conflicts",
+                rusqlite::params![
+                    stats.day,
+                    stats.local_changes,
+                    stats.remote_changes,
+                    stats.bytes_published,
+                    stats.bytes_applied,
+                    stats.errors,
+                    stats.conflicts
+                ],
+            )
+            .context("unable to record the statistics")?;
+        Ok(())
+    }
+}
+
+impl EventSink for StatsStore {
+    fn handle(&self, event: &SyncEvent) {
+        let file_size = |path: &Path| std::fs::metadata(path).map_or(0, |metadata| metadata.len());
+        let mut stats = DailyStats {
+            day: today(),
+            ..DailyStats::default()
+        };
+        match event {
+            SyncEvent::LocalChanged { .. } => stats.local_changes = 1,
+            SyncEvent::RemoteChanged { .. } => stats.remote_changes = 1,
+            SyncEvent::Published { path } => stats.bytes_published = file_size(path),
+            SyncEvent::Applied { path } => stats.bytes_applied = file_size(path),
+            SyncEvent::PublishFailed { .. } | SyncEvent::ApplyFailed { .. } => stats.errors = 1,
+            SyncEvent::Conflict { .. } => stats.conflicts = 1,
+            SyncEvent::Watching { .. }
+            | SyncEvent::Unwatched { .. }
+            | SyncEvent::VerificationAlert { .. }
+            | SyncEvent::Removed { .. }
+            | SyncEvent::PeerJoined { .. }
+            | SyncEvent::PeerLeft { .. } => return,
+        }
+        if let Err(error) = self.add(&stats) {
+            error!("unable to count the event {:?}. Error: {:?}", event, error);
+        }
+    }
+}
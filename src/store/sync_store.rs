@@ -0,0 +1,88 @@
+use std::path::{Path, PathBuf};
+
+/// Operations on the remote storage of the synchronized files.
+/// Contents are given and returned compressed, as produced by `LocalFSStore`.
+pub trait SyncStore: Send + 'static {
+    fn new_file(
+        &self,
+        emitter_id: u64,
+        path: PathBuf,
+        content: &[u8],
+        hash: u64,
+    ) -> Result<(), anyhow::Error>;
+
+    fn modified_file(
+        &self,
+        emitter_id: u64,
+        path: PathBuf,
+        content: &[u8],
+        hash: u64,
+    ) -> Result<(), anyhow::Error>;
+
+    fn renamed_file(
+        &self,
+        emitter_id: u64,
+        old_path: PathBuf,
+        new_path: PathBuf,
+    ) -> Result<(), anyhow::Error>;
+
+    fn removed_file(&self, emitter_id: u64, path: PathBuf) -> Result<(), anyhow::Error>;
+
+    /// Ask the peers to upload again the content of a file whose content disappeared
+    fn request_missing_content(&self, emitter_id: u64, path: PathBuf) -> Result<(), anyhow::Error>;
+
+    /// Ask the peers to upload again the content of a file whose fetched content is unusable
+    fn reject_content(
+        &self,
+        emitter_id: u64,
+        path: PathBuf,
+        reason: String,
+    ) -> Result<(), anyhow::Error>;
+
+    /// Claim the upload again of a reported content, so that a single peer answers the report
+    /// instead of all the peers holding the file. The stores without shared state let every
+    /// peer answer.
+    fn claim_upload_again(&self, _path: &Path, _claimer_id: u64) -> Result<bool, anyhow::Error> {
+        Ok(true)
+    }
+
+    /// Whether the removal of the content of the file, as notified by the store, was made by
+    /// this instance. The removal is forgotten once taken. The stores without notifications
+    /// never made any.
+    fn take_own_removal(&self, _path: &Path) -> bool {
+        false
+    }
+
+    fn get_all_remote_files(&self) -> Result<Vec<String>, anyhow::Error>;
+
+    /// The files changed after this unix time, with the unix time of their last change. The
+    /// stores which do not record it know none.
+    fn get_changed_since(&self, _since: u64) -> Result<Vec<(PathBuf, u64)>, anyhow::Error> {
+        Ok(Vec::new())
+    }
+
+    /// Unix time of the last approval of the staged rollout, None when it was never approved
+    fn get_rollout_approval(&self) -> Result<Option<u64>, anyhow::Error> {
+        Ok(None)
+    }
+
+    /// Counter incremented by every change of the files, or None when the store has none
+    fn get_generation(&self) -> Result<Option<u64>, anyhow::Error> {
+        Ok(None)
+    }
+
+    /// Returns the decompressed content, or None when the content does not exist on the store
+    fn get_remote_file_content(&self, path: &Path) -> Result<Option<Vec<u8>>, anyhow::Error>;
+
+    /// Stores may answer from a cache, kept up to date with the `invalidate_*` methods
+    fn get_remote_file_hash(&self, path: &Path) -> Result<u64, anyhow::Error>;
+
+    /// Forget the cached remote hash of a path changed by a peer
+    fn invalidate_cached_hash(&self, _path: &Path) {}
+
+    /// Forget all the cached remote hashes, when events of the peers may have been missed
+    fn invalidate_all_cached_hashes(&self) {}
+
+    /// Size of the content as stored, i.e. compressed
+    fn get_remote_file_compressed_size(&self, path: &Path) -> Result<u64, anyhow::Error>;
+}
@@ -0,0 +1,44 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Hash of the content this instance last published or applied, for each local path. A local
+/// change to this content is not published again: it is the echo of an apply, or a write
+/// leaving the file as it was. Cloning it gives a handle on the same hashes.
+#[derive(Debug, Clone, Default)]
+pub struct SyncedHashes {
+    hashes: Arc<Mutex<HashMap<PathBuf, u64>>>,
+}
+
+impl SyncedHashes {
+    pub fn record(&self, path: &Path, hash: u64) {
+        self.hashes
+            .lock()
+            .expect("synced hashes lock poisoned")
+            .insert(path.to_path_buf(), hash);
+    }
+
+    pub fn forget(&self, path: &Path) {
+        self.hashes
+            .lock()
+            .expect("synced hashes lock poisoned")
+            .remove(path);
+    }
+
+    pub fn rename(&self, old_path: &Path, new_path: &Path) {
+        let mut hashes = self.hashes.lock().expect("synced hashes lock poisoned");
+        match hashes.remove(old_path) {
+            Some(hash) => hashes.insert(new_path.to_path_buf(), hash),
+            None => hashes.remove(new_path),
+        };
+    }
+
+    /// Whether this content of the file is the one last synchronized
+    pub fn is_synced(&self, path: &Path, hash: u64) -> bool {
+        self.hashes
+            .lock()
+            .expect("synced hashes lock poisoned")
+            .get(path)
+            .is_some_and(|synced_hash| *synced_hash == hash)
+    }
+}
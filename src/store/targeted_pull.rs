@@ -0,0 +1,120 @@
+use crate::client::event_bus::EventBus;
+use crate::client::redis_client::RedisPublishPayload;
+use crate::event_handler::file_events;
+use crate::store::namespace::Namespace;
+use anyhow::{anyhow, bail, Context};
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Delay between two checks of the timeout while no acknowledgement arrives
+const TICK: Duration = Duration::from_millis(100);
+
+/// The answer of an instance to a pull
+#[derive(Debug, Clone)]
+pub struct PullAcknowledgement {
+    pub instance_id: u64,
+    /// The paths which failed to apply, and why
+    pub failures: Vec<(PathBuf, String)>,
+}
+
+/// Asks instances to apply the remote state of some paths now, e.g. the deploy hosts fetching
+/// the artifacts a CI job just published, and waits until they acknowledge it
+#[derive(Debug, Clone)]
+pub struct TargetedPull {
+    events: Arc<dyn EventBus>,
+    namespace: Namespace,
+    emitter_id: u64,
+}
+
+impl TargetedPull {
+    pub fn new(events: Arc<dyn EventBus>, namespace: Namespace, emitter_id: u64) -> TargetedPull {
+        TargetedPull {
+            events,
+            namespace,
+            emitter_id,
+        }
+    }
+
+    /// The acknowledgements received within the timeout, in order of reception. The instances
+    /// missing from them did not acknowledge in time.
+    pub fn send(
+        &self,
+        instance_ids: Vec<u64>,
+        paths: Vec<PathBuf>,
+        timeout: Duration,
+    ) -> Result<Vec<PullAcknowledgement>, anyhow::Error> {
+        let request_id: u64 = rand::random();
+        let event_channel = self.namespace.key(file_events::FILE_EVENT);
+        let (sender, receiver) = channel();
+        let events = self.events.clone();
+        let listened_channel = event_channel.clone();
+        // the listener ends once the acknowledgements are not awaited anymore
+        std::thread::Builder::new()
+            .name(String::from("pull acknowledgements"))
+            .spawn(move || {
+                let result = events.listen(&listened_channel, TICK, &mut |msg| {
+                    let payload = msg.map(|msg| RedisPublishPayload::from_bytes(&msg.payload));
+                    let acknowledgement = match payload {
+                        Some(Ok(RedisPublishPayload::PullAcknowledged(
+                            instance_id,
+                            acknowledged_id,
+                            failures,
+                        ))) if acknowledged_id == request_id => Some(PullAcknowledgement {
+                            instance_id,
+                            failures,
+                        }),
+                        _ => None,
+                    };
+                    sender
+                        .send(Ok(acknowledgement))
+                        .map_err(|_| anyhow!("the pull is over"))
+                });
+                if let Err(error) = result {
+                    let _ = sender.send(Err(error));
+                }
+            })
+            .context("unable to create pull acknowledgements thread")?;
+
+        let deadline = Instant::now() + timeout;
+        let mut pending: BTreeSet<u64> = instance_ids.iter().copied().collect();
+        let mut acknowledgements = Vec::new();
+        let mut is_sent = false;
+        while !pending.is_empty() {
+            let received =
+                match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                    Err(RecvTimeoutError::Timeout) => break,
+                    Err(RecvTimeoutError::Disconnected) => {
+                        bail!("stopped listening to the acknowledgements")
+                    }
+                    Ok(received) => received.context("unable to listen to the acknowledgements")?,
+                };
+            // the acknowledgements published before the subscription would be missed
+            if !is_sent {
+                self.events
+                    .publish(
+                        &event_channel,
+                        RedisPublishPayload::PullRequested(
+                            self.emitter_id,
+                            request_id,
+                            instance_ids.clone(),
+                            paths.clone(),
+                        ),
+                    )
+                    .context("unable to send the pull request")?;
+                is_sent = true;
+            }
+            if let Some(acknowledgement) = received {
+                if pending.remove(&acknowledgement.instance_id) {
+                    acknowledgements.push(acknowledgement);
+                }
+            }
+        }
+        if !is_sent {
+            bail!("unable to listen to the acknowledgements within the timeout");
+        }
+        Ok(acknowledgements)
+    }
+}
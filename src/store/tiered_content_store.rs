@@ -0,0 +1,295 @@
+use crate::client::redis_client::RedisClient;
+use crate::store::blob_content_store::BlobStore;
+use crate::store::content_store::{ContentStore, RedisContentStore, CONTENT_KEY_PREFIX};
+use crate::store::fleet_semaphore::{FleetPermit, FleetSemaphore};
+use crate::store::namespace::Namespace;
+use anyhow::{bail, Context};
+use log::{debug, error, info};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Sorted set of the paths, scored by the last time their content was written or read
+const CONTENT_ACCESS_KEY: &str = "content-access";
+/// Hash of the paths whose content is in the cold tier -> id of their blob
+const COLD_CONTENTS_KEY: &str = "cold-contents";
+/// Name of the semaphore letting one instance of the fleet at a time run the tiering pass
+pub const TIERING_SEMAPHORE: &str = "cold-tiering";
+/// Delay between two tiering passes
+const TIERING_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Move the content to the cold tier, unless it changed since it was copied there.
+/// KEYS[1]: content key, KEYS[2]: cold contents hash
+/// ARGV: SHA-1 of the copied content, path, blob id
+const DEMOTE_SCRIPT: &str = r"
+local content = redis.call('GET', KEYS[1])
+if not content or redis.sha1hex(content) ~= ARGV[1] then
+    return 0
+end
+redis.call('DEL', KEYS[1])
+redis.call('HSET', KEYS[2], ARGV[2], ARGV[3])
+return 1
+";
+
+/// Move the cold content of a path to another path.
+/// KEYS[1]: cold contents hash
+/// ARGV: old path, new path
+const RENAME_COLD_SCRIPT: &str = r"
+local blob_id = redis.call('HGET', KEYS[1], ARGV[1])
+if not blob_id then
+    return 0
+end
+redis.call('HSET', KEYS[1], ARGV[2], blob_id)
+redis.call('HDEL', KEYS[1], ARGV[1])
+return 1
+";
+
+/// When to move a content to the cold tier
+#[derive(Debug, Clone)]
+pub struct TieringPolicy {
+    /// Contents neither written nor read for this long are cold
+    pub cold_after: Duration,
+    /// Smaller contents stay in redis, where they cost less than a blob
+    pub min_size: u64,
+}
+
+/// Contents stored in redis, and moved to a cheaper blob store (local directory or S3) once
+/// they are large and rarely accessed. The hashes, the list of files and the access times stay
+/// in redis. Reading a cold content fetches it from the blob store, and writing it brings it
+/// back in redis.
+///
+/// Only the contents written or read since the tiering was enabled have an access time, and
+/// can become cold.
+#[derive(Debug)]
+pub struct TieredContentStore {
+    client: RedisClient,
+    namespace: Namespace,
+    hot: RedisContentStore,
+    cold: Box<dyn BlobStore>,
+    policy: TieringPolicy,
+}
+
+impl TieredContentStore {
+    pub fn new(
+        client: RedisClient,
+        namespace: Namespace,
+        cold: Box<dyn BlobStore>,
+        policy: TieringPolicy,
+    ) -> TieredContentStore {
+        TieredContentStore {
+            hot: RedisContentStore::new(client.clone(), namespace.clone()),
+            client,
+            namespace,
+            cold,
+            policy,
+        }
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("system time is before the unix epoch")
+            .as_secs()
+    }
+
+    fn touch(&self, path: &str) -> Result<(), anyhow::Error> {
+        self.client
+            .zadd(
+                &self.namespace.key(CONTENT_ACCESS_KEY),
+                TieredContentStore::now(),
+                path,
+            )
+            .context("unable to record the access time of the content")
+    }
+
+    fn cold_blob_id(&self, path: &str) -> Result<Option<String>, anyhow::Error> {
+        self.client
+            .hget(&self.namespace.key(COLD_CONTENTS_KEY), path)
+            .context("unable to read the cold tier pointer from redis server")
+    }
+
+    /// The cold blob is not referenced anymore. Failing to delete it only wastes space.
+    fn remove_cold_content(&self, path: &str) -> Result<(), anyhow::Error> {
+        let blob_id = match self.cold_blob_id(path)? {
+            None => return Ok(()),
+            Some(blob_id) => blob_id,
+        };
+        self.client
+            .hdel(&self.namespace.key(COLD_CONTENTS_KEY), path)
+            .context("unable to remove the cold tier pointer from redis server")?;
+        debug!(
+            "[tiered_content_store] deleting cold blob {} of {}",
+            blob_id, path
+        );
+        if let Err(error) = self.cold.delete(&blob_id) {
+            error!(
+                "unable to delete the cold blob {} of {}: {:?}",
+                blob_id, path, error
+            );
+        }
+        Ok(())
+    }
+
+    /// Copy the content to the cold tier, then drop it from redis if it did not change meanwhile
+    fn demote(&self, path: &str) -> Result<bool, anyhow::Error> {
+        let content = match self.hot.get_content(path)? {
+            // already cold, or removed
+            None => return Ok(false),
+            Some(content) => content,
+        };
+        if (content.len() as u64) < self.policy.min_size {
+            return Ok(false);
+        }
+        let blob_id = format!("{:032x}", rand::random::<u128>());
+        debug!(
+            "[tiered_content_store] moving {} to cold blob {}",
+            path, blob_id
+        );
+        self.cold
+            .put(&blob_id, &content)
+            .with_context(|| format!("unable to write the cold blob of {}", path))?;
+        let content_sha1 = ring::digest::digest(&ring::digest::SHA1_FOR_LEGACY_USE_ONLY, &content)
+            .as_ref()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        let is_demoted = self.client.eval(
+            DEMOTE_SCRIPT,
+            &[
+                &self
+                    .namespace
+                    .key(&format!("{}{}", CONTENT_KEY_PREFIX, path)),
+                &self.namespace.key(COLD_CONTENTS_KEY),
+            ],
+            &[content_sha1, path.to_string(), blob_id.clone()],
+        );
+        if is_demoted.as_ref().ok() != Some(&1) {
+            // written meanwhile: it is not cold anymore
+            if let Err(error) = self.cold.delete(&blob_id) {
+                error!(
+                    "unable to delete the unused cold blob {} of {}: {:?}",
+                    blob_id, path, error
+                );
+            }
+        }
+        Ok(is_demoted.context("unable to move the content to the cold tier")? == 1)
+    }
+
+    /// Move the contents idle for too long to the cold tier
+    pub fn demote_idle_contents(&self, permit: &FleetPermit<'_>) -> Result<usize, anyhow::Error> {
+        let idle_paths = self
+            .client
+            .zrange_up_to_score(
+                &self.namespace.key(CONTENT_ACCESS_KEY),
+                TieredContentStore::now().saturating_sub(self.policy.cold_after.as_secs()),
+            )
+            .context("unable to list the idle contents")?;
+        let mut demoted = 0;
+        for path in idle_paths {
+            permit.keep_alive();
+            match self.demote(&path) {
+                Ok(true) => demoted += 1,
+                Ok(false) => (),
+                Err(error) => error!(
+                    "unable to move the content of {} to the cold tier: {:?}",
+                    path, error
+                ),
+            }
+        }
+        Ok(demoted)
+    }
+
+    /// Run the tiering pass regularly, on one instance of the fleet at a time
+    pub fn demote_periodically(
+        self: Arc<Self>,
+        semaphore: FleetSemaphore,
+    ) -> Result<JoinHandle<()>, anyhow::Error> {
+        let handle = std::thread::Builder::new()
+            .name(String::from("cold tiering"))
+            .spawn(move || loop {
+                std::thread::sleep(TIERING_INTERVAL);
+                let result = semaphore
+                    .acquire()
+                    .and_then(|permit| self.demote_idle_contents(&permit));
+                match result {
+                    Ok(0) => debug!("[tiered_content_store] no content to move to the cold tier"),
+                    Ok(demoted) => info!("moved {} contents to the cold tier", demoted),
+                    Err(error) => error!("unable to run the cold tiering: {:?}", error),
+                }
+            })
+            .context("unable to create cold tiering thread")?;
+        Ok(handle)
+    }
+}
+
+impl ContentStore for TieredContentStore {
+    fn backend_name(&self) -> &'static str {
+        "tiered-redis"
+    }
+
+    /// A written content is hot again
+    fn set_content(&self, path: &str, content: &[u8]) -> Result<(), anyhow::Error> {
+        self.hot.set_content(path, content)?;
+        self.touch(path)?;
+        self.remove_cold_content(path)
+    }
+
+    fn get_content(&self, path: &str) -> Result<Option<Vec<u8>>, anyhow::Error> {
+        let content = match self.hot.get_content(path)? {
+            Some(content) => Some(content),
+            None => match self.cold_blob_id(path)? {
+                None => return Ok(None),
+                Some(blob_id) => {
+                    debug!(
+                        "[tiered_content_store] reading {} from cold blob {}",
+                        path, blob_id
+                    );
+                    self.cold
+                        .get(&blob_id)
+                        .with_context(|| format!("unable to read the cold blob of {}", path))?
+                }
+            },
+        };
+        self.touch(path)?;
+        Ok(content)
+    }
+
+    fn rename_content(&self, old_path: &str, new_path: &str) -> Result<(), anyhow::Error> {
+        let is_hot = self.hot.content_size(old_path)? > 0;
+        if is_hot {
+            self.hot.rename_content(old_path, new_path)?;
+        }
+        let is_cold = self
+            .client
+            .eval(
+                RENAME_COLD_SCRIPT,
+                &[&self.namespace.key(COLD_CONTENTS_KEY)],
+                &[old_path.to_string(), new_path.to_string()],
+            )
+            .context("unable to rename the cold tier pointer")?
+            == 1;
+        if !is_hot && !is_cold {
+            bail!("no content for {} to rename", old_path);
+        }
+        self.client
+            .zrem(&self.namespace.key(CONTENT_ACCESS_KEY), old_path)?;
+        self.touch(new_path)
+    }
+
+    fn remove_content(&self, path: &str) -> Result<(), anyhow::Error> {
+        self.hot.remove_content(path)?;
+        self.remove_cold_content(path)?;
+        self.client
+            .zrem(&self.namespace.key(CONTENT_ACCESS_KEY), path)
+    }
+
+    fn content_size(&self, path: &str) -> Result<u64, anyhow::Error> {
+        match self.hot.content_size(path)? {
+            0 => match self.cold_blob_id(path)? {
+                None => Ok(0),
+                Some(blob_id) => self.cold.size(&blob_id),
+            },
+            size => Ok(size),
+        }
+    }
+}
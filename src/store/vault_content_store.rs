@@ -0,0 +1,134 @@
+use crate::client::vault_client::VaultClient;
+use crate::event_handler::path_filter::PathFilter;
+use crate::store::content_store::ContentStore;
+use crate::store::local_fs_store::LocalFSStore;
+use anyhow::{anyhow, Context};
+use log::debug;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Prefix of the contents which are a reference to a Vault secret, followed by the secret path
+const VAULT_REFERENCE_PREFIX: &[u8] = b"vault-secret:";
+
+/// Keeps the contents of the secret files in Vault. The wrapped store only holds a reference
+/// to the secret, resolved by the receivers when they apply the file.
+/// The other contents go to the wrapped store unchanged.
+#[derive(Debug)]
+pub struct VaultContentStore {
+    inner: Arc<dyn ContentStore>,
+    /// None when this instance has no access to Vault: the secrets cannot be applied
+    vault: Option<VaultClient>,
+    /// Files whose content is stored in Vault
+    secrets: PathFilter,
+    /// Prefix of the secret paths, under the KV mount
+    path_prefix: String,
+}
+
+impl VaultContentStore {
+    pub fn new(
+        inner: Arc<dyn ContentStore>,
+        vault: Option<VaultClient>,
+        secrets: PathFilter,
+        path_prefix: String,
+    ) -> VaultContentStore {
+        VaultContentStore {
+            inner,
+            vault,
+            secrets,
+            path_prefix: path_prefix.trim_matches('/').to_string(),
+        }
+    }
+
+    fn vault(&self, path: &str) -> Result<&VaultClient, anyhow::Error> {
+        self.vault.as_ref().ok_or_else(|| {
+            anyhow!(
+                "{} is a secret stored in Vault, but no --vault-addr is given",
+                path
+            )
+        })
+    }
+
+    fn to_secret_path(&self, path: &str) -> String {
+        format!("{}/{}", self.path_prefix, path.trim_start_matches('/'))
+    }
+
+    /// The secret path when the content is a reference to a Vault secret
+    fn secret_reference(content: &[u8]) -> Option<String> {
+        content
+            .strip_prefix(VAULT_REFERENCE_PREFIX)
+            .map(|secret_path| String::from_utf8_lossy(secret_path).into_owned())
+    }
+}
+
+impl ContentStore for VaultContentStore {
+    /// Same as the wrapped store: the peers without Vault access share the namespace
+    fn backend_name(&self) -> &'static str {
+        self.inner.backend_name()
+    }
+
+    fn set_content(&self, path: &str, content: &[u8]) -> Result<(), anyhow::Error> {
+        if !self.secrets.matches(Path::new(path)) {
+            return self.inner.set_content(path, content);
+        }
+        let secret = String::from_utf8(LocalFSStore::decompress(content)?)
+            .with_context(|| format!("secret file {} is not valid UTF-8", path))?;
+        let secret_path = self.to_secret_path(path);
+        debug!("[vault_content_store] writing {} to {}", path, secret_path);
+        self.vault(path)?
+            .write_secret(&secret_path, &secret)
+            .with_context(|| format!("unable to write the secret {} to vault", secret_path))?;
+
+        let mut reference = VAULT_REFERENCE_PREFIX.to_vec();
+        reference.extend_from_slice(secret_path.as_bytes());
+        self.inner.set_content(path, &reference)
+    }
+
+    fn get_content(&self, path: &str) -> Result<Option<Vec<u8>>, anyhow::Error> {
+        let content = match self.inner.get_content(path)? {
+            None => return Ok(None),
+            Some(content) => content,
+        };
+        let secret_path = match VaultContentStore::secret_reference(&content) {
+            None => return Ok(Some(content)),
+            Some(secret_path) => secret_path,
+        };
+        debug!(
+            "[vault_content_store] reading {} from {}",
+            path, secret_path
+        );
+        let secret = self
+            .vault(path)?
+            .read_secret(&secret_path)
+            .with_context(|| format!("unable to read the secret {} from vault", secret_path))?;
+        Ok(secret.map(|secret| LocalFSStore::compress(secret.as_bytes())))
+    }
+
+    /// The renamed reference keeps pointing to the secret of the old path
+    fn rename_content(&self, old_path: &str, new_path: &str) -> Result<(), anyhow::Error> {
+        self.inner.rename_content(old_path, new_path)
+    }
+
+    fn remove_content(&self, path: &str) -> Result<(), anyhow::Error> {
+        let stored_content = self.inner.get_content(path)?;
+        let secret_path = stored_content
+            .as_deref()
+            .and_then(VaultContentStore::secret_reference);
+        if let Some(secret_path) = secret_path {
+            debug!("[vault_content_store] deleting the secret {}", secret_path);
+            self.vault(path)?
+                .delete_secret(&secret_path)
+                .with_context(|| format!("unable to delete the secret {} in vault", secret_path))?;
+        }
+        self.inner.remove_content(path)
+    }
+
+    /// Size of the stored reference, for the secrets
+    fn content_size(&self, path: &str) -> Result<u64, anyhow::Error> {
+        self.inner.content_size(path)
+    }
+
+    /// The secrets never leave Vault
+    fn is_inlinable(&self, path: &str) -> bool {
+        !self.secrets.matches(Path::new(path)) && self.inner.is_inlinable(path)
+    }
+}
@@ -0,0 +1,166 @@
+use crate::client::redis_client::RedisClient;
+use crate::store::namespace::Namespace;
+use anyhow::Context;
+use log::{debug, error, info, warn};
+use std::fs::OpenOptions;
+use std::io::{ErrorKind, Write};
+use std::path::Path;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// File written at the root of a watched path, holding the id of the volume. The instances
+/// mounting the same shared volume read the same id.
+pub const VOLUME_MARKER: &str = ".fssync-volume";
+
+/// A crashed holder releases the volume after this delay, a standby instance then takes over
+const LEASE: Duration = Duration::from_secs(15);
+/// Delay between two renewals, or two acquisition attempts of the standby instances
+const RENEWAL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Take the lease when it is free, or extend it when we hold it.
+/// KEYS[1]: holder of the lease
+/// ARGV: holder, lease (ms)
+const TRY_ACQUIRE_SCRIPT: &str = r"
+local holder = redis.call('GET', KEYS[1])
+if not holder or holder == ARGV[1] then
+    redis.call('SET', KEYS[1], ARGV[1], 'PX', ARGV[2])
+    return 1
+end
+return 0
+";
+
+/// The id of the volume of this root, from its marker, which is written when missing
+pub fn volume_id(root: &Path) -> Result<String, anyhow::Error> {
+    let marker_path = root.join(VOLUME_MARKER);
+    let volume_id = format!("{:016x}", rand::random::<u64>());
+    match OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&marker_path)
+    {
+        Ok(mut marker) => {
+            marker
+                .write_all(volume_id.as_bytes())
+                .and_then(|_| marker.sync_all())
+                .with_context(|| format!("unable to write {}", marker_path.display()))?;
+            info!("volume marker {} created", marker_path.display());
+            Ok(volume_id)
+        }
+        // written by another instance mounting the volume, or by a previous run
+        Err(error) if error.kind() == ErrorKind::AlreadyExists => {
+            let volume_id = std::fs::read_to_string(&marker_path)
+                .with_context(|| format!("unable to read {}", marker_path.display()))?;
+            Ok(volume_id.trim().to_string())
+        }
+        Err(error) => {
+            Err(error).with_context(|| format!("unable to create {}", marker_path.display()))
+        }
+    }
+}
+
+/// Elects the single instance watching a volume shared by several instances, e.g. a volume
+/// mounted by several pods: the others would publish each change once per instance.
+#[derive(Debug, Clone)]
+pub struct VolumeLease {
+    client: RedisClient,
+    namespace: Namespace,
+    volume_id: String,
+    holder_id: u64,
+}
+
+impl VolumeLease {
+    pub fn new(
+        client: RedisClient,
+        namespace: Namespace,
+        volume_id: String,
+        holder_id: u64,
+    ) -> VolumeLease {
+        VolumeLease {
+            client,
+            namespace,
+            volume_id,
+            holder_id,
+        }
+    }
+
+    fn try_acquire(&self) -> Result<bool, anyhow::Error> {
+        let acquired = self
+            .client
+            .eval(
+                TRY_ACQUIRE_SCRIPT,
+                &[&self.namespace.key(&format!("volume:{}", self.volume_id))],
+                &[self.holder_id.to_string(), LEASE.as_millis().to_string()],
+            )
+            .with_context(|| {
+                format!(
+                    "unable to send the redis command to acquire the lease of the volume {}",
+                    self.volume_id
+                )
+            })?;
+        Ok(acquired == 1)
+    }
+
+    /// Wait for the lease in the background, then start watching the volume, and keep the lease
+    /// until the process exits. The process exits when the lease is lost, as another instance
+    /// may be watching the volume then.
+    pub fn hold(
+        self,
+        start_watching: impl FnOnce() -> Result<(), anyhow::Error> + Send + 'static,
+    ) -> Result<JoinHandle<()>, anyhow::Error> {
+        let handle = std::thread::Builder::new()
+            .name(String::from("volume lease"))
+            .spawn(move || {
+                let mut start_watching = Some(start_watching);
+                let mut last_renewal: Option<Instant> = None;
+                let mut is_standing_by = false;
+                loop {
+                    match self.try_acquire() {
+                        Ok(true) => {
+                            if let Some(start_watching) = start_watching.take() {
+                                info!(
+                                    "this instance watches the volume {}",
+                                    self.volume_id
+                                );
+                                if let Err(error) = start_watching() {
+                                    error!("unable to watch the volume. Error: {:?}", error);
+                                    std::process::exit(1);
+                                }
+                            }
+                            last_renewal = Some(Instant::now());
+                        }
+                        Ok(false) if last_renewal.is_some() => {
+                            error!(
+                                "another instance took the lease of the volume {}: exiting, so that a single instance watches it",
+                                self.volume_id
+                            );
+                            std::process::exit(1);
+                        }
+                        Ok(false) if !is_standing_by => {
+                            info!(
+                                "another instance watches the volume {}: only applying the remote files until it stops",
+                                self.volume_id
+                            );
+                            is_standing_by = true;
+                        }
+                        Ok(false) => debug!("[volume_lease] standing by"),
+                        Err(error) => match last_renewal {
+                            Some(last_renewal) if last_renewal.elapsed() >= LEASE => {
+                                error!(
+                                    "the lease of the volume {} expired: exiting, so that a single instance watches it. Error: {:?}",
+                                    self.volume_id, error
+                                );
+                                std::process::exit(1);
+                            }
+                            _ => warn!(
+                                "unable to renew the lease of the volume {}. Error: {:?}",
+                                self.volume_id, error
+                            ),
+                        },
+                    }
+                    std::thread::sleep(RENEWAL_INTERVAL);
+                }
+            })
+            .context("unable to create volume lease thread")?;
+        Ok(handle)
+    }
+}
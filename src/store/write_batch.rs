@@ -0,0 +1,233 @@
+use crate::store::local_fs_store::LocalFSStore;
+use anyhow::{bail, Context};
+use log::debug;
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// A batch is flushed when it holds this many changes, or when its oldest change is this old
+const MAX_BATCH_CHANGES: usize = 512;
+const MAX_BATCH_AGE: Duration = Duration::from_secs(1);
+
+/// When the applied changes are flushed to the disk
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Durability {
+    /// Leave it to the OS. A crash may lose the last changes applied.
+    #[default]
+    None,
+    /// Flush the changes by batches, with one sync per filesystem (or per file and directory
+    /// where the whole filesystem cannot be synced). A crash may lose the last batch.
+    Batch,
+    /// Flush every file, and its directory, before going on
+    File,
+}
+
+impl std::str::FromStr for Durability {
+    type Err = anyhow::Error;
+
+    fn from_str(durability: &str) -> Result<Durability, anyhow::Error> {
+        match durability {
+            "none" => Ok(Durability::None),
+            "batch" => Ok(Durability::Batch),
+            "file" => Ok(Durability::File),
+            _ => bail!("durability must be none, batch or file, got {}", durability),
+        }
+    }
+}
+
+/// The changes applied on the local fs, and not flushed to the disk yet.
+/// A file changed several times in a batch is flushed once.
+#[derive(Debug, Default)]
+pub struct WriteBatch {
+    durability: Durability,
+    /// Write the files open in another program next to them, then rename them over
+    replace_locked_files: bool,
+    files: BTreeSet<PathBuf>,
+    directories: BTreeSet<PathBuf>,
+    /// When the oldest change of the batch was applied
+    started: Option<Instant>,
+}
+
+impl WriteBatch {
+    pub fn new(durability: Durability, replace_locked_files: bool) -> WriteBatch {
+        WriteBatch {
+            durability,
+            replace_locked_files,
+            ..WriteBatch::default()
+        }
+    }
+
+    /// On Windows, a file open in another program without write sharing cannot be written, but
+    /// it can be replaced when the program shares its deletion, as most editors and viewers do
+    pub fn write_file(&mut self, path: &Path, contents: Vec<u8>) -> Result<(), anyhow::Error> {
+        match self.write(path, &contents) {
+            Err(error)
+                if self.replace_locked_files && LocalFSStore::is_sharing_violation(&error) =>
+            {
+                debug!(
+                    "[write_batch] {} is open in another program, replacing it",
+                    path.display()
+                );
+                let replacement = LocalFSStore::replacement_path(path);
+                self.write(&replacement, &contents)?;
+                let result = self.rename_file(&replacement, path);
+                if result.is_err() {
+                    // written again by the next attempt
+                    let _ = std::fs::remove_file(&replacement);
+                }
+                result
+            }
+            result => result,
+        }
+    }
+
+    fn write(&mut self, path: &Path, contents: &[u8]) -> Result<(), anyhow::Error> {
+        if self.durability == Durability::None {
+            return LocalFSStore::write_file(path, contents);
+        }
+        debug!("[write_batch] writing file {}", path.display());
+        LocalFSStore::ensure_directory_exists(path)?;
+        let mut file = File::create(path)
+            .with_context(|| format!("unable to create on local fs the file {}", path.display()))?;
+        file.write_all(contents)
+            .with_context(|| format!("unable to write on local fs the file {}", path.display()))?;
+        if self.durability == Durability::File {
+            file.sync_all()
+                .with_context(|| format!("unable to flush the file {}", path.display()))?;
+            return sync_parent_directory(path);
+        }
+        self.files.insert(path.to_path_buf());
+        self.changed_directory(path);
+        Ok(())
+    }
+
+    pub fn remove_file(&mut self, path: &Path) -> Result<(), anyhow::Error> {
+        LocalFSStore::remove_file(path)?;
+        self.files.remove(path);
+        match self.durability {
+            Durability::None => Ok(()),
+            Durability::Batch => {
+                self.changed_directory(path);
+                Ok(())
+            }
+            Durability::File => sync_parent_directory(path),
+        }
+    }
+
+    pub fn write_link(&mut self, path: &Path, target: &Path) -> Result<(), anyhow::Error> {
+        LocalFSStore::write_link(path, target)?;
+        self.files.remove(path);
+        match self.durability {
+            Durability::None => Ok(()),
+            Durability::Batch => {
+                self.changed_directory(path);
+                Ok(())
+            }
+            Durability::File => sync_parent_directory(path),
+        }
+    }
+
+    pub fn rename_file(&mut self, old: &Path, new: &Path) -> Result<(), anyhow::Error> {
+        LocalFSStore::rename_file(old, new)?;
+        match self.durability {
+            Durability::None => Ok(()),
+            Durability::Batch => {
+                if self.files.remove(old) {
+                    self.files.insert(new.to_path_buf());
+                }
+                self.changed_directory(old);
+                self.changed_directory(new);
+                Ok(())
+            }
+            Durability::File => sync_parent_directory(old).and_then(|_| sync_parent_directory(new)),
+        }
+    }
+
+    /// True when the batch is big or old enough to be flushed
+    pub fn is_due(&self) -> bool {
+        self.files.len() + self.directories.len() >= MAX_BATCH_CHANGES
+            || self
+                .started
+                .is_some_and(|started| started.elapsed() >= MAX_BATCH_AGE)
+    }
+
+    /// Flush the changes of the batch to the disk
+    pub fn flush(&mut self) -> Result<(), anyhow::Error> {
+        if self.started.is_none() {
+            return Ok(());
+        }
+        debug!(
+            "[write_batch] flushing {} files in {} directories",
+            self.files.len(),
+            self.directories.len()
+        );
+        let files = std::mem::take(&mut self.files);
+        let directories = std::mem::take(&mut self.directories);
+        self.started = None;
+        sync_all(&files, &directories)
+    }
+
+    fn changed_directory(&mut self, path: &Path) {
+        if let Some(directory) = path.parent() {
+            self.directories.insert(directory.to_path_buf());
+        }
+        self.started.get_or_insert_with(Instant::now);
+    }
+}
+
+fn sync_parent_directory(path: &Path) -> Result<(), anyhow::Error> {
+    let directory = path.parent().context("file cannot be /")?;
+    File::open(directory)
+        .and_then(|directory| directory.sync_all())
+        .with_context(|| format!("unable to flush the directory {}", directory.display()))
+}
+
+/// One syncfs per filesystem: it flushes the files and the directories at once
+#[cfg(target_os = "linux")]
+fn sync_all(
+    _files: &BTreeSet<PathBuf>,
+    directories: &BTreeSet<PathBuf>,
+) -> Result<(), anyhow::Error> {
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::io::AsRawFd;
+
+    let mut synced_devices = BTreeSet::new();
+    for directory in directories {
+        // the directory may have been removed since
+        let directory_file = match File::open(directory) {
+            Err(_) => continue,
+            Ok(directory_file) => directory_file,
+        };
+        let device = directory_file.metadata()?.dev();
+        if !synced_devices.insert(device) {
+            continue;
+        }
+        if unsafe { libc::syncfs(directory_file.as_raw_fd()) } != 0 {
+            return Err(std::io::Error::last_os_error()).with_context(|| {
+                format!("unable to flush the filesystem of {}", directory.display())
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn sync_all(
+    files: &BTreeSet<PathBuf>,
+    directories: &BTreeSet<PathBuf>,
+) -> Result<(), anyhow::Error> {
+    for file in files {
+        // the file may have been removed since
+        if let Ok(file) = File::open(file) {
+            file.sync_all()?;
+        }
+    }
+    for directory in directories {
+        if let Ok(directory) = File::open(directory) {
+            directory.sync_all()?;
+        }
+    }
+    Ok(())
+}